@@ -0,0 +1,151 @@
+use arrayvec::ArrayVec;
+use crate::elm_commands::{HexDigits, PidCommand};
+use crate::obd_protocol::FrameFormat;
+
+/// Most signals taken from a single broadcast frame
+pub const MAX_SIGNALS_PER_FRAME: usize = 4;
+/// "ATCRA", up to 8 digits (a 29 bit ID), `\r`
+const MAX_RECEIVE_FILTER_COMMAND_LEN: usize = 14;
+/// "STFAP", 8 digit ID, `,`, 8 digit mask, `\r`
+const MAX_PASS_FILTER_COMMAND_LEN: usize = 23;
+
+/// A value an ECU broadcasts on the CAN bus by itself, without being asked.
+/// It's read from `len` bytes (big endian) at `start` in the data of frames with ID `can_id`, as `raw * scale + offset`
+pub struct BroadcastSignal {
+    /// The PID this stands in for: it isn't polled while the bus is monitored, and the value is sent to main
+    /// as the same `Datum` the PID would give
+    pub pid: &'static PidCommand,
+    /// 11 or 29 bit, like the frame format of the session
+    pub can_id: u32,
+    pub start: usize,
+    pub len: usize,
+    pub scale: f64,
+    pub offset: f64,
+}
+
+impl BroadcastSignal {
+    pub fn replaces(&self, pid: &PidCommand) -> bool {
+        self.pid.service == pid.service && self.pid.pid == pid.pid
+    }
+
+    /// The value in `frame` (header included, as printed by `ATMA` with headers on), `None` if the frame
+    /// has another ID or is too short
+    pub fn decode(&self, frame: &[u8], frame_format: FrameFormat) -> Option<f64> {
+        let header = frame.get(..frame_format.header_len())?;
+        let data = &frame[header.len()..];
+        if big_endian(header) != self.can_id {
+            return None
+        }
+        let bytes = data.get(self.start..self.start + self.len)?;
+        Some(big_endian(bytes) as f64 * self.scale + self.offset)
+    }
+}
+
+fn big_endian(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0u32, |value, byte| value << 8 | *byte as u32)
+}
+
+/// `ATCRA` with the IDs of every signal, ex: `ATCRA2C4\r`. Digits that differ between the IDs are `X`,
+/// which the ELM takes as "any digit", so some other IDs get through too and are dropped by `decode`
+pub fn receive_filter_command(signals: &[BroadcastSignal], frame_format: FrameFormat) -> ArrayVec<u8, MAX_RECEIVE_FILTER_COMMAND_LEN> {
+    let digits: usize = match frame_format {
+        FrameFormat::Can29Bit => 8,
+        _ => 3,
+    };
+    let mut command: ArrayVec<u8, MAX_RECEIVE_FILTER_COMMAND_LEN> = ArrayVec::new();
+    command.extend(*b"ATCRA");
+    for digit in (0..digits).rev() {
+        let mut values = signals.iter().map(|signal| (signal.can_id >> (digit * 4)) as u8 & 0x0f);
+        let first = values.next().unwrap_or(0);
+        if values.all(|value| value == first) {
+            command.push(HexDigits::from_val(first) as u8);
+        } else {
+            command.push(b'X');
+        }
+    }
+    command.push(b'\r');
+    command
+}
+
+/// The STN's version of `receive_filter_command`: one exact `STFAP` pass filter per ID (ex: `STFAP2C4,7FF\r`),
+/// so only the frames that are decoded come through. IDs several signals share get a single filter
+pub fn pass_filter_commands(signals: &[BroadcastSignal], frame_format: FrameFormat) -> impl Iterator<Item = ArrayVec<u8, MAX_PASS_FILTER_COMMAND_LEN>> + '_ {
+    let (digits, mask) = match frame_format {
+        FrameFormat::Can29Bit => (8, 0x1fff_ffff),
+        _ => (3, 0x7ff),
+    };
+    signals.iter()
+        .enumerate()
+        .filter(|(index, signal)| signals[..*index].iter().all(|earlier| earlier.can_id != signal.can_id))
+        .map(move |(_, signal)| {
+            let mut command: ArrayVec<u8, MAX_PASS_FILTER_COMMAND_LEN> = ArrayVec::new();
+            command.extend(*b"STFAP");
+            push_hex(&mut command, signal.can_id, digits);
+            command.push(b',');
+            push_hex(&mut command, mask, digits);
+            command.push(b'\r');
+            command
+        })
+}
+
+fn push_hex<const N: usize>(command: &mut ArrayVec<u8, N>, value: u32, digits: usize) {
+    for digit in (0..digits).rev() {
+        command.push(HexDigits::from_val((value >> (digit * 4)) as u8) as u8);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::elm_commands::{ENGINE_COOLANT_TEMP_PID, ENGINE_RPM_PID};
+    use super::*;
+
+    const RPM_SIGNAL: BroadcastSignal = BroadcastSignal {
+        pid: &ENGINE_RPM_PID,
+        can_id: 0x2c4,
+        start: 0,
+        len: 2,
+        scale: 1.0,
+        offset: 0.0,
+    };
+    const COOLANT_SIGNAL: BroadcastSignal = BroadcastSignal {
+        pid: &ENGINE_COOLANT_TEMP_PID,
+        can_id: 0x3b7,
+        start: 3,
+        len: 1,
+        scale: 1.0,
+        offset: -40.0,
+    };
+
+    #[test]
+    fn test_decode() {
+        // 11 bit IDs are 2 bytes
+        let frame = [0x02, 0xc4, 0x07, 0xd0, 0x00, 0x5a];
+        assert_eq!(RPM_SIGNAL.decode(&frame, FrameFormat::Can11Bit), Some(2000.0));
+        assert_eq!(COOLANT_SIGNAL.decode(&frame, FrameFormat::Can11Bit), None);
+        assert_eq!(COOLANT_SIGNAL.decode(&[0x03, 0xb7, 0x00, 0x00, 0x00, 0x5a], FrameFormat::Can11Bit), Some(50.0));
+        // too short for the signal
+        assert_eq!(COOLANT_SIGNAL.decode(&[0x03, 0xb7, 0x00], FrameFormat::Can11Bit), None);
+        assert!(RPM_SIGNAL.replaces(&ENGINE_RPM_PID));
+        assert!(!RPM_SIGNAL.replaces(&ENGINE_COOLANT_TEMP_PID));
+    }
+
+    #[test]
+    fn test_receive_filter_command() {
+        assert_eq!(receive_filter_command(&[RPM_SIGNAL], FrameFormat::Can11Bit).as_slice(), b"ATCRA2C4\r");
+        assert_eq!(receive_filter_command(&[RPM_SIGNAL, COOLANT_SIGNAL], FrameFormat::Can11Bit).as_slice(), b"ATCRAXXX\r");
+        let extended = BroadcastSignal { can_id: 0x18fef100, ..RPM_SIGNAL };
+        let other = BroadcastSignal { can_id: 0x18fef200, ..RPM_SIGNAL };
+        assert_eq!(receive_filter_command(&[extended, other], FrameFormat::Can29Bit).as_slice(), b"ATCRA18FEFX00\r");
+
+        let filters: ArrayVec<ArrayVec<u8, MAX_PASS_FILTER_COMMAND_LEN>, 2> = pass_filter_commands(&[RPM_SIGNAL, COOLANT_SIGNAL], FrameFormat::Can11Bit).collect();
+        assert_eq!(filters[0].as_slice(), b"STFAP2C4,7FF\r");
+        assert_eq!(filters[1].as_slice(), b"STFAP3B7,7FF\r");
+        let same_id = BroadcastSignal { start: 2, ..RPM_SIGNAL };
+        assert_eq!(pass_filter_commands(&[RPM_SIGNAL, same_id], FrameFormat::Can11Bit).count(), 1);
+        assert_eq!(
+            pass_filter_commands(&[BroadcastSignal { can_id: 0x18fef100, ..RPM_SIGNAL }], FrameFormat::Can29Bit).next().unwrap().as_slice(),
+            b"STFAP18FEF100,1FFFFFFF\r"
+        );
+    }
+}
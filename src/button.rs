@@ -0,0 +1,6 @@
+#[derive(defmt::Format, Debug, Copy, Clone, PartialEq)]
+pub enum ButtonPress {
+    Short,
+    /// Used for anything that shouldn't happen by accident, like clearing the trouble codes
+    Long,
+}
@@ -0,0 +1,31 @@
+use embassy_rp::gpio::{Input, Pull};
+use embassy_time::{Duration, Timer, WithTimeout};
+use to_rust_a_gauge::button::ButtonPress;
+use to_rust_a_gauge::ToMainEvents;
+use crate::{ButtonPins, INCOMING_EVENT_CHANNEL};
+
+/// Held at least this long, it's a long press. It's sent as soon as it's that long, without waiting for the release
+const LONG_PRESS_TIME: Duration = Duration::from_millis(3000);
+/// Contacts bounce for a few ms after every press and release
+const DEBOUNCE_TIME: Duration = Duration::from_millis(20);
+
+/// The push button on the front of the gauge, between the pin and ground
+#[embassy_executor::task]
+pub async fn button_task(r: ButtonPins) {
+    let mut button = Input::new(r.button_pin, Pull::Up);
+    loop {
+        button.wait_for_low().await;
+        Timer::after(DEBOUNCE_TIME).await;
+        if button.is_high() {
+            continue;
+        }
+        let press = match button.wait_for_high().with_timeout(LONG_PRESS_TIME).await {
+            Ok(()) => ButtonPress::Short,
+            Err(_) => ButtonPress::Long,
+        };
+        defmt::info!("Button: {:?}", press);
+        INCOMING_EVENT_CHANNEL.send(ToMainEvents::ButtonPressed(press)).await;
+        button.wait_for_high().await;
+        Timer::after(DEBOUNCE_TIME).await;
+    }
+}
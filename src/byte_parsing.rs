@@ -1,62 +1,5 @@
-use core::fmt::{Debug, Formatter};
-use core::marker::PhantomData;
 use crate::errors::ToRustAGaugeError;
 
-pub trait BufferMode {}
-
-
-/// A buffer with this mode contains raw ascii u8's received from the elm
-pub struct CharByte;
-impl BufferMode for CharByte {}
-
-
-/// These u8's are just hex digits that were sent by the elm in string form and then parsed to be values from 0 to 15 inclusive
-pub struct HexDigit;
-impl BufferMode for HexDigit {}
-
-
-/// These u8's are actual bytes that were sent by the elm in hexadecimal form and then parsed
-pub struct FullyAssembledByte;
-impl BufferMode for FullyAssembledByte {}
-
-
-
-
-impl<M: BufferMode> SizedUartBuffer<M>{
-    ///true if success, false if full
-    pub fn add_element(&mut self, byte: u8) -> bool{
-        if self.end < crate::elm_uart::LOCAL_RX_BUFFER_LEN {
-            self.buffer[self.end] = byte;
-            self.end += 1;
-            true
-        } else {
-            false
-        }
-    }
-    
-    pub fn get_slice(&self) -> &[u8]{
-        &self.buffer[0..self.end]
-    }
-}
-
-pub struct SizedUartBuffer<MODE: BufferMode>{
-    pub buffer: [u8; crate::elm_uart::LOCAL_RX_BUFFER_LEN],
-    pub end: usize,
-    pub phantom: PhantomData<MODE>,
-}
-
-impl<M: BufferMode> defmt::Format for SizedUartBuffer<M> {
-    fn format(&self, f: defmt::Formatter<'_>) {
-        defmt::write!(f, "{:?}", self.get_slice())
-    }
-}
-
-impl<M: BufferMode> Debug for SizedUartBuffer<M> {
-    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
-        write!(f, "{:?}", self.get_slice())
-    }
-}
-
 pub fn parse_byte(input: &u8) -> Result<u8, ToRustAGaugeError>{
     match input{
         b'0' => {Ok(0x0)}
@@ -85,73 +28,8 @@ pub fn parse_byte(input: &u8) -> Result<u8, ToRustAGaugeError>{
     }
 }
 
-pub fn combine_4bit_pair(input_slice: &[u8]) -> Result<u8, ToRustAGaugeError> {
-    if input_slice.len() != 2 { return Err(ToRustAGaugeError::UartByteParseError()); }
-    let byte_1 = input_slice[0];
-    let byte_2 = input_slice[1];
-    if byte_1>15 || byte_2>15 { return Err(ToRustAGaugeError::UartByteParseError()); }
-
-    Ok(byte_1 << 4 | byte_2)
-}
-impl SizedUartBuffer<CharByte>
-
-{
-    const NO_DATA_MESSAGE: &'static[u8] = &[0x4E, 0x4F, 0x20, 0x44, 0x41, 0x54, 0x41, 0x0D, 0x0D];
-    pub fn parse_bytes(&self, parsed_buf: &mut SizedUartBuffer<HexDigit>) {
-        parsed_buf.end = 0;
-        self.get_slice().iter().for_each(|char_byte|{
-            match parse_byte(char_byte){
-                Ok(parsed_byte) => {
-                    if !parsed_buf.add_element(parsed_byte){
-                        panic!("Parsed buffer was somehow longer than the unparsed buffer??")
-                        // This should not be possible, but should be mentioned
-                    }
-                }
-                Err(_) => {
-                    // parsing byte failed, skipping. 
-                    // This can be because one of the char-bytes in the pair wasn't a valid hex digit, 
-                    // or this is the last byte in the buffer and couldn't form a pair
-                }
-            }
-        });
-    }
-    
-    pub fn is_no_data(&self) -> bool{
-        let slice = &self.buffer[0..self.end];
-        slice == Self::NO_DATA_MESSAGE
-    }
-}
-
-impl SizedUartBuffer<FullyAssembledByte> {
-
-    /// If this function fails, `parsed_buf` the calling instance becomes poisoned and should not be used
-    pub fn populate_from_hex_digit_buffer(&mut self,
-                                          parsed_byte_buffer: &SizedUartBuffer<HexDigit>
-    ) -> Result<(), ToRustAGaugeError>{
-        let digit_slice = &parsed_byte_buffer.buffer[0..parsed_byte_buffer.end];
-        self.end = 0;
-        
-        digit_slice.chunks(2).try_for_each(|parsed_hex_digit_pair|{
-            match combine_4bit_pair(parsed_hex_digit_pair){
-                Ok(full_parsed_byte) => {
-                    if self.add_element(full_parsed_byte) {
-                        Ok(())
-                    } else {
-                        panic!("Parsed buffer was somehow longer than the unparsed buffer??")
-                        // This should not be possible, but should be mentioned
-                    }
-                }
-                Err(e) => {
-                    Err(e)
-                }
-            }
-        })
-    }
-}
-
-pub fn parse_voltage(buffer: &mut SizedUartBuffer<CharByte>) -> Result<f64, ToRustAGaugeError>{
-    let slice = buffer.get_slice();
-    
+/// Parses the reply to `ATRV`, ex: `12.3V`
+pub fn parse_voltage(slice: &[u8]) -> Result<f64, ToRustAGaugeError>{
     const MAX_NUM_DIGITS: usize = 4;
     
     let mut valid_digits: [u8; MAX_NUM_DIGITS] = [0u8; MAX_NUM_DIGITS];
@@ -196,6 +74,15 @@ pub fn parse_voltage(buffer: &mut SizedUartBuffer<CharByte>) -> Result<f64, ToRu
     Ok(voltage)
 }
 
+/// Parses the reply to `ATIGN`, `ON` or `OFF`
+pub fn parse_ignition(slice: &[u8]) -> Result<bool, ToRustAGaugeError>{
+    match slice.trim_ascii() {
+        b"ON" => Ok(true),
+        b"OFF" => Ok(false),
+        _ => Err(ToRustAGaugeError::UartIgnitionParseError()),
+    }
+}
+
 
 /// for a number: 420.69, place 0 is `0`, place 1 is `2`, place 2 is `4`, place -1 is `6`, place -2 is `9`. 
 /// Places are inclusive so parsing 420.69 with `place_start` = 2 and `place_end` = -1 yields `"420.6"` 
@@ -261,39 +148,8 @@ pub fn powi(float: f64, n: i32) -> f64 {
 
 #[cfg(test)]
 mod tests {
-    use crate::elm_uart::LOCAL_RX_BUFFER_LEN;
     use super::*;
 
-    #[test]
-    fn test_parsing() {
-        let input: [u8; 51] = [0x30, 0x30, 0x20, 0x30, 0x31, 0x30, 0x32, 0x30, 0x33, 0x30, 0x34, 0x30, 0x35, 0x30, 0x36, 0x30, 0x37, 0x30, 0x38, 0x30, 0x39, 0x30, 0x41, 0x30, 0x62, 0x30, 0x43, 0x30, 0x20, 0x64, 0x30, 0x45, 0x30, 0x66, 0x66, 0x66, 0x31, 0x30, 0x20, 0x31, 0x31, 0x31, 0x32, 0x20, 0x31, 0x33, 0x31, 0x34, 0x31, 0x35, 0x0A];
-        // 00 0102030405060708090A0b0C0 d0E0fff10 1112 131415
-        // 00 01 02 03 04 05 06 07 08 09 0A 0b 0C 0d 0E 0f ff 10 11 12 13 14 15
-
-        let mut raw_buf: SizedUartBuffer<CharByte> = SizedUartBuffer{
-            buffer: [0u8; LOCAL_RX_BUFFER_LEN],
-            end: 0,
-            phantom: PhantomData,
-        };
-        let mut hex_buf: SizedUartBuffer<HexDigit> = SizedUartBuffer{
-            buffer: [0u8; LOCAL_RX_BUFFER_LEN],
-            end: 0,
-            phantom: PhantomData,
-        };
-        let mut parsed_byte_buf: SizedUartBuffer<FullyAssembledByte> = SizedUartBuffer{
-            buffer: [0u8; LOCAL_RX_BUFFER_LEN],
-            end: 0,
-            phantom: PhantomData,
-        };
-        for byte in input.iter() {
-            raw_buf.add_element(*byte);
-        }
-        raw_buf.parse_bytes(&mut hex_buf);
-        parsed_byte_buf.populate_from_hex_digit_buffer(&hex_buf).unwrap();
-
-        defmt::println!("{:?}", parsed_byte_buf)
-    }
-
     #[test]
     fn test_float_to_str() {
         let mut local_buffer = [0u8; 12];
@@ -302,4 +158,11 @@ mod tests {
         let expected = "420";
         assert_eq!(str_ref, expected, "brother...");
     }
+
+    #[test]
+    fn test_parse_ignition() {
+        assert_eq!(parse_ignition(b"ON\r"), Ok(true));
+        assert_eq!(parse_ignition(b"OFF"), Ok(false));
+        assert_eq!(parse_ignition(b"?"), Err(ToRustAGaugeError::UartIgnitionParseError()));
+    }
 }
\ No newline at end of file
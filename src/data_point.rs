@@ -1,15 +1,19 @@
 use core::fmt::{Debug, Formatter};
+use crate::obd_protocol::Ecu;
 
 #[derive(defmt::Format)]
 pub struct DataPoint {
     pub data: Datum,
     pub time: embassy_time::Instant,
+    /// The ECU that answered, `None` for values that aren't an ECU's answer (the ELM's voltage reading, the measured RPM,
+    /// values broadcast on the bus)
+    pub ecu: Option<Ecu>,
 }
 
 impl Debug for DataPoint {
     fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
-        write!(f, "DataPoint: data: {:?}, time received: {:?}", 
-               self.data, self.time)
+        write!(f, "DataPoint: data: {:?}, time received: {:?}, from: {:?}", 
+               self.data, self.time, self.ecu)
     }
 }
 
@@ -18,6 +22,10 @@ pub enum Datum{
     RPM(f64),
     VBat(f64),
     CoolantTempC(f64),
+    /// Any other Mode 01 PID, see `mode_01_pids` for the units of each one
+    ObdPid(u8, f64),
+    /// A manufacturer specific value: service, identifier, value
+    ExtendedPid(u8, u16, f64),
 }
 
 
@@ -49,6 +57,7 @@ impl Datum{
             Datum::RPM(value) => is_rpm_sane_check(*value),
             Datum::VBat(value) => value.is_finite() && value < &MAX_SANE_VBAT && value > &MIN_SANE_VBAT,
             Datum::CoolantTempC(value) => value.is_finite() && value < &MAX_SANE_COOL_TEMP && value > &MIN_SANE_COOL_TEMP,
+            Datum::ObdPid(_, value) | Datum::ExtendedPid(_, _, value) => value.is_finite(),
         }
     }
     
@@ -57,6 +66,7 @@ impl Datum{
             Datum::RPM(value) => is_rpm_normal_check(*value),
             Datum::VBat(value) => value.is_finite() && value < &MAX_NORMAL_VBAT && value > &MIN_NORMAL_VBAT,
             Datum::CoolantTempC(value) => value.is_finite() && value < &MAX_NORMAL_COOL_TEMP && value > &MIN_NORMAL_COOL_TEMP,
+            Datum::ObdPid(_, value) | Datum::ExtendedPid(_, _, value) => value.is_finite(),
         }
     }
 }
@@ -25,13 +25,14 @@ use embedded_graphics::text;
 use embedded_graphics::text::Text;
 use mipidsi::models::ST7789;
 use mipidsi::options::{ColorInversion, Orientation};
-use {defmt_rtt as _, panic_probe as _};
-use crate::{DisplayPins, ToLcdEvents, ToMainEvents, INCOMING_EVENT_CHANNEL, LCD_EVENT_CHANNEL};
+use crate::{DisplayPins, ToLcdEvents, INCOMING_EVENT_CHANNEL, LCD_EVENT_CHANNEL};
 use tinybmp::Bmp;
 use profont;
-use crate::byte_parsing::float_as_str;
-use crate::data_point::Datum;
-use crate::errors::{ToRustAGaugeError, ToRustAGaugeErrorWithSeverity};
+use to_rust_a_gauge::ToMainEvents;
+use to_rust_a_gauge::byte_parsing::float_as_str;
+use to_rust_a_gauge::data_point::Datum;
+use to_rust_a_gauge::dtc::FreezeFrame;
+use to_rust_a_gauge::errors::{ToRustAGaugeError, ToRustAGaugeErrorWithSeverity, DISPLAY_TEXT_LEN};
 
 const DISPLAY_FREQ: u32 = 64_000_000;
 
@@ -39,11 +40,13 @@ const BG_COLOR: Rgb565 = Rgb565::BLACK;
 const ORANG: Rgb565 = Rgb565::new(29, 24, 3);
 const VBAT_TEXT_POINT: Point = Point::new(108, 48);
 const COOLANT_TEXT_POINT: Point = Point::new(108, 134);
+const ERROR_TEXT_POINT: Point = Point::new(206, 103);
 const MAIN_TEXT_STYLE: MonoTextStyle<Rgb565> = MonoTextStyle::new(&profont::PROFONT_24_POINT, ORANG);
 
 const MIN_GOOD_VOLTAGE: f64 = 11f64;
 const BRIGHT_LIGHT_PWM: u16 = 0x8000;
 const DIM_LIGHT_PWM: u16 = 0x2000;
+const OFF_LIGHT_PWM: u16 = 0;
 
 #[embassy_executor::task]
 pub async fn display_task(r: DisplayPins) {
@@ -141,10 +144,18 @@ pub async fn display_task(r: DisplayPins) {
         .into_styled(PrimitiveStyle::with_fill(BG_COLOR));
     
     let mut last_error: Option<ToRustAGaugeErrorWithSeverity> = None;
+    let mut freeze_frame: Option<FreezeFrame> = None;
+    // a trace entry or the timing summary, shown instead of the errors
+    let mut is_page_shown = false;
+    
+    let mut is_backlight_on = true;
+    let mut is_ignition_on = true;
 
     let mut counter: u64 = 0;
 
-    let mut error_text = Text::new("Hello World\nLine 2??\nLine 3??\nLine 4??", Point::new(206, 103), error_text_style);
+    // every error string is the same size, so any of them gives the area to clear
+    let error_text_area = Text::new(ToRustAGaugeError::NondescriptError().to_str(), ERROR_TEXT_POINT, error_text_style).bounding_box();
+    let mut error_str_buf = [0u8; DISPLAY_TEXT_LEN];
 
     rust_logo.draw(&mut display).expect("failed to draw rust_logo");
     coolant_temp_icon.draw(&mut display).expect("failed to draw coolant_temp_icon");
@@ -179,34 +190,113 @@ pub async fn display_task(r: DisplayPins) {
                 }
             }
             ToLcdEvents::IsBackLightOn(new_bl_state) => {
+                is_backlight_on = new_bl_state;
                 match new_bl_state{
                     true => {
-                        c.compare_b = BRIGHT_LIGHT_PWM;
-                        pwm.set_config(&c);
                         light_icon.clear_bounding_box(&mut display, BG_COLOR).expect("failed to clear light icon");
                     }
                     false => {
-                        c.compare_b = DIM_LIGHT_PWM;
-                        pwm.set_config(&c);
                         light_icon.draw(&mut display).expect("failed to draw light icon");
                     }
                 }
+                // stays dark until the ignition is back
+                if is_ignition_on {
+                    c.compare_b = backlight_pwm(is_backlight_on);
+                    pwm.set_config(&c);
+                }
+            }
+            ToLcdEvents::IsIgnitionOn(new_ignition_state) => {
+                match (is_ignition_on, new_ignition_state) {
+                    (true, false) => {
+                        c.compare_b = OFF_LIGHT_PWM;
+                        pwm.set_config(&c);
+                        // keeps what's drawn, and can still be drawn to
+                        display.sleep(&mut Delay).expect("failed to put display to sleep");
+                    }
+                    (false, true) => {
+                        display.wake(&mut Delay).expect("failed to wake display");
+                        c.compare_b = backlight_pwm(is_backlight_on);
+                        pwm.set_config(&c);
+                    }
+                    _ => {}
+                }
+                is_ignition_on = new_ignition_state;
+            }
+            ToLcdEvents::AdapterInfo(profile) => {
+                // replaced by the vehicle info once it's read, like it, only shown until the first error
+                if last_error.is_none() && !is_page_shown {
+                    rust_logo.clear_bounding_box(&mut display, BG_COLOR).expect("failed to clear rust logo");
+                    display.fill_solid(&error_text_area, BG_COLOR).expect("failed to clear text");
+                    Text::new(profile.to_display_str(&mut error_str_buf), ERROR_TEXT_POINT, error_text_style)
+                        .draw(&mut display).expect("failed to draw adapter info");
+                }
+            }
+            ToLcdEvents::VehicleInfo(info) => {
+                // only shown until the first error, after that the quadrant goes back to the logo
+                if last_error.is_none() && !is_page_shown {
+                    rust_logo.clear_bounding_box(&mut display, BG_COLOR).expect("failed to clear rust logo");
+                    // the adapter info can be here already
+                    display.fill_solid(&error_text_area, BG_COLOR).expect("failed to clear text");
+                    Text::new(info.to_display_str(&mut error_str_buf), ERROR_TEXT_POINT, error_text_style)
+                        .draw(&mut display).expect("failed to draw vehicle info");
+                }
+            }
+            ToLcdEvents::FreezeFrame(new_freeze_frame) => {
+                // shown with the next error update
+                freeze_frame = new_freeze_frame;
+            }
+            ToLcdEvents::Trace(new_trace) => {
+                display.fill_solid(&error_text_area, BG_COLOR).expect("failed to clear text");
+                match (&new_trace, &last_error) {
+                    (Some(summary), _) => {
+                        rust_logo.clear_bounding_box(&mut display, BG_COLOR).expect("failed to clear rust logo");
+                        Text::new(summary.to_display_str(&mut error_str_buf), ERROR_TEXT_POINT, error_text_style)
+                            .draw(&mut display).expect("failed to draw trace");
+                    }
+                    // back to whatever the errors left there
+                    (None, Some(last_error)) => {
+                        Text::new(error_display_str(&last_error.error, &freeze_frame, &mut error_str_buf), ERROR_TEXT_POINT, error_text_style)
+                            .draw(&mut display).expect("failed to draw error_text");
+                    }
+                    (None, None) => {
+                        rust_logo.draw(&mut display).expect("failed to draw ferris in error quad");
+                    }
+                }
+                is_page_shown = new_trace.is_some();
+            }
+            // closed like a trace entry, with `Trace(None)`
+            ToLcdEvents::Timing(summary) => {
+                display.fill_solid(&error_text_area, BG_COLOR).expect("failed to clear text");
+                rust_logo.clear_bounding_box(&mut display, BG_COLOR).expect("failed to clear rust logo");
+                Text::new(summary.to_display_str(&mut error_str_buf), ERROR_TEXT_POINT, error_text_style)
+                    .draw(&mut display).expect("failed to draw timing");
+                is_page_shown = true;
+            }
+            // only the warning icon while a page is shown, the text is drawn once it's closed
+            ToLcdEvents::Error(new_error) if is_page_shown => {
+                match &new_error {
+                    Some(_) => warning_icon.draw(&mut display).expect("failed to draw warning icon"),
+                    None => warning_icon.clear_bounding_box(&mut display, BG_COLOR).expect("failed to clear warning icon"),
+                }
+                last_error = new_error;
             }
             ToLcdEvents::Error(new_error) => {
                 match (&new_error, &last_error){
                     (Some(some_new_error), Some(_last_error)) => {
-                        error_text.clear_bounding_box(&mut display, BG_COLOR).expect("failed to clear text");
-                        error_text.text = some_new_error.error.to_str();
-                        error_text.draw(&mut display).expect("failed to draw error_text");
+                        display.fill_solid(&error_text_area, BG_COLOR).expect("failed to clear text");
+                        Text::new(error_display_str(&some_new_error.error, &freeze_frame, &mut error_str_buf), ERROR_TEXT_POINT, error_text_style)
+                            .draw(&mut display).expect("failed to draw error_text");
                     }
                     (Some(some_new_error), None) => {
                         rust_logo.clear_bounding_box(&mut display, BG_COLOR).expect("failed to clear rust logo");
-                        error_text.text = some_new_error.error.to_str();
-                        error_text.draw(&mut display).expect("failed to draw error_text");
+                        // the adapter or vehicle info text can be here instead of the logo
+                        display.fill_solid(&error_text_area, BG_COLOR).expect("failed to clear text");
+                        Text::new(error_display_str(&some_new_error.error, &freeze_frame, &mut error_str_buf), ERROR_TEXT_POINT, error_text_style)
+                            .draw(&mut display).expect("failed to draw error_text");
                         warning_icon.draw(&mut display).expect("failed to draw warning icon");
                     }
                     (None, Some(_last_error)) => {
-                        error_text.clear_bounding_box(&mut display, BG_COLOR).expect("failed to clear text");
+                        display.fill_solid(&error_text_area, BG_COLOR).expect("failed to clear text");
                         rust_logo.draw(&mut display).expect("failed to draw ferris in error quad");
                         warning_icon.clear_bounding_box(&mut display, BG_COLOR).expect("failed to clear warning icon");
                     }
@@ -225,6 +315,21 @@ pub async fn display_task(r: DisplayPins) {
 
     }
 }
+/// The error's display string, or if it's the stored code `freeze_frame` is for, the freeze frame
+fn error_display_str<'b>(error: &ToRustAGaugeError,
+                         freeze_frame: &Option<FreezeFrame>,
+                         buffer: &'b mut [u8; DISPLAY_TEXT_LEN],
+) -> &'b str {
+    match (error, freeze_frame) {
+        (ToRustAGaugeError::StoredDtc(_, dtc), Some(freeze_frame)) if freeze_frame.dtc == *dtc => freeze_frame.to_display_str(buffer),
+        _ => error.to_display_str(buffer),
+    }
+}
+
+fn backlight_pwm(is_backlight_on: bool) -> u16 {
+    if is_backlight_on { BRIGHT_LIGHT_PWM } else { DIM_LIGHT_PWM }
+}
+
 const COOLANT_TEMP_UTF_8_UNIT_STR: [u8; 3] = [0xC2, 0xB0, b'C'];
 const VBAT_UTF_8_UNIT_STR: u8 = b'V';
 fn draw_vbat_text<D>(vbat_val: f64, display_ref: &mut D, byte_buf: &mut [u8])
@@ -0,0 +1,349 @@
+use arrayvec::ArrayVec;
+use defmt::Formatter;
+use crate::elm_commands::HexDigits;
+use crate::obd_protocol::{Ecu, FrameFormat, IsoTpMessage};
+use crate::errors::{ToRustAGaugeError, ToRustAGaugeErrorSeverity, ToRustAGaugeErrorWithSeverity, DISPLAY_TEXT_LEN};
+
+/// Most codes kept per list. Codes past this are logged and dropped, so they're never shown
+pub const MAX_DTCS: usize = 6;
+
+/// Most ECUs whose codes are kept from one response
+const MAX_DTC_SOURCES: usize = 4;
+
+pub type DtcList = ArrayVec<EcuDtc, MAX_DTCS>;
+
+/// Mode 03/07 response service bytes
+pub const STORED_DTC_RESPONSE_SERVICE: u8 = 0x43;
+pub const PENDING_DTC_RESPONSE_SERVICE: u8 = 0x47;
+pub const CLEAR_DTC_RESPONSE_SERVICE: u8 = 0x44;
+
+#[derive(defmt::Format, Debug, Copy, Clone, PartialEq)]
+pub enum DtcCategory {
+    Powertrain,
+    Chassis,
+    Body,
+    Network,
+}
+
+impl DtcCategory {
+    pub const fn letter(&self) -> u8 {
+        match self {
+            DtcCategory::Powertrain => { b'P' }
+            DtcCategory::Chassis => { b'C' }
+            DtcCategory::Body => { b'B' }
+            DtcCategory::Network => { b'U' }
+        }
+    }
+}
+
+/// A diagnostic trouble code, stored as the 2 raw bytes the ECU sent.
+/// The top 2 bits are the category, the rest are the 4 digits of the code (the first one is 0 - 3)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Dtc(pub u16);
+
+impl Dtc {
+    pub const fn from_bytes(high: u8, low: u8) -> Self {
+        Self(((high as u16) << 8) | low as u16)
+    }
+
+    pub const fn category(&self) -> DtcCategory {
+        match self.0 >> 14 {
+            0 => DtcCategory::Powertrain,
+            1 => DtcCategory::Chassis,
+            2 => DtcCategory::Body,
+            _ => DtcCategory::Network,
+        }
+    }
+
+    /// The code as it's printed in a manual, ex: `P0301`
+    pub const fn as_ascii(&self) -> [u8; 5] {
+        [
+            self.category().letter(),
+            HexDigits::from_val(((self.0 >> 12) & 0b11) as u8) as u8,
+            HexDigits::from_val((self.0 >> 8) as u8) as u8,
+            HexDigits::from_val((self.0 >> 4) as u8) as u8,
+            HexDigits::from_val(self.0 as u8) as u8,
+        ]
+    }
+}
+
+impl defmt::Format for Dtc {
+    fn format(&self, fmt: Formatter) {
+        let ascii = self.as_ascii();
+        // Safety: `as_ascii` only ever returns ascii letters and digits
+        defmt::write!(fmt, "{}", core::str::from_utf8(&ascii).unwrap())
+    }
+}
+
+/// A code and the ECU that reported it. The same code from two ECUs is two entries
+#[derive(defmt::Format, Debug, Copy, Clone, PartialEq)]
+pub struct EcuDtc {
+    pub ecu: Ecu,
+    pub dtc: Dtc,
+}
+
+/// Snapshot of the engine at the moment a code was set, read from freeze frame 0 (Mode 02).
+/// Values the ECU didn't store are `None`
+#[derive(defmt::Format, Debug, Clone, PartialEq)]
+pub struct FreezeFrame {
+    /// The code that caused this freeze frame to be stored
+    pub dtc: Dtc,
+    pub rpm: Option<f64>,
+    pub coolant_temp_c: Option<f64>,
+    pub engine_load_percent: Option<f64>,
+}
+
+impl FreezeFrame {
+    /// Shown instead of the stored code's own error string, ex: `"Check P0301\nwhen set:  \n1726 rpm   \n83C 40%    "`.
+    /// Values the ECU didn't store are `--`
+    pub fn to_display_str<'b>(&self, buffer: &'b mut [u8; DISPLAY_TEXT_LEN]) -> &'b str {
+        const TEMPLATE: &'static [u8] = b"Check ?????\nwhen set:  \n           \n           ";
+        const CODE_START: usize = 6;
+        const RPM_START: usize = 24;
+        const COOLANT_AND_LOAD_START: usize = 36;
+        buffer.copy_from_slice(TEMPLATE);
+        buffer[CODE_START..CODE_START + 5].copy_from_slice(&self.dtc.as_ascii());
+        let mut line = DisplayLine::new();
+        push_value(&mut line, self.rpm, b" rpm");
+        buffer[RPM_START..RPM_START + line.len()].copy_from_slice(&line);
+        line.clear();
+        push_value(&mut line, self.coolant_temp_c, b"C ");
+        push_value(&mut line, self.engine_load_percent, b"%");
+        buffer[COOLANT_AND_LOAD_START..COOLANT_AND_LOAD_START + line.len()].copy_from_slice(&line);
+        core::str::from_utf8(buffer).unwrap_or("")
+    }
+}
+
+/// One line of a display string
+type DisplayLine = ArrayVec<u8, 11>;
+
+/// `value` rounded to a whole number (or `--`), then `unit`. Whatever doesn't fit on the line is left out
+fn push_value(line: &mut DisplayLine, value: Option<f64>, unit: &[u8]) {
+    match value {
+        Some(value) => {
+            // no `f64::round` without std
+            let rounded = (if value < 0.0 { value - 0.5 } else { value + 0.5 }) as i32;
+            if rounded < 0 {
+                let _ = line.try_push(b'-');
+            }
+            let magnitude = rounded.unsigned_abs();
+            let digits = magnitude.checked_ilog10().unwrap_or(0) + 1;
+            for digit in (0..digits).rev() {
+                let _ = line.try_push(b'0' + (magnitude / 10u32.pow(digit) % 10) as u8);
+            }
+        }
+        None => {
+            let _ = line.try_extend_from_slice(b"--");
+        }
+    }
+    for c in unit {
+        let _ = line.try_push(*c);
+    }
+}
+
+/// Stored (Mode 03) and pending (Mode 07) codes from one read
+#[derive(Debug, Clone, PartialEq)]
+pub struct DtcReport {
+    pub stored: DtcList,
+    pub pending: DtcList,
+    /// Only read when there is a stored code, and only kept if it belongs to one of them
+    pub freeze_frame: Option<FreezeFrame>,
+}
+
+impl DtcReport {
+    pub fn new() -> Self {
+        Self {
+            stored: DtcList::new(),
+            pending: DtcList::new(),
+            freeze_frame: None,
+        }
+    }
+
+    /// One error per code, so they can be shown in the error quadrant like any other error
+    pub fn to_errors(&self) -> impl Iterator<Item = ToRustAGaugeErrorWithSeverity> + '_ {
+        let stored = self.stored.iter().map(|code| ToRustAGaugeErrorWithSeverity {
+            error: ToRustAGaugeError::StoredDtc(code.ecu, code.dtc),
+            severity: ToRustAGaugeErrorSeverity::LossOfSomeFunctionality,
+        });
+        let pending = self.pending.iter().map(|code| ToRustAGaugeErrorWithSeverity {
+            error: ToRustAGaugeError::PendingDtc(code.ecu, code.dtc),
+            severity: ToRustAGaugeErrorSeverity::BadIfReoccurring,
+        });
+        stored.chain(pending)
+    }
+}
+
+impl defmt::Format for DtcReport {
+    fn format(&self, fmt: Formatter) {
+        defmt::write!(fmt, "DtcReport(stored = {:?}, pending = {:?}, freeze_frame = {:?})", self.stored.as_slice(), self.pending.as_slice(), self.freeze_frame)
+    }
+}
+
+/// Decodes every code in a Mode 03 or Mode 07 response, from every ECU that answered, each tagged with its ECU.
+/// An ECU whose answer doesn't decode is skipped, it's only an error if no ECU's did
+pub fn decode_dtc_response<'a>(frames: impl Iterator<Item = &'a [u8]> + Clone, frame_format: FrameFormat, response_service: u8) -> Result<DtcList, ToRustAGaugeError> {
+    let mut sources: ArrayVec<Ecu, MAX_DTC_SOURCES> = ArrayVec::new();
+    for ecu in frames.clone().filter_map(|frame| frame_format.source_ecu(frame)) {
+        if !sources.contains(&ecu) && sources.try_push(ecu).is_err() {
+            defmt::warn!("More than {} ECUs answered, ignoring the codes from {:?}", MAX_DTC_SOURCES, ecu);
+        }
+    }
+
+    let mut dtcs = DtcList::new();
+    let mut first_error = None;
+    let mut decoded_any = false;
+    for ecu in sources {
+        let ecu_frames = frames.clone().filter(move |frame| frame_format.source_ecu(frame) == Some(ecu));
+        match decode_ecu_dtcs(ecu_frames, frame_format, response_service) {
+            Ok(codes) => {
+                decoded_any = true;
+                add_codes(&mut dtcs, ecu, &codes);
+            }
+            Err(error) => {
+                defmt::warn!("Skipping the trouble codes from {:?}: {:?}", ecu, error);
+                first_error.get_or_insert(error);
+            }
+        }
+    }
+    match first_error {
+        Some(error) if !decoded_any => Err(error),
+        _ => Ok(dtcs),
+    }
+}
+
+/// The code bytes in one ECU's frames. On KWP/ISO 9141/J1850 each frame holds up to 3 codes,
+/// unused slots are padded with `0000`. On CAN it's one message: the service byte, the number of codes, then the codes
+fn decode_ecu_dtcs<'a>(frames: impl Iterator<Item = &'a [u8]>, frame_format: FrameFormat, response_service: u8) -> Result<IsoTpMessage, ToRustAGaugeError> {
+    if frame_format.is_can() {
+        let message = frame_format.iso_tp_message(frames)?;
+        if message.first() != Some(&response_service) {
+            defmt::warn!("UartServiceMismatchError: {:?}", message.as_slice());
+            return Err(ToRustAGaugeError::UartServiceMismatchError())
+        }
+        if message.len() < 2 {
+            defmt::warn!("UartIncorrectLengthError: {:?}", message.as_slice());
+            return Err(ToRustAGaugeError::UartIncorrectLengthError())
+        }
+        return Ok(message[2..].iter().copied().collect())
+    }
+    let mut code_bytes = IsoTpMessage::new();
+    for frame in frames {
+        let frame_data = frame_format.frame_data(frame)?;
+        if frame_data.first() != Some(&response_service) {
+            defmt::warn!("UartServiceMismatchError: {:?}", frame_data);
+            return Err(ToRustAGaugeError::UartServiceMismatchError())
+        }
+        // an odd byte at the end isn't part of a code, it's left out so the next frame's codes stay aligned
+        let codes = &frame_data[1..frame_data.len() - (frame_data.len() - 1) % 2];
+        if code_bytes.try_extend_from_slice(codes).is_err() {
+            defmt::warn!("Too many frames of trouble codes, dropping the rest");
+            break;
+        }
+    }
+    Ok(code_bytes)
+}
+
+/// Adds every nonzero code in `code_bytes` (2 bytes each) to `dtcs`, from `ecu`
+fn add_codes(dtcs: &mut DtcList, ecu: Ecu, code_bytes: &[u8]) {
+    for code_bytes in code_bytes.chunks_exact(2) {
+        let dtc = Dtc::from_bytes(code_bytes[0], code_bytes[1]);
+        if dtc.0 == 0 {
+            continue;
+        }
+        if dtcs.try_push(EcuDtc { ecu, dtc }).is_err() {
+            defmt::warn!("More than {} trouble codes, dropping {:?} from {:?}", MAX_DTCS, dtc, ecu);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dtc_ascii() {
+        assert_eq!(&Dtc::from_bytes(0x03, 0x01).as_ascii(), b"P0301");
+        assert_eq!(&Dtc::from_bytes(0x41, 0x23).as_ascii(), b"C0123");
+        assert_eq!(&Dtc::from_bytes(0x9a, 0xbc).as_ascii(), b"B1ABC");
+        assert_eq!(&Dtc::from_bytes(0xc1, 0x00).as_ascii(), b"U0100");
+    }
+
+    #[test]
+    fn test_freeze_frame_display_str() {
+        let mut buffer = [0u8; DISPLAY_TEXT_LEN];
+        let freeze_frame = FreezeFrame {
+            dtc: Dtc::from_bytes(0x03, 0x01),
+            rpm: Some(1726.25),
+            coolant_temp_c: Some(83.0),
+            engine_load_percent: Some(39.6),
+        };
+        assert_eq!(freeze_frame.to_display_str(&mut buffer), "Check P0301\nwhen set:  \n1726 rpm   \n83C 40%    ");
+        let freeze_frame = FreezeFrame {
+            rpm: None,
+            coolant_temp_c: Some(-40.0),
+            ..freeze_frame
+        };
+        assert_eq!(freeze_frame.to_display_str(&mut buffer), "Check P0301\nwhen set:  \n-- rpm     \n-40C 40%   ");
+    }
+
+    #[test]
+    fn test_decode_dtc_response() {
+        // two KWP frames, 4 codes and 2 padding slots. Checksums are filled in below
+        let mut response: [u8; 22] = [
+            0x87, 0xf1, 0x10, 0x43, 0x01, 0x43, 0x01, 0x96, 0x02, 0x34, 0x00,
+            0x87, 0xf1, 0x10, 0x43, 0x02, 0x35, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        for frame in response.chunks_exact_mut(11) {
+            frame[10] = frame[..10].iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        }
+
+        let dtcs = decode_dtc_response(response.chunks_exact(11), FrameFormat::Kwp, STORED_DTC_RESPONSE_SERVICE).unwrap();
+        assert_eq!(dtcs.as_slice(), &engine_codes([0x0143, 0x0196, 0x0234, 0x0235]));
+
+        // the transmission (source 18) answers too, its frame comes between the engine's
+        let mut response: [u8; 33] = [
+            0x87, 0xf1, 0x10, 0x43, 0x01, 0x43, 0x01, 0x96, 0x02, 0x34, 0x00,
+            0x87, 0xf1, 0x18, 0x43, 0x07, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x87, 0xf1, 0x10, 0x43, 0x02, 0x35, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ];
+        for frame in response.chunks_exact_mut(11) {
+            frame[10] = frame[..10].iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        }
+        let dtcs = decode_dtc_response(response.chunks_exact(11), FrameFormat::Kwp, STORED_DTC_RESPONSE_SERVICE).unwrap();
+        let mut expected: ArrayVec<EcuDtc, 5> = engine_codes([0x0143, 0x0196, 0x0234, 0x0235]).into_iter().collect();
+        expected.push(EcuDtc { ecu: Ecu::Transmission, dtc: Dtc(0x0700) });
+        assert_eq!(dtcs.as_slice(), expected.as_slice());
+
+        // a bad checksum only loses that ECU's codes
+        response[21] = response[21].wrapping_add(1);
+        let dtcs = decode_dtc_response(response.chunks_exact(11), FrameFormat::Kwp, STORED_DTC_RESPONSE_SERVICE).unwrap();
+        assert_eq!(dtcs.as_slice(), &engine_codes([0x0143, 0x0196, 0x0234, 0x0235]));
+    }
+
+    fn engine_codes(codes: [u16; 4]) -> [EcuDtc; 4] {
+        codes.map(|code| EcuDtc { ecu: Ecu::Engine, dtc: Dtc(code) })
+    }
+
+    #[test]
+    fn test_decode_can_dtc_response() {
+        // 7E8 10 0A 43 04 01 43 01 96 / 7E8 21 02 34 02 35 00 00 00
+        let frames: [&[u8]; 2] = [
+            &[0x07, 0xe8, 0x10, 0x0a, 0x43, 0x04, 0x01, 0x43, 0x01, 0x96],
+            &[0x07, 0xe8, 0x21, 0x02, 0x34, 0x02, 0x35, 0x00, 0x00, 0x00],
+        ];
+        let dtcs = decode_dtc_response(frames.iter().copied(), FrameFormat::Can11Bit, STORED_DTC_RESPONSE_SERVICE).unwrap();
+        assert_eq!(dtcs.as_slice(), &engine_codes([0x0143, 0x0196, 0x0234, 0x0235]));
+
+        // 7E9 answers with a single frame in the middle of the engine's message
+        let frames: [&[u8]; 3] = [
+            &[0x07, 0xe8, 0x10, 0x0a, 0x43, 0x04, 0x01, 0x43, 0x01, 0x96],
+            &[0x07, 0xe9, 0x04, 0x43, 0x01, 0x07, 0x00],
+            &[0x07, 0xe8, 0x21, 0x02, 0x34, 0x02, 0x35, 0x00, 0x00, 0x00],
+        ];
+        let dtcs = decode_dtc_response(frames.iter().copied(), FrameFormat::Can11Bit, STORED_DTC_RESPONSE_SERVICE).unwrap();
+        let mut expected: ArrayVec<EcuDtc, 5> = engine_codes([0x0143, 0x0196, 0x0234, 0x0235]).into_iter().collect();
+        expected.push(EcuDtc { ecu: Ecu::Transmission, dtc: Dtc(0x0700) });
+        assert_eq!(dtcs.as_slice(), expected.as_slice());
+    }
+}
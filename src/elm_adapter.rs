@@ -0,0 +1,299 @@
+use arrayvec::ArrayVec;
+use defmt::Formatter;
+use crate::elm_commands;
+use crate::elm_commands::StaticCommand;
+use crate::errors::DISPLAY_TEXT_LEN;
+
+/// Characters kept from the banner, `ELM327 v1.5` is 11 and `STN1110 v4.2.0` 14
+const MAX_IDENTITY_LEN: usize = 16;
+
+pub type Identity = ArrayVec<u8, MAX_IDENTITY_LEN>;
+
+/// Firmware version from the banner, ex: 1.4 from `ELM327 v1.4b`
+#[derive(defmt::Format, Debug, Copy, Clone, PartialEq, PartialOrd)]
+pub struct ElmVersion {
+    pub major: u8,
+    pub minor: u8,
+}
+
+/// Elm Electronics never released a v1.5, it's what most cheap clones claim to be
+const CLONE_VERSION: ElmVersion = ElmVersion { major: 1, minor: 5 };
+/// The first version with `ATBRD`
+const BAUD_RATE_DIVISOR_VERSION: ElmVersion = ElmVersion { major: 1, minor: 2 };
+/// The first version with `ATLP`
+const LOW_POWER_VERSION: ElmVersion = ElmVersion { major: 1, minor: 4 };
+
+/// Which commands requests, bus monitoring and baud rate changes are sent with
+#[derive(defmt::Format, Debug, Copy, Clone, PartialEq)]
+pub enum Backend {
+    /// Plain ELM327 commands, what every adapter understands
+    Elm,
+    /// The extensions of ScanTool's STN chips (OBDLink): `STPX` requests, `STMA` with exact pass filters, `STBR`.
+    /// They understand every ELM327 command as well
+    Stn,
+}
+
+/// Optional commands that clones often leave out. Each one is probed once per init
+#[derive(defmt::Format, Debug, Copy, Clone, PartialEq)]
+pub enum Capability {
+    /// `ATST`, without it the timeout stays at the ELM's default and `AdaptiveTiming` is off
+    SetTimeout,
+    /// `ATAT1`
+    AdaptiveTiming,
+    /// `ATRV`, without it the battery voltage isn't polled
+    ReadVoltage,
+    /// `ATCRA`, without it the bus isn't monitored for broadcast values
+    ReceiveFilter,
+    /// `ATIGN`, without it key-off is only told from the battery voltage, see `IgnitionSense`
+    IgnitionMonitor,
+}
+
+pub const CAPABILITIES: [Capability; 5] = [
+    Capability::SetTimeout,
+    Capability::AdaptiveTiming,
+    Capability::ReadVoltage,
+    Capability::ReceiveFilter,
+    Capability::IgnitionMonitor,
+];
+
+impl Capability {
+    /// Sent to find out whether the adapter knows the command. These are commands init sends anyway
+    /// (or that don't change anything), so nothing has to be undone after probing
+    pub const fn probe(&self) -> StaticCommand {
+        match self {
+            Capability::SetTimeout => elm_commands::SET_TIMEOUT_64,
+            Capability::AdaptiveTiming => elm_commands::ENABLE_AUTO_TIMINGS_1,
+            Capability::ReadVoltage => elm_commands::ELM_REQUEST_VBAT,
+            Capability::ReceiveFilter => elm_commands::RESET_RECEIVE_FILTER,
+            Capability::IgnitionMonitor => elm_commands::READ_IGNITION,
+        }
+    }
+
+    /// The capability `command` is the probe of, if it is one
+    pub fn probed_by(command: &StaticCommand) -> Option<Capability> {
+        CAPABILITIES.into_iter().find(|capability| capability.probe().name() == command.name())
+    }
+
+    /// The first genuine ELM327 version that has the command
+    const fn since(&self) -> ElmVersion {
+        match self {
+            Capability::SetTimeout | Capability::ReadVoltage => ElmVersion { major: 1, minor: 0 },
+            Capability::AdaptiveTiming => ElmVersion { major: 1, minor: 2 },
+            Capability::ReceiveFilter | Capability::IgnitionMonitor => ElmVersion { major: 1, minor: 4 },
+        }
+    }
+
+    const fn bit(&self) -> u8 {
+        1 << *self as u8
+    }
+}
+
+/// What the adapter says it is, and which of the optional commands it actually takes.
+/// The init sequence and the poll loop leave out what it doesn't support
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdapterProfile {
+    /// `ATI`'s answer, ex: `ELM327 v1.5`
+    pub identity: Identity,
+    pub version: Option<ElmVersion>,
+    /// `STI`'s answer if it's an STN chip, ex: `STN1110 v4.2.0`. Empty for anything else
+    pub stn_identity: Identity,
+    /// The banner printed after `ATZ` isn't the same as `ATI`'s answer
+    banner_mismatch: bool,
+    supported: u8,
+}
+
+impl AdapterProfile {
+    /// `reset_text` is the response to `ATZ`, `identify_text` the response to `ATI`.
+    /// Nothing is supported until it's `record`ed
+    pub fn new(reset_text: &[u8], identify_text: &[u8]) -> Self {
+        let identified = identity(identify_text);
+        Self {
+            version: parse_version(&identified),
+            banner_mismatch: identity(reset_text) != identified,
+            identity: identified,
+            stn_identity: Identity::new(),
+            supported: 0,
+        }
+    }
+
+    pub fn record(&mut self, capability: Capability, supported: bool) {
+        if supported {
+            self.supported |= capability.bit();
+        } else {
+            self.supported &= !capability.bit();
+        }
+    }
+
+    pub fn supports(&self, capability: Capability) -> bool {
+        self.supported & capability.bit() != 0
+    }
+
+    /// `stn_text` is the response to `STI`, only kept if it's an STN ID
+    pub fn record_stn(&mut self, stn_text: &[u8]) {
+        let identified = identity(stn_text);
+        if identified.starts_with(b"STN") {
+            self.stn_identity = identified;
+        }
+    }
+
+    pub fn backend(&self) -> Backend {
+        if self.stn_identity.is_empty() { Backend::Elm } else { Backend::Stn }
+    }
+
+    /// Says it knows `STBR` or `ATBRD`. It isn't probed (that would switch), clones that get it wrong
+    /// are caught by the handshake in `ElmDriver::switch_baud_rate`
+    pub fn can_switch_baud_rate(&self) -> bool {
+        self.backend() == Backend::Stn || self.version.is_some_and(|version| version >= BAUD_RATE_DIVISOR_VERSION)
+    }
+
+    /// Says it knows `ATLP`. It isn't probed either (that would put it to sleep), a clone that rejects it
+    /// is only left awake while the key is off
+    pub fn can_sleep(&self) -> bool {
+        self.backend() == Backend::Stn || self.version.is_some_and(|version| version >= LOW_POWER_VERSION)
+    }
+
+    /// Claims a version that was never released, answers `ATZ` and `ATI` differently, or rejects a command
+    /// the version it claims has. Nothing else answers `STI`, so STN chips never are
+    pub fn is_likely_clone(&self) -> bool {
+        if self.backend() == Backend::Stn {
+            return false
+        }
+        let Some(version) = self.version else {
+            return true
+        };
+        version == CLONE_VERSION
+            || self.banner_mismatch
+            || CAPABILITIES.iter().any(|capability| version >= capability.since() && !self.supports(*capability))
+    }
+
+    /// Boot screen text, same 4 lines of 11 characters as the error strings:
+    /// `ADAPTER:`, the identity, then whether it's likely a clone or the STN ID
+    pub fn to_display_str<'b>(&self, buffer: &'b mut [u8; DISPLAY_TEXT_LEN]) -> &'b str {
+        const TEMPLATE: &'static [u8] = b"ADAPTER:   \n???????????\n           \n           ";
+        const IDENTITY_START: usize = 12;
+        const DETAIL_START: usize = 24;
+        buffer.copy_from_slice(TEMPLATE);
+        if !self.identity.is_empty() {
+            let len = self.identity.len().min(11);
+            buffer[IDENTITY_START..IDENTITY_START + 11].fill(b' ');
+            buffer[IDENTITY_START..IDENTITY_START + len].copy_from_slice(&self.identity[..len]);
+        }
+        if self.is_likely_clone() {
+            buffer[DETAIL_START..DETAIL_START + 6].copy_from_slice(b"CLONE?");
+        } else if !self.stn_identity.is_empty() {
+            let len = self.stn_identity.len().min(11);
+            buffer[DETAIL_START..DETAIL_START + len].copy_from_slice(&self.stn_identity[..len]);
+        }
+        // anything that isn't printable would break the layout
+        buffer.iter_mut()
+            .filter(|c| **c != b'\n' && !c.is_ascii_graphic() && **c != b' ')
+            .for_each(|c| *c = b'?');
+        core::str::from_utf8(buffer).unwrap_or("")
+    }
+}
+
+impl defmt::Format for AdapterProfile {
+    fn format(&self, fmt: Formatter) {
+        defmt::write!(fmt, "AdapterProfile(identity = {:?}, version = {:?}, supports = [",
+            core::str::from_utf8(&self.identity).unwrap_or("<not ascii>"), self.version);
+        for capability in CAPABILITIES.iter().filter(|capability| self.supports(**capability)) {
+            defmt::write!(fmt, "{:?} ", capability);
+        }
+        defmt::write!(fmt, "], stn = {:?}, likely clone = {})",
+            core::str::from_utf8(&self.stn_identity).unwrap_or("<not ascii>"), self.is_likely_clone())
+    }
+}
+
+/// The banner line of a response: the one that starts with `ELM327`, or else the first line that isn't empty.
+/// Cut at `MAX_IDENTITY_LEN`
+pub fn identity(text: &[u8]) -> Identity {
+    let mut lines = text.split(|c| *c == b'\r' || *c == b'\n')
+        .map(|line| line.trim_ascii())
+        .filter(|line| !line.is_empty());
+    let first = lines.clone().next().unwrap_or(b"");
+    let line = lines.find(|line| line.starts_with(b"ELM327")).unwrap_or(first);
+    line.iter().copied().take(MAX_IDENTITY_LEN).collect()
+}
+
+/// The digits after the `v` of the banner, ex: `ELM327 v1.4b`. Letters after the minor version are ignored
+fn parse_version(banner: &[u8]) -> Option<ElmVersion> {
+    let start = banner.iter().position(|c| *c == b'v' || *c == b'V')? + 1;
+    let mut parts = banner[start..].split(|c| *c == b'.');
+    Some(ElmVersion {
+        major: leading_number(parts.next()?)?,
+        minor: leading_number(parts.next()?)?,
+    })
+}
+
+fn leading_number(digits: &[u8]) -> Option<u8> {
+    let digits = &digits[..digits.iter().take_while(|c| c.is_ascii_digit()).count()];
+    if digits.is_empty() {
+        return None
+    }
+    Some(digits.iter().fold(0u8, |number, digit| number.wrapping_mul(10).wrapping_add(digit - b'0')))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(reset_text: &[u8], identify_text: &[u8], missing: &[Capability]) -> AdapterProfile {
+        let mut profile = AdapterProfile::new(reset_text, identify_text);
+        for capability in CAPABILITIES {
+            profile.record(capability, !missing.contains(&capability));
+        }
+        profile
+    }
+
+    #[test]
+    fn test_version() {
+        assert_eq!(parse_version(b"ELM327 v1.5"), Some(ElmVersion { major: 1, minor: 5 }));
+        assert_eq!(parse_version(b"ELM327 v1.4b"), Some(ElmVersion { major: 1, minor: 4 }));
+        assert_eq!(parse_version(b"ELM327 v2.10"), Some(ElmVersion { major: 2, minor: 10 }));
+        assert_eq!(parse_version(b"OBDII to RS232 Interpreter"), None);
+        // the echo and blank lines around the banner don't matter
+        assert_eq!(identity(b"\r\rELM327 v1.4b\r").as_slice(), b"ELM327 v1.4b");
+        assert_eq!(identity(b"ATI\rELM327 v2.1\r").as_slice(), b"ELM327 v2.1");
+    }
+
+    #[test]
+    fn test_clone_detection() {
+        let genuine = profile(b"ELM327 v1.4b", b"ELM327 v1.4b", &[]);
+        assert!(!genuine.is_likely_clone());
+        assert!(genuine.supports(Capability::AdaptiveTiming));
+        // older than ATCRA, so not having it is fine
+        assert!(!profile(b"ELM327 v1.3a", b"ELM327 v1.3a", &[Capability::ReceiveFilter, Capability::IgnitionMonitor]).is_likely_clone());
+        assert!(!profile(b"ELM327 v1.3a", b"ELM327 v1.3a", &[]).can_sleep());
+        assert!(genuine.can_sleep());
+        assert!(genuine.can_switch_baud_rate());
+        assert!(!profile(b"ELM327 v1.0", b"ELM327 v1.0", &[Capability::AdaptiveTiming, Capability::ReceiveFilter, Capability::IgnitionMonitor]).can_switch_baud_rate());
+
+        assert!(profile(b"ELM327 v1.5", b"ELM327 v1.5", &[]).is_likely_clone());
+        assert!(profile(b"ELM327 v2.1", b"ELM327 v2.1", &[Capability::AdaptiveTiming]).is_likely_clone());
+        assert!(profile(b"ELM327 v1.4", b"ELM327 v2.1", &[]).is_likely_clone());
+        assert!(profile(b"OBDII", b"OBDII", &[]).is_likely_clone());
+
+        let mut buffer = [0u8; DISPLAY_TEXT_LEN];
+        assert_eq!(
+            profile(b"ELM327 v1.5", b"ELM327 v1.5", &[]).to_display_str(&mut buffer),
+            "ADAPTER:   \nELM327 v1.5\nCLONE?     \n           "
+        );
+        assert_eq!(
+            genuine.to_display_str(&mut buffer),
+            "ADAPTER:   \nELM327 v1.4\n           \n           "
+        );
+
+        // an OBDLink says it's a 1.4b, and answers STI
+        let mut stn = profile(b"ELM327 v1.4b", b"ELM327 v1.4b", &[]);
+        stn.record_stn(b"?\r");
+        assert_eq!(stn.backend(), Backend::Elm);
+        stn.record_stn(b"STN1110 v4.2.0\r");
+        assert_eq!(stn.backend(), Backend::Stn);
+        assert!(!stn.is_likely_clone());
+        assert_eq!(
+            stn.to_display_str(&mut buffer),
+            "ADAPTER:   \nELM327 v1.4\nSTN1110 v4.\n           "
+        );
+    }
+}
@@ -1,5 +1,8 @@
+use arrayvec::ArrayVec;
 use defmt::Formatter;
+use crate::data_point::Datum;
 use crate::errors::ToRustAGaugeError;
+use crate::obd_protocol::{FrameFormat, ObdProtocol};
 
 #[derive(defmt::Format, Debug)]
 pub struct StaticCommand(&'static str);
@@ -9,22 +12,157 @@ impl StaticCommand {
     pub fn as_bytes(&self) -> &[u8] {
         self.0.as_bytes()
     }
+
+    /// The command without the trailing `\r`, used to say which command failed
+    pub fn name(&self) -> &'static str {
+        self.0.trim_end_matches('\r')
+    }
+}
+
+/// What the ELM should answer an AT command with
+#[derive(defmt::Format, Debug, Copy, Clone, PartialEq)]
+pub enum AtReply {
+    /// `OK`
+    Ok,
+    /// The version banner printed after a reset, ex: `ELM327 v1.5`
+    Banner,
+}
+
+impl AtReply {
+    /// Whether any line of `reply` is this reply
+    pub fn is_in(&self, reply: &[u8]) -> bool {
+        reply.split(|c| *c == b'\r' || *c == b'\n').any(|line| match self {
+            AtReply::Ok => line == b"OK",
+            AtReply::Banner => line.starts_with(b"ELM327"),
+        })
+    }
+}
+
+/// An AT command and the reply it should get. 
+/// Optional commands are ones that some clones don't implement, init carries on without them
+#[derive(defmt::Format, Debug)]
+pub struct AtCommand {
+    pub command: StaticCommand,
+    pub expected_reply: AtReply,
+    pub optional: bool,
+}
+
+impl AtCommand {
+    pub const fn required(command: StaticCommand, expected_reply: AtReply) -> Self {
+        Self {
+            command,
+            expected_reply,
+            optional: false,
+        }
+    }
+
+    pub const fn optional(command: StaticCommand, expected_reply: AtReply) -> Self {
+        Self {
+            command,
+            expected_reply,
+            optional: true,
+        }
+    }
+
+    /// Looks for the expected reply on any line, so an echo of the command (echo is on until `ATE0`) doesn't matter
+    pub fn check_reply(&self, reply: &[u8]) -> Result<(), ToRustAGaugeError> {
+        if self.expected_reply.is_in(reply) {
+            Ok(())
+        } else {
+            Err(ToRustAGaugeError::AtCommandRejected(self.command.name()))
+        }
+    }
 }
 
 
 pub const ELM_RESET: StaticCommand = StaticCommand("ATZ\r");
+/// Answered with the same banner as `ATZ`, see `elm_adapter`
+pub const IDENTIFY: StaticCommand = StaticCommand("ATI\r");
 pub const DISABLE_ECHO: StaticCommand = StaticCommand("ATE0\r");
 pub const ENABLE_HEADERS: StaticCommand = StaticCommand("ATH1\r");
+pub const SET_PROTOCOL_AUTO: StaticCommand = StaticCommand("ATSP0\r");
 pub const SET_PROTOCOL_5: StaticCommand = StaticCommand("ATSP5\r");
+pub const DESCRIBE_PROTOCOL_NUMBER: StaticCommand = StaticCommand("ATDPN\r");
+/// Any OBD request starts the ELM's protocol search after `ATSP0`, PID 0x00 is supported by everything
+pub const PROTOCOL_SEARCH_REQUEST: StaticCommand = StaticCommand("0100\r");
 pub const SET_TIMEOUT_64: StaticCommand = StaticCommand("ATST64\r");
 pub const DISABLE_SPACES: StaticCommand = StaticCommand("ATS0\r");
 pub const DISABLE_MEMORY: StaticCommand = StaticCommand("ATM0\r");
 pub const ENABLE_AUTO_TIMINGS_1: StaticCommand = StaticCommand("ATAT1\r");
 pub const SET_CUSTOM_HEADERS: StaticCommand = StaticCommand("ATSH8210F0\r");
+/// Name used in errors for `get_set_timeout_command`
+pub const SET_TIMEOUT_NAME: &str = "ATST";
+/// Name used in errors for `get_set_protocol_command`
+pub const SET_PROTOCOL_NAME: &str = "ATSP";
 pub const ELM_REQUEST_VBAT: StaticCommand = StaticCommand("ATRV\r");
+/// `ON` or `OFF`, the level of the adapter's ignition input (IgnMon). Adapters that don't wire it to the ignition
+/// always say `ON`
+pub const READ_IGNITION: StaticCommand = StaticCommand("ATIGN\r");
+/// Answered with `OK`, then the adapter goes to sleep. See `ElmDriver::sleep`
+pub const LOW_POWER_MODE: StaticCommand = StaticCommand("ATLP\r");
+pub const REQUEST_STORED_DTCS: StaticCommand = StaticCommand("03\r");
+pub const REQUEST_PENDING_DTCS: StaticCommand = StaticCommand("07\r");
+pub const CLEAR_DTCS: StaticCommand = StaticCommand("04\r");
+pub const REQUEST_VIN: StaticCommand = StaticCommand("0902\r");
+pub const REQUEST_CALIBRATION_IDS: StaticCommand = StaticCommand("0904\r");
+/// Prints every frame on the bus (that passes the receive filter) until any character is sent
+pub const MONITOR_ALL: StaticCommand = StaticCommand("ATMA\r");
+/// Broadcast frames aren't ISO-TP, without this the ELM takes their first byte for a PCI byte
+pub const DISABLE_CAN_AUTO_FORMATTING: StaticCommand = StaticCommand("ATCAF0\r");
+pub const ENABLE_CAN_AUTO_FORMATTING: StaticCommand = StaticCommand("ATCAF1\r");
+/// `ATCRA` without an address goes back to receiving the answers to requests
+pub const RESET_RECEIVE_FILTER: StaticCommand = StaticCommand("ATCRA\r");
+/// Name used in errors for `bus_monitor::receive_filter_command`
+pub const SET_RECEIVE_FILTER_NAME: &str = "ATCRA";
 
+/// STN chips (OBDLink) answer with their own ID, ex: `STN1110 v4.2.0`. Any ELM answers `?`
+pub const STN_IDENTIFY: StaticCommand = StaticCommand("STI\r");
+/// `ATMA` with the STN's own filters, see `bus_monitor::pass_filter_commands`
+pub const STN_MONITOR_ALL: StaticCommand = StaticCommand("STMA\r");
+/// The STN uses its pass filters for requests too, so they have to be cleared after monitoring
+pub const STN_CLEAR_PASS_FILTERS: StaticCommand = StaticCommand("STFCP\r");
+/// Name used in errors for `bus_monitor::pass_filter_commands`
+pub const STN_ADD_PASS_FILTER_NAME: &str = "STFAP";
+/// Name used in errors for `get_baud_rate_command`
+pub const STN_BAUD_RATE_NAME: &str = "STBR";
+/// Name used in errors for `get_baud_rate_divisor_command`
+pub const SET_BAUD_RATE_DIVISOR_NAME: &str = "ATBRD";
+/// `ATBRD` sets the ELM's baud rate to this divided by the divisor
+pub const BAUD_RATE_DIVISOR_CLOCK: u32 = 4_000_000;
+/// 500 kbaud, the fastest `ATBRD` goes on a v1.x ELM
+const MIN_BAUD_RATE_DIVISOR: u32 = 8;
+/// "STPX D:", 16 request bytes, `\r`
+pub const MAX_STPX_COMMAND_LEN: usize = 40;
 
-const PID_COMMAND_PADDING: [u8; 7] = [0x32, 0x31, 0x30, 0x30, 0x30, 0x31, 0x0d];
+/// Sent in order after power up, before the protocol is picked. 
+/// `ATST` and `ATAT` only tune timing, so the ELM still works if a clone rejects them.
+/// Init sends those two as probes after the rest instead, see `elm_adapter::Capability`
+pub const ELM_INIT_SEQUENCE: [AtCommand; 7] = [
+    AtCommand::required(ELM_RESET, AtReply::Banner),
+    AtCommand::required(DISABLE_ECHO, AtReply::Ok),
+    AtCommand::required(ENABLE_HEADERS, AtReply::Ok),
+    AtCommand::optional(SET_TIMEOUT_64, AtReply::Ok),
+    AtCommand::required(DISABLE_SPACES, AtReply::Ok),
+    AtCommand::required(DISABLE_MEMORY, AtReply::Ok),
+    AtCommand::optional(ENABLE_AUTO_TIMINGS_1, AtReply::Ok),
+];
+
+
+/// SAE J1979 Mode 01, current data for the standard PIDs
+pub const STANDARD_PID_SERVICE: u8 = 0x01;
+/// KWP2000 ReadDataByLocalIdentifier (Mode 21), manufacturer specific one byte identifiers
+pub const LOCAL_IDENTIFIER_SERVICE: u8 = 0x21;
+/// ReadDataByCommonIdentifier (KWP2000) / ReadDataByIdentifier (UDS), two byte identifiers
+pub const DATA_IDENTIFIER_SERVICE: u8 = 0x22;
+/// Positive responses come back with the request's service plus this
+pub const RESPONSE_SERVICE_OFFSET: u8 = 0x40;
+/// Mode 21 requests end with the transmission mode, 01 is a single response
+const LOCAL_IDENTIFIER_TRANSMISSION_MODE: u8 = 0x01;
+/// Service, 2 byte identifier, transmission mode, `\r`
+const MAX_PID_COMMAND_LEN: usize = 9;
+/// "02" + PID + frame number
+const FREEZE_FRAME_COMMAND_PADDING: [u8; 7] = [0x30, 0x32, 0x30, 0x30, 0x30, 0x30, 0x0d];
+pub const FREEZE_FRAME_RESPONSE_SERVICE: u8 = 0x42;
 
 #[repr(u8)]
 pub enum PID{
@@ -34,68 +172,352 @@ pub enum PID{
 }
 
 
+/// What the value returned by a `PidCommand`'s calculation is measured in
+#[derive(defmt::Format, Debug, Copy, Clone, PartialEq)]
+pub enum PidUnits{
+    /// Raw bit flags packed into an integer (supported PIDs, monitor status, etc.)
+    Bitfield,
+    /// An enumerated value, see SAE J1979 for the meaning of each value
+    Enumerated,
+    Count,
+    Percent,
+    DegreesC,
+    DegreesBeforeTdc,
+    Kpa,
+    Pa,
+    Rpm,
+    KmH,
+    Km,
+    GramsPerSecond,
+    LitersPerHour,
+    Volts,
+    MilliAmps,
+    /// Air-fuel equivalence ratio (lambda)
+    Ratio,
+    Seconds,
+    Minutes,
+    NewtonMeters,
+}
+
+impl PidUnits{
+    pub const fn symbol(&self) -> &'static str {
+        match self {
+            PidUnits::Bitfield => { "" }
+            PidUnits::Enumerated => { "" }
+            PidUnits::Count => { "" }
+            PidUnits::Percent => { "%" }
+            PidUnits::DegreesC => { "C" }
+            PidUnits::DegreesBeforeTdc => { "deg" }
+            PidUnits::Kpa => { "kPa" }
+            PidUnits::Pa => { "Pa" }
+            PidUnits::Rpm => { "rpm" }
+            PidUnits::KmH => { "km/h" }
+            PidUnits::Km => { "km" }
+            PidUnits::GramsPerSecond => { "g/s" }
+            PidUnits::LitersPerHour => { "L/h" }
+            PidUnits::Volts => { "V" }
+            PidUnits::MilliAmps => { "mA" }
+            PidUnits::Ratio => { "lambda" }
+            PidUnits::Seconds => { "s" }
+            PidUnits::Minutes => { "min" }
+            PidUnits::NewtonMeters => { "Nm" }
+        }
+    }
+}
+
+
 pub struct PidCommand{
-    pub pid: u8,
+    /// The service the request is sent with, the response comes back with `service + 0x40`
+    pub service: u8,
+    /// The PID or identifier, `identifier_len` bytes of it are sent (big endian)
+    pub pid: u16,
+    pub identifier_len: usize,
+    /// SAE J1979 PIDs are listed in the supported PID bitmaps and can be batched or read from a freeze frame,
+    /// manufacturer specific identifiers can't
+    pub standard: bool,
     pub num_bytes_in_response: usize,
+    /// Short human-readable name, used for logging
+    pub name: &'static str,
+    pub units: PidUnits,
     value_calculation: fn(&[u8]) -> f64,
-    pub ascii_command: [u8; 7]
-}
-pub const fn get_ascii_command(pid: u8) -> [u8; 7] {
-    let mut output = PID_COMMAND_PADDING;
-    let hex_digit_1: u8 = HexDigits::from_val(pid >> 4) as u8;
-    let hex_digit_2: u8 = HexDigits::from_val(pid) as u8;
-    output[2] = hex_digit_1;
-    output[3] = hex_digit_2;
+    ascii_command: [u8; MAX_PID_COMMAND_LEN],
+    ascii_command_len: usize,
+}
+
+/// Request for `identifier` (`identifier_len` bytes) with `service`, ex: "010C\r", "218101\r" or "22F40D\r".
+/// Returns the command and how many bytes of it are used
+pub const fn get_ascii_command(service: u8, identifier: u16, identifier_len: usize) -> ([u8; MAX_PID_COMMAND_LEN], usize) {
+    let mut output = [0u8; MAX_PID_COMMAND_LEN];
+    let mut len = 0;
+    let mut bytes = [0u8; 4];
+    let mut num_bytes = 0;
+    bytes[num_bytes] = service;
+    num_bytes += 1;
+    if identifier_len == 2 {
+        bytes[num_bytes] = (identifier >> 8) as u8;
+        num_bytes += 1;
+    }
+    bytes[num_bytes] = identifier as u8;
+    num_bytes += 1;
+    if service == LOCAL_IDENTIFIER_SERVICE {
+        bytes[num_bytes] = LOCAL_IDENTIFIER_TRANSMISSION_MODE;
+        num_bytes += 1;
+    }
+    let mut i = 0;
+    while i < num_bytes {
+        output[len] = HexDigits::from_val(bytes[i] >> 4) as u8;
+        output[len + 1] = HexDigits::from_val(bytes[i]) as u8;
+        len += 2;
+        i += 1;
+    }
+    output[len] = b'\r';
+    (output, len + 1)
+}
+
+/// Most PIDs a single Mode 01 request can ask for (ISO 15765-4, so CAN only)
+pub const MAX_PIDS_PER_REQUEST: usize = 6;
+pub const BATCH_RESPONSE_SERVICE: u8 = 0x41;
+
+/// Values from a batched request, in the order the ECU sent them
+pub type PidValues = ArrayVec<(&'static PidCommand, f64), MAX_PIDS_PER_REQUEST>;
+
+/// Mode 01 request for up to `MAX_PIDS_PER_REQUEST` PIDs at once, ex: "010C050D\r". Extra PIDs are left out
+pub fn get_batch_ascii_command(pids: &[&PidCommand]) -> ArrayVec<u8, { 3 + 2 * MAX_PIDS_PER_REQUEST }> {
+    let mut output: ArrayVec<u8, { 3 + 2 * MAX_PIDS_PER_REQUEST }> = ArrayVec::new();
+    output.extend([b'0', b'1']);
+    for pid in pids.iter().filter_map(|pid| pid.standard_pid()).take(MAX_PIDS_PER_REQUEST) {
+        output.extend([HexDigits::from_val(pid >> 4) as u8, HexDigits::from_val(pid) as u8]);
+    }
+    output.push(b'\r');
+    output
+}
+
+/// Splits the (reassembled) response to a batched request into one value per PID: `0x41`, then each PID 
+/// followed by its data. PIDs the ECU doesn't support are left out of the response, and so out of the result
+pub fn decode_batch_response(message: &[u8], pids: &[&'static PidCommand]) -> Result<PidValues, ToRustAGaugeError> {
+    let Some((&service, mut rest)) = message.split_first() else {
+        return Err(ToRustAGaugeError::UartIncorrectLengthError())
+    };
+    if service != BATCH_RESPONSE_SERVICE {
+        defmt::warn!("UartServiceMismatchError: {:?}", message);
+        return Err(ToRustAGaugeError::UartServiceMismatchError())
+    }
+    let mut values = PidValues::new();
+    while let Some((&pid, data)) = rest.split_first() {
+        let Some(command) = pids.iter().find(|command| command.standard_pid() == Some(pid)) else {
+            defmt::warn!("UartPidMismatchError: {:?}", message);
+            return Err(ToRustAGaugeError::UartPidMismatchError())
+        };
+        if data.len() < command.num_bytes_in_response {
+            defmt::warn!("UartIncorrectLengthError: {:?}", message);
+            return Err(ToRustAGaugeError::UartIncorrectLengthError())
+        }
+        let (value_bytes, remaining) = data.split_at(command.num_bytes_in_response);
+        if values.try_push((*command, command.get_value(value_bytes))).is_err() {
+            defmt::warn!("UartPidMismatchError (same PID twice): {:?}", message);
+            return Err(ToRustAGaugeError::UartPidMismatchError())
+        }
+        rest = remaining;
+    }
+    Ok(values)
+}
+
+/// `ATSTxx`: how long the ELM waits for the ECU to answer, in units of 4.096 ms
+pub const fn get_set_timeout_command(timeout: u8) -> [u8; 7] {
+    [b'A', b'T', b'S', b'T', HexDigits::from_val(timeout >> 4) as u8, HexDigits::from_val(timeout) as u8, b'\r']
+}
+
+/// `ATSPn`: only `protocol` is tried, without a search
+pub const fn get_set_protocol_command(protocol: ObdProtocol) -> [u8; 6] {
+    [b'A', b'T', b'S', b'P', protocol.number(), b'\r']
+}
+
+/// The STN's `STPX` version of a request (ex: "010C\r" is "STPX D:010C\r"). It takes requests of any length
+/// and sends the ones that don't fit in a CAN frame as ISO-TP multi-frame messages, the ELM can't.
+/// Extra bytes are left out
+pub fn get_stpx_command(request: &[u8]) -> ArrayVec<u8, MAX_STPX_COMMAND_LEN> {
+    let mut output: ArrayVec<u8, MAX_STPX_COMMAND_LEN> = ArrayVec::new();
+    output.extend(*b"STPX D:");
+    let data = request.strip_suffix(b"\r").unwrap_or(request);
+    output.extend(data.iter().copied().take(MAX_STPX_COMMAND_LEN - output.len() - 1));
+    output.push(b'\r');
+    output
+}
+
+/// `STBR` or `ATBRD` and its argument
+pub type BaudRateCommand = ArrayVec<u8, 14>;
+
+/// `STBRxxxx`: switches the STN's UART to `baud_rate` (in baud, decimal), see `ElmDriver::switch_baud_rate`
+pub fn get_baud_rate_command(baud_rate: u32) -> BaudRateCommand {
+    let mut output = BaudRateCommand::new();
+    output.extend(*b"STBR");
+    let digits = baud_rate.checked_ilog10().unwrap_or(0) + 1;
+    for digit in (0..digits).rev() {
+        output.push(b'0' + (baud_rate / 10u32.pow(digit) % 10) as u8);
+    }
+    output.push(b'\r');
+    output
+}
+
+/// The `ATBRD` divisor closest to `baud_rate`, ex: 8 for 500000. 230400 isn't exact, it's 17 (235294 baud)
+pub fn baud_rate_divisor(baud_rate: u32) -> u8 {
+    let divisor = (BAUD_RATE_DIVISOR_CLOCK + baud_rate / 2) / baud_rate.max(1);
+    divisor.clamp(MIN_BAUD_RATE_DIVISOR, 0xff) as u8
+}
+
+/// `ATBRDxx`: switches the ELM's UART to `BAUD_RATE_DIVISOR_CLOCK / divisor`, see `ElmDriver::switch_baud_rate`
+pub fn get_baud_rate_divisor_command(divisor: u8) -> BaudRateCommand {
+    let mut output = BaudRateCommand::new();
+    output.extend(*b"ATBRD");
+    output.extend([HexDigits::from_val(divisor >> 4) as u8, HexDigits::from_val(divisor) as u8, b'\r']);
+    output
+}
+
+/// Mode 02 request for `pid` in freeze frame `frame`
+pub const fn get_freeze_frame_ascii_command(pid: u8, frame: u8) -> [u8; 7] {
+    let mut output = FREEZE_FRAME_COMMAND_PADDING;
+    output[2] = HexDigits::from_val(pid >> 4) as u8;
+    output[3] = HexDigits::from_val(pid) as u8;
+    output[4] = HexDigits::from_val(frame >> 4) as u8;
+    output[5] = HexDigits::from_val(frame) as u8;
     output
 }
 impl PidCommand{
 
+    /// An SAE J1979 PID, requested with Mode 01 (`01 PP`)
     pub const fn new(pid: u8,
                num_bytes_in_response: usize,
+               name: &'static str,
+               units: PidUnits,
                value_calculation: fn(&[u8]) -> f64
     ) -> Self {
+        let mut command = Self::extended(STANDARD_PID_SERVICE, pid as u16, 1, num_bytes_in_response, name, units, value_calculation);
+        command.standard = true;
+        command
+    }
+
+    /// A manufacturer specific value, ex: a Mode 21 local identifier (1 byte) or a Mode 22 data identifier (2 bytes).
+    /// `value_calculation` gets the data bytes after the identifier
+    pub const fn extended(service: u8,
+                          identifier: u16,
+                          identifier_len: usize,
+                          num_bytes_in_response: usize,
+                          name: &'static str,
+                          units: PidUnits,
+                          value_calculation: fn(&[u8]) -> f64
+    ) -> Self {
+        assert!(identifier_len == 1 || identifier_len == 2, "identifiers are 1 or 2 bytes");
+        assert!(identifier_len == 2 || identifier <= 0xff, "identifier doesn't fit in 1 byte");
+        let (ascii_command, ascii_command_len) = get_ascii_command(service, identifier, identifier_len);
         Self {
-            pid,
+            service,
+            pid: identifier,
+            identifier_len,
+            standard: false,
             num_bytes_in_response,
+            name,
+            units,
             value_calculation,
-            ascii_command: get_ascii_command(pid),
+            ascii_command,
+            ascii_command_len,
+        }
+    }
+
+    /// The request, ex: "010C\r"
+    pub fn ascii_command(&self) -> &[u8] {
+        &self.ascii_command[..self.ascii_command_len]
+    }
+
+    /// The SAE J1979 PID number, `None` for manufacturer specific identifiers
+    pub const fn standard_pid(&self) -> Option<u8> {
+        if self.standard { Some(self.pid as u8) } else { None }
+    }
+
+    /// Wraps a value calculated by this command in the matching `Datum`. 
+    /// PIDs that the rest of the firmware has a dedicated variant for (RPM, coolant) get that variant, 
+    /// other PIDs become a `Datum::ObdPid` and manufacturer specific values a `Datum::ExtendedPid`
+    pub fn to_datum(&self, value: f64) -> Datum {
+        match self.standard_pid() {
+            Some(0x05) => Datum::CoolantTempC(value),
+            Some(0x0c) => Datum::RPM(value),
+            Some(pid) => Datum::ObdPid(pid, value),
+            None => Datum::ExtendedPid(self.service, self.pid, value),
         }
     }
 
 
-    pub fn extract_val_from_parsed_resp(&self, response: &[u8]) -> Result<f64, ToRustAGaugeError>{
-        let resp_len = response.len();
-        if resp_len != self.num_bytes_in_response+6{
+    pub fn extract_val_from_parsed_resp(&self, response: &[u8], frame_format: FrameFormat) -> Result<f64, ToRustAGaugeError>{
+        Ok(self.get_value(self.extract_data_from_parsed_resp(response, frame_format)?))
+    }
+
+    /// Checks the framing (see `FrameFormat::frame_data`), length, service and identifier of a single frame response 
+    /// and returns just the data bytes
+    pub fn extract_data_from_parsed_resp<'b>(&self, response: &'b [u8], frame_format: FrameFormat) -> Result<&'b [u8], ToRustAGaugeError>{
+        let frame_data = frame_format.frame_data(response)?;
+        let data_start = 1 + self.identifier_len;
+        if frame_data.len() != data_start + self.num_bytes_in_response{
             defmt::warn!("UartIncorrectLengthError: {:?}", response);
             return Err(ToRustAGaugeError::UartIncorrectLengthError())
         }
-        if response[4] != self.pid{
+        if frame_data[0] != self.service + RESPONSE_SERVICE_OFFSET{
+            defmt::warn!("UartServiceMismatchError: {:?}", response);
+            return Err(ToRustAGaugeError::UartServiceMismatchError())
+        }
+        if frame_data[1..data_start] != self.pid.to_be_bytes()[2 - self.identifier_len..]{
             defmt::warn!("UartPidMismatchError: {:?}", response);
             return Err(ToRustAGaugeError::UartPidMismatchError())
         }
-        let mut actual_sum: u8 = 0;
-        for temp_byte in &response[0..resp_len-1]{
-            actual_sum = actual_sum.overflowing_add(*temp_byte).0;
+
+        Ok(&frame_data[data_start..])
+    }
+
+    /// Mode 02 version of `extract_data_from_parsed_resp`. The response has an extra frame number byte 
+    /// after the PID: `0x42`, PID, frame, data
+    pub fn extract_freeze_frame_data_from_parsed_resp<'b>(&self, response: &'b [u8], frame_format: FrameFormat, frame: u8) -> Result<&'b [u8], ToRustAGaugeError>{
+        let frame_data = frame_format.frame_data(response)?;
+        if frame_data.len() != self.num_bytes_in_response+3{
+            defmt::warn!("UartIncorrectLengthError: {:?}", response);
+            return Err(ToRustAGaugeError::UartIncorrectLengthError())
+        }
+        if frame_data[0] != FREEZE_FRAME_RESPONSE_SERVICE{
+            defmt::warn!("UartServiceMismatchError: {:?}", response);
+            return Err(ToRustAGaugeError::UartServiceMismatchError())
         }
-        if response[resp_len-1] != actual_sum {
-            return Err(ToRustAGaugeError::UartBadChecksumError())
+        if self.standard_pid() != Some(frame_data[1]) || frame_data[2] != frame{
+            defmt::warn!("UartPidMismatchError (PID or freeze frame number): {:?}", response);
+            return Err(ToRustAGaugeError::UartPidMismatchError())
         }
 
-        Ok((self.value_calculation)(&response[5..5+self.num_bytes_in_response]))
+        Ok(&frame_data[3..])
+    }
 
+    /// Applies this command's formula to the data bytes of a response (no header, PID or checksum)
+    pub fn get_value(&self, data: &[u8]) -> f64 {
+        (self.value_calculation)(data)
     }
 }
 
 
+/// The checksum used by ISO 9141 and KWP2000 frames, the sum of every byte before it
+pub fn additive_checksum(bytes: &[u8]) -> u8 {
+    let mut actual_sum: u8 = 0;
+    for temp_byte in bytes{
+        actual_sum = actual_sum.overflowing_add(*temp_byte).0;
+    }
+    actual_sum
+}
+
 impl defmt::Format for PidCommand{
     fn format(&self, fmt: Formatter) {
-        defmt::write!(fmt, "PidCommand(pid = {:?}, num_resp_bytes = {:?}, ascii_command = {:?})", self.pid, self.num_bytes_in_response, self.ascii_command)
+        defmt::write!(fmt, "PidCommand(service = {:x}, pid = {:x}, name = {:?}, num_resp_bytes = {:?}, ascii_command = {:?})", self.service, self.pid, self.name, self.num_bytes_in_response, self.ascii_command())
     }
 }
 
 pub const ENGINE_RPM_PID: PidCommand = PidCommand::new(
     0x0c,
     2,
+    "Engine speed",
+    PidUnits::Rpm,
     |slice| {
         assert_eq!(slice.len(), 2);
         (slice[0] as f64 * 256.0 + slice[1] as f64) / 4f64
@@ -105,17 +527,23 @@ pub const ENGINE_RPM_PID: PidCommand = PidCommand::new(
 pub const ENGINE_COOLANT_TEMP_PID: PidCommand = PidCommand::new(
     0x05,
     1,
+    "Engine coolant temperature",
+    PidUnits::DegreesC,
     |slice|{
         assert_eq!(slice.len(), 1);
         slice[0] as f64 -40f64
     }
 );
 
+/// The bitmap is decoded from the raw bytes by `SupportedPids`, the value is only for logging
 pub const HEARTBEAT_PID: PidCommand = PidCommand::new(
     0x00,
     4,
-    |_|{ 0f64 } // <- this isn't actually how to interpret the response,
-    // but I don't ever use the return in this project
+    "PIDs supported [01 - 20]",
+    PidUnits::Bitfield,
+    |slice|{
+        slice.iter().fold(0u32, |bitmap, byte| bitmap << 8 | *byte as u32) as f64
+    }
 );
 
 
@@ -163,4 +591,120 @@ impl HexDigits{
             _ => panic!("this should not be possible, u8 & 00001111 returned a value greater than 15")
         }
     }
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_at_reply_check() {
+        let reset = AtCommand::required(ELM_RESET, AtReply::Banner);
+        assert_eq!(reset.check_reply(b"ATZ\r\r\rELM327 v1.5\r\r"), Ok(()));
+        assert_eq!(reset.check_reply(b"\r\r"), Err(ToRustAGaugeError::AtCommandRejected("ATZ")));
+
+        let echo = AtCommand::required(DISABLE_ECHO, AtReply::Ok);
+        assert_eq!(echo.check_reply(b"ATE0\rOK\r\r"), Ok(()));
+
+        let auto_timing = AtCommand::optional(ENABLE_AUTO_TIMINGS_1, AtReply::Ok);
+        assert_eq!(auto_timing.check_reply(b"?\r\r"), Err(ToRustAGaugeError::AtCommandRejected("ATAT1")));
+    }
+
+    #[test]
+    fn test_pid_commands() {
+        // standard PIDs are Mode 01, `01 PP`, and answered with 41
+        assert_eq!(ENGINE_RPM_PID.ascii_command(), b"010C\r");
+        assert_eq!(ENGINE_RPM_PID.service, STANDARD_PID_SERVICE);
+        assert_eq!(ENGINE_RPM_PID.standard_pid(), Some(0x0c));
+        // KWP: 84 F1 10 | 41 0C 1A F8 | checksum
+        let mut response = [0x84, 0xf1, 0x10, 0x41, 0x0c, 0x1a, 0xf8, 0x00];
+        response[7] = additive_checksum(&response[..7]);
+        assert_eq!(ENGINE_RPM_PID.extract_val_from_parsed_resp(&response, FrameFormat::Kwp), Ok(1726.0));
+        // a Mode 21 answer isn't one
+        let mut response = [0x84, 0xf1, 0x10, 0x61, 0x0c, 0x1a, 0xf8, 0x00];
+        response[7] = additive_checksum(&response[..7]);
+        assert_eq!(ENGINE_RPM_PID.extract_data_from_parsed_resp(&response, FrameFormat::Kwp), Err(ToRustAGaugeError::UartServiceMismatchError()));
+        // negative response: 7F 01 12 (sub function not supported)
+        let mut response = [0x83, 0xf1, 0x10, 0x7f, 0x01, 0x12, 0x00];
+        response[6] = additive_checksum(&response[..6]);
+        assert_eq!(ENGINE_RPM_PID.extract_data_from_parsed_resp(&response, FrameFormat::Kwp), Err(ToRustAGaugeError::UartIncorrectLengthError()));
+
+        let local = PidCommand::extended(LOCAL_IDENTIFIER_SERVICE, 0x81, 1, 1, "Local 81", PidUnits::Count, |slice| slice[0] as f64);
+        assert_eq!(local.ascii_command(), b"218101\r");
+        assert_eq!(local.standard_pid(), None);
+        let data_identifier = PidCommand::extended(DATA_IDENTIFIER_SERVICE, 0xf40d, 2, 1, "Speed", PidUnits::KmH, |slice| slice[0] as f64);
+        assert_eq!(data_identifier.ascii_command(), b"22F40D\r");
+        // CAN: 7E8 04 62 F4 0D 3C
+        let response = [0x07, 0xe8, 0x04, 0x62, 0xf4, 0x0d, 0x3c];
+        assert_eq!(data_identifier.extract_val_from_parsed_resp(&response, FrameFormat::Can11Bit), Ok(60.0));
+        let response = [0x07, 0xe8, 0x04, 0x62, 0xf4, 0x0c, 0x3c];
+        assert_eq!(data_identifier.extract_data_from_parsed_resp(&response, FrameFormat::Can11Bit), Err(ToRustAGaugeError::UartPidMismatchError()));
+        let response = [0x07, 0xe8, 0x04, 0x61, 0xf4, 0x0d, 0x3c];
+        assert_eq!(data_identifier.extract_data_from_parsed_resp(&response, FrameFormat::Can11Bit), Err(ToRustAGaugeError::UartServiceMismatchError()));
+        assert!(matches!(data_identifier.to_datum(60.0), Datum::ExtendedPid(0x22, 0xf40d, value) if value == 60.0));
+    }
+
+    #[test]
+    fn test_freeze_frame_response() {
+        assert_eq!(&get_freeze_frame_ascii_command(0x0c, 0), b"020C00\r");
+        // KWP: 85 F1 10 | 42 0C 00 1A F8 | checksum
+        let mut response = [0x85, 0xf1, 0x10, 0x42, 0x0c, 0x00, 0x1a, 0xf8, 0x00];
+        response[8] = additive_checksum(&response[..8]);
+        assert_eq!(ENGINE_RPM_PID.extract_freeze_frame_data_from_parsed_resp(&response, FrameFormat::Kwp, 0), Ok(&[0x1a, 0xf8][..]));
+        // the right PID, from another freeze frame
+        assert_eq!(
+            ENGINE_RPM_PID.extract_freeze_frame_data_from_parsed_resp(&response, FrameFormat::Kwp, 1),
+            Err(ToRustAGaugeError::UartPidMismatchError())
+        );
+        // same length, another PID
+        let other_pid = PidCommand::new(0x0d, 2, "Other", PidUnits::KmH, |slice| slice[0] as f64);
+        assert_eq!(
+            other_pid.extract_freeze_frame_data_from_parsed_resp(&response, FrameFormat::Kwp, 0),
+            Err(ToRustAGaugeError::UartPidMismatchError())
+        );
+
+        // CAN: 7E8 05 42 0C 00 1A F8
+        let response = [0x07, 0xe8, 0x05, 0x42, 0x0c, 0x00, 0x1a, 0xf8];
+        assert_eq!(ENGINE_RPM_PID.extract_freeze_frame_data_from_parsed_resp(&response, FrameFormat::Can11Bit, 0), Ok(&[0x1a, 0xf8][..]));
+        // a Mode 01 answer has no frame number, and isn't 0x42
+        let response = [0x07, 0xe8, 0x05, 0x41, 0x0c, 0x00, 0x1a, 0xf8];
+        assert_eq!(
+            ENGINE_RPM_PID.extract_freeze_frame_data_from_parsed_resp(&response, FrameFormat::Can11Bit, 0),
+            Err(ToRustAGaugeError::UartServiceMismatchError())
+        );
+        let response = [0x07, 0xe8, 0x04, 0x41, 0x0c, 0x1a, 0xf8];
+        assert_eq!(
+            ENGINE_RPM_PID.extract_freeze_frame_data_from_parsed_resp(&response, FrameFormat::Can11Bit, 0),
+            Err(ToRustAGaugeError::UartIncorrectLengthError())
+        );
+    }
+
+    #[test]
+    fn test_stn_commands() {
+        assert_eq!(get_stpx_command(ENGINE_RPM_PID.ascii_command()).as_slice(), b"STPX D:010C\r");
+        assert_eq!(get_stpx_command(b"0902\r").as_slice(), b"STPX D:0902\r");
+        assert_eq!(get_baud_rate_command(2_000_000).as_slice(), b"STBR2000000\r");
+        assert_eq!(get_baud_rate_command(115200).as_slice(), b"STBR115200\r");
+        assert_eq!(get_baud_rate_divisor_command(baud_rate_divisor(500_000)).as_slice(), b"ATBRD08\r");
+        assert_eq!(get_baud_rate_divisor_command(baud_rate_divisor(230_400)).as_slice(), b"ATBRD11\r");
+        // faster than a v1.x ELM goes
+        assert_eq!(baud_rate_divisor(1_000_000), 8);
+    }
+
+    #[test]
+    fn test_batch_request() {
+        let pids = [&ENGINE_RPM_PID, &ENGINE_COOLANT_TEMP_PID, &crate::mode_01_pids::VEHICLE_SPEED_PID];
+        assert_eq!(get_batch_ascii_command(&pids).as_slice(), b"010C050D\r");
+
+        // coolant first, no speed
+        let values = decode_batch_response(&[0x41, 0x05, 0x5a, 0x0c, 0x1a, 0xf8], &pids).unwrap();
+        assert_eq!(values.len(), 2);
+        assert_eq!((values[0].0.pid, values[0].1), (0x05, 50.0));
+        assert_eq!((values[1].0.pid, values[1].1), (0x0c, 1726.0));
+
+        assert_eq!(decode_batch_response(&[0x41, 0x0c, 0x1a], &pids).err(), Some(ToRustAGaugeError::UartIncorrectLengthError()));
+        assert_eq!(decode_batch_response(&[0x41, 0x0f, 0x40], &pids).err(), Some(ToRustAGaugeError::UartPidMismatchError()));
+        assert_eq!(decode_batch_response(&[0x7f, 0x01, 0x12], &pids).err(), Some(ToRustAGaugeError::UartServiceMismatchError()));
+    }
+}
@@ -0,0 +1,1065 @@
+use arrayvec::ArrayVec;
+use embassy_time::{Duration, Instant, WithTimeout};
+use embedded_io_async::{ErrorKind, Read, Write};
+use crate::bus_monitor::{pass_filter_commands, receive_filter_command, BroadcastSignal};
+use crate::byte_parsing::{parse_ignition, parse_voltage};
+use crate::dtc::{decode_dtc_response, DtcList, CLEAR_DTC_RESPONSE_SERVICE};
+use crate::elm_commands;
+use crate::elm_adapter::Backend;
+use crate::elm_commands::{AtCommand, AtReply, PidCommand, PidValues, StaticCommand, MAX_STPX_COMMAND_LEN};
+use crate::elm_timing::ExchangeTiming;
+use crate::elm_trace::ElmTrace;
+use crate::errors::ToRustAGaugeError;
+use crate::obd_protocol::{Ecu, FrameFormat, ObdProtocol};
+use crate::response_parser::ResponseParser;
+
+pub const UART_TIMEOUT: Duration = Duration::from_millis(1000u64);
+/// What the ELM talks at after power up or `ATZ`
+pub const DEFAULT_BAUD_RATE: u32 = 115200;
+/// How long the adapter's ID can take to arrive at the new baud rate. It only waits about 75 ms for the answer
+/// (`ATBRT`), so there's no point waiting much longer
+const BAUD_RATE_SWITCH_TIMEOUT: Duration = Duration::from_millis(100u64);
+
+const DELIMITER_U8: u8 = '>' as u8;
+
+/// A transport whose speed can be changed on the fly, see `ElmDriver::switch_baud_rate`
+pub trait SetBaudRate {
+    fn set_baud_rate(&mut self, baud_rate: u32);
+}
+
+/// A command that was written before its response was asked for, see `send_next`
+struct Pipelined {
+    command: ArrayVec<u8, MAX_STPX_COMMAND_LEN>,
+    started: Instant,
+    written: Instant,
+}
+
+/// `ATMA` (or `STMA`) is running, see `start_monitor`
+struct Monitor {
+    frame_format: FrameFormat,
+    started: Instant,
+}
+
+/// The protocol side of talking to an ELM327: sending commands, reading until the `>` prompt and
+/// checking the responses. Works over anything that can read and write bytes (a UART, USB-CDC, a mock in tests),
+/// and owns the parser responses are read into
+pub struct ElmDriver<T: Read + Write> {
+    transport: T,
+    response: ResponseParser,
+    /// When the first byte of the current response arrived
+    first_byte_at: Option<Instant>,
+    last_exchange: ExchangeTiming,
+    pipelined: Option<Pipelined>,
+    monitor: Option<Monitor>,
+    trace: ElmTrace,
+    /// Set from the adapter profile after init, requests and monitoring use the STN's commands when it's `Stn`
+    backend: Backend,
+    baud_rate: u32,
+    /// How long the adapter has to answer, see `set_uart_timeout`
+    uart_timeout: Duration,
+}
+
+/// Only the kind is kept, so errors from any transport fit in `ToRustAGaugeError`
+fn transport_error<E: embedded_io_async::Error>(error: E) -> ToRustAGaugeError {
+    ToRustAGaugeError::UartError(error.kind())
+}
+
+impl<T: Read + Write> ElmDriver<T> {
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            response: ResponseParser::new(),
+            first_byte_at: None,
+            last_exchange: ExchangeTiming {
+                first_byte: None,
+                round_trip: Duration::from_ticks(0),
+                pipelined: false,
+            },
+            pipelined: None,
+            monitor: None,
+            trace: ElmTrace::new(),
+            backend: Backend::Elm,
+            baud_rate: DEFAULT_BAUD_RATE,
+            uart_timeout: UART_TIMEOUT,
+        }
+    }
+
+    /// How long to wait for the adapter before giving up on it, `UART_TIMEOUT` unless it's changed.
+    /// Commands that take longer (like a protocol search) are given their own timeout
+    pub fn set_uart_timeout(&mut self, timeout: Duration) {
+        self.uart_timeout = timeout;
+    }
+
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.backend = backend;
+    }
+
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    /// What the transport and the adapter are talking at, see `switch_baud_rate`
+    pub fn baud_rate(&self) -> u32 {
+        self.baud_rate
+    }
+
+    /// The text of the response to the last command: every line that isn't a frame, without the echo or the prompt
+    pub fn last_response(&self) -> &[u8] {
+        self.response.text()
+    }
+
+    /// How long the last command took to answer, whether it succeeded or not
+    pub fn last_exchange(&self) -> &ExchangeTiming {
+        &self.last_exchange
+    }
+
+    /// The last few exchanges, raw
+    pub fn trace(&self) -> &ElmTrace {
+        &self.trace
+    }
+
+    /// The frames of the last response sent with `request_frames`
+    pub fn frames(&self) -> impl Iterator<Item = &[u8]> + '_ {
+        self.response.frames()
+    }
+
+    /// reads until it finds the delimiter, feeding every byte to the parser as it arrives.
+    /// Could read past the delimiter, but in this use case there are no unprompted writes from the ELM
+    ///
+    /// # Arguments
+    ///
+    /// * `delimiter`:
+    /// * `timeout`: how long to wait for each byte
+    ///
+    /// returns: Result<()>, ToRustAGaugeError>
+    async fn read_until_char(&mut self, delimiter: u8, timeout: Duration) -> Result<(), ToRustAGaugeError> {
+        self.first_byte_at = None;
+
+        loop {
+            let c = self.read_byte(timeout).await?;
+            self.first_byte_at.get_or_insert_with(Instant::now);
+            if c == delimiter{
+                self.response.finish();
+                return Ok(())
+            }
+            // the parser stops keeping what doesn't fit, the rest is still read up to the prompt
+            self.response.push(c);
+            self.trace.response_byte(c);
+        }
+    }
+
+    async fn read_byte(&mut self, timeout: Duration) -> Result<u8, ToRustAGaugeError> {
+        let mut temp_buffer: [u8; 1] = [0u8];
+        match self.transport.read(&mut temp_buffer).with_timeout(timeout).await{
+            Ok(Ok(0)) => { // the transport was closed
+                Err(ToRustAGaugeError::UartError(ErrorKind::NotConnected))
+            }
+            Ok(Ok(_)) => { // timeout OK( Read OK( length read ) )
+                Ok(temp_buffer[0])
+            }
+            Ok(Err(e)) => { // timeout OK( Read Err( transport error ) )
+                Err(transport_error(e))
+            }
+            Err(e) => {
+                Err(ToRustAGaugeError::UartTimeoutError(e))
+            }
+        }
+    }
+
+    /// Reads lines until one that isn't empty, for the few replies that don't end with the prompt
+    async fn read_line(&mut self, timeout: Duration) -> Result<(), ToRustAGaugeError> {
+        while self.response.text().is_empty() {
+            self.read_until_char(b'\r', timeout).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends `message` and reads the response as text, see `last_response`
+    pub async fn write_read(&mut self, message: &[u8]) -> Result<(), ToRustAGaugeError> {
+        self.write_read_with_timeout(message, self.uart_timeout).await
+    }
+
+    /// Same as `write_read`, for the few commands that can take longer than the UART timeout to answer
+    pub async fn write_read_with_timeout(&mut self, message: &[u8], timeout: Duration) -> Result<(), ToRustAGaugeError> {
+        self.exchange(message, None, timeout).await
+    }
+
+    /// Sends `message` and parses the response as it arrives, into frames of `frame_format` (or only text if it's `None`).
+    /// If `message` was already sent with `send_next` only its response is read
+    async fn exchange(&mut self, message: &[u8], frame_format: Option<FrameFormat>, timeout: Duration) -> Result<(), ToRustAGaugeError> {
+        let (started, written, pipelined) = match self.pipelined.take() {
+            Some(pipelined) if pipelined.command.as_slice() == message => (pipelined.started, pipelined.written, true),
+            Some(pipelined) => {
+                defmt::debug!("Dropping the response to {:?}, it was sent too early", core::str::from_utf8(&pipelined.command).unwrap_or("<not ascii>"));
+                // the ELM only takes a new command once it has printed the prompt.
+                // If it never does, the new command fails the same way and that's what gets reported
+                self.response.start(&pipelined.command, None);
+                let dropped = self.read_until_char(DELIMITER_U8, self.uart_timeout).await;
+                self.trace.finish(pipelined.started.elapsed(), &dropped);
+                if let Err(e) = dropped {
+                    defmt::warn!("No prompt after the dropped response: {:?}", e);
+                }
+                let (started, written) = self.write(message).await?;
+                (started, written, false)
+            }
+            None => {
+                let (started, written) = self.write(message).await?;
+                (started, written, false)
+            }
+        };
+        self.response.start(message, frame_format);
+        let result = self.read_until_char(DELIMITER_U8, timeout).await;
+        self.last_exchange = ExchangeTiming {
+            first_byte: self.first_byte_at.filter(|_| !pipelined).map(|at| at - written),
+            round_trip: started.elapsed(),
+            pipelined,
+        };
+        // defmt::info!("`write_read` read: {:?}", self.response);
+        let result = result.and_then(|()| match self.response.parse_error() {
+            Some(error) => {
+                defmt::warn!("Failed to parse the response to {:?}: {:?}\nresponse was {:?}", core::str::from_utf8(message).unwrap_or("<not ascii>"), error, self.response);
+                Err(error.into())
+            }
+            None => Ok(()),
+        });
+        self.trace.finish(self.last_exchange.round_trip, &result);
+        result
+    }
+
+    /// Sends the request `message` now, so the ELM is already working on it while the last response is being handled.
+    /// The next `request_frames` of the same message only reads the response, any other command reads and drops it first.
+    /// Does nothing if a command is already waiting for its response
+    pub async fn send_next(&mut self, message: &[u8]) -> Result<(), ToRustAGaugeError> {
+        if self.pipelined.is_some() {
+            return Ok(())
+        }
+        let command = self.request_command(message);
+        let (started, written) = self.write(&command).await?;
+        self.pipelined = Some(Pipelined { command, started, written });
+        Ok(())
+    }
+
+    /// Writes and flushes `message`, returns when it started and when the last byte went out.
+    /// There is no delay before reading, the reads wait for the ELM with a timeout
+    async fn write(&mut self, message: &[u8]) -> Result<(Instant, Instant), ToRustAGaugeError> {
+        let started = Instant::now();
+        self.trace.start(message, started);
+        let written = self.transport.write_all(message).await;
+        let flushed = match written {
+            Ok(()) => self.transport.flush().await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = flushed {
+            let error = transport_error(e);
+            self.trace.finish(started.elapsed(), &Err(error.clone()));
+            return Err(error)
+        }
+        // defmt::info!("`write_read` wrote and flushed: {:?}", message);
+        Ok((started, Instant::now()))
+    }
+
+    /// Sends an AT command and checks its reply. Any failure of a required command is returned as
+    /// `AtCommandRejected` so it says which command failed, an optional command that fails is only logged
+    pub async fn send_at_command(&mut self, at_command: &AtCommand) -> Result<(), ToRustAGaugeError> {
+        let result = match self.write_read(at_command.command.as_bytes()).await {
+            Ok(()) => at_command.check_reply(self.response.text()),
+            Err(er) => {
+                defmt::warn!("Sending {:?} failed: {:?}", at_command.command.name(), er);
+                Err(ToRustAGaugeError::AtCommandRejected(at_command.command.name()))
+            }
+        };
+        match result {
+            Err(er) if at_command.optional => {
+                defmt::warn!("Optional command {:?} failed, continuing without it: {:?}\nresponse was {:?}", at_command.command.name(), er, self.response);
+                Ok(())
+            }
+            Err(er) => {
+                defmt::warn!("Command {:?} failed\nresponse was {:?}", at_command.command.name(), self.response);
+                self.trace.failed(&er);
+                Err(er)
+            }
+            Ok(()) => Ok(()),
+        }
+    }
+
+    /// `message` the way the backend sends requests, wrapped in `STPX` for an STN
+    fn request_command(&self, message: &[u8]) -> ArrayVec<u8, MAX_STPX_COMMAND_LEN> {
+        match self.backend {
+            Backend::Elm => message.iter().copied().take(MAX_STPX_COMMAND_LEN).collect(),
+            Backend::Stn => elm_commands::get_stpx_command(message),
+        }
+    }
+
+    /// Sends `message` and parses the response into `frame_format` frames, one per line.
+    /// The ELM's own messages are returned as errors, "NO DATA" is `UartResponseNoData`
+    pub async fn request_frames(&mut self, message: &[u8], frame_format: FrameFormat) -> Result<(), ToRustAGaugeError> {
+        let command = self.request_command(message);
+        let result = self.exchange(&command, Some(frame_format), self.uart_timeout).await;
+        if let Err(ToRustAGaugeError::UartError(_) | ToRustAGaugeError::UartTimeoutError(_)) = result {
+            return result
+        }
+        // the ELM's own message says more than a line that couldn't be parsed
+        if let Some(elm_error) = self.response.elm_error(){
+            self.trace.failed(&elm_error);
+            return Err(elm_error)
+        }
+        result
+    }
+
+    pub async fn get_pid(&mut self, pid: &PidCommand, frame_format: FrameFormat) -> Result<f64, ToRustAGaugeError> {
+        let data = self.get_pid_data(pid, frame_format).await?;
+        Ok(pid.get_value(data))
+    }
+
+    /// Same as `get_pid`, but only takes the answer from `ecu` if several ECUs answer
+    /// (`None` takes whichever answers first). Returns the ECU the value came from
+    pub async fn get_pid_from(&mut self, pid: &PidCommand, frame_format: FrameFormat, ecu: Option<Ecu>) -> Result<(Ecu, f64), ToRustAGaugeError> {
+        let (ecu, data) = self.get_pid_data_from(pid, frame_format, ecu).await?;
+        Ok((ecu, pid.get_value(data)))
+    }
+
+    /// Same as `get_pid`, but returns the data bytes of the response instead of running them through
+    /// the PID's formula. The slice borrows from the driver's frame buffer
+    pub async fn get_pid_data(&mut self, pid: &PidCommand, frame_format: FrameFormat) -> Result<&[u8], ToRustAGaugeError> {
+        self.get_pid_data_from(pid, frame_format, None).await.map(|(_, data)| data)
+    }
+
+    pub async fn get_pid_data_from(&mut self, pid: &PidCommand, frame_format: FrameFormat, ecu: Option<Ecu>) -> Result<(Ecu, &[u8]), ToRustAGaugeError> {
+        self.request_frames(pid.ascii_command(), frame_format).await?;
+        let result = ecu_frames(&self.response, frame_format, ecu)
+            .and_then(|(ecu, mut frames)| {
+                let frame = frames.next().ok_or(ToRustAGaugeError::UartIncorrectLengthError())?;
+                pid.extract_data_from_parsed_resp(frame, frame_format).map(|data| (ecu, data))
+            });
+        if let Err(er) = &result {
+            defmt::warn!("Failed to get PID: {:?}\nSent: {:?}\nresponse was {:?}", er, pid.ascii_command(), self.response);
+            self.trace.failed(er);
+        }
+        result
+    }
+
+    /// The ECU the last response started with, `None` if it had no frames
+    pub fn last_response_source(&self, frame_format: FrameFormat) -> Option<Ecu> {
+        self.response.frames().next().and_then(|frame| frame_format.source_ecu(frame))
+    }
+
+    /// Same as `get_pid_data`, but for every ECU that answers: `each` is called with the ECU and its data.
+    /// Frames that fail the checks are skipped, it's only an error if no ECU gave a usable answer
+    pub async fn get_pid_data_each_ecu(&mut self, pid: &PidCommand, frame_format: FrameFormat, mut each: impl FnMut(Ecu, &[u8])) -> Result<(), ToRustAGaugeError> {
+        self.request_frames(pid.ascii_command(), frame_format).await?;
+        let mut result = Err(ToRustAGaugeError::UartIncorrectLengthError());
+        for frame in self.response.frames() {
+            match (frame_format.source_ecu(frame), pid.extract_data_from_parsed_resp(frame, frame_format)) {
+                (Some(ecu), Ok(data)) => {
+                    each(ecu, data);
+                    result = Ok(());
+                }
+                (_, Err(er)) if result.is_err() => result = Err(er),
+                _ => {}
+            }
+        }
+        if let Err(er) = &result {
+            defmt::warn!("Failed to get PID from any ECU: {:?}\nSent: {:?}\nresponse was {:?}", er, pid.ascii_command(), self.response);
+            self.trace.failed(er);
+        }
+        result
+    }
+
+    /// Asks for several Mode 01 PIDs in one request (CAN only, up to `MAX_PIDS_PER_REQUEST`). 
+    /// The ECU leaves out PIDs it doesn't support, so there can be fewer values than `pids`.
+    /// `ecu` picks the ECU to take the answer from, like `get_pid_from`
+    pub async fn get_pid_batch(&mut self, pids: &[&'static PidCommand], frame_format: FrameFormat, ecu: Option<Ecu>) -> Result<(Ecu, PidValues), ToRustAGaugeError> {
+        let ascii_command = elm_commands::get_batch_ascii_command(pids);
+        self.request_frames(&ascii_command, frame_format).await?;
+        let result = ecu_frames(&self.response, frame_format, ecu)
+            .and_then(|(ecu, frames)| {
+                let message = frame_format.iso_tp_message(frames)?;
+                elm_commands::decode_batch_response(&message, pids).map(|values| (ecu, values))
+            });
+        if let Err(er) = &result {
+            defmt::warn!("Failed to get PID batch: {:?}\nSent: {:?}\nresponse was {:?}", er, ascii_command.as_slice(), self.response);
+            self.trace.failed(er);
+        }
+        result
+    }
+
+    /// Mode 02 version of `get_pid_data`, reads `pid` from freeze frame `frame`. Only SAE J1979 PIDs are stored in freeze frames
+    pub async fn get_freeze_frame_data(&mut self, pid: &PidCommand, frame: u8, frame_format: FrameFormat) -> Result<&[u8], ToRustAGaugeError> {
+        let Some(standard_pid) = pid.standard_pid() else {
+            defmt::warn!("{:?} isn't an SAE J1979 PID, it can't be read from a freeze frame", pid.name);
+            return Err(ToRustAGaugeError::UartPidMismatchError())
+        };
+        let ascii_command = elm_commands::get_freeze_frame_ascii_command(standard_pid, frame);
+        self.request_frames(&ascii_command, frame_format).await?;
+        let result = first_frame(&self.response)
+            .and_then(|response| pid.extract_freeze_frame_data_from_parsed_resp(response, frame_format, frame));
+        if let Err(er) = &result {
+            defmt::warn!("Failed to get freeze frame PID: {:?}\nSent: {:?}\nresponse was {:?}", er, &ascii_command, self.response);
+            self.trace.failed(er);
+        }
+        result
+    }
+
+    pub async fn get_dtcs(&mut self, command: &StaticCommand, response_service: u8, frame_format: FrameFormat) -> Result<DtcList, ToRustAGaugeError> {
+        match self.request_frames(command.as_bytes(), frame_format).await {
+            Ok(()) => {}
+            // some ECUs don't answer at all when there are no codes
+            Err(ToRustAGaugeError::UartResponseNoData()) => return Ok(DtcList::new()),
+            Err(er) => return Err(er),
+        }
+        let result = decode_dtc_response(self.response.frames(), frame_format, response_service);
+        if let Err(er) = &result {
+            defmt::warn!("Failed to get DTCs: {:?}\nSent: {:?}\nresponse was {:?}", er, command, self.response);
+            self.trace.failed(er);
+        }
+        result
+    }
+
+    /// Sends Mode 04. This also turns off the MIL and wipes freeze frames and monitor status, the caller is
+    /// responsible for checking that the engine is off first
+    pub async fn clear_dtcs(&mut self, frame_format: FrameFormat) -> Result<(), ToRustAGaugeError> {
+        self.request_frames(elm_commands::CLEAR_DTCS.as_bytes(), frame_format).await?;
+        match self.response.frames().next().map(|frame| frame_format.frame_data(frame)) {
+            Some(Ok(frame_data)) if frame_data.first() == Some(&CLEAR_DTC_RESPONSE_SERVICE) => Ok(()),
+            Some(Err(er)) => Err(er),
+            _ => Err(ToRustAGaugeError::UartServiceMismatchError()),
+        }
+    }
+
+    /// Sends `command` to find out whether the ELM knows it: `Ok(false)` if it answers `?`
+    pub async fn probe(&mut self, command: &StaticCommand) -> Result<bool, ToRustAGaugeError> {
+        self.write_read(command.as_bytes()).await?;
+        Ok(self.response.elm_error() != Some(ToRustAGaugeError::ElmUnknownCommand()))
+    }
+
+    /// Sends `0100` on `protocol` (`ATSPn`, waking up from `sleep` resets the adapter) only to see whether any
+    /// ECU answers. Anything but one of the ELM's own messages counts, the response isn't parsed
+    pub async fn ecu_answers(&mut self, protocol: ObdProtocol, timeout: Duration) -> Result<bool, ToRustAGaugeError> {
+        self.write_read(&elm_commands::get_set_protocol_command(protocol)).await?;
+        if !AtReply::Ok.is_in(self.response.text()) {
+            let error = ToRustAGaugeError::AtCommandRejected(elm_commands::SET_PROTOCOL_NAME);
+            self.trace.failed(&error);
+            return Err(error)
+        }
+        self.write_read_with_timeout(elm_commands::PROTOCOL_SEARCH_REQUEST.as_bytes(), timeout).await?;
+        Ok(self.response.elm_error().is_none() && !self.response.text().is_empty())
+    }
+
+    /// Sends `ATSTxx`, see `AdaptiveTiming`
+    pub async fn set_timeout(&mut self, timeout: u8) -> Result<(), ToRustAGaugeError> {
+        self.write_read(&elm_commands::get_set_timeout_command(timeout)).await?;
+        if AtReply::Ok.is_in(self.response.text()) {
+            Ok(())
+        } else {
+            defmt::warn!("ELM rejected timeout {:x}\nresponse was {:?}", timeout, self.response);
+            let error = ToRustAGaugeError::AtCommandRejected(elm_commands::SET_TIMEOUT_NAME);
+            self.trace.failed(&error);
+            Err(error)
+        }
+    }
+
+    /// Filters the bus down to the IDs of `signals` and starts printing every frame that passes (`ATMA`).
+    /// The ELM gets an `ATCRA` filter (see `bus_monitor::receive_filter_command`), an STN exact pass filters
+    /// and `STMA`. Read the frames with `read_monitored_frame`. Nothing else can be sent until `stop_monitor`
+    pub async fn start_monitor(&mut self, signals: &[BroadcastSignal], frame_format: FrameFormat) -> Result<(), ToRustAGaugeError> {
+        self.send_at_command(&AtCommand::required(elm_commands::DISABLE_CAN_AUTO_FORMATTING, AtReply::Ok)).await?;
+        let monitor_command = match self.backend {
+            Backend::Elm => {
+                self.set_filter(&receive_filter_command(signals, frame_format), elm_commands::SET_RECEIVE_FILTER_NAME).await?;
+                elm_commands::MONITOR_ALL
+            }
+            Backend::Stn => {
+                // in case a monitor that failed to start left some behind
+                self.send_at_command(&AtCommand::required(elm_commands::STN_CLEAR_PASS_FILTERS, AtReply::Ok)).await?;
+                for filter in pass_filter_commands(signals, frame_format) {
+                    self.set_filter(&filter, elm_commands::STN_ADD_PASS_FILTER_NAME).await?;
+                }
+                elm_commands::STN_MONITOR_ALL
+            }
+        };
+        let (started, _) = self.write(monitor_command.as_bytes()).await?;
+        self.response.start(monitor_command.as_bytes(), Some(frame_format));
+        self.monitor = Some(Monitor { frame_format, started });
+        Ok(())
+    }
+
+    /// Waits for the next frame while monitoring, header included. `timeout` is how long the bus can be silent.
+    /// If the ELM stops monitoring by itself (usually `BUFFER FULL`, the UART couldn't keep up) its message is
+    /// returned as an error, `stop_monitor` still has to be called
+    pub async fn read_monitored_frame(&mut self, timeout: Duration) -> Result<&[u8], ToRustAGaugeError> {
+        let Some(frame_format) = self.monitor.as_ref().map(|monitor| monitor.frame_format) else {
+            return Err(ToRustAGaugeError::ElmStopped())
+        };
+        // the parser only ever holds the frame handed out last time (or the echo of ATMA)
+        if self.response.frames().next().is_some() || self.response.parse_error().is_some() {
+            self.response.start(b"", Some(frame_format));
+        }
+        loop {
+            let c = self.read_byte(timeout).await?;
+            if c == DELIMITER_U8 {
+                self.response.finish();
+                let error = self.response.elm_error().unwrap_or(ToRustAGaugeError::ElmStopped());
+                defmt::warn!("ELM stopped monitoring by itself: {:?}", error);
+                if let Some(monitor) = self.monitor.take() {
+                    self.trace.finish(monitor.started.elapsed(), &Err(error.clone()));
+                }
+                return Err(error)
+            }
+            self.response.push(c);
+            self.trace.response_byte(c);
+            if c != b'\r' {
+                continue;
+            }
+            if let Some(error) = self.response.parse_error() {
+                defmt::warn!("Dropping a monitored frame that couldn't be parsed: {:?}", error);
+                self.response.start(b"", Some(frame_format));
+            } else if self.response.frames().next().is_some() {
+                break;
+            }
+        }
+        first_frame(&self.response)
+    }
+
+    /// Sends a filter command made up at run time, `name` says which one failed
+    async fn set_filter(&mut self, filter: &[u8], name: &'static str) -> Result<(), ToRustAGaugeError> {
+        self.write_read(filter).await?;
+        if AtReply::Ok.is_in(self.response.text()) {
+            return Ok(())
+        }
+        defmt::warn!("ELM rejected filter {:?}\nresponse was {:?}", core::str::from_utf8(filter).unwrap_or("<not ascii>"), self.response);
+        let error = ToRustAGaugeError::AtCommandRejected(name);
+        self.trace.failed(&error);
+        Err(error)
+    }
+
+    /// Stops `ATMA` and puts the filter and formatting back, so requests are answered normally again
+    pub async fn stop_monitor(&mut self) -> Result<(), ToRustAGaugeError> {
+        if let Some(monitor) = self.monitor.take() {
+            // any character stops it, and a space is ignored if the ELM has just stopped by itself
+            let written = match self.transport.write_all(b" ").await {
+                Ok(()) => self.transport.flush().await,
+                Err(e) => Err(e),
+            };
+            // frames that were already on their way come before the prompt, and maybe `STOPPED`
+            self.response.start(b"", None);
+            let stopped = match written {
+                Ok(()) => self.read_until_char(DELIMITER_U8, self.uart_timeout).await,
+                Err(e) => Err(transport_error(e)),
+            };
+            self.trace.finish(monitor.started.elapsed(), &stopped);
+            stopped?;
+        }
+        self.send_at_command(&AtCommand::required(elm_commands::ENABLE_CAN_AUTO_FORMATTING, AtReply::Ok)).await?;
+        let reset_filter = match self.backend {
+            Backend::Elm => elm_commands::RESET_RECEIVE_FILTER,
+            Backend::Stn => elm_commands::STN_CLEAR_PASS_FILTERS,
+        };
+        self.send_at_command(&AtCommand::required(reset_filter, AtReply::Ok)).await
+    }
+
+    /// Moves the adapter and the transport to `baud_rate`, the way `STBR` and `ATBRD` want it: the adapter answers
+    /// `OK` at the old rate, then prints its ID at the new one and only keeps it if a `\r` comes back in time.
+    /// If anything goes wrong both go back to the old rate, the last one that worked.
+    /// The ELM can only divide its clock, so it ends up at the closest rate it can do, see `baud_rate`
+    pub async fn switch_baud_rate(&mut self, baud_rate: u32) -> Result<(), ToRustAGaugeError> where T: SetBaudRate {
+        let (command, name, baud_rate) = match self.backend {
+            Backend::Stn => (elm_commands::get_baud_rate_command(baud_rate), elm_commands::STN_BAUD_RATE_NAME, baud_rate),
+            Backend::Elm => {
+                let divisor = elm_commands::baud_rate_divisor(baud_rate);
+                (
+                    elm_commands::get_baud_rate_divisor_command(divisor),
+                    elm_commands::SET_BAUD_RATE_DIVISOR_NAME,
+                    elm_commands::BAUD_RATE_DIVISOR_CLOCK / divisor as u32,
+                )
+            }
+        };
+        let old_baud_rate = self.baud_rate;
+        let (started, _) = self.write(&command).await?;
+        // no prompt after the OK, the next thing comes at the new rate
+        self.response.start(&command, None);
+        if let Err(e) = self.read_line(self.uart_timeout).await {
+            self.trace.finish(started.elapsed(), &Err(e.clone()));
+            return Err(e)
+        }
+        if !AtReply::Ok.is_in(self.response.text()) {
+            defmt::warn!("Adapter rejected {:?}\nresponse was {:?}", core::str::from_utf8(&command).unwrap_or("<not ascii>"), self.response);
+            let rejected = self.read_until_char(DELIMITER_U8, self.uart_timeout).await
+                .and(Err(ToRustAGaugeError::AtCommandRejected(name)));
+            self.trace.finish(started.elapsed(), &rejected);
+            return rejected
+        }
+
+        self.set_transport_baud_rate(baud_rate);
+        self.response.start(b"", None);
+        let mut switched = self.read_line(BAUD_RATE_SWITCH_TIMEOUT).await;
+        if switched.is_ok() {
+            // only the ID is at the new rate so far, the adapter needs something back to keep it
+            switched = match self.transport.write_all(b"\r").await {
+                Ok(()) => self.transport.flush().await.map_err(transport_error),
+                Err(e) => Err(transport_error(e)),
+            };
+        }
+        if switched.is_ok() {
+            self.response.start(b"", None);
+            switched = self.read_until_char(DELIMITER_U8, self.uart_timeout).await
+                .and_then(|()| if AtReply::Ok.is_in(self.response.text()) {
+                    Ok(())
+                } else {
+                    Err(ToRustAGaugeError::AtCommandRejected(name))
+                });
+        }
+        if let Err(e) = &switched {
+            defmt::warn!("Could not switch to {} baud, staying at {}: {:?}", baud_rate, old_baud_rate, e);
+            // the adapter goes back by itself and prints the prompt at the old rate
+            self.set_transport_baud_rate(old_baud_rate);
+            self.response.start(b"", None);
+            if let Err(e) = self.read_until_char(DELIMITER_U8, self.uart_timeout).await {
+                defmt::warn!("No prompt after going back to {} baud: {:?}", old_baud_rate, e);
+            }
+        }
+        self.trace.finish(started.elapsed(), &switched);
+        switched
+    }
+
+    /// Puts the adapter and the transport back to `DEFAULT_BAUD_RATE` with an `ATZ` sent at the current rate,
+    /// so init can start over. Does nothing if they're already there
+    pub async fn reset_baud_rate(&mut self) -> Result<(), ToRustAGaugeError> where T: SetBaudRate {
+        if self.baud_rate == DEFAULT_BAUD_RATE {
+            return Ok(())
+        }
+        let (started, _) = self.write(elm_commands::ELM_RESET.as_bytes()).await?;
+        self.set_transport_baud_rate(DEFAULT_BAUD_RATE);
+        // the banner comes at the default rate
+        self.response.start(elm_commands::ELM_RESET.as_bytes(), None);
+        let result = self.read_until_char(DELIMITER_U8, self.uart_timeout).await;
+        self.trace.finish(started.elapsed(), &result);
+        result
+    }
+
+    fn set_transport_baud_rate(&mut self, baud_rate: u32) where T: SetBaudRate {
+        self.transport.set_baud_rate(baud_rate);
+        self.baud_rate = baud_rate;
+    }
+
+    pub async fn get_voltage(&mut self) -> Result<f64, ToRustAGaugeError> {
+        self.write_read(elm_commands::ELM_REQUEST_VBAT.as_bytes()).await?;
+        if let Some(elm_error) = self.response.elm_error(){
+            self.trace.failed(&elm_error);
+            return Err(elm_error)
+        }
+        let voltage = parse_voltage(self.response.text());
+        if let Err(er) = &voltage {
+            self.trace.failed(er);
+        }
+        voltage
+    }
+
+    /// Reads the adapter's ignition input (`ATIGN`), `true` for `ON`
+    pub async fn get_ignition(&mut self) -> Result<bool, ToRustAGaugeError> {
+        self.write_read(elm_commands::READ_IGNITION.as_bytes()).await?;
+        if let Some(elm_error) = self.response.elm_error(){
+            self.trace.failed(&elm_error);
+            return Err(elm_error)
+        }
+        let ignition = parse_ignition(self.response.text());
+        if let Err(er) = &ignition {
+            self.trace.failed(er);
+        }
+        ignition
+    }
+
+    /// Puts the adapter in low power mode (`ATLP`). It wakes up when anything is sent (see `wake`), or by itself
+    /// when its ignition input goes high, and starts over like after a reset. It's put back to `DEFAULT_BAUD_RATE`
+    /// first, so both sides agree on the rate whatever it does with a faster one while asleep
+    pub async fn sleep(&mut self) -> Result<(), ToRustAGaugeError> where T: SetBaudRate {
+        self.reset_baud_rate().await?;
+        self.send_at_command(&AtCommand::required(elm_commands::LOW_POWER_MODE, AtReply::Ok)).await
+    }
+
+    /// Waits up to `timeout` for the adapter to say something without being asked, after `sleep` that's it
+    /// waking up by itself. Whatever it says is read up to the prompt and dropped. Returns whether anything came
+    pub async fn wait_for_wake(&mut self, timeout: Duration) -> bool {
+        self.response.start(b"", None);
+        if self.read_byte(timeout).await.is_err() {
+            return false
+        }
+        if let Err(e) = self.read_until_char(DELIMITER_U8, self.uart_timeout).await {
+            defmt::warn!("No prompt after the adapter woke up: {:?}", e);
+        }
+        true
+    }
+
+    /// Wakes the adapter up after `sleep` with a character it ignores, and waits for the prompt after its banner
+    pub async fn wake(&mut self) -> Result<(), ToRustAGaugeError> {
+        let (started, _) = self.write(b" ").await?;
+        self.response.start(b"", None);
+        let result = self.read_until_char(DELIMITER_U8, self.uart_timeout).await;
+        self.trace.finish(started.elapsed(), &result);
+        result
+    }
+}
+
+/// The frames of a response that came from `ecu`, or from the ECU that answered first if it's `None`.
+/// When several ECUs answer one request their frames are interleaved, this keeps them apart
+fn ecu_frames(response: &ResponseParser, frame_format: FrameFormat, ecu: Option<Ecu>) -> Result<(Ecu, impl Iterator<Item = &[u8]>), ToRustAGaugeError> {
+    let ecu = match ecu {
+        Some(ecu) => ecu,
+        None => response.frames()
+            .next()
+            .and_then(|frame| frame_format.source_ecu(frame))
+            .ok_or(ToRustAGaugeError::UartIncorrectLengthError())?,
+    };
+    let mut frames = response.frames()
+        .filter(move |frame| frame_format.source_ecu(frame) == Some(ecu))
+        .peekable();
+    if frames.peek().is_none() {
+        defmt::warn!("{:?} didn't answer, only other ECUs did", ecu);
+        return Err(ToRustAGaugeError::UartResponseNoData())
+    }
+    Ok((ecu, frames))
+}
+
+/// Single frame requests only look at the first frame of the response
+fn first_frame(response: &ResponseParser) -> Result<&[u8], ToRustAGaugeError> {
+    response.frames().next().ok_or(ToRustAGaugeError::UartIncorrectLengthError())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+    use embassy_time::TimeoutError;
+    use crate::dtc::{Dtc, EcuDtc, PENDING_DTC_RESPONSE_SERVICE, STORED_DTC_RESPONSE_SERVICE};
+    use arrayvec::ArrayVec;
+    use crate::elm_commands::{PidUnits, DATA_IDENTIFIER_SERVICE, ELM_INIT_SEQUENCE, ENGINE_COOLANT_TEMP_PID, ENGINE_RPM_PID,
+                              LOCAL_IDENTIFIER_SERVICE, REQUEST_PENDING_DTCS, REQUEST_STORED_DTCS};
+    use crate::mode_01_pids::{INTAKE_AIR_TEMP_PID, VEHICLE_SPEED_PID};
+    use crate::elm_emulator::{ElmEmulator, Fault, TEST_UART_TIMEOUT};
+    use crate::obd_protocol::ObdProtocol;
+    use crate::supported_pids::SUPPORTED_PID_RANGE_COMMANDS;
+    use super::*;
+
+    /// 0x1AF8 / 4
+    const RPM: f64 = 1726.0;
+    const RPM_SIGNAL: BroadcastSignal = BroadcastSignal {
+        pid: &ENGINE_RPM_PID,
+        can_id: 0x2c4,
+        start: 0,
+        len: 2,
+        scale: 1.0,
+        offset: 0.0,
+    };
+
+    fn initialized(emulator: ElmEmulator) -> ElmDriver<ElmEmulator> {
+        let mut elm = ElmDriver::new(emulator.with_pid(0x0c, &[0x1a, 0xf8]));
+        elm.set_uart_timeout(TEST_UART_TIMEOUT);
+        for at_command in ELM_INIT_SEQUENCE.iter() {
+            block_on(elm.send_at_command(at_command)).unwrap();
+        }
+        elm
+    }
+
+    #[test]
+    fn test_pid_with_faults() {
+        let mut elm = initialized(ElmEmulator::new(ObdProtocol::Iso14230FastInit));
+        // the first request after power up also prints SEARCHING...
+        assert_eq!(block_on(elm.get_pid(&ENGINE_RPM_PID, FrameFormat::Kwp)), Ok(RPM));
+
+        let faults = [
+            (Fault::Echo, Ok(RPM)),
+            (Fault::Searching, Ok(RPM)),
+            (Fault::NoData, Err(ToRustAGaugeError::UartResponseNoData())),
+            (Fault::BadChecksum, Err(ToRustAGaugeError::UartBadChecksumError())),
+            (Fault::Truncated, Err(ToRustAGaugeError::UartIncorrectLengthError())),
+            (Fault::Silent, Err(ToRustAGaugeError::UartTimeoutError(TimeoutError))),
+        ];
+        for (fault, expected) in faults {
+            elm.transport.inject(fault);
+            assert_eq!(block_on(elm.get_pid(&ENGINE_RPM_PID, FrameFormat::Kwp)), expected, "{:?}", fault);
+        }
+        // and back to normal
+        assert_eq!(block_on(elm.get_pid(&ENGINE_RPM_PID, FrameFormat::Kwp)), Ok(RPM));
+    }
+
+    #[test]
+    fn test_ecu_answers() {
+        let mut elm = ElmDriver::new(ElmEmulator::new(ObdProtocol::Iso14230FastInit).with_pid(0x0c, &[0x1a, 0xf8]));
+        // straight after a reset, on the protocol it was using
+        assert_eq!(block_on(elm.ecu_answers(ObdProtocol::Iso14230FastInit, UART_TIMEOUT)), Ok(true));
+        elm.transport.inject(Fault::NoData);
+        assert_eq!(block_on(elm.ecu_answers(ObdProtocol::Iso14230FastInit, UART_TIMEOUT)), Ok(false));
+        elm.transport.inject(Fault::Searching);
+        assert_eq!(block_on(elm.ecu_answers(ObdProtocol::Iso14230FastInit, UART_TIMEOUT)), Ok(true));
+        // it has to know the protocol was set
+        let mut elm = ElmDriver::new(ElmEmulator::new(ObdProtocol::Iso14230FastInit).rejecting_at_command("SP5"));
+        assert_eq!(
+            block_on(elm.ecu_answers(ObdProtocol::Iso14230FastInit, UART_TIMEOUT)),
+            Err(ToRustAGaugeError::AtCommandRejected(elm_commands::SET_PROTOCOL_NAME))
+        );
+    }
+
+    #[test]
+    fn test_can_pid() {
+        let mut elm = initialized(ElmEmulator::new(ObdProtocol::Can11Bit500k));
+        assert_eq!(block_on(elm.get_pid(&ENGINE_RPM_PID, FrameFormat::Can11Bit)), Ok(RPM));
+        elm.transport.inject(Fault::Truncated);
+        assert_eq!(
+            block_on(elm.get_pid(&ENGINE_RPM_PID, FrameFormat::Can11Bit)),
+            Err(ToRustAGaugeError::UartIncorrectLengthError())
+        );
+    }
+
+    #[test]
+    fn test_pipelined_request() {
+        let mut elm = initialized(ElmEmulator::new(ObdProtocol::Can11Bit500k)
+            .with_pid(0x05, &[0x5a]));
+        let requests = elm.transport.obd_requests;
+        // sent early, so reading it doesn't send it again
+        block_on(elm.send_next(ENGINE_RPM_PID.ascii_command())).unwrap();
+        assert_eq!(block_on(elm.get_pid(&ENGINE_RPM_PID, FrameFormat::Can11Bit)), Ok(RPM));
+        assert_eq!(elm.transport.obd_requests, requests + 1);
+        // and when its answer came is unknown
+        assert!(elm.last_exchange().pipelined);
+        assert_eq!(elm.last_exchange().first_byte, None);
+        // a different command gets the prompt of the early one first
+        block_on(elm.send_next(ENGINE_RPM_PID.ascii_command())).unwrap();
+        assert_eq!(block_on(elm.get_pid(&ENGINE_COOLANT_TEMP_PID, FrameFormat::Can11Bit)), Ok(50.0));
+        assert_eq!(elm.transport.obd_requests, requests + 3);
+        assert!(!elm.last_exchange().pipelined);
+        assert!(elm.last_exchange().first_byte.is_some());
+    }
+
+    #[test]
+    fn test_monitor() {
+        let mut elm = initialized(ElmEmulator::new(ObdProtocol::Can11Bit500k)
+            .with_broadcast(0x2c4, &[0x07, 0xd0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00])
+            .with_broadcast(0x3b7, &[0x00, 0x00, 0x00, 0x5a]));
+        block_on(elm.start_monitor(&[RPM_SIGNAL], FrameFormat::Can11Bit)).unwrap();
+        // only the filtered ID comes through
+        for _ in 0..3 {
+            let frame = block_on(elm.read_monitored_frame(UART_TIMEOUT)).unwrap();
+            assert_eq!(frame, &[0x02, 0xc4, 0x07, 0xd0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        }
+        block_on(elm.stop_monitor()).unwrap();
+        // and requests are answered normally again
+        assert_eq!(block_on(elm.get_pid(&ENGINE_RPM_PID, FrameFormat::Can11Bit)), Ok(RPM));
+    }
+
+    #[test]
+    fn test_stn() {
+        // requests go out as STPX, which an ELM doesn't know
+        let mut elm = initialized(ElmEmulator::new(ObdProtocol::Can11Bit500k));
+        elm.set_backend(Backend::Stn);
+        assert_eq!(block_on(elm.get_pid(&ENGINE_RPM_PID, FrameFormat::Can11Bit)), Err(ToRustAGaugeError::ElmUnknownCommand()));
+
+        let mut elm = initialized(ElmEmulator::new(ObdProtocol::Can11Bit500k)
+            .as_stn()
+            .with_broadcast(0x2c4, &[0x07, 0xd0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00])
+            .with_broadcast(0x3b7, &[0x00, 0x00, 0x00, 0x5a]));
+        elm.set_backend(Backend::Stn);
+        assert_eq!(block_on(elm.get_pid(&ENGINE_RPM_PID, FrameFormat::Can11Bit)), Ok(RPM));
+        block_on(elm.send_next(ENGINE_RPM_PID.ascii_command())).unwrap();
+        assert_eq!(block_on(elm.get_pid(&ENGINE_RPM_PID, FrameFormat::Can11Bit)), Ok(RPM));
+
+        assert_eq!(block_on(elm.switch_baud_rate(2_000_000)), Ok(()));
+        assert_eq!(elm.baud_rate(), 2_000_000);
+        assert_eq!(block_on(elm.get_pid(&ENGINE_RPM_PID, FrameFormat::Can11Bit)), Ok(RPM));
+
+        // the pass filter is exact, nothing else comes through
+        block_on(elm.start_monitor(&[RPM_SIGNAL], FrameFormat::Can11Bit)).unwrap();
+        for _ in 0..3 {
+            let frame = block_on(elm.read_monitored_frame(UART_TIMEOUT)).unwrap();
+            assert_eq!(&frame[..2], &[0x02, 0xc4]);
+        }
+        block_on(elm.stop_monitor()).unwrap();
+        assert_eq!(block_on(elm.get_pid(&ENGINE_RPM_PID, FrameFormat::Can11Bit)), Ok(RPM));
+
+        // ATZ at the fast rate takes both back
+        assert_eq!(block_on(elm.reset_baud_rate()), Ok(()));
+        assert_eq!(elm.baud_rate(), DEFAULT_BAUD_RATE);
+        assert!(AtReply::Banner.is_in(elm.last_response()));
+    }
+
+    #[test]
+    fn test_baud_rate_fallback() {
+        // the wiring only carries up to 250k, so 500k fails and leaves it at the default
+        let mut elm = initialized(ElmEmulator::new(ObdProtocol::Iso14230FastInit).with_max_baud_rate(250_000));
+        assert!(block_on(elm.switch_baud_rate(500_000)).is_err());
+        assert_eq!(elm.baud_rate(), DEFAULT_BAUD_RATE);
+        assert_eq!(block_on(elm.get_pid(&ENGINE_RPM_PID, FrameFormat::Kwp)), Ok(RPM));
+
+        assert_eq!(block_on(elm.switch_baud_rate(250_000)), Ok(()));
+        assert_eq!(elm.baud_rate(), 250_000);
+        assert_eq!(block_on(elm.get_pid(&ENGINE_RPM_PID, FrameFormat::Kwp)), Ok(RPM));
+        // and a failure from there goes back to 250k, not the default
+        assert!(block_on(elm.switch_baud_rate(500_000)).is_err());
+        assert_eq!(elm.baud_rate(), 250_000);
+        assert_eq!(block_on(elm.get_pid(&ENGINE_RPM_PID, FrameFormat::Kwp)), Ok(RPM));
+
+        // ATBRD divides 4 MHz, so 230400 is 235294 on both sides
+        let mut elm = initialized(ElmEmulator::new(ObdProtocol::Iso14230FastInit));
+        assert_eq!(block_on(elm.switch_baud_rate(230_400)), Ok(()));
+        assert_eq!(elm.baud_rate(), 235_294);
+        assert_eq!(block_on(elm.get_pid(&ENGINE_RPM_PID, FrameFormat::Kwp)), Ok(RPM));
+
+        let mut elm = initialized(ElmEmulator::new(ObdProtocol::Iso14230FastInit).rejecting_at_command("BRD08"));
+        assert_eq!(block_on(elm.switch_baud_rate(500_000)), Err(ToRustAGaugeError::AtCommandRejected("ATBRD")));
+        assert_eq!(elm.baud_rate(), DEFAULT_BAUD_RATE);
+    }
+
+    #[test]
+    fn test_extended_pids() {
+        let local = PidCommand::extended(LOCAL_IDENTIFIER_SERVICE, 0x81, 1, 2, "Local 81", PidUnits::Volts, |slice| {
+            (slice[0] as f64 * 256.0 + slice[1] as f64) / 1000.0
+        });
+        let data_identifier = PidCommand::extended(DATA_IDENTIFIER_SERVICE, 0x1234, 2, 1, "Common 1234", PidUnits::Percent, |slice| {
+            slice[0] as f64 * 100.0 / 255.0
+        });
+        for (protocol, frame_format) in [
+            (ObdProtocol::Iso14230FastInit, FrameFormat::Kwp),
+            (ObdProtocol::Can11Bit500k, FrameFormat::Can11Bit),
+        ] {
+            let mut elm = initialized(ElmEmulator::new(protocol)
+                .with_pid(0x0c, &[0x1a, 0xf8])
+                .with_local_identifier(0x81, &[0x30, 0x39])
+                .with_data_identifier(0x1234, &[0xff]));
+            assert_eq!(block_on(elm.get_pid(&local, frame_format)), Ok(12.345));
+            // local identifier 0C isn't PID 0C, the ECU answers 7F 21 31
+            let local_0c = PidCommand::extended(LOCAL_IDENTIFIER_SERVICE, 0x0c, 1, 2, "Local 0C", PidUnits::Count, |slice| slice[0] as f64);
+            assert!(block_on(elm.get_pid(&local_0c, frame_format)).is_err());
+            assert_eq!(block_on(elm.get_pid(&data_identifier, frame_format)), Ok(100.0));
+            // the ECU answers 7F 22 31 for an identifier it doesn't have
+            let unknown = PidCommand::extended(DATA_IDENTIFIER_SERVICE, 0x4321, 2, 1, "Common 4321", PidUnits::Count, |slice| slice[0] as f64);
+            assert!(block_on(elm.get_pid(&unknown, frame_format)).is_err());
+            // and extended PIDs aren't in freeze frames
+            assert_eq!(
+                block_on(elm.get_freeze_frame_data(&local, 0, frame_format)).err(),
+                Some(ToRustAGaugeError::UartPidMismatchError())
+            );
+        }
+    }
+
+    #[test]
+    fn test_pid_batch() {
+        let mut elm = initialized(ElmEmulator::new(ObdProtocol::Can11Bit500k)
+            .with_pid(0x05, &[0x5a])
+            .with_pid(0x0d, &[0x3c]));
+        // 0x0F isn't supported, the response is long enough to need a first and consecutive frame
+        let pids = [&ENGINE_RPM_PID, &ENGINE_COOLANT_TEMP_PID, &VEHICLE_SPEED_PID, &INTAKE_AIR_TEMP_PID];
+        let (ecu, values) = block_on(elm.get_pid_batch(&pids, FrameFormat::Can11Bit, None)).unwrap();
+        assert_eq!(ecu, Ecu::Engine);
+        let values: ArrayVec<(u16, f64), 4> = values.iter().map(|(pid, value)| (pid.pid, *value)).collect();
+        assert_eq!(values.as_slice(), &[(0x0c, RPM), (0x05, 50.0), (0x0d, 60.0)]);
+
+        elm.transport.inject(Fault::Truncated);
+        assert_eq!(
+            block_on(elm.get_pid_batch(&pids, FrameFormat::Can11Bit, None)).err(),
+            Some(ToRustAGaugeError::UartMissingFrameError())
+        );
+    }
+
+    #[test]
+    fn test_multiple_ecus() {
+        for (protocol, frame_format) in [
+            (ObdProtocol::Iso14230FastInit, FrameFormat::Kwp),
+            (ObdProtocol::Can11Bit500k, FrameFormat::Can11Bit),
+            (ObdProtocol::Can29Bit500k, FrameFormat::Can29Bit),
+        ] {
+            // the transmission answers first, with its own idea of the RPM
+            let mut elm = initialized(ElmEmulator::new(protocol)
+                .with_pid(0x0d, &[0x3c])
+                .with_transmission_pid(0x0c, &[0x1a, 0xf0])
+                .with_transmission_pid(0x0e, &[0x88]));
+            assert_eq!(block_on(elm.get_pid_from(&ENGINE_RPM_PID, frame_format, None)), Ok((Ecu::Transmission, 1724.0)));
+            assert_eq!(block_on(elm.get_pid_from(&ENGINE_RPM_PID, frame_format, Some(Ecu::Engine))), Ok((Ecu::Engine, RPM)));
+            assert_eq!(
+                block_on(elm.get_pid_from(&ENGINE_RPM_PID, frame_format, Some(Ecu::Other(0x20)))),
+                Err(ToRustAGaugeError::UartResponseNoData())
+            );
+
+            let mut bitmaps: ArrayVec<(Ecu, u32), 2> = ArrayVec::new();
+            block_on(elm.get_pid_data_each_ecu(&SUPPORTED_PID_RANGE_COMMANDS[0], frame_format, |ecu, bitmap| {
+                bitmaps.push((ecu, u32::from_be_bytes(bitmap.try_into().unwrap())));
+            })).unwrap();
+            // both have PID 0C, the transmission 0E and the engine 0D
+            assert_eq!(bitmaps.as_slice(), &[(Ecu::Transmission, 0x0014_0000), (Ecu::Engine, 0x0018_0000)]);
+        }
+
+        let mut elm = initialized(ElmEmulator::new(ObdProtocol::Can11Bit500k)
+            .with_pid(0x0d, &[0x3c])
+            .with_transmission_pid(0x0d, &[0x3b]));
+        let pids = [&ENGINE_RPM_PID, &VEHICLE_SPEED_PID];
+        let (ecu, values) = block_on(elm.get_pid_batch(&pids, FrameFormat::Can11Bit, Some(Ecu::Engine))).unwrap();
+        assert_eq!(ecu, Ecu::Engine);
+        let values: ArrayVec<(u16, f64), 2> = values.iter().map(|(pid, value)| (pid.pid, *value)).collect();
+        assert_eq!(values.as_slice(), &[(0x0c, RPM), (0x0d, 60.0)]);
+    }
+
+    #[test]
+    fn test_at_command_rejected() {
+        let mut elm = ElmDriver::new(ElmEmulator::new(ObdProtocol::Iso14230FastInit)
+            .rejecting_at_command("H1")
+            .rejecting_at_command("AT1"));
+        let results: [Result<(), ToRustAGaugeError>; 7] = [
+            Ok(()),
+            Ok(()),
+            Err(ToRustAGaugeError::AtCommandRejected("ATH1")),
+            Ok(()),
+            Ok(()),
+            Ok(()),
+            // optional
+            Ok(()),
+        ];
+        for (at_command, expected) in ELM_INIT_SEQUENCE.iter().zip(results) {
+            assert_eq!(block_on(elm.send_at_command(at_command)), expected);
+        }
+    }
+
+    #[test]
+    fn test_voltage_and_dtcs() {
+        let dtcs = [Dtc(0x0301), Dtc(0x0420), Dtc(0x4123)];
+        for (protocol, frame_format) in [
+            (ObdProtocol::Iso14230FastInit, FrameFormat::Kwp),
+            (ObdProtocol::Can11Bit500k, FrameFormat::Can11Bit),
+        ] {
+            let mut elm = initialized(ElmEmulator::new(protocol)
+                .with_voltage("13.8V")
+                .with_stored_dtcs(&dtcs)
+                .with_pending_dtcs(&[Dtc(0x0171)])
+                .with_transmission_stored_dtcs(&[Dtc(0x0700)]));
+            let voltage = block_on(elm.get_voltage()).unwrap();
+            assert!((voltage - 13.8).abs() < 0.001, "{}", voltage);
+            // both ECUs' codes, each with the ECU it came from
+            let stored = block_on(elm.get_dtcs(&REQUEST_STORED_DTCS, STORED_DTC_RESPONSE_SERVICE, frame_format)).unwrap();
+            let mut expected: ArrayVec<EcuDtc, 4> = dtcs.iter().map(|dtc| EcuDtc { ecu: Ecu::Engine, dtc: *dtc }).collect();
+            expected.push(EcuDtc { ecu: Ecu::Transmission, dtc: Dtc(0x0700) });
+            assert_eq!(stored.as_slice(), expected.as_slice());
+            let pending = block_on(elm.get_dtcs(&REQUEST_PENDING_DTCS, PENDING_DTC_RESPONSE_SERVICE, frame_format)).unwrap();
+            assert_eq!(pending.as_slice(), &[EcuDtc { ecu: Ecu::Engine, dtc: Dtc(0x0171) }]);
+            assert_eq!(block_on(elm.clear_dtcs(frame_format)), Ok(()));
+            let stored = block_on(elm.get_dtcs(&REQUEST_STORED_DTCS, STORED_DTC_RESPONSE_SERVICE, frame_format));
+            assert!(stored.unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_sleep() {
+        let mut elm = initialized(ElmEmulator::new(ObdProtocol::Iso14230FastInit).with_key_off(0, Duration::from_millis(10)));
+        assert_eq!(block_on(elm.get_ignition()), Ok(true));
+        // the key goes off at the first request
+        assert!(block_on(elm.get_pid(&ENGINE_RPM_PID, FrameFormat::Kwp)).is_err());
+        assert_eq!(block_on(elm.get_ignition()), Ok(false));
+        assert_eq!(block_on(elm.sleep()), Ok(()));
+        // the key comes back while it sleeps and it wakes up by itself
+        assert!(block_on(elm.wait_for_wake(Duration::from_millis(100))));
+        assert_eq!(block_on(elm.get_ignition()), Ok(true));
+        assert!(!block_on(elm.wait_for_wake(Duration::from_millis(10))));
+        // woken up from here
+        assert_eq!(block_on(elm.sleep()), Ok(()));
+        assert_eq!(block_on(elm.wake()), Ok(()));
+        assert_eq!(block_on(elm.get_ignition()), Ok(true));
+
+        let mut elm = initialized(ElmEmulator::new(ObdProtocol::Iso14230FastInit).rejecting_at_command("LP"));
+        assert_eq!(block_on(elm.sleep()), Err(ToRustAGaugeError::AtCommandRejected("ATLP")));
+    }
+}
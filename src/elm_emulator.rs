@@ -0,0 +1,858 @@
+//! A software ELM327 for host-side tests. It implements the same `Read + Write` traits as the UART,
+//! so an `ElmDriver` (and everything in `elm_uart` built on it) can run against it in `cargo test`.
+//!
+//! It answers the AT commands the firmware sends, keeps track of echo/headers/spaces like the real chip,
+//! and answers OBD requests from a script of PID values, formatted for whichever protocol it was created with.
+//! Broadcast frames can be scripted as well, they're printed while monitoring (`ATMA`).
+//! It can also be an STN chip (`as_stn`), which adds the `ST` commands. Both switch baud rates (`ATBRD`, `STBR`)
+//! and garble everything while the two sides disagree.
+//! Faults can be queued to make the next OBD requests go wrong in the ways real adapters and ECUs do,
+//! and the key can be turned off and back on (`with_key_off`), with `ATIGN` and `ATLP` behaving like on the chip.
+
+use arrayvec::ArrayVec;
+use embassy_time::{Duration, Instant, Timer};
+use embedded_io_async::{ErrorKind, ErrorType, Read, Write};
+use crate::dtc::Dtc;
+use crate::elm_commands::{additive_checksum, HexDigits, BAUD_RATE_DIVISOR_CLOCK};
+use crate::elm_driver::{SetBaudRate, DEFAULT_BAUD_RATE};
+use crate::obd_protocol::{j1850_crc, FrameFormat, ObdProtocol, ENGINE_ECU_ADDRESS, TRANSMISSION_ECU_ADDRESS};
+
+pub const BANNER: &str = "ELM327 v1.5";
+/// `STI`'s answer when it's an STN
+pub const STN_BANNER: &str = "STN1110 v4.2.0";
+/// `ATRV`'s answer while the key is off, a resting battery
+const KEY_OFF_VOLTAGE: &str = "12.2V";
+/// For `ElmDriver::set_uart_timeout` in tests. The emulator answers straight away, so it only matters when it doesn't
+pub const TEST_UART_TIMEOUT: Duration = Duration::from_millis(20);
+
+const MAX_SCRIPTED_PIDS: usize = 16;
+const MAX_QUEUED_FAULTS: usize = 16;
+const MAX_BROADCASTS: usize = 4;
+const MAX_PASS_FILTERS: usize = 4;
+const MAX_COMMAND_LEN: usize = 32;
+const MAX_OUTPUT_LEN: usize = 512;
+/// Longest CAN single frame payload (after the PCI byte)
+const CAN_SINGLE_FRAME_LEN: usize = 7;
+
+/// PID and the data bytes it's answered with
+type ScriptedPids = ArrayVec<(u8, ArrayVec<u8, 4>), MAX_SCRIPTED_PIDS>;
+
+/// Something that goes wrong with one OBD request. Queued faults are used up in order, one per request
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Fault {
+    /// The command is echoed back before the reply, like a clone that ignores `ATE0`
+    Echo,
+    /// `SEARCHING...` before the reply, like the first request after `ATSP0`
+    Searching,
+    /// The ECU doesn't answer. `UNABLE TO CONNECT` instead while the ELM is searching for the protocol
+    NoData,
+    /// The checksum byte is off by one (KWP/ISO 9141/J1850 only, CAN frames have no checksum)
+    BadChecksum,
+    /// The last byte of the reply is cut off
+    Truncated,
+    /// Nothing at all comes back, not even the prompt
+    Silent,
+}
+
+pub struct ElmEmulator {
+    protocol: ObdProtocol,
+    /// Protocol 0 (`ATSP0`, the power up default), the ELM searches on the first OBD request
+    automatic_protocol: bool,
+    /// `true` until the first OBD request after `ATSP0`, which prints `SEARCHING...`
+    searching: bool,
+    echo: bool,
+    headers: bool,
+    spaces: bool,
+    pids: ScriptedPids,
+    /// PIDs a second ECU (the transmission) answers. If there are any it answers PID requests too, before the engine
+    transmission_pids: ScriptedPids,
+    /// Mode 21 local identifiers the engine ECU answers
+    local_identifiers: ScriptedPids,
+    /// Mode 22 identifiers the engine ECU answers
+    data_identifiers: ArrayVec<(u16, ArrayVec<u8, 4>), MAX_SCRIPTED_PIDS>,
+    stored_dtcs: ArrayVec<Dtc, 6>,
+    pending_dtcs: ArrayVec<Dtc, 6>,
+    /// Stored codes the transmission ECU answers Mode 03 with, after the engine
+    transmission_stored_dtcs: ArrayVec<Dtc, 6>,
+    voltage: &'static str,
+    /// Frames that are on the bus whether they're asked for or not, printed in turn while monitoring (`ATMA`)
+    broadcasts: ArrayVec<(u32, ArrayVec<u8, 8>), MAX_BROADCASTS>,
+    next_broadcast: usize,
+    /// Monitoring stops with `BUFFER FULL` after this many more broadcast frames, once
+    buffer_full_after: Option<usize>,
+    /// Set by `ATMA` (or `STMA`), until the next character is written
+    monitoring: bool,
+    /// The digits of the last `ATCRA`, `X` matches any digit. Empty lets every ID through
+    receive_filter: ArrayVec<u8, 8>,
+    /// Answers `STI` and the other `ST` commands, any ELM answers `?`
+    stn: bool,
+    /// IDs added with `STFAP` (the mask is taken to be exact). Empty lets every ID through
+    pass_filters: ArrayVec<u32, MAX_PASS_FILTERS>,
+    /// The adapter's side of the UART. While it isn't the same as `host_baud_rate` everything is garbled both ways
+    baud_rate: u32,
+    host_baud_rate: u32,
+    /// What the wiring can carry, faster than this is garbled even if both sides agree
+    max_baud_rate: u32,
+    /// After `STBR`/`ATBRD`: the position in the output from which the adapter talks at the new rate
+    baud_rate_switch: Option<(usize, u32)>,
+    /// After `STBR`/`ATBRD`: the rate to go back to unless the next character is a `\r` at the new one
+    confirming_baud_rate: Option<u32>,
+    /// The key is turned off once this many OBD requests have been answered
+    key_off_after: Option<usize>,
+    /// How long the key stays off once it's turned off
+    key_off_time: Duration,
+    /// While the key is off: when it's turned back on
+    key_on_at: Option<Instant>,
+    /// What `ATIGN` says. While it's off the ECU doesn't answer and the battery is at `KEY_OFF_VOLTAGE`
+    ignition: bool,
+    /// Set by `ATLP` until anything is written, or the key is turned back on
+    asleep: bool,
+    /// AT commands (without `AT` and `\r`) answered with `?`, like clones that don't implement them
+    rejected_at_commands: ArrayVec<&'static str, 4>,
+    faults: ArrayVec<Fault, MAX_QUEUED_FAULTS>,
+    /// Every fault after the queue is empty, `None` for a healthy ECU
+    persistent_fault: Option<Fault>,
+    command: ArrayVec<u8, MAX_COMMAND_LEN>,
+    output: ArrayVec<u8, MAX_OUTPUT_LEN>,
+    read_position: usize,
+    /// OBD requests answered so far, faulty or not
+    pub obd_requests: usize,
+}
+
+impl ElmEmulator {
+    pub fn new(protocol: ObdProtocol) -> Self {
+        Self {
+            protocol,
+            automatic_protocol: true,
+            searching: true,
+            echo: true,
+            headers: false,
+            spaces: true,
+            pids: ArrayVec::new(),
+            transmission_pids: ArrayVec::new(),
+            local_identifiers: ArrayVec::new(),
+            data_identifiers: ArrayVec::new(),
+            stored_dtcs: ArrayVec::new(),
+            pending_dtcs: ArrayVec::new(),
+            transmission_stored_dtcs: ArrayVec::new(),
+            voltage: "12.6V",
+            broadcasts: ArrayVec::new(),
+            next_broadcast: 0,
+            buffer_full_after: None,
+            monitoring: false,
+            receive_filter: ArrayVec::new(),
+            stn: false,
+            pass_filters: ArrayVec::new(),
+            baud_rate: DEFAULT_BAUD_RATE,
+            host_baud_rate: DEFAULT_BAUD_RATE,
+            max_baud_rate: u32::MAX,
+            baud_rate_switch: None,
+            confirming_baud_rate: None,
+            key_off_after: None,
+            key_off_time: Duration::from_ticks(0),
+            key_on_at: None,
+            ignition: true,
+            asleep: false,
+            rejected_at_commands: ArrayVec::new(),
+            faults: ArrayVec::new(),
+            persistent_fault: None,
+            command: ArrayVec::new(),
+            output: ArrayVec::new(),
+            read_position: 0,
+            obd_requests: 0,
+        }
+    }
+
+    /// Answers Mode 01 requests for `pid` with `data`.
+    /// The supported PID bitmaps (PID 0x00, 0x20, ...) are built from the scripted PIDs unless they are scripted themselves
+    pub fn with_pid(mut self, pid: u8, data: &[u8]) -> Self {
+        self.pids.retain(|(scripted, _)| *scripted != pid);
+        self.pids.push((pid, data.iter().copied().collect()));
+        self
+    }
+
+    /// Adds a transmission ECU that also answers Mode 01 requests, with `data` for `pid`.
+    /// Its frames come before the engine's, like on cars where the transmission ECU is quicker to answer
+    pub fn with_transmission_pid(mut self, pid: u8, data: &[u8]) -> Self {
+        self.transmission_pids.retain(|(scripted, _)| *scripted != pid);
+        self.transmission_pids.push((pid, data.iter().copied().collect()));
+        self
+    }
+
+    /// Answers Mode 21 requests for `identifier` with `data`
+    pub fn with_local_identifier(mut self, identifier: u8, data: &[u8]) -> Self {
+        self.local_identifiers.retain(|(scripted, _)| *scripted != identifier);
+        self.local_identifiers.push((identifier, data.iter().copied().collect()));
+        self
+    }
+
+    /// Answers Mode 22 requests for `identifier` with `data`
+    pub fn with_data_identifier(mut self, identifier: u16, data: &[u8]) -> Self {
+        self.data_identifiers.retain(|(scripted, _)| *scripted != identifier);
+        self.data_identifiers.push((identifier, data.iter().copied().collect()));
+        self
+    }
+
+    pub fn with_stored_dtcs(mut self, dtcs: &[Dtc]) -> Self {
+        self.stored_dtcs = dtcs.iter().copied().collect();
+        self
+    }
+
+    pub fn with_pending_dtcs(mut self, dtcs: &[Dtc]) -> Self {
+        self.pending_dtcs = dtcs.iter().copied().collect();
+        self
+    }
+
+    /// Adds a transmission ECU that answers Mode 03 with `dtcs` too
+    pub fn with_transmission_stored_dtcs(mut self, dtcs: &[Dtc]) -> Self {
+        self.transmission_stored_dtcs = dtcs.iter().copied().collect();
+        self
+    }
+
+    pub fn with_voltage(mut self, voltage: &'static str) -> Self {
+        self.voltage = voltage;
+        self
+    }
+
+    /// Puts a frame with `can_id` and `data` on the bus, only seen while monitoring. CAN protocols only
+    pub fn with_broadcast(mut self, can_id: u32, data: &[u8]) -> Self {
+        self.broadcasts.push((can_id, data.iter().copied().collect()));
+        self
+    }
+
+    /// Stops monitoring with `BUFFER FULL` after `frames` broadcast frames, like an ELM whose UART can't keep up
+    /// with the bus. Only the first time
+    pub fn with_buffer_full(mut self, frames: usize) -> Self {
+        self.buffer_full_after = Some(frames);
+        self
+    }
+
+    /// An STN chip (OBDLink): answers `STI`, takes `STPX` requests, `STFAP`/`STFCP` filters, `STMA` and `STBR`
+    pub fn as_stn(mut self) -> Self {
+        self.stn = true;
+        self
+    }
+
+    /// Garbles everything faster than `baud_rate`, like long or noisy wiring to the adapter
+    pub fn with_max_baud_rate(mut self, baud_rate: u32) -> Self {
+        self.max_baud_rate = baud_rate;
+        self
+    }
+
+    /// Turns the key off after `after_requests` OBD requests, and back on `for_time` later.
+    /// The adapter wakes up from `ATLP` by itself then, its ignition input is wired
+    pub fn with_key_off(mut self, after_requests: usize, for_time: Duration) -> Self {
+        self.key_off_after = Some(after_requests);
+        self.key_off_time = for_time;
+        self
+    }
+
+    /// ex: `rejecting_at_command("AT1")` for a clone that doesn't know `ATAT1`
+    pub fn rejecting_at_command(mut self, command: &'static str) -> Self {
+        self.rejected_at_commands.push(command);
+        self
+    }
+
+    /// Queues a fault for the next OBD request that doesn't already have one
+    pub fn inject(&mut self, fault: Fault) {
+        self.faults.push(fault);
+    }
+
+    /// Every OBD request after the queued faults gets `fault`, or is answered normally again with `None`
+    pub fn set_persistent_fault(&mut self, fault: Option<Fault>) {
+        self.persistent_fault = fault;
+    }
+
+    fn process_command(&mut self) {
+        self.update_ignition();
+        let command: ArrayVec<u8, MAX_COMMAND_LEN> = self.command.iter()
+            .filter(|c| **c != b' ')
+            .map(|c| c.to_ascii_uppercase())
+            .collect();
+        self.command.clear();
+        self.output.clear();
+        self.read_position = 0;
+
+        // `STPX` carries an OBD request, the other `ST` commands are like AT commands
+        let is_adapter_command = command.starts_with(b"AT") || (command.starts_with(b"ST") && !command.starts_with(b"STPX"));
+        let fault = if is_adapter_command {
+            None
+        } else {
+            self.obd_requests += 1;
+            if self.key_off_after.is_some_and(|after| self.obd_requests > after) {
+                self.key_off_after = None;
+                self.ignition = false;
+                self.key_on_at = Some(Instant::now() + self.key_off_time);
+            }
+            if !self.ignition {
+                Some(Fault::NoData)
+            } else if self.faults.is_empty() { self.persistent_fault } else { Some(self.faults.remove(0)) }
+        };
+        if fault == Some(Fault::Silent) {
+            return
+        }
+
+        if self.echo || fault == Some(Fault::Echo) {
+            self.output.try_extend_from_slice(&command).unwrap();
+            self.output.push(b'\r');
+        }
+        if command.as_slice() == b"ATMA" || (self.stn && command.as_slice() == b"STMA") {
+            // no prompt until something stops it
+            self.monitoring = true;
+            return
+        }
+        // no prompt after these either, it comes once the new rate is confirmed
+        if let (true, Some(digits)) = (self.stn, command.strip_prefix(b"STBR")) {
+            let baud_rate = digits.iter()
+                .try_fold(0u32, |baud_rate, c| c.is_ascii_digit().then(|| baud_rate * 10 + (c - b'0') as u32));
+            return self.switch_baud_rate(baud_rate)
+        }
+        if let Some(digits) = command.strip_prefix(b"ATBRD") {
+            if !self.rejects(&command[2..]) {
+                let divisor = hex_number(digits).filter(|divisor| *divisor > 0);
+                return self.switch_baud_rate(divisor.map(|divisor| BAUD_RATE_DIVISOR_CLOCK / divisor))
+            }
+        }
+        if command.starts_with(b"AT") {
+            self.at_command(&command[2..]);
+        } else if is_adapter_command {
+            self.stn_command(&command[2..]);
+        } else if command.starts_with(b"STPX") && !self.stn {
+            self.line(b"?");
+        } else {
+            // `STPXD:010C,R:1` is the request 010C
+            let command = match command.strip_prefix(b"STPX") {
+                Some(parameters) => parameters.split(|c| *c == b',')
+                    .find_map(|parameter| parameter.strip_prefix(b"D:"))
+                    .unwrap_or(b""),
+                None => &command[..],
+            };
+            if self.searching || fault == Some(Fault::Searching) {
+                self.line(b"SEARCHING...");
+            }
+            if self.searching && fault == Some(Fault::NoData) {
+                // nothing answered on any protocol, so it searches again on the next request
+                self.line(b"UNABLE TO CONNECT");
+            } else if fault == Some(Fault::NoData) {
+                self.line(b"NO DATA");
+            } else {
+                self.obd_request(&command, fault);
+            }
+            self.searching &= fault == Some(Fault::NoData);
+        }
+        self.output.try_extend_from_slice(b"\r>").unwrap();
+    }
+
+    /// `command` is without `AT`
+    fn rejects(&self, command: &[u8]) -> bool {
+        self.rejected_at_commands.iter().any(|rejected| rejected.as_bytes() == command)
+    }
+
+    fn at_command(&mut self, command: &[u8]) {
+        if self.rejects(command) {
+            return self.line(b"?")
+        }
+        match command {
+            b"Z" => self.reset(),
+            b"I" => self.line(BANNER.as_bytes()),
+            b"E0" | b"E1" => { self.echo = command[1] == b'1'; self.line(b"OK") }
+            b"H0" | b"H1" => { self.headers = command[1] == b'1'; self.line(b"OK") }
+            b"S0" | b"S1" => { self.spaces = command[1] == b'1'; self.line(b"OK") }
+            b"SP0" => {
+                self.automatic_protocol = true;
+                self.searching = true;
+                self.line(b"OK")
+            }
+            b"DPN" => {
+                let number = self.protocol.number();
+                match (self.automatic_protocol, self.searching) {
+                    (true, true) => self.line(b"A0"),
+                    (true, false) => self.line(&[b'A', number]),
+                    (false, _) => self.line(&[number]),
+                }
+            }
+            b"RV" => {
+                let voltage = if self.ignition { self.voltage } else { KEY_OFF_VOLTAGE };
+                self.line(voltage.as_bytes())
+            }
+            b"IGN" => self.line(if self.ignition { b"ON" } else { b"OFF" }),
+            b"LP" => {
+                self.asleep = true;
+                self.line(b"OK")
+            }
+            _ if command.starts_with(b"CRA") => {
+                self.receive_filter = command[3..].iter().copied().collect();
+                self.line(b"OK")
+            }
+            _ if command.starts_with(b"SP") => {
+                self.automatic_protocol = false;
+                self.searching = false;
+                self.line(b"OK")
+            }
+            _ => self.line(b"OK"),
+        }
+    }
+
+    /// `ATZ`, or waking up from `ATLP`: a power up, at the default rate whatever `STBR` said
+    fn reset(&mut self) {
+        self.echo = true;
+        self.headers = false;
+        self.spaces = true;
+        self.pass_filters.clear();
+        self.baud_rate = DEFAULT_BAUD_RATE;
+        self.baud_rate_switch = None;
+        self.confirming_baud_rate = None;
+        self.asleep = false;
+        self.output.clear();
+        self.read_position = 0;
+        self.line(b"");
+        self.line(BANNER.as_bytes());
+    }
+
+    /// Turns the key back on once it's been off long enough, which wakes the adapter up if it's asleep
+    fn update_ignition(&mut self) {
+        if self.key_on_at.is_some_and(|at| Instant::now() >= at) {
+            self.key_on_at = None;
+            self.ignition = true;
+            if self.asleep {
+                self.wake_up();
+            }
+        }
+    }
+
+    /// Out of `ATLP`, the banner and a prompt come by themselves
+    fn wake_up(&mut self) {
+        self.reset();
+        self.output.try_extend_from_slice(b"\r>").unwrap();
+    }
+
+    /// Reads from the output up to `end`, garbled if the two sides disagree on the baud rate
+    fn read_output(&mut self, buf: &mut [u8], end: usize) -> Result<usize, ErrorKind> {
+        let remaining = &self.output[self.read_position..end];
+        let len = remaining.len().min(buf.len());
+        buf[..len].copy_from_slice(&remaining[..len]);
+        self.read_position += len;
+        if !self.is_line_clear() {
+            // nothing read at the wrong rate is ASCII
+            buf[..len].iter_mut().for_each(|c| *c |= 0x80);
+        }
+        Ok(len)
+    }
+
+    /// `command` is without `ST`. Only the ones the firmware sends
+    fn stn_command(&mut self, command: &[u8]) {
+        if !self.stn {
+            return self.line(b"?")
+        }
+        match command {
+            b"I" => self.line(STN_BANNER.as_bytes()),
+            b"FCP" => {
+                self.pass_filters.clear();
+                self.line(b"OK")
+            }
+            _ if command.starts_with(b"FAP") => {
+                let id = command[3..].split(|c| *c == b',').next().and_then(hex_number);
+                match id.map(|id| self.pass_filters.try_push(id)) {
+                    Some(Ok(())) => self.line(b"OK"),
+                    _ => self.line(b"?"),
+                }
+            }
+            _ => self.line(b"?"),
+        }
+    }
+
+    /// `STBR`/`ATBRD`: `OK` at the old rate, then the ID at the new one. The rest is up to what the host does next
+    fn switch_baud_rate(&mut self, baud_rate: Option<u32>) {
+        let Some(baud_rate) = baud_rate.filter(|baud_rate| *baud_rate > 0) else {
+            self.line(b"?");
+            return self.output.try_extend_from_slice(b"\r>").unwrap()
+        };
+        self.line(b"OK");
+        self.baud_rate_switch = Some((self.output.len(), baud_rate));
+        self.confirming_baud_rate = Some(self.baud_rate);
+        self.line(if self.stn { STN_BANNER.as_bytes() } else { BANNER.as_bytes() });
+    }
+
+    /// After a baud rate switch: a `\r` that gets through at the new rate keeps it. Anything else, or nothing
+    /// at all in time (`None`), goes back to `old_baud_rate`
+    fn confirm_baud_rate(&mut self, byte: Option<u8>, old_baud_rate: u32) {
+        self.output.clear();
+        self.read_position = 0;
+        self.baud_rate_switch = None;
+        if byte == Some(b'\r') && self.is_line_clear() {
+            self.line(b"OK");
+        } else {
+            self.baud_rate = old_baud_rate;
+        }
+        self.output.try_extend_from_slice(b"\r>").unwrap();
+    }
+
+    /// Both sides are at the same rate, and the wiring can carry it
+    fn is_line_clear(&self) -> bool {
+        self.baud_rate == self.host_baud_rate && self.baud_rate <= self.max_baud_rate
+    }
+
+    fn obd_request(&mut self, command: &[u8], fault: Option<Fault>) {
+        let mut request: ArrayVec<u8, { MAX_COMMAND_LEN / 2 }> = ArrayVec::new();
+        for pair in command.chunks(2) {
+            match (pair.first().and_then(|c| hex_value(*c)), pair.get(1).and_then(|c| hex_value(*c))) {
+                (Some(high), Some(low)) => request.push(high << 4 | low),
+                _ => return self.line(b"?"),
+            }
+        }
+        let mut reply: ArrayVec<u8, 64> = ArrayVec::new();
+        match request.as_slice() {
+            // every ECU with a scripted PID answers. Mode 01 can ask for several PIDs at once,
+            // the ones that aren't scripted are left out
+            [0x01, pids @ ..] if !pids.is_empty() => {
+                let mut answered = false;
+                for (source, scripted) in self.answering_ecus() {
+                    let mut reply: ArrayVec<u8, 64> = ArrayVec::new();
+                    reply.push(0x41);
+                    for pid in pids {
+                        if let Some(data) = pid_data(&scripted, *pid) {
+                            reply.push(*pid);
+                            reply.extend(data);
+                        }
+                    }
+                    if reply.len() > 1 {
+                        self.frame_reply(source, &reply, fault);
+                        answered = true;
+                    }
+                }
+                if !answered {
+                    self.line(b"NO DATA");
+                }
+                return
+            }
+            // one local identifier followed by the transmission mode, 01
+            [0x21, identifier, 0x01] => {
+                match self.local_identifiers.iter().find(|(scripted, _)| scripted == identifier) {
+                    Some((_, data)) => {
+                        reply.extend([0x61, *identifier]);
+                        reply.extend(data.iter().copied());
+                    }
+                    // requestOutOfRange
+                    None => reply.extend([0x7f, 0x21, 0x31]),
+                }
+            }
+            [0x22, high, low] => {
+                let identifier = u16::from_be_bytes([*high, *low]);
+                match self.data_identifiers.iter().find(|(scripted, _)| *scripted == identifier) {
+                    Some((_, data)) => {
+                        reply.extend([0x62, *high, *low]);
+                        reply.extend(data.iter().copied());
+                    }
+                    // requestOutOfRange
+                    None => reply.extend([0x7f, 0x22, 0x31]),
+                }
+            }
+            [0x02, pid, frame] => {
+                match pid_data(&self.pids, *pid) {
+                    Some(data) => {
+                        reply.extend([0x42, *pid, *frame]);
+                        reply.extend(data);
+                    }
+                    None => return self.line(b"NO DATA"),
+                }
+            }
+            [service @ (0x03 | 0x07)] => {
+                let dtcs = match service {
+                    0x03 => self.stored_dtcs.clone(),
+                    _ => self.pending_dtcs.clone(),
+                };
+                self.dtc_reply(ENGINE_ECU_ADDRESS, service + 0x40, &dtcs, fault);
+                if *service == 0x03 && !self.transmission_stored_dtcs.is_empty() {
+                    let dtcs = self.transmission_stored_dtcs.clone();
+                    self.dtc_reply(TRANSMISSION_ECU_ADDRESS, service + 0x40, &dtcs, fault);
+                }
+                return
+            }
+            [0x04] => {
+                self.stored_dtcs.clear();
+                self.pending_dtcs.clear();
+                self.transmission_stored_dtcs.clear();
+                reply.push(0x44);
+            }
+            _ => return self.line(b"NO DATA"),
+        }
+        self.frame_reply(ENGINE_ECU_ADDRESS, &reply, fault);
+    }
+
+    /// Source address and scripted PIDs of each ECU that answers PID requests, in the order they answer
+    fn answering_ecus(&self) -> ArrayVec<(u8, ScriptedPids), 2> {
+        let mut ecus = ArrayVec::new();
+        if !self.transmission_pids.is_empty() {
+            ecus.push((TRANSMISSION_ECU_ADDRESS, self.transmission_pids.clone()));
+        }
+        ecus.push((ENGINE_ECU_ADDRESS, self.pids.clone()));
+        ecus
+    }
+
+    /// `source` answers with `dtcs`
+    fn dtc_reply(&mut self, source: u8, service: u8, dtcs: &[Dtc], fault: Option<Fault>) {
+        let code_bytes = |dtc: &Dtc| dtc.0.to_be_bytes();
+        if self.protocol.frame_format().is_can() {
+            let mut reply: ArrayVec<u8, 64> = ArrayVec::new();
+            reply.extend([service, dtcs.len() as u8]);
+            reply.extend(dtcs.iter().flat_map(code_bytes));
+            return self.frame_reply(source, &reply, fault)
+        }
+        // 3 codes per frame, padded with 0000, and one empty frame if there are no codes
+        let mut chunks = dtcs.chunks(3).peekable();
+        if chunks.peek().is_none() {
+            return self.frame_reply(source, &[service, 0, 0, 0, 0, 0, 0], fault)
+        }
+        for chunk in chunks {
+            let mut reply = [0u8; 7];
+            reply[0] = service;
+            for (slot, dtc) in reply[1..].chunks_exact_mut(2).zip(chunk) {
+                slot.copy_from_slice(&code_bytes(dtc));
+            }
+            self.frame_reply(source, &reply, fault);
+        }
+    }
+
+    /// Wraps `data` in the header and checksum (or CAN ID and ISO-TP framing) of the protocol, then applies `fault`.
+    /// `source` is the physical address of the answering ECU
+    fn frame_reply(&mut self, source: u8, data: &[u8], fault: Option<Fault>) {
+        let frame_format = self.protocol.frame_format();
+        let mut frames: ArrayVec<ArrayVec<u8, 16>, 8> = ArrayVec::new();
+        match frame_format {
+            FrameFormat::Iso9141 | FrameFormat::Kwp | FrameFormat::J1850 => {
+                let mut frame: ArrayVec<u8, 16> = ArrayVec::new();
+                match frame_format {
+                    FrameFormat::Kwp => frame.extend([0x80 | data.len() as u8, 0xf1, source]),
+                    _ => frame.extend([0x48, 0x6b, source]),
+                }
+                frame.try_extend_from_slice(data).unwrap();
+                let checksum = match frame_format {
+                    FrameFormat::J1850 => j1850_crc(&frame),
+                    _ => additive_checksum(&frame),
+                };
+                frame.push(if fault == Some(Fault::BadChecksum) { checksum.wrapping_add(1) } else { checksum });
+                frames.push(frame);
+            }
+            FrameFormat::Can11Bit | FrameFormat::Can29Bit => {
+                let header: &[u8] = match frame_format {
+                    // 11 bit IDs number the ECUs from 7E8
+                    FrameFormat::Can11Bit if source == TRANSMISSION_ECU_ADDRESS => &[0x07, 0xe9],
+                    FrameFormat::Can11Bit => &[0x07, 0xe8],
+                    _ => &[0x18, 0xda, 0xf1, source],
+                };
+                let mut frame: ArrayVec<u8, 16> = header.iter().copied().collect();
+                if data.len() <= CAN_SINGLE_FRAME_LEN {
+                    frame.push(data.len() as u8);
+                    frame.try_extend_from_slice(data).unwrap();
+                    frames.push(frame);
+                } else {
+                    frame.extend([0x10 | (data.len() >> 8) as u8, data.len() as u8]);
+                    frame.try_extend_from_slice(&data[..6]).unwrap();
+                    frames.push(frame);
+                    for (index, chunk) in data[6..].chunks(7).enumerate() {
+                        let mut frame: ArrayVec<u8, 16> = header.iter().copied().collect();
+                        frame.push(0x20 | ((index + 1) % 0x10) as u8);
+                        frame.try_extend_from_slice(chunk).unwrap();
+                        frames.push(frame);
+                    }
+                }
+            }
+        }
+        if fault == Some(Fault::Truncated) {
+            frames.last_mut().unwrap().pop();
+        }
+        for frame in frames.iter() {
+            self.hex_line(frame, frame_format);
+        }
+    }
+
+    /// Prints a frame the way the ELM does: hex bytes, with the header left out when headers are off
+    fn hex_line(&mut self, frame: &[u8], frame_format: FrameFormat) {
+        let (header, rest) = frame.split_at(frame_format.header_len());
+        let mut first = true;
+        if self.headers {
+            if frame_format == FrameFormat::Can11Bit {
+                // 11 bit IDs are printed as 3 digits
+                self.output.push(HexDigits::from_val(header[0]) as u8);
+                self.output.push(HexDigits::from_val(header[1] >> 4) as u8);
+                self.output.push(HexDigits::from_val(header[1]) as u8);
+                first = false;
+            } else {
+                for byte in header {
+                    self.hex_byte(*byte, &mut first);
+                }
+            }
+        }
+        let data = match (self.headers, frame_format.is_can()) {
+            // without headers the checksum isn't shown either
+            (false, false) => &rest[..rest.len().saturating_sub(1)],
+            // or the PCI byte of a single frame
+            (false, true) => &rest[1.min(rest.len())..],
+            (true, _) => rest,
+        };
+        for byte in data {
+            self.hex_byte(*byte, &mut first);
+        }
+        self.output.push(b'\r');
+    }
+
+    /// Replaces the output with the next broadcast frame that passes the receive filter, if there is one
+    fn broadcast(&mut self) {
+        self.output.clear();
+        self.read_position = 0;
+        match self.buffer_full_after {
+            Some(0) => {
+                self.buffer_full_after = None;
+                self.monitoring = false;
+                self.line(b"BUFFER FULL");
+                self.output.try_extend_from_slice(b"\r>").unwrap();
+                return
+            }
+            Some(frames) => self.buffer_full_after = Some(frames - 1),
+            None => {}
+        }
+        let frame_format = self.protocol.frame_format();
+        for _ in 0..self.broadcasts.len() {
+            let (can_id, data) = self.broadcasts[self.next_broadcast].clone();
+            self.next_broadcast = (self.next_broadcast + 1) % self.broadcasts.len();
+            let id_bytes = can_id.to_be_bytes();
+            let header = &id_bytes[4 - frame_format.header_len()..];
+            let digits = header.iter()
+                .flat_map(|byte| [HexDigits::from_val(byte >> 4) as u8, HexDigits::from_val(*byte) as u8])
+                // 11 bit IDs are 3 digits
+                .skip(if frame_format == FrameFormat::Can11Bit { 1 } else { 0 });
+            let passes_receive_filter = self.receive_filter.is_empty()
+                || digits.zip(self.receive_filter.iter()).all(|(digit, filter)| *filter == b'X' || digit == *filter);
+            if passes_receive_filter && (self.pass_filters.is_empty() || self.pass_filters.contains(&can_id)) {
+                let mut frame: ArrayVec<u8, 16> = header.iter().copied().collect();
+                frame.try_extend_from_slice(&data).unwrap();
+                return self.hex_line(&frame, frame_format)
+            }
+        }
+    }
+
+    fn hex_byte(&mut self, byte: u8, first: &mut bool) {
+        if self.spaces && !*first {
+            self.output.push(b' ');
+        }
+        *first = false;
+        self.output.push(HexDigits::from_val(byte >> 4) as u8);
+        self.output.push(HexDigits::from_val(byte) as u8);
+    }
+
+    fn line(&mut self, text: &[u8]) {
+        self.output.try_extend_from_slice(text).unwrap();
+        self.output.push(b'\r');
+    }
+}
+
+/// The scripted data for `pid`, or a supported PID bitmap built from the scripted PIDs
+fn pid_data(pids: &ScriptedPids, pid: u8) -> Option<ArrayVec<u8, 4>> {
+    if let Some((_, data)) = pids.iter().find(|(scripted, _)| *scripted == pid) {
+        return Some(data.clone())
+    }
+    if pid % 0x20 != 0 {
+        return None
+    }
+    let range_start = pid as u16;
+    let mut bitmap: u32 = 0;
+    for scripted in pids.iter().map(|(scripted, _)| *scripted as u16) {
+        if scripted > range_start && scripted <= range_start + 0x20 {
+            bitmap |= 1 << (range_start + 0x20 - scripted);
+        } else if scripted > range_start + 0x20 {
+            // the last bit says whether the next range is supported
+            bitmap |= 1;
+        }
+    }
+    Some(bitmap.to_be_bytes().into_iter().collect())
+}
+
+fn hex_value(c: u8) -> Option<u8> {
+    (c as char).to_digit(16).map(|digit| digit as u8)
+}
+
+fn hex_number(digits: &[u8]) -> Option<u32> {
+    digits.iter().try_fold(0u32, |number, c| hex_value(*c).map(|digit| number << 4 | digit as u32))
+}
+
+impl ErrorType for ElmEmulator {
+    type Error = ErrorKind;
+}
+
+impl Read for ElmEmulator {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.update_ignition();
+        if self.monitoring && self.read_position == self.output.len() {
+            self.broadcast();
+        }
+        let mut end = self.output.len();
+        if let Some((position, baud_rate)) = self.baud_rate_switch {
+            if self.read_position >= position {
+                self.baud_rate = baud_rate;
+                self.baud_rate_switch = None;
+            } else {
+                end = position;
+            }
+        }
+        if self.read_position >= end {
+            // the ID after a baud rate switch went unanswered, so the adapter goes back
+            if let Some(old_baud_rate) = self.confirming_baud_rate.take() {
+                self.confirm_baud_rate(None, old_baud_rate);
+            } else if let Some(key_on_at) = self.key_on_at {
+                // the key coming back is the only thing that makes an idle adapter say anything
+                Timer::at(key_on_at).await;
+                self.update_ignition();
+                if self.read_position < self.output.len() {
+                    return self.read_output(buf, self.output.len())
+                }
+            }
+            // nothing comes back until something is written, it's up to the host to give up waiting
+            return core::future::pending().await
+        }
+        self.read_output(buf, end)
+    }
+}
+
+impl Write for ElmEmulator {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        for byte in buf {
+            if let Some(old_baud_rate) = self.confirming_baud_rate.take() {
+                self.confirm_baud_rate(Some(*byte), old_baud_rate);
+                continue;
+            }
+            if !self.is_line_clear() {
+                // garbled, the adapter doesn't understand it
+                continue;
+            }
+            if self.asleep {
+                // only wakes it up
+                self.wake_up();
+                continue;
+            }
+            if self.monitoring {
+                // any character stops it, and isn't part of the next command.
+                // Whatever was already printed is still read first
+                self.monitoring = false;
+                self.line(b"STOPPED");
+                self.output.try_extend_from_slice(b"\r>").unwrap();
+                continue;
+            }
+            match byte {
+                b'\r' => self.process_command(),
+                b'\n' => {}
+                _ => self.command.try_push(*byte).map_err(|_| ErrorKind::OutOfMemory)?,
+            }
+        }
+        Ok(buf.len())
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl SetBaudRate for ElmEmulator {
+    fn set_baud_rate(&mut self, baud_rate: u32) {
+        self.host_baud_rate = baud_rate;
+    }
+}
@@ -0,0 +1,146 @@
+use embassy_time::Duration;
+
+/// Consecutive failed requests before the link is reported as degraded
+pub const DEGRADED_AFTER_FAILURES: u8 = 3;
+/// Consecutive failed requests before the ELM is initialized again from scratch
+pub const REINIT_AFTER_FAILURES: u8 = 10;
+
+/// Wait before the second init attempt, doubled for every failed attempt after that
+const BASE_REINIT_BACKOFF: Duration = Duration::from_millis(1000);
+const MAX_REINIT_BACKOFF: Duration = Duration::from_millis(30000);
+
+#[derive(defmt::Format, Debug, Copy, Clone, PartialEq)]
+pub enum LinkState {
+    /// Not initialized, or given up on after too many failures
+    Disconnected,
+    Initialising,
+    Connected,
+    /// Still connected, but the last few requests failed
+    Degraded,
+}
+
+/// Keeps track of the state of the connection to the ELM (and the ECU behind it) and decides when to start over.
+/// Every method that can change the state returns the new state if it did change, so it's only reported once
+#[derive(defmt::Format, Debug, Clone, PartialEq)]
+pub struct LinkSupervisor {
+    state: LinkState,
+    consecutive_failures: u8,
+    failed_init_attempts: u8,
+}
+
+impl LinkSupervisor {
+    pub const fn new() -> Self {
+        Self {
+            state: LinkState::Disconnected,
+            consecutive_failures: 0,
+            failed_init_attempts: 0,
+        }
+    }
+
+    pub fn state(&self) -> LinkState {
+        self.state
+    }
+
+    pub fn needs_init(&self) -> bool {
+        self.state == LinkState::Disconnected
+    }
+
+    /// How long to wait before the next init attempt. No wait after a working link was lost,
+    /// only once init itself keeps failing
+    pub fn backoff(&self) -> Duration {
+        match self.failed_init_attempts {
+            0 => Duration::from_ticks(0),
+            attempts => {
+                let multiplier = 1u32 << (attempts - 1).min(15);
+                (BASE_REINIT_BACKOFF * multiplier).min(MAX_REINIT_BACKOFF)
+            }
+        }
+    }
+
+    pub fn start_init(&mut self) -> Option<LinkState> {
+        self.set_state(LinkState::Initialising)
+    }
+
+    pub fn init_finished(&mut self, success: bool) -> Option<LinkState> {
+        self.consecutive_failures = 0;
+        if success {
+            self.failed_init_attempts = 0;
+            self.set_state(LinkState::Connected)
+        } else {
+            self.failed_init_attempts = self.failed_init_attempts.saturating_add(1);
+            self.set_state(LinkState::Disconnected)
+        }
+    }
+
+    /// Called with the outcome of every request to the ECU while connected
+    pub fn record(&mut self, success: bool) -> Option<LinkState> {
+        if success {
+            self.consecutive_failures = 0;
+            return self.set_state(LinkState::Connected)
+        }
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        if self.consecutive_failures >= REINIT_AFTER_FAILURES {
+            self.consecutive_failures = 0;
+            self.set_state(LinkState::Disconnected)
+        } else if self.consecutive_failures >= DEGRADED_AFTER_FAILURES {
+            self.set_state(LinkState::Degraded)
+        } else {
+            None
+        }
+    }
+
+    fn set_state(&mut self, state: LinkState) -> Option<LinkState> {
+        if self.state == state {
+            return None
+        }
+        self.state = state;
+        Some(state)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_link_state_transitions() {
+        let mut link = LinkSupervisor::new();
+        assert!(link.needs_init());
+        assert_eq!(link.start_init(), Some(LinkState::Initialising));
+        assert_eq!(link.init_finished(true), Some(LinkState::Connected));
+
+        for _ in 1..DEGRADED_AFTER_FAILURES {
+            assert_eq!(link.record(false), None);
+        }
+        assert_eq!(link.record(false), Some(LinkState::Degraded));
+        assert_eq!(link.record(true), Some(LinkState::Connected));
+        assert_eq!(link.record(true), None);
+
+        for _ in 1..REINIT_AFTER_FAILURES {
+            link.record(false);
+        }
+        assert_eq!(link.record(false), Some(LinkState::Disconnected));
+        assert!(link.needs_init());
+    }
+
+    #[test]
+    fn test_reinit_backoff() {
+        let mut link = LinkSupervisor::new();
+        assert_eq!(link.backoff(), Duration::from_ticks(0));
+        link.start_init();
+        link.init_finished(false);
+        assert_eq!(link.backoff(), BASE_REINIT_BACKOFF);
+        link.start_init();
+        link.init_finished(false);
+        assert_eq!(link.backoff(), BASE_REINIT_BACKOFF * 2);
+        for _ in 0..10 {
+            link.start_init();
+            link.init_finished(false);
+        }
+        assert_eq!(link.backoff(), MAX_REINIT_BACKOFF);
+        link.start_init();
+        link.init_finished(true);
+        assert_eq!(link.backoff(), Duration::from_ticks(0));
+    }
+}
@@ -0,0 +1,363 @@
+use arrayvec::ArrayVec;
+use defmt::Formatter;
+use embassy_time::Duration;
+use crate::errors::{ToRustAGaugeError, DISPLAY_TEXT_LEN};
+use crate::poll_scheduler::MAX_POLL_ITEMS;
+
+/// `ATST` counts in units of 4.096 ms
+const TIMEOUT_UNIT_US: u64 = 4096;
+/// What init sets the timeout to (`ATST64`, about 410 ms)
+pub const INITIAL_TIMEOUT: u8 = 0x64;
+/// What the ELM uses after a reset (about 205 ms), and keeps if it doesn't know `ATST`
+pub const DEFAULT_TIMEOUT: u8 = 0x32;
+/// About 65 ms. KWP ECUs can take up to 50 ms to answer (P2max)
+const MIN_TIMEOUT: u8 = 0x10;
+/// About 820 ms, the ELM has to give up before `UART_TIMEOUT` does
+const MAX_TIMEOUT: u8 = 0xc8;
+/// The timeout is kept at this many times the slowest recent response
+const TIMEOUT_MARGIN: u32 = 3;
+/// Responses between attempts to lower the timeout
+const ADJUST_EVERY: u8 = 16;
+/// Responses after a NO DATA before the timeout is lowered again
+const HOLD_OFF_AFTER_NO_DATA: u8 = 64;
+/// Smaller changes than this (about 16 ms) aren't worth a command
+const MIN_TIMEOUT_CHANGE: u8 = 4;
+
+/// Running min/avg/max of a latency
+#[derive(defmt::Format, Debug, Copy, Clone, PartialEq)]
+pub struct LatencyStats {
+    pub samples: u32,
+    pub min: Duration,
+    pub max: Duration,
+    /// Sum of every sample, for the average
+    total: Duration,
+}
+
+impl LatencyStats {
+    pub const fn new() -> Self {
+        Self {
+            samples: 0,
+            min: Duration::MAX,
+            max: Duration::from_ticks(0),
+            total: Duration::from_ticks(0),
+        }
+    }
+
+    pub fn record(&mut self, latency: Duration) {
+        self.samples = self.samples.saturating_add(1);
+        self.min = self.min.min(latency);
+        self.max = self.max.max(latency);
+        self.total += latency;
+    }
+
+    /// `None` until there is a sample
+    pub fn average(&self) -> Option<Duration> {
+        (self.samples > 0).then(|| self.total / self.samples)
+    }
+}
+
+impl Default for LatencyStats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How long one exchange with the ELM took
+#[derive(defmt::Format, Debug, Copy, Clone, PartialEq)]
+pub struct ExchangeTiming {
+    /// From the end of the write to the first byte of the answer, `None` if nothing came back
+    pub first_byte: Option<Duration>,
+    /// From the start of the write to the prompt (or the error)
+    pub round_trip: Duration,
+    /// Sent with `send_next` before its response was asked for. The response may have been waiting in the UART's
+    /// buffer for a while by then, so neither time says how quickly the ECU answered and `first_byte` is `None`
+    pub pipelined: bool,
+}
+
+/// Latency statistics sent to main every so often, for the diagnostics page
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimingReport {
+    /// The `ATST` value the ELM is set to
+    pub timeout: u8,
+    /// First byte latency of every answered request
+    pub response: LatencyStats,
+    /// Round trip of every request
+    pub round_trip: LatencyStats,
+    /// Round trip of each polled item, by its label
+    pub items: ArrayVec<(&'static str, LatencyStats), MAX_POLL_ITEMS>,
+}
+
+impl defmt::Format for TimingReport {
+    fn format(&self, fmt: Formatter) {
+        defmt::write!(fmt, "TimingReport(timeout = {:x}, response = {:?}, round_trip = {:?}, items = [", self.timeout, self.response, self.round_trip);
+        for (label, stats) in self.items.iter() {
+            defmt::write!(fmt, "{:?}: {:?} ", label, stats);
+        }
+        defmt::write!(fmt, "])")
+    }
+}
+
+impl TimingReport {
+    /// What fits on the display, the per item stats are only logged
+    pub fn summary(&self) -> TimingSummary {
+        TimingSummary {
+            timeout: self.timeout,
+            response: self.response.average(),
+            round_trip: self.round_trip.average(),
+            slowest: (self.round_trip.samples > 0).then_some(self.round_trip.max),
+        }
+    }
+}
+
+/// The start of a `TimingReport`, small enough to send to the display, see `ToLcdEvents::Timing`
+#[derive(defmt::Format, Debug, Copy, Clone, PartialEq)]
+pub struct TimingSummary {
+    pub timeout: u8,
+    /// Average first byte latency
+    pub response: Option<Duration>,
+    /// Average round trip
+    pub round_trip: Option<Duration>,
+    /// Slowest round trip
+    pub slowest: Option<Duration>,
+}
+
+impl TimingSummary {
+    /// Same 4 lines of 11 characters as the error strings: the timeout, the average response and round trip,
+    /// then the slowest round trip, all in ms. `--` before there's a sample
+    pub fn to_display_str<'b>(&self, buffer: &'b mut [u8; DISPLAY_TEXT_LEN]) -> &'b str {
+        const TEMPLATE: &'static [u8] = b"ATST   --ms\nresp   --ms\ntrip   --ms\nmax    --ms";
+        const LINE_LEN: usize = 12;
+        /// Right aligned before the "ms", 9999 at most
+        const LATENCY_END: usize = 9;
+        buffer.copy_from_slice(TEMPLATE);
+        let timeout = Duration::from_micros(self.timeout as u64 * TIMEOUT_UNIT_US);
+        for (line, latency) in [Some(timeout), self.response, self.round_trip, self.slowest].into_iter().enumerate() {
+            let Some(latency) = latency else { continue };
+            let end = line * LINE_LEN + LATENCY_END;
+            let mut latency_ms = latency.as_millis().min(9999);
+            buffer[end - 4..end].fill(b' ');
+            for c in buffer[end - 4..end].iter_mut().rev() {
+                *c = b'0' + (latency_ms % 10) as u8;
+                latency_ms /= 10;
+                if latency_ms == 0 {
+                    break
+                }
+            }
+        }
+        core::str::from_utf8(buffer).unwrap_or("")
+    }
+}
+
+/// Adjusts the ELM's timeout (`ATST`) to how quickly the ECU actually answers.
+/// The ELM waits the whole timeout after the last frame for more ECUs to answer, so a timeout that's longer than
+/// it needs to be slows every request down, and one that's too short makes it give up with NO DATA.
+/// The timeout follows the slowest recent response (times `TIMEOUT_MARGIN`): it goes up as soon as a response is
+/// slower than that, down only every `ADJUST_EVERY` responses, and not at all for a while after a NO DATA
+#[derive(defmt::Format, Debug, Clone, PartialEq)]
+pub struct AdaptiveTiming {
+    timeout: u8,
+    /// Slowest response since the last adjustment
+    window_max: Duration,
+    window_responses: u8,
+    hold_off: u8,
+    /// Cleared if the ELM rejects `ATST`
+    enabled: bool,
+    response: LatencyStats,
+    round_trip: LatencyStats,
+}
+
+impl AdaptiveTiming {
+    /// Starts from the timeout init sets
+    pub const fn new() -> Self {
+        Self {
+            timeout: INITIAL_TIMEOUT,
+            window_max: Duration::from_ticks(0),
+            window_responses: 0,
+            hold_off: 0,
+            enabled: true,
+            response: LatencyStats::new(),
+            round_trip: LatencyStats::new(),
+        }
+    }
+
+    /// The `ATST` value the ELM is set to
+    pub fn timeout(&self) -> u8 {
+        self.timeout
+    }
+
+    /// First byte latency of every answered request
+    pub fn response_stats(&self) -> &LatencyStats {
+        &self.response
+    }
+
+    /// Round trip of every request, answered or not
+    pub fn round_trip_stats(&self) -> &LatencyStats {
+        &self.round_trip
+    }
+
+    /// Records the timing of a request and returns the `ATST` value to send, if it should change.
+    /// Only a NO DATA or a valid answer say anything about the timeout, other errors are only counted in the stats.
+    /// A pipelined request's times aren't counted at all
+    pub fn observe<T>(&mut self, result: &Result<T, ToRustAGaugeError>, timing: &ExchangeTiming) -> Option<u8> {
+        if !timing.pipelined {
+            self.round_trip.record(timing.round_trip);
+        }
+        match (result, timing.first_byte) {
+            (Ok(_), Some(first_byte)) => {
+                self.response.record(first_byte);
+                self.response_received(first_byte)
+            }
+            (Err(ToRustAGaugeError::UartResponseNoData()), _) => self.no_data(),
+            _ => None,
+        }
+    }
+
+    /// The ELM rejected `ATST`, stop trying. `timeout` is what it is still set to
+    pub fn disable(&mut self, timeout: u8) {
+        self.enabled = false;
+        self.timeout = timeout;
+    }
+
+    fn response_received(&mut self, latency: Duration) -> Option<u8> {
+        self.window_max = self.window_max.max(latency);
+        self.window_responses = self.window_responses.saturating_add(1);
+        self.hold_off = self.hold_off.saturating_sub(1);
+        let target = timeout_for(self.window_max);
+        if target > self.timeout {
+            return self.change_to(target)
+        }
+        if self.window_responses < ADJUST_EVERY {
+            return None
+        }
+        self.window_max = Duration::from_ticks(0);
+        self.window_responses = 0;
+        if self.hold_off > 0 || self.timeout - target < MIN_TIMEOUT_CHANGE {
+            return None
+        }
+        self.change_to(target)
+    }
+
+    /// The ECU might have answered if the ELM had waited longer, so the timeout is doubled
+    fn no_data(&mut self) -> Option<u8> {
+        self.hold_off = HOLD_OFF_AFTER_NO_DATA;
+        self.window_max = Duration::from_ticks(0);
+        self.window_responses = 0;
+        self.change_to(self.timeout.saturating_mul(2).min(MAX_TIMEOUT))
+    }
+
+    fn change_to(&mut self, timeout: u8) -> Option<u8> {
+        if !self.enabled || timeout == self.timeout {
+            return None
+        }
+        defmt::debug!("Changing the ELM timeout from {:x} to {:x}", self.timeout, timeout);
+        self.timeout = timeout;
+        Some(timeout)
+    }
+}
+
+/// The `ATST` value for a response that took `latency`, with the margin
+fn timeout_for(latency: Duration) -> u8 {
+    let units = (latency * TIMEOUT_MARGIN).as_micros().div_ceil(TIMEOUT_UNIT_US);
+    units.clamp(MIN_TIMEOUT as u64, MAX_TIMEOUT as u64) as u8
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn answered(first_byte_ms: u64) -> ExchangeTiming {
+        ExchangeTiming {
+            first_byte: Some(Duration::from_millis(first_byte_ms)),
+            round_trip: Duration::from_millis(first_byte_ms + 10),
+            pipelined: false,
+        }
+    }
+
+    #[test]
+    fn test_latency_stats() {
+        let mut stats = LatencyStats::new();
+        assert_eq!(stats.average(), None);
+        for ms in [30, 10, 20] {
+            stats.record(Duration::from_millis(ms));
+        }
+        assert_eq!(stats.samples, 3);
+        assert_eq!(stats.min, Duration::from_millis(10));
+        assert_eq!(stats.max, Duration::from_millis(30));
+        assert_eq!(stats.average(), Some(Duration::from_millis(20)));
+    }
+
+    #[test]
+    fn test_timeout_follows_latency() {
+        let mut timing = AdaptiveTiming::new();
+        // 40 ms responses: 120 ms is 30 units, only lowered once a window has gone by
+        for _ in 0..ADJUST_EVERY - 1 {
+            assert_eq!(timing.observe(&Ok(()), &answered(40)), None);
+        }
+        assert_eq!(timing.observe(&Ok(()), &answered(40)), Some(0x1e));
+        // a slower response raises it right away
+        assert_eq!(timing.observe(&Ok(()), &answered(100)), Some(0x4a));
+        // failures that aren't NO DATA only count in the stats
+        let silent = ExchangeTiming { first_byte: None, round_trip: Duration::from_secs(1), pipelined: false };
+        assert_eq!(timing.observe::<()>(&Err(ToRustAGaugeError::UartBadChecksumError()), &silent), None);
+        assert_eq!(timing.round_trip_stats().samples, ADJUST_EVERY as u32 + 2);
+        // nor do pipelined ones, however long they seem to have taken
+        let pipelined = ExchangeTiming { first_byte: None, round_trip: Duration::from_millis(300), pipelined: true };
+        assert_eq!(timing.observe(&Ok(()), &pipelined), None);
+        assert_eq!(timing.round_trip_stats().samples, ADJUST_EVERY as u32 + 2);
+        assert_eq!(timing.response_stats().max, Duration::from_millis(100));
+        // very fast responses don't go below the minimum
+        for _ in 0..2 * ADJUST_EVERY {
+            timing.observe(&Ok(()), &answered(1));
+        }
+        assert_eq!(timing.timeout(), MIN_TIMEOUT);
+    }
+
+    #[test]
+    fn test_no_data_backs_off() {
+        let mut timing = AdaptiveTiming::new();
+        for _ in 0..ADJUST_EVERY {
+            timing.observe(&Ok(()), &answered(40));
+        }
+        assert_eq!(timing.timeout(), 0x1e);
+        let no_data: Result<(), ToRustAGaugeError> = Err(ToRustAGaugeError::UartResponseNoData());
+        assert_eq!(timing.observe(&no_data, &answered(0)), Some(0x3c));
+        // not lowered again until the hold off is over
+        for _ in 0..HOLD_OFF_AFTER_NO_DATA - 1 {
+            assert_eq!(timing.observe(&Ok(()), &answered(40)), None);
+        }
+        for _ in 0..ADJUST_EVERY {
+            timing.observe(&Ok(()), &answered(40));
+        }
+        assert_eq!(timing.timeout(), 0x1e);
+        // and never above the maximum
+        for _ in 0..4 {
+            timing.observe(&no_data, &answered(0));
+        }
+        assert_eq!(timing.timeout(), MAX_TIMEOUT);
+
+        timing.disable(INITIAL_TIMEOUT);
+        assert_eq!(timing.observe(&no_data, &answered(0)), None);
+        assert_eq!(timing.timeout(), INITIAL_TIMEOUT);
+    }
+
+    #[test]
+    fn test_summary_display_str() {
+        let mut timing = AdaptiveTiming::new();
+        let mut report = TimingReport {
+            timeout: timing.timeout(),
+            response: *timing.response_stats(),
+            round_trip: *timing.round_trip_stats(),
+            items: ArrayVec::new(),
+        };
+        let mut buffer = [0u8; DISPLAY_TEXT_LEN];
+        assert_eq!(report.summary().to_display_str(&mut buffer), "ATST  409ms\nresp   --ms\ntrip   --ms\nmax    --ms");
+
+        for ms in [5, 40] {
+            timing.observe(&Ok(()), &answered(ms));
+        }
+        report.response = *timing.response_stats();
+        report.round_trip = *timing.round_trip_stats();
+        assert_eq!(report.summary().to_display_str(&mut buffer), "ATST  409ms\nresp   22ms\ntrip   32ms\nmax    50ms");
+    }
+}
@@ -0,0 +1,272 @@
+use arrayvec::ArrayVec;
+use defmt::Formatter;
+use embassy_time::{Duration, Instant};
+use crate::errors::{ToRustAGaugeError, DISPLAY_TEXT_LEN};
+
+/// Exchanges kept, the oldest is overwritten
+pub const TRACE_LEN: usize = 16;
+/// Longest command kept, longer ones are cut
+const MAX_TRACED_COMMAND_LEN: usize = 16;
+/// Raw characters kept from each response, enough for a few frames
+const MAX_TRACED_RESPONSE_LEN: usize = 48;
+/// One line of the display
+const SUMMARY_LINE_LEN: usize = 11;
+
+/// One request to the ELM and what came back, exactly as it was on the wire
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEntry {
+    pub sent_at: Instant,
+    /// From the start of the write to the prompt (or the error)
+    pub latency: Duration,
+    pub command: ArrayVec<u8, MAX_TRACED_COMMAND_LEN>,
+    /// Without the prompt, cut at `MAX_TRACED_RESPONSE_LEN` characters
+    pub response: ArrayVec<u8, MAX_TRACED_RESPONSE_LEN>,
+    /// More of the response arrived than was kept
+    pub truncated: bool,
+    /// The first error anything found in the response, from the transport up to the checks on the data
+    pub outcome: Result<(), ToRustAGaugeError>,
+}
+
+impl TraceEntry {
+    fn new(command: &[u8], sent_at: Instant) -> Self {
+        Self {
+            sent_at,
+            latency: Duration::from_ticks(0),
+            command: command.iter().copied().take(MAX_TRACED_COMMAND_LEN).collect(),
+            response: ArrayVec::new(),
+            truncated: false,
+            outcome: Ok(()),
+        }
+    }
+}
+
+impl TraceEntry {
+    /// What fits on the display, `index` exchanges back from the newest
+    fn summary(&self, index: usize) -> TraceSummary {
+        TraceSummary {
+            index: index as u8,
+            latency: self.latency,
+            command: summary_line(&self.command),
+            response: summary_line(&self.response),
+            outcome: self.outcome.clone(),
+        }
+    }
+}
+
+/// The start of `raw` on one display line, line ends as spaces, padded with spaces
+fn summary_line(raw: &[u8]) -> [u8; SUMMARY_LINE_LEN] {
+    let mut line = [b' '; SUMMARY_LINE_LEN];
+    line.iter_mut().zip(raw.iter()).for_each(|(c, raw)| *c = match *raw {
+        b'\r' | b'\n' | b' ' => b' ',
+        raw if raw.is_ascii_graphic() => raw,
+        _ => b'?',
+    });
+    line
+}
+
+/// The start of one trace entry, small enough to send to main and show on the display,
+/// see `ToElmEvents::DumpTrace`
+#[derive(defmt::Format, Debug, Clone, PartialEq)]
+pub struct TraceSummary {
+    /// 0 is the newest exchange
+    pub index: u8,
+    pub latency: Duration,
+    pub command: [u8; SUMMARY_LINE_LEN],
+    pub response: [u8; SUMMARY_LINE_LEN],
+    pub outcome: Result<(), ToRustAGaugeError>,
+}
+
+impl TraceSummary {
+    /// Same 4 lines of 11 characters as the error strings: the index and latency, the command,
+    /// the start of the response, then `ok` or the first line of the error
+    pub fn to_display_str<'b>(&self, buffer: &'b mut [u8; DISPLAY_TEXT_LEN]) -> &'b str {
+        const TEMPLATE: &'static [u8] = b"#??      ms\n???????????\n???????????\nok         ";
+        const INDEX_START: usize = 1;
+        /// Right aligned before the "ms", 9999 at most
+        const LATENCY_END: usize = 9;
+        const COMMAND_START: usize = 12;
+        const RESPONSE_START: usize = 24;
+        const OUTCOME_START: usize = 36;
+        buffer.copy_from_slice(TEMPLATE);
+        buffer[INDEX_START] = b'0' + self.index / 10 % 10;
+        buffer[INDEX_START + 1] = b'0' + self.index % 10;
+        let mut latency_ms = self.latency.as_millis().min(9999);
+        for c in buffer[LATENCY_END - 4..LATENCY_END].iter_mut().rev() {
+            *c = b'0' + (latency_ms % 10) as u8;
+            latency_ms /= 10;
+            if latency_ms == 0 {
+                break
+            }
+        }
+        buffer[COMMAND_START..COMMAND_START + SUMMARY_LINE_LEN].copy_from_slice(&self.command);
+        buffer[RESPONSE_START..RESPONSE_START + SUMMARY_LINE_LEN].copy_from_slice(&self.response);
+        if let Err(error) = &self.outcome {
+            buffer[OUTCOME_START..OUTCOME_START + SUMMARY_LINE_LEN].copy_from_slice(&error.to_str().as_bytes()[..SUMMARY_LINE_LEN]);
+        }
+        core::str::from_utf8(buffer).unwrap_or("")
+    }
+}
+
+impl defmt::Format for TraceEntry {
+    fn format(&self, fmt: Formatter) {
+        defmt::write!(
+            fmt,
+            "TraceEntry(sent_at = {} ms, latency = {} ms, command = {:?}, response = {:?}, truncated = {}, outcome = {:?})",
+            self.sent_at.as_millis(),
+            self.latency.as_millis(),
+            core::str::from_utf8(&self.command).unwrap_or("<not ascii>"),
+            core::str::from_utf8(&self.response).unwrap_or("<not ascii>"),
+            self.truncated,
+            self.outcome
+        )
+    }
+}
+
+/// The last `TRACE_LEN` exchanges with the ELM, so problems that only happen now and then in the car
+/// can be looked at afterwards, without a probe attached, by stepping through them on the display with the button.
+/// See `ToElmEvents::DumpTrace`
+pub struct ElmTrace {
+    entries: ArrayVec<TraceEntry, TRACE_LEN>,
+    /// Where the next entry goes once `entries` is full, which is also the oldest one
+    next: usize,
+    /// Set while the last entry is still being filled in by `response_byte` and `finish`
+    in_progress: bool,
+}
+
+impl ElmTrace {
+    pub const fn new() -> Self {
+        Self {
+            entries: ArrayVec::new_const(),
+            next: 0,
+            in_progress: false,
+        }
+    }
+
+    /// Starts an entry for `command`, overwriting the oldest one if the trace is full
+    pub fn start(&mut self, command: &[u8], sent_at: Instant) {
+        let entry = TraceEntry::new(command, sent_at);
+        if self.entries.is_full() {
+            self.entries[self.next] = entry;
+            self.next = (self.next + 1) % TRACE_LEN;
+        } else {
+            self.entries.push(entry);
+        }
+        self.in_progress = true;
+    }
+
+    /// A byte of the response to the command from `start`
+    pub fn response_byte(&mut self, c: u8) {
+        if let Some(entry) = self.current() {
+            if entry.response.try_push(c).is_err() {
+                entry.truncated = true;
+            }
+        }
+    }
+
+    /// The exchange is over, `outcome` is what the driver made of it so far
+    pub fn finish(&mut self, latency: Duration, outcome: &Result<(), ToRustAGaugeError>) {
+        if let Some(entry) = self.current() {
+            entry.latency = latency;
+            entry.outcome = outcome.clone();
+        }
+        self.in_progress = false;
+    }
+
+    /// Something found a problem with the last response after it was read (a bad checksum, the wrong PID, ...).
+    /// Only the first error is kept
+    pub fn failed(&mut self, error: &ToRustAGaugeError) {
+        if let Some(entry) = self.last_mut() {
+            if entry.outcome.is_ok() {
+                entry.outcome = Err(error.clone());
+            }
+        }
+    }
+
+    /// Oldest first
+    pub fn entries(&self) -> impl Iterator<Item = &TraceEntry> + '_ {
+        let (newer, older) = self.entries.split_at(self.next);
+        older.iter().chain(newer.iter())
+    }
+
+    /// The exchange `index` back from the newest, `None` past the oldest
+    pub fn summary(&self, index: usize) -> Option<TraceSummary> {
+        let newest_first = self.entries.len().checked_sub(index + 1)?;
+        self.entries().nth(newest_first).map(|entry| entry.summary(index))
+    }
+
+    fn current(&mut self) -> Option<&mut TraceEntry> {
+        if self.in_progress { self.last_mut() } else { None }
+    }
+
+    fn last_mut(&mut self) -> Option<&mut TraceEntry> {
+        let last = match self.next {
+            0 => self.entries.len().checked_sub(1)?,
+            next => next - 1,
+        };
+        self.entries.get_mut(last)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exchange(trace: &mut ElmTrace, command: &[u8], response: &[u8], at_ms: u64) {
+        trace.start(command, Instant::from_millis(at_ms));
+        for c in response.iter() {
+            trace.response_byte(*c);
+        }
+        trace.finish(Duration::from_millis(20), &Ok(()));
+    }
+
+    #[test]
+    fn test_trace_wraps_around() {
+        let mut trace = ElmTrace::new();
+        for i in 0..TRACE_LEN as u64 + 3 {
+            exchange(&mut trace, b"010C\r", b"410C1AF8\r", i * 100);
+        }
+        assert_eq!(trace.entries().count(), TRACE_LEN);
+        // the oldest 3 are gone, and the rest are in order
+        let times: ArrayVec<u64, TRACE_LEN> = trace.entries().map(|entry| entry.sent_at.as_millis()).collect();
+        assert_eq!(times.first(), Some(&300));
+        assert!(times.windows(2).all(|pair| pair[0] < pair[1]));
+
+        trace.failed(&ToRustAGaugeError::UartPidMismatchError());
+        trace.failed(&ToRustAGaugeError::UartBadChecksumError());
+        let last = trace.entries().last().unwrap();
+        assert_eq!(last.outcome, Err(ToRustAGaugeError::UartPidMismatchError()));
+        assert_eq!(last.response.as_slice(), b"410C1AF8\r");
+    }
+
+    #[test]
+    fn test_long_response_is_cut() {
+        let mut trace = ElmTrace::new();
+        exchange(&mut trace, b"0902\r", &[b'0'; MAX_TRACED_RESPONSE_LEN + 1], 0);
+        let entry = trace.entries().next().unwrap();
+        assert_eq!(entry.response.len(), MAX_TRACED_RESPONSE_LEN);
+        assert!(entry.truncated);
+        assert_eq!(entry.command.as_slice(), b"0902\r");
+    }
+
+    #[test]
+    fn test_summary() {
+        let mut trace = ElmTrace::new();
+        exchange(&mut trace, b"ATZ\r", b"ELM327 v1.5\r\r", 0);
+        exchange(&mut trace, b"010C\r", b"410C1AF8\r", 100);
+        trace.failed(&ToRustAGaugeError::UartPidMismatchError());
+
+        let mut buffer = [0u8; DISPLAY_TEXT_LEN];
+        assert_eq!(
+            trace.summary(0).unwrap().to_display_str(&mut buffer),
+            "#00    20ms\n010C       \n410C1AF8   \nUART resp. "
+        );
+        let oldest = trace.summary(1).unwrap();
+        assert_eq!(oldest.index, 1);
+        assert_eq!(
+            oldest.to_display_str(&mut buffer),
+            "#01    20ms\nATZ        \nELM327 v1.5\nok         "
+        );
+        assert_eq!(trace.summary(2), None);
+    }
+}
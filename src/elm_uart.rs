@@ -1,197 +1,757 @@
-use core::marker::PhantomData;
-use embassy_rp::peripherals::UART0;
-use embassy_rp::uart;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
-use embassy_sync::channel::Sender;
-use embassy_time::{Duration, Ticker, WithTimeout};
-use embedded_hal_async::delay::DelayNs;
-use crate::{elm_commands, ElmUart, ToMainEvents, Irqs, INCOMING_EVENT_CHANNEL, data_point};
-use crate::byte_parsing::{parse_voltage, CharByte, FullyAssembledByte, HexDigit, SizedUartBuffer};
+use embassy_sync::channel::{Receiver, Sender};
+use embassy_time::{Duration, Instant, Ticker, Timer, WithTimeout};
+use embedded_io_async::{Read, Write};
+use arrayvec::ArrayVec;
+use crate::elm_commands::{AtCommand, AtReply, PidCommand, MAX_PIDS_PER_REQUEST};
+use crate::{elm_commands, mode_01_pids, ToMainEvents, data_point, ToElmEvents};
+use crate::bus_monitor::{BroadcastSignal, MAX_SIGNALS_PER_FRAME};
+use crate::dtc::{Dtc, DtcReport, FreezeFrame, PENDING_DTC_RESPONSE_SERVICE, STORED_DTC_RESPONSE_SERVICE};
+use crate::elm_adapter::{identity, AdapterProfile, Backend, Capability, Identity, CAPABILITIES};
+use crate::elm_driver::{ElmDriver, SetBaudRate, DEFAULT_BAUD_RATE};
+use crate::elm_link::{LinkState, LinkSupervisor};
+use crate::elm_timing::{AdaptiveTiming, TimingReport, DEFAULT_TIMEOUT};
 use crate::errors::{ToRustAGaugeError, ToRustAGaugeErrorSeverity, ToRustAGaugeErrorWithSeverity};
+use crate::ignition::{ClockGate, IgnitionSense, IgnitionState};
+use crate::obd_protocol::{Ecu, FrameFormat, ObdProtocol};
+use crate::poll_scheduler::{PollItem, PollPriority, PollRegistration, PollScheduler};
+use crate::supported_pids::{SupportedPids, SUPPORTED_PID_RANGE_COMMANDS};
+use crate::vehicle_info::{decode_calibration_ids, decode_vin, VehicleInfo};
 
-pub(crate) const LOCAL_RX_BUFFER_LEN: usize = 256;
-const UART_TIMEOUT: Duration = Duration::from_millis(1000u64);
+/// The ELM tries every protocol in turn after `ATSP0`, which can take several seconds on slow init protocols
+const PROTOCOL_SEARCH_TIMEOUT: Duration = Duration::from_millis(15000u64);
+/// Used when the search fails, this is what the Hijet uses
+const FALLBACK_PROTOCOL: ObdProtocol = ObdProtocol::Iso14230FastInit;
 
-const DELIMITER_U8: u8 = '>' as u8;
+/// Read from freeze frame 0 when there is a stored code, in the order of the `FreezeFrame` fields
+const FREEZE_FRAME_PIDS: [elm_commands::PidCommand; 3] = [
+    elm_commands::ENGINE_RPM_PID,
+    elm_commands::ENGINE_COOLANT_TEMP_PID,
+    mode_01_pids::CALCULATED_ENGINE_LOAD_PID,
+];
 
-#[embassy_executor::task]
-pub async fn elm_uart_task(r: ElmUart){
-    let sender: Sender<CriticalSectionRawMutex, ToMainEvents, 10> = INCOMING_EVENT_CHANNEL.sender();
+/// Tried in turn after init (`STBR`) until one works. The RP2040's UART goes well past these,
+/// the limit is the wiring to the adapter
+const STN_BAUD_RATES: [u32; 3] = [2_000_000, 1_000_000, 500_000];
+/// Same for an ELM (`ATBRD`). At 115200 sending a request and reading the answer takes a good part
+/// of each poll slot. 230400 is really 235294, the closest the ELM can do
+const ELM_BAUD_RATES: [u32; 3] = [500_000, 250_000, 230_400];
 
-    let mut uart_config = uart::Config::default();
-    uart_config.baudrate = 115200;
+/// How often the latency statistics are sent to main
+const TIMING_REPORT_INTERVAL: Duration = Duration::from_millis(10000u64);
 
-    let mut uart = embassy_rp::uart::Uart::new(r.uart0, r.tx_pin, r.rx_pin, Irqs, r.dma0, r.dma1, uart_config);
-    
-    let mut raw_rx_buf: SizedUartBuffer<CharByte> = SizedUartBuffer{
-        buffer: [0u8; LOCAL_RX_BUFFER_LEN],
-        end: 0,
-        phantom: PhantomData,
-    };
+/// Mode 04 is only sent if the last RPM the ECU reported is below this (engine off, key on)
+const MAX_RPM_FOR_DTC_CLEAR: f64 = 1.0;
+/// Or if the ECU doesn't report RPM, if the vehicle speed is below this (km/h)
+const MAX_SPEED_FOR_DTC_CLEAR: f64 = 1.0;
+/// About two RPM polls (see `POLL_SCHEDULE`). An older RPM can be from before the engine was started, so it doesn't count
+const MAX_RPM_AGE_FOR_DTC_CLEAR: Duration = Duration::from_millis(200u64);
+/// Batched requests that can fail in a row, for reasons that don't show the ECU can't take them, before PIDs are
+/// requested one at a time until the next init
+const MAX_BATCH_FAILURES: u8 = 3;
 
-    let mut hex_rx_buf: SizedUartBuffer<HexDigit> = SizedUartBuffer{
-        buffer: [0u8; LOCAL_RX_BUFFER_LEN],
-        end: 0,
-        phantom: PhantomData,
-    };
+/// Values the ECU broadcasts on the CAN bus by itself. When the protocol is CAN, these are listened for (`ATMA`)
+/// whenever nothing is due to be polled, and the PIDs they stand in for aren't polled at all.
+/// The Hijet's diagnostic bus is KWP, so they're never used on it. This is engine RPM as Toyota and Daihatsu
+/// CAN ECUs send it (ID 2C4, the first 2 bytes, in RPM); other vehicles need their own IDs here
+pub const BROADCAST_SIGNALS: [BroadcastSignal; 1] = [
+    BroadcastSignal {
+        pid: &elm_commands::ENGINE_RPM_PID,
+        can_id: 0x2c4,
+        start: 0,
+        len: 2,
+        scale: 1.0,
+        offset: 0.0,
+    },
+];
+/// How long the bus can be quiet while monitoring before it counts as a failed request
+const MONITOR_TIMEOUT: Duration = Duration::from_millis(1000u64);
+/// Monitoring is stopped at least this often, so `ToElmEvents` are still handled when nothing is polled for a while
+const MAX_MONITOR_PERIOD: Duration = Duration::from_millis(2000u64);
 
-    let mut byte_rx_buf: SizedUartBuffer<FullyAssembledByte> = SizedUartBuffer{
-        buffer: [0u8; LOCAL_RX_BUFFER_LEN],
-        end: 0,
-        phantom: PhantomData,
-    };
-    let mut short_ticker = Ticker::every(Duration::from_millis(160));
-    let mut long_ticker = Ticker::every(Duration::from_millis(500));
-
-
-    long_ticker.next().await;
-    // defmt::info!("sending {:?} ({:?})", elm_commands::ELM_RESET, elm_commands::ELM_RESET.as_bytes());
-    result_unpacker(uart_write_read(
-        &mut uart, elm_commands::ELM_RESET.as_bytes(), &mut raw_rx_buf
-    ).await, sender, ToRustAGaugeErrorSeverity::MaybeRecoverable).await;
-
-    long_ticker.next().await;
-    // defmt::info!("sending {:?} ({:?})", elm_commands::DISABLE_ECHO, elm_commands::DISABLE_ECHO.as_bytes());
-    result_unpacker(uart_write_read(
-        &mut uart, elm_commands::DISABLE_ECHO.as_bytes(), &mut raw_rx_buf
-    ).await, sender, ToRustAGaugeErrorSeverity::MaybeRecoverable).await;
-
-    long_ticker.next().await;
-    // defmt::info!("sending {:?} ({:?})", elm_commands::ENABLE_HEADERS, elm_commands::ENABLE_HEADERS.as_bytes());
-    result_unpacker(uart_write_read(
-        &mut uart, elm_commands::ENABLE_HEADERS.as_bytes(), &mut raw_rx_buf
-    ).await, sender, ToRustAGaugeErrorSeverity::MaybeRecoverable).await;
-
-    long_ticker.next().await;
-    // defmt::info!("sending {:?} ({:?})", elm_commands::SET_PROTOCOL_5, elm_commands::SET_PROTOCOL_5.as_bytes());
-    result_unpacker(uart_write_read(
-        &mut uart, elm_commands::SET_PROTOCOL_5.as_bytes(), &mut raw_rx_buf
-    ).await, sender, ToRustAGaugeErrorSeverity::MaybeRecoverable).await;
-
-    long_ticker.next().await;
-    // defmt::info!("sending {:?} ({:?})", elm_commands::SET_TIMEOUT_64, elm_commands::SET_TIMEOUT_64.as_bytes());
-    result_unpacker(uart_write_read(
-        &mut uart, elm_commands::SET_TIMEOUT_64.as_bytes(), &mut raw_rx_buf
-    ).await, sender, ToRustAGaugeErrorSeverity::EntirelyRecoverable).await;
+/// While the key is off, the ignition and the battery (and if they can't tell, whether the ECU answers) are checked this often.
+/// The adapter is asleep in between if it can be, and one whose ignition input is wired wakes up by itself before that
+const IGNITION_CHECK_INTERVAL: Duration = Duration::from_millis(30000u64);
+/// How long the ECU gets to answer while the key is off. The protocol is known by then, so there's no search
+const ECU_CHECK_TIMEOUT: Duration = Duration::from_millis(3000u64);
 
-    long_ticker.next().await;
-    // defmt::info!("sending {:?} ({:?})", elm_commands::DISABLE_SPACES, elm_commands::DISABLE_SPACES.as_bytes());
-    result_unpacker(uart_write_read(
-        &mut uart, elm_commands::DISABLE_SPACES.as_bytes(), &mut raw_rx_buf
-    ).await, sender, ToRustAGaugeErrorSeverity::MaybeRecoverable).await;
-
-    long_ticker.next().await;
-    // defmt::info!("sending {:?} ({:?})", elm_commands::DISABLE_MEMORY, elm_commands::DISABLE_MEMORY.as_bytes());
-    result_unpacker(uart_write_read(
-        &mut uart, elm_commands::DISABLE_MEMORY.as_bytes(), &mut raw_rx_buf
-    ).await, sender, ToRustAGaugeErrorSeverity::MaybeRecoverable).await;
-
-    long_ticker.next().await;
-    // defmt::info!("sending {:?} ({:?})", elm_commands::ENABLE_AUTO_TIMINGS_1, elm_commands::ENABLE_AUTO_TIMINGS_1.as_bytes());
-    result_unpacker(uart_write_read(
-        &mut uart, elm_commands::ENABLE_AUTO_TIMINGS_1.as_bytes(), &mut raw_rx_buf
-    ).await, sender, ToRustAGaugeErrorSeverity::EntirelyRecoverable).await;
+/// What the poll loop asks for, how often (ms) and what goes first when several are due. 
+/// PIDs are checked against the ECU's supported PID bitmaps after init, and the ones it doesn't support are left out.
+/// `from_ecu` picks which ECU's answer is used for a value more than one ECU reports
+const POLL_SCHEDULE: [PollRegistration; 5] = [
+    PollRegistration::new("RPM", PollItem::Pid(&elm_commands::ENGINE_RPM_PID), 100, PollPriority::High)
+        .from_ecu(Ecu::Engine),
+    PollRegistration::new("Coolant", PollItem::Pid(&elm_commands::ENGINE_COOLANT_TEMP_PID), 2500, PollPriority::Normal)
+        .from_ecu(Ecu::Engine),
+    PollRegistration::new("VBat", PollItem::Voltage, 2500, PollPriority::Normal),
+    PollRegistration::new("DTCs", PollItem::TroubleCodes, 40000, PollPriority::Low),
+    // the ECU going quiet usually says the key is off first, this is only for an input that's wired
+    PollRegistration::new("Ignition", PollItem::Ignition, 10000, PollPriority::Low),
+];
 
-    long_ticker.next().await;
-    // defmt::info!("sending {:?} ({:?})", elm_commands::SET_CUSTOM_HEADERS, elm_commands::SET_CUSTOM_HEADERS.as_bytes());
-    result_unpacker(uart_write_read(
-        &mut uart, elm_commands::SET_CUSTOM_HEADERS.as_bytes(), &mut raw_rx_buf
-    ).await, sender, ToRustAGaugeErrorSeverity::MaybeRecoverable).await;
+/// Init, then poll until the link is lost, then init again, forever. 
+/// When the key is turned off polling stops until it's back, see `sleep_until_ignition`.
+/// On CAN, `broadcast_signals` are listened for between requests, see `monitor_broadcasts`.
+/// `short_ticker` paces the poll loop, `long_ticker` the init sequence
+pub async fn run_elm<T: Read + Write + SetBaudRate, C: ClockGate>(elm: &mut ElmDriver<T>,
+                                  clocks: &mut C,
+                                  broadcast_signals: &[BroadcastSignal],
+                                  short_ticker: &mut Ticker,
+                                  long_ticker: &mut Ticker,
+                                  sender: Sender<'_, CriticalSectionRawMutex, ToMainEvents, 10>,
+                                  elm_receiver: Receiver<'_, CriticalSectionRawMutex, ToElmEvents, 4>,
+) -> ! {
+    let mut link = LinkSupervisor::new();
+    let mut ignition = IgnitionSense::new();
 
-    long_ticker.next().await;
-    result_unpacker(
-        get_pid(
-            elm_commands::HEARTBEAT_PID, 
-            &mut uart, 
-            &mut raw_rx_buf, 
-            &mut hex_rx_buf, 
-            &mut byte_rx_buf
-        ).await, 
-        sender, 
-        ToRustAGaugeErrorSeverity::MaybeRecoverable
-    ).await;
+    loop {
+        let backoff = link.backoff();
+        if backoff > Duration::from_ticks(0) {
+            defmt::info!("Waiting {} ms before initializing the ELM again", backoff.as_millis());
+            Timer::after(backoff).await;
+        }
+        report_link_state(link.start_init(), sender).await;
+        // an STN that was switched to a faster baud rate stays there until it's reset
+        if let Err(e) = elm.reset_baud_rate().await {
+            defmt::warn!("Could not put the adapter back to {} baud: {:?}", DEFAULT_BAUD_RATE, e);
+        }
+        let session = initialize_elm(elm, long_ticker, sender).await;
+        report_link_state(link.init_finished(session.is_some()), sender).await;
+        let Some(mut session) = session else {
+            continue;
+        };
+        let frame_format = session.frame_format;
+        let monitored = if frame_format.is_can() && session.adapter.supports(Capability::ReceiveFilter) {
+            broadcast_signals
+        } else {
+            &[]
+        };
 
-    sender.send(ToMainEvents::ElmInitComplete).await;
+        read_vehicle_info(elm, frame_format, long_ticker, sender).await;
 
-    let mut loop_counter: u8 = 0;
-    
+        let mut scheduler = PollScheduler::new();
+        for registration in POLL_SCHEDULE.iter() {
+            if let PollItem::Pid(pid) = registration.item {
+                if !session.supported_pids.supports(pid) {
+                    continue;
+                }
+                if monitored.iter().any(|signal| signal.replaces(pid)) {
+                    defmt::info!("{:?} is broadcast, not polling it", registration.label);
+                    continue;
+                }
+            }
+            if matches!(registration.item, PollItem::Voltage) && !session.adapter.supports(Capability::ReadVoltage) {
+                defmt::warn!("Adapter doesn't know ATRV, not polling {:?}", registration.label);
+                continue;
+            }
+            if matches!(registration.item, PollItem::Ignition) && !session.adapter.supports(Capability::IgnitionMonitor) {
+                defmt::warn!("Adapter doesn't know ATIGN, not polling {:?}", registration.label);
+                continue;
+            }
+            if matches!(registration.item, PollItem::Ignition) && ignition.pin_unwired() {
+                defmt::info!("Adapter's ignition input isn't wired, not polling {:?}", registration.label);
+                continue;
+            }
+            if !scheduler.register(*registration, Instant::now()) {
+                defmt::warn!("Poll schedule is full, not polling {:?}", registration.label);
+            }
+        }
 
-    loop {
-        short_ticker.next().await;
-        match result_unpacker(
-            get_pid(
-                elm_commands::ENGINE_RPM_PID,
-                &mut uart,
-                &mut raw_rx_buf,
-                &mut hex_rx_buf,
-                &mut byte_rx_buf
-            ).await,
-            sender,
-            ToRustAGaugeErrorSeverity::BadIfReoccurring
-        ).await {
-            Some(v) => {
-                sender.send(
-                    ToMainEvents::ElmDataPoint(
-                        data_point::DataPoint{
-                            data: data_point::Datum::RPM(v),
-                            time: embassy_time::Instant::now()
+        // the last RPM the ECU reported and when, see `recent_rpm`
+        let mut last_ecu_rpm: Option<(f64, Instant)> = None;
+        // init has just sent ATST64, if the adapter knows it
+        let mut timing = AdaptiveTiming::new();
+        if !session.adapter.supports(Capability::SetTimeout) {
+            timing.disable(DEFAULT_TIMEOUT);
+        }
+        let mut next_timing_report = Instant::now() + TIMING_REPORT_INTERVAL;
+
+        while !link.needs_init() && ignition.state() == IgnitionState::On {
+            match elm_receiver.try_receive() {
+                Ok(ToElmEvents::ClearDiagnosticCodes { confirmed }) => {
+                    let rpm = recent_rpm(last_ecu_rpm, Instant::now());
+                    // only needed when there's no RPM to go by
+                    let speed = match rpm {
+                        None if session.supported_pids.supports(&mode_01_pids::VEHICLE_SPEED_PID) => {
+                            short_ticker.next().await;
+                            elm.get_pid(&mode_01_pids::VEHICLE_SPEED_PID, frame_format).await.ok()
                         }
-                    )
-                ).await;
-            }
-            None => {}
-        }
-
-        if loop_counter & 0x0F == 0{
-            short_ticker.next().await;
-            match result_unpacker(
-                get_pid(
-                    elm_commands::ENGINE_COOLANT_TEMP_PID,
-                    &mut uart,
-                    &mut raw_rx_buf,
-                    &mut hex_rx_buf,
-                    &mut byte_rx_buf
-                ).await,
-                sender,
-                ToRustAGaugeErrorSeverity::BadIfReoccurring
-            ).await {
-                Some(v) => {
-                    sender.send(
-                        ToMainEvents::ElmDataPoint(
-                            data_point::DataPoint{
-                                data: data_point::Datum::CoolantTempC(v),
-                                time: embassy_time::Instant::now()
+                        _ => None,
+                    };
+                    match dtc_clear_check(rpm, speed, confirmed) {
+                        Ok(()) => {
+                            short_ticker.next().await;
+                            if result_unpacker(
+                                elm.clear_dtcs(frame_format).await,
+                                sender,
+                                ToRustAGaugeErrorSeverity::MaybeRecoverable
+                            ).await.is_some() {
+                                defmt::info!("Cleared diagnostic trouble codes");
                             }
-                        )
+                            read_dtcs(elm, frame_format, short_ticker, sender).await;
+                        }
+                        Err(refusal) => {
+                            defmt::warn!("Refusing to clear trouble codes, ECU RPM was {:?}, speed {:?}: {:?}", rpm, speed, refusal);
+                            sender.send(ToMainEvents::ElmError(ToRustAGaugeErrorWithSeverity{
+                                error: refusal,
+                                severity: ToRustAGaugeErrorSeverity::EntirelyRecoverable,
+                            })).await;
+                        }
+                    }
+                }
+                Ok(ToElmEvents::DumpTrace { index }) => {
+                    sender.send(ToMainEvents::ElmTraceEntry(elm.trace().summary(index))).await;
+                }
+                Err(_) => {}
+            }
+
+            let now = Instant::now();
+            if now >= next_timing_report {
+                next_timing_report = now + TIMING_REPORT_INTERVAL;
+                let report = TimingReport {
+                    timeout: timing.timeout(),
+                    response: *timing.response_stats(),
+                    round_trip: *timing.round_trip_stats(),
+                    items: scheduler.stats().map(|(registration, stats)| (registration.label, stats.latency)).collect(),
+                };
+                sender.send(ToMainEvents::ElmTiming(report)).await;
+            }
+            for starved in scheduler.newly_starved(now) {
+                defmt::warn!("{:?} has not been polled for several intervals", starved.label);
+                sender.send(ToMainEvents::ElmError(ToRustAGaugeErrorWithSeverity{
+                    error: ToRustAGaugeError::PollItemStarved(starved.label),
+                    severity: ToRustAGaugeErrorSeverity::BadIfReoccurring,
+                })).await;
+            }
+
+            let Some(index) = scheduler.next_due(now) else {
+                if !monitored.is_empty() {
+                    let until = scheduler.next_wakeup()
+                        .map_or(now + MAX_MONITOR_PERIOD, |wakeup| wakeup.min(now + MAX_MONITOR_PERIOD));
+                    monitor_broadcasts(elm, monitored, frame_format, until, &mut link, &mut ignition, &mut last_ecu_rpm, sender).await;
+                    continue;
+                }
+                match scheduler.next_wakeup() {
+                    Some(wakeup) => Timer::at(wakeup).await,
+                    // nothing the ECU supports is scheduled, only DTC clear requests are left to handle
+                    None => short_ticker.next().await,
+                }
+                continue;
+            };
+            let ecu = scheduler.registration(index).ecu;
+            if let (true, PollItem::Pid(PidCommand { standard: true, .. })) = (session.batch_pids.enabled(), scheduler.registration(index).item) {
+                let batch = scheduler.pid_batch(index, now);
+                if batch.len() > 1 {
+                    let pids: ArrayVec<&'static PidCommand, MAX_PIDS_PER_REQUEST> = batch.iter()
+                        .filter_map(|index| match scheduler.registration(*index).item {
+                            PollItem::Pid(pid) => Some(pid),
+                            _ => None,
+                        })
+                        .collect();
+                    let values = elm.get_pid_batch(&pids, frame_format, ecu).await;
+                    adapt_timeout(elm, &mut timing, &values).await;
+                    let exchange = *elm.last_exchange();
+                    for (index, pid) in batch.iter().zip(pids.iter()) {
+                        let success = values.as_ref()
+                            .is_ok_and(|(_, values)| values.iter().any(|(value_pid, _)| value_pid.pid == pid.pid));
+                        scheduler.completed(*index, now, success);
+                        scheduler.record_latency(*index, &exchange);
+                        if let Err(error) = &values {
+                            follow_answering_ecu(elm, &mut scheduler, *index, frame_format, error);
+                        }
+                    }
+                    session.batch_pids.record(values.as_ref().err());
+                    send_next_pid(elm, &scheduler, &session).await;
+                    let values = result_unpacker(
+                        values,
+                        sender,
+                        ToRustAGaugeErrorSeverity::BadIfReoccurring
                     ).await;
+                    report_link_state(link.record(values.is_some()), sender).await;
+                    report_ignition(ignition.record_ecu(values.is_some(), Instant::now()), sender).await;
+                    if let Some((source, values)) = values {
+                        for (value_pid, v) in values.iter() {
+                            let data = value_pid.to_datum(*v);
+                            if let data_point::Datum::RPM(rpm) = data {
+                                last_ecu_rpm = Some((rpm, Instant::now()));
+                            }
+                            sender.send(ToMainEvents::ElmDataPoint(data_point::DataPoint{
+                                data,
+                                time: Instant::now(),
+                                ecu: Some(source),
+                            })).await;
+                        }
+                    }
+                    continue;
                 }
-                None => {}
             }
-        } else if loop_counter & 0x0F == 0x08 {
-            short_ticker.next().await;
-            match result_unpacker(
-                get_voltage(
-                    &mut uart,
-                    &mut raw_rx_buf,
-                ).await,
-                sender,
-                ToRustAGaugeErrorSeverity::BadIfReoccurring
-            ).await {
-                Some(v) => {
-                    sender.send(
-                        ToMainEvents::ElmDataPoint(
-                            data_point::DataPoint{
+
+            let success = match scheduler.registration(index).item {
+                PollItem::Pid(pid) => {
+                    let value = elm.get_pid_from(pid, frame_format, ecu).await;
+                    adapt_timeout(elm, &mut timing, &value).await;
+                    scheduler.record_latency(index, elm.last_exchange());
+                    scheduler.completed(index, now, value.is_ok());
+                    if let Err(error) = &value {
+                        follow_answering_ecu(elm, &mut scheduler, index, frame_format, error);
+                    }
+                    send_next_pid(elm, &scheduler, &session).await;
+                    let value = result_unpacker(
+                        value,
+                        sender,
+                        ToRustAGaugeErrorSeverity::BadIfReoccurring
+                    ).await;
+                    report_link_state(link.record(value.is_some()), sender).await;
+                    report_ignition(ignition.record_ecu(value.is_some(), Instant::now()), sender).await;
+                    if let Some((source, v)) = value {
+                        let data = pid.to_datum(v);
+                        if let data_point::Datum::RPM(rpm) = data {
+                            last_ecu_rpm = Some((rpm, Instant::now()));
+                        }
+                        sender.send(ToMainEvents::ElmDataPoint(data_point::DataPoint{
+                            data,
+                            time: Instant::now(),
+                            ecu: Some(source),
+                        })).await;
+                    }
+                    // already completed, before the next request went out
+                    continue;
+                }
+                PollItem::Voltage => {
+                    let voltage = result_unpacker(
+                        elm.get_voltage().await,
+                        sender,
+                        ToRustAGaugeErrorSeverity::BadIfReoccurring
+                    ).await;
+                    // answered by the ELM, so it says nothing about the ECU's timing
+                    scheduler.record_latency(index, elm.last_exchange());
+                    match voltage {
+                        Some(v) => {
+                            report_ignition(ignition.record_voltage(v, Instant::now()), sender).await;
+                            sender.send(ToMainEvents::ElmDataPoint(data_point::DataPoint{
                                 data: data_point::Datum::VBat(v),
-                                time: embassy_time::Instant::now()
-                            }
-                        )
+                                time: Instant::now(),
+                                ecu: None,
+                            })).await;
+                        }
+                        // ATRV is answered by the ELM itself, so only a failure says anything about the link
+                        None => report_link_state(link.record(false), sender).await,
+                    }
+                    voltage.is_some()
+                }
+                PollItem::Ignition => {
+                    let on = result_unpacker(
+                        elm.get_ignition().await,
+                        sender,
+                        ToRustAGaugeErrorSeverity::BadIfReoccurring
                     ).await;
+                    scheduler.record_latency(index, elm.last_exchange());
+                    if let Some(on) = on {
+                        report_ignition(ignition.record_pin(on, Instant::now()), sender).await;
+                    }
+                    on.is_some()
                 }
-                None => {}
+                PollItem::TroubleCodes => read_dtcs(elm, frame_format, short_ticker, sender).await,
+            };
+            scheduler.completed(index, now, success);
+        }
+        if ignition.state() == IgnitionState::On {
+            // the ECU going quiet can be the key being turned off
+            check_ignition(elm, &session.adapter, &mut ignition, sender).await;
+        }
+        if ignition.state() == IgnitionState::Off {
+            sleep_until_ignition(elm, clocks, &session, &mut ignition, sender).await;
+            continue;
+        }
+        defmt::warn!("Too many failed requests, initializing the ELM again");
+    }
+}
+
+/// Reads the ignition input (unless it's known not to be wired) and the battery voltage once, if the adapter can
+async fn check_ignition<T: Read + Write>(elm: &mut ElmDriver<T>,
+                                         adapter: &AdapterProfile,
+                                         ignition: &mut IgnitionSense,
+                                         sender: Sender<'_, CriticalSectionRawMutex, ToMainEvents, 10>,
+) {
+    if adapter.supports(Capability::IgnitionMonitor) && !ignition.pin_unwired() {
+        match elm.get_ignition().await {
+            Ok(on) => report_ignition(ignition.record_pin(on, Instant::now()), sender).await,
+            Err(e) => defmt::warn!("Could not read the ignition input: {:?}", e),
+        }
+    }
+    if adapter.supports(Capability::ReadVoltage) {
+        match elm.get_voltage().await {
+            Ok(vbat) => report_ignition(ignition.record_voltage(vbat, Instant::now()), sender).await,
+            Err(e) => defmt::warn!("Could not read the battery voltage: {:?}", e),
+        }
+    }
+}
+
+/// Nothing is polled while the key is off. The adapter is put to sleep (`ATLP`) if it can be, and only woken
+/// every `IGNITION_CHECK_INTERVAL` to check again, unless it wakes up by itself first because its ignition input went high.
+/// If the input isn't wired and the battery doesn't say the key is back, the ECU is asked (`0100` on the session's
+/// protocol): an alternator that only charges when it has to can stay below `MIN_CHARGING_VBAT` with the engine running.
+/// Returns once the ignition is back on, the adapter has started over by then so it has to be initialized again.
+/// The RP2040 gates the `clocks` it doesn't need meanwhile, dormant mode would stop the timer the checks run on
+async fn sleep_until_ignition<T: Read + Write + SetBaudRate, C: ClockGate>(elm: &mut ElmDriver<T>,
+                                                             clocks: &mut C,
+                                                             session: &ElmSession,
+                                                             ignition: &mut IgnitionSense,
+                                                             sender: Sender<'_, CriticalSectionRawMutex, ToMainEvents, 10>,
+) {
+    let adapter = &session.adapter;
+    defmt::info!("Ignition is off, waiting for it to come back");
+    clocks.gate();
+    while ignition.state() == IgnitionState::Off {
+        let asleep = adapter.can_sleep() && match elm.sleep().await {
+            Ok(()) => true,
+            Err(e) => {
+                defmt::warn!("Adapter didn't go to sleep, leaving it on: {:?}", e);
+                false
+            }
+        };
+        let woke = elm.wait_for_wake(IGNITION_CHECK_INTERVAL).await;
+        if asleep && !woke {
+            if let Err(e) = elm.wake().await {
+                defmt::warn!("Adapter didn't wake up: {:?}", e);
+            }
+        }
+        check_ignition(elm, adapter, ignition, sender).await;
+        if ignition.needs_ecu_check() {
+            match elm.ecu_answers(session.protocol, ECU_CHECK_TIMEOUT).await {
+                Ok(answered) => report_ignition(ignition.record_ecu(answered, Instant::now()), sender).await,
+                Err(e) => defmt::warn!("Could not ask the ECU: {:?}", e),
             }
         }
-        loop_counter = loop_counter.overflowing_add(1).0;
-        
+    }
+    clocks.restore();
+    defmt::info!("Ignition is back on");
+}
+
+/// The last RPM the ECU reported, unless it's older than `MAX_RPM_AGE_FOR_DTC_CLEAR`
+fn recent_rpm(last_rpm: Option<(f64, Instant)>, now: Instant) -> Option<f64> {
+    last_rpm.filter(|(_, at)| now - *at <= MAX_RPM_AGE_FOR_DTC_CLEAR).map(|(rpm, _)| rpm)
+}
+
+/// Whether the trouble codes can be cleared (Mode 04), from the RPM the ECU just reported or if it doesn't
+/// report RPM, the vehicle speed. With neither, only once it's `confirmed`. The error says why not
+fn dtc_clear_check(rpm: Option<f64>, speed: Option<f64>, confirmed: bool) -> Result<(), ToRustAGaugeError> {
+    match (rpm, speed) {
+        (Some(rpm), _) if rpm < MAX_RPM_FOR_DTC_CLEAR => Ok(()),
+        (Some(_), _) => Err(ToRustAGaugeError::DtcClearRefused()),
+        (None, Some(speed)) if speed < MAX_SPEED_FOR_DTC_CLEAR => Ok(()),
+        (None, Some(_)) => Err(ToRustAGaugeError::DtcClearRefusedMoving()),
+        (None, None) if confirmed => Ok(()),
+        (None, None) => Err(ToRustAGaugeError::DtcClearUnconfirmed()),
+    }
+}
+
+/// What the poll loop needs to know from init
+struct ElmSession {
+    adapter: AdapterProfile,
+    protocol: ObdProtocol,
+    frame_format: FrameFormat,
+    supported_pids: SupportedPids,
+    /// Ask for several due PIDs in one request (CAN only). Every init starts with it on again
+    batch_pids: BatchMode,
+}
+
+/// Whether due PIDs are batched into one request. Some ECUs only answer one PID at a time, which shows as
+/// an answer in the wrong shape, so batching stops after one of those. Any other failure (no answer, a bus error)
+/// says nothing about batching, so it takes `MAX_BATCH_FAILURES` of them in a row
+struct BatchMode {
+    enabled: bool,
+    failures: u8,
+}
+
+impl BatchMode {
+    fn new(enabled: bool) -> Self {
+        Self { enabled, failures: 0 }
+    }
+
+    fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Call after each batched request, with its error if it failed
+    fn record(&mut self, error: Option<&ToRustAGaugeError>) {
+        let Some(error) = error else {
+            self.failures = 0;
+            return
+        };
+        self.failures = self.failures.saturating_add(1);
+        let wrong_shape = matches!(error,
+            ToRustAGaugeError::UartPidMismatchError() |
+            ToRustAGaugeError::UartIncorrectLengthError() |
+            ToRustAGaugeError::UartServiceMismatchError()
+        );
+        if self.enabled && (wrong_shape || self.failures >= MAX_BATCH_FAILURES) {
+            defmt::warn!("Batched PID request failed {} times, last with {:?}, requesting PIDs one at a time", self.failures, error);
+            self.enabled = false;
+        }
+    }
+}
+
+/// Runs the whole init sequence: AT setup, adapter fingerprinting, protocol detection and PID discovery. 
+/// An STN is switched to its own commands once it's recognized, and the adapter to a faster baud rate.
+/// Returns `None` if a required AT command failed (the adapter isn't answering properly)
+async fn initialize_elm<T: Read + Write + SetBaudRate>(elm: &mut ElmDriver<T>,
+                                                       ticker: &mut Ticker,
+                                                       sender: Sender<'_, CriticalSectionRawMutex, ToMainEvents, 10>,
+) -> Option<ElmSession> {
+    elm.set_backend(Backend::Elm);
+    let mut reset_banner = Identity::new();
+    for at_command in elm_commands::ELM_INIT_SEQUENCE.iter() {
+        // sent by `fingerprint_adapter` instead
+        if Capability::probed_by(&at_command.command).is_some() {
+            continue;
+        }
+        ticker.next().await;
+        result_unpacker(
+            elm.send_at_command(at_command).await,
+            sender,
+            ToRustAGaugeErrorSeverity::MaybeRecoverable
+        ).await?;
+        if matches!(at_command.expected_reply, AtReply::Banner) {
+            reset_banner = identity(elm.last_response());
+        }
+    }
+    let adapter = fingerprint_adapter(elm, &reset_banner, ticker).await;
+    sender.send(ToMainEvents::ElmAdapter(adapter.clone())).await;
+    elm.set_backend(adapter.backend());
+    raise_baud_rate(elm, &adapter, ticker).await;
+
+    let protocol = detect_protocol(elm, ticker, sender).await;
+    let frame_format = protocol.frame_format();
+    defmt::info!("Using OBD protocol {:?}, frame format {:?}", protocol, frame_format);
+
+    if let Some(header_command) = protocol.header_command() {
+        ticker.next().await;
+        result_unpacker(
+            elm.send_at_command(&AtCommand::required(header_command, AtReply::Ok)).await,
+            sender,
+            ToRustAGaugeErrorSeverity::MaybeRecoverable
+        ).await?;
+    }
+
+    let supported_pids = discover_supported_pids(
+        elm,
+        frame_format,
+        ticker,
+        sender
+    ).await;
+
+    for pid in POLL_SCHEDULE.iter().filter_map(|registration| match registration.item {
+        PollItem::Pid(pid) => pid.standard_pid(),
+        _ => None,
+    }) {
+        if !supported_pids.is_supported(pid) {
+            defmt::warn!("ECU does not support polled PID {:x}, skipping it", pid);
+            sender.send(ToMainEvents::ElmError(ToRustAGaugeErrorWithSeverity{
+                error: ToRustAGaugeError::UnsupportedPid(pid),
+                severity: ToRustAGaugeErrorSeverity::LossOfSomeFunctionality,
+            })).await;
+        }
+    }
+
+    sender.send(ToMainEvents::ElmInitComplete).await;
+
+    Some(ElmSession {
+        adapter,
+        protocol,
+        frame_format,
+        supported_pids,
+        batch_pids: BatchMode::new(frame_format.is_can()),
+    })
+}
+
+/// Asks the adapter what it is (`ATI`, and `STI` for STN chips) and tries each of the optional commands,
+/// see `AdapterProfile`. A probe that isn't answered at all counts as not supported
+async fn fingerprint_adapter<T: Read + Write>(elm: &mut ElmDriver<T>,
+                                              reset_banner: &[u8],
+                                              ticker: &mut Ticker,
+) -> AdapterProfile {
+    ticker.next().await;
+    if let Err(e) = elm.write_read(elm_commands::IDENTIFY.as_bytes()).await {
+        defmt::warn!("ATI failed: {:?}", e);
+    }
+    let mut adapter = AdapterProfile::new(reset_banner, elm.last_response());
+    for capability in CAPABILITIES {
+        ticker.next().await;
+        let supported = match elm.probe(&capability.probe()).await {
+            Ok(supported) => supported,
+            Err(e) => {
+                defmt::warn!("Probing {:?} failed: {:?}", capability, e);
+                false
+            }
+        };
+        adapter.record(capability, supported);
+    }
+    ticker.next().await;
+    match elm.write_read(elm_commands::STN_IDENTIFY.as_bytes()).await {
+        Ok(()) => adapter.record_stn(elm.last_response()),
+        Err(e) => defmt::warn!("STI failed: {:?}", e),
+    }
+    if adapter.is_likely_clone() {
+        defmt::warn!("Adapter is likely a clone: {:?}", adapter);
+    } else {
+        defmt::info!("Adapter: {:?}", adapter);
+    }
+    adapter
+}
+
+/// Tries the baud rates the adapter might do, fastest first, and stays at the first one that works.
+/// Each one that fails leaves the adapter at the last one that worked (the default if none did), which still
+/// works, only slower
+async fn raise_baud_rate<T: Read + Write + SetBaudRate>(elm: &mut ElmDriver<T>,
+                                                        adapter: &AdapterProfile,
+                                                        ticker: &mut Ticker,
+) {
+    let baud_rates: &[u32] = match adapter.backend() {
+        _ if !adapter.can_switch_baud_rate() => &[],
+        Backend::Stn => &STN_BAUD_RATES,
+        Backend::Elm => &ELM_BAUD_RATES,
+    };
+    for baud_rate in baud_rates {
+        ticker.next().await;
+        // the driver logs why it didn't work
+        if elm.switch_baud_rate(*baud_rate).await.is_ok() {
+            defmt::info!("Talking to the adapter at {} baud", elm.baud_rate());
+            return
+        }
+    }
+}
+
+/// Feeds the timing of the last request to `timing` and sends `ATST` if it says so.
+/// The ELM keeps its timeout and adaptation stops for the session if it rejects the command
+async fn adapt_timeout<T: Read + Write, V>(elm: &mut ElmDriver<T>,
+                                           timing: &mut AdaptiveTiming,
+                                           result: &Result<V, ToRustAGaugeError>,
+) {
+    let previous = timing.timeout();
+    if let Some(timeout) = timing.observe(result, elm.last_exchange()) {
+        if let Err(e) = elm.set_timeout(timeout).await {
+            defmt::warn!("Could not change the ELM timeout: {:?}", e);
+            timing.disable(previous);
+        }
+    }
+}
+
+/// Listens to the bus until `until` and sends the value of every signal in a frame that comes past to main,
+/// the same way as polled values. Only adapter commands are sent, nothing goes to the ECU.
+/// The monitor is always stopped again before returning, so the next request is answered normally
+async fn monitor_broadcasts<T: Read + Write>(elm: &mut ElmDriver<T>,
+                                             signals: &[BroadcastSignal],
+                                             frame_format: FrameFormat,
+                                             until: Instant,
+                                             link: &mut LinkSupervisor,
+                                             ignition: &mut IgnitionSense,
+                                             last_ecu_rpm: &mut Option<(f64, Instant)>,
+                                             sender: Sender<'_, CriticalSectionRawMutex, ToMainEvents, 10>,
+) {
+    let started = result_unpacker(
+        elm.start_monitor(signals, frame_format).await,
+        sender,
+        ToRustAGaugeErrorSeverity::BadIfReoccurring
+    ).await;
+    while started.is_some() && Instant::now() < until {
+        let frame = match elm.read_monitored_frame(MONITOR_TIMEOUT).with_deadline(until).await {
+            Ok(Ok(frame)) => frame,
+            // BUFFER FULL (the ELM stopped by itself), a quiet bus or the UART: a failed request like any other.
+            // Monitoring starts over the next time nothing is due
+            Ok(Err(error)) => {
+                defmt::warn!("Monitoring the bus failed: {:?}", error);
+                // only a quiet bus says anything about the key
+                if matches!(error, ToRustAGaugeError::UartTimeoutError(_)) {
+                    report_ignition(ignition.record_ecu(false, Instant::now()), sender).await;
+                }
+                result_unpacker::<()>(Err(error), sender, ToRustAGaugeErrorSeverity::BadIfReoccurring).await;
+                report_link_state(link.record(false), sender).await;
+                break
+            }
+            // nothing more before it's time to poll
+            Err(_) => break,
+        };
+        let values: ArrayVec<data_point::Datum, MAX_SIGNALS_PER_FRAME> = signals.iter()
+            .filter_map(|signal| signal.decode(frame, frame_format).map(|value| signal.pid.to_datum(value)))
+            .take(MAX_SIGNALS_PER_FRAME)
+            .collect();
+        // a frame that isn't one of the signals (the filter can let others through) says nothing either way
+        if values.is_empty() {
+            continue;
+        }
+        report_link_state(link.record(true), sender).await;
+        report_ignition(ignition.record_ecu(true, Instant::now()), sender).await;
+        for data in values {
+            if let data_point::Datum::RPM(rpm) = data {
+                *last_ecu_rpm = Some((rpm, Instant::now()));
+            }
+            sender.send(ToMainEvents::ElmDataPoint(data_point::DataPoint{
+                data,
+                time: Instant::now(),
+                ecu: None,
+            })).await;
+        }
+    }
+    if result_unpacker(
+        elm.stop_monitor().await,
+        sender,
+        ToRustAGaugeErrorSeverity::MaybeRecoverable
+    ).await.is_none() {
+        report_link_state(link.record(false), sender).await;
+    }
+}
+
+/// The item's ECU (`PollRegistration::ecu`) didn't answer but another one did: takes the item from that one
+/// from now on. The next init starts from `POLL_SCHEDULE` again, so the chosen ECU gets another chance then
+fn follow_answering_ecu<T: Read + Write>(elm: &ElmDriver<T>,
+                                         scheduler: &mut PollScheduler,
+                                         index: usize,
+                                         frame_format: FrameFormat,
+                                         error: &ToRustAGaugeError,
+) {
+    let Some(preferred) = scheduler.registration(index).ecu else {
+        return
+    };
+    if *error != ToRustAGaugeError::UartResponseNoData() {
+        return
+    }
+    if let Some(answered) = elm.last_response_source(frame_format).filter(|answered| *answered != preferred) {
+        defmt::warn!("{:?} didn't answer for {:?}, taking it from {:?}", preferred, scheduler.registration(index).label, answered);
+        scheduler.set_ecu(index, Some(answered));
+    }
+}
+
+/// If the next item is a PID that is already due and asked for on its own, sends its request now so the ELM
+/// works on it while the last response is being handed to main. See `ElmDriver::send_next`
+async fn send_next_pid<T: Read + Write>(elm: &mut ElmDriver<T>, scheduler: &PollScheduler, session: &ElmSession) {
+    let now = Instant::now();
+    let Some(index) = scheduler.next_due(now) else {
+        return
+    };
+    let PollItem::Pid(pid) = scheduler.registration(index).item else {
+        return
+    };
+    if session.batch_pids.enabled() && pid.standard && scheduler.pid_batch(index, now).len() > 1 {
+        return
+    }
+    if let Err(e) = elm.send_next(pid.ascii_command()).await {
+        // the request is sent again when it's polled, and that failure is reported
+        defmt::warn!("Could not send the next request early: {:?}", e);
+    }
+}
+
+async fn report_link_state(change: Option<LinkState>, sender: Sender<'_, CriticalSectionRawMutex, ToMainEvents, 10>) {
+    if let Some(state) = change {
+        defmt::info!("ELM link is now {:?}", state);
+        sender.send(ToMainEvents::ElmLinkState(state)).await;
+    }
+}
+
+async fn report_ignition(change: Option<IgnitionState>, sender: Sender<'_, CriticalSectionRawMutex, ToMainEvents, 10>) {
+    if let Some(state) = change {
+        defmt::info!("Ignition is now {:?}", state);
+        sender.send(ToMainEvents::ElmIgnition(state)).await;
     }
 }
 
@@ -203,15 +763,9 @@ async fn result_unpacker<'a, T>(result: Result<T, ToRustAGaugeError>,
         Ok(v) => {
             Some(v)
         }
-        Err(ToRustAGaugeError::UartResponseNoData()) => {
-            sender.send(ToMainEvents::ElmError(ToRustAGaugeErrorWithSeverity{
-                error: ToRustAGaugeError::UartResponseNoData(),
-                severity: ToRustAGaugeErrorSeverity::EntirelyRecoverable,
-            })).await;
-            None
-        }
         Err(e) => {
-            let error = ToRustAGaugeErrorWithSeverity::from_with_severity(e, error_severity);
+            let severity = e.elm_response_severity().unwrap_or(error_severity);
+            let error = ToRustAGaugeErrorWithSeverity::from_with_severity(e, severity);
             sender.send(ToMainEvents::ElmError(error)).await;
             None
         }
@@ -220,108 +774,548 @@ async fn result_unpacker<'a, T>(result: Result<T, ToRustAGaugeError>,
 
 
 
-/// reads from Uart until it finds the delimiter. Could read past the delimiter, but in this use case
-/// there are no unprompted writes to uart 
-/// 
-/// # Arguments 
-/// 
-/// * `uart`: 
-/// * `delimiter_char`: 
-/// * `buffer`:
-/// 
-/// returns: Result<()>, ToRustAGaugeError>
-/// 
-/// 
-/// # Examples 
-/// 
-/// ```
-/// 
-/// ```
-async fn uart_read_until_char<'a>(uart: &mut uart::Uart<'a, UART0, uart::Async>,
-                                  delimiter: u8,
-                                  rx_buffer: &mut SizedUartBuffer<CharByte>
-) -> Result<(), ToRustAGaugeError>{
-    rx_buffer.end = 0;
-
-    let mut temp_buffer: [u8; 1] = [0u8];
-    
-    while rx_buffer.end < LOCAL_RX_BUFFER_LEN {
-        
-        match uart.read(&mut temp_buffer).with_timeout(UART_TIMEOUT).await{
-            Ok(Ok(_)) => { // timeout OK( UartRead OK( length read ) )
-                if temp_buffer[0] == delimiter{
-                    return Ok(())
-                }
-                rx_buffer.add_element(temp_buffer[0]);
-                // `add_element` returns false for failures, 
-                // but the only failure mode is a full buffer, 
-                // which is already explicitly check for in this function. 
-                // Therefore, it can be safely ignored
-            }
-            Ok(Err(e)) => { // timeout OK( UartRead Err( UartError ) ) 
-                return Err(ToRustAGaugeError::UartError(e));
+/// Lets the ELM search for the vehicle's protocol (`ATSP0` followed by any OBD request), then reads back what 
+/// it found with `ATDPN`. If nothing was found, the ELM is set to `FALLBACK_PROTOCOL` instead
+async fn detect_protocol<T: Read + Write>(elm: &mut ElmDriver<T>,
+                                          ticker: &mut Ticker,
+                                          sender: Sender<'_, CriticalSectionRawMutex, ToMainEvents, 10>,
+) -> ObdProtocol {
+    ticker.next().await;
+    result_unpacker(
+        elm.send_at_command(&AtCommand::required(elm_commands::SET_PROTOCOL_AUTO, AtReply::Ok)).await,
+        sender,
+        ToRustAGaugeErrorSeverity::MaybeRecoverable
+    ).await;
+
+    // the response doesn't matter, only that the search ran
+    ticker.next().await;
+    result_unpacker(elm.write_read_with_timeout(
+        elm_commands::PROTOCOL_SEARCH_REQUEST.as_bytes(), PROTOCOL_SEARCH_TIMEOUT
+    ).await, sender, ToRustAGaugeErrorSeverity::EntirelyRecoverable).await;
+
+    ticker.next().await;
+    let detected = result_unpacker(elm.write_read(
+        elm_commands::DESCRIBE_PROTOCOL_NUMBER.as_bytes()
+    ).await, sender, ToRustAGaugeErrorSeverity::MaybeRecoverable).await
+        .and_then(|_| ObdProtocol::from_protocol_number_response(elm.last_response()));
+
+    match detected {
+        Some(protocol) => protocol,
+        None => {
+            defmt::warn!("ELM did not find an OBD protocol, ATDPN returned {:?}", core::str::from_utf8(elm.last_response()).unwrap_or("<not ascii>"));
+            sender.send(ToMainEvents::ElmError(ToRustAGaugeErrorWithSeverity{
+                error: ToRustAGaugeError::ProtocolDetectionFailed(),
+                severity: ToRustAGaugeErrorSeverity::MaybeRecoverable,
+            })).await;
+            ticker.next().await;
+            result_unpacker(
+                elm.send_at_command(&AtCommand::required(elm_commands::SET_PROTOCOL_5, AtReply::Ok)).await,
+                sender,
+                ToRustAGaugeErrorSeverity::MaybeRecoverable
+            ).await;
+            FALLBACK_PROTOCOL
+        }
+    }
+}
+
+/// Asks the ECU for the PID 0x00, 0x20, 0x40 ... bitmaps until one says the next range isn't supported.
+/// The bitmaps of every ECU that answers are combined.
+/// If the ECU won't answer PID 0x00 at all, every PID is assumed to be supported so polling still gets a chance.
+async fn discover_supported_pids<T: Read + Write>(elm: &mut ElmDriver<T>,
+                                                  frame_format: FrameFormat,
+                                                  ticker: &mut Ticker,
+                                                  sender: Sender<'_, CriticalSectionRawMutex, ToMainEvents, 10>,
+) -> SupportedPids {
+    let mut supported_pids = SupportedPids::new();
+    for range_command in SUPPORTED_PID_RANGE_COMMANDS.iter() {
+        let Some(range_pid) = range_command.standard_pid() else { break };
+        if !supported_pids.is_supported(range_pid) {
+            break;
+        }
+        ticker.next().await;
+        match result_unpacker(
+            elm.get_pid_data_each_ecu(range_command, frame_format, |ecu, bitmap| {
+                defmt::info!("{:?} supported PID bitmap for range {:x}: {:?}", ecu, range_pid, bitmap);
+                supported_pids.add_range(range_pid, bitmap);
+            }).await,
+            sender,
+            ToRustAGaugeErrorSeverity::MaybeRecoverable
+        ).await {
+            Some(()) => {}
+            None if range_command.pid == 0x00 => {
+                defmt::warn!("ECU did not report its supported PIDs, assuming all are supported");
+                return SupportedPids::all();
             }
-            Err(e) => {
-                return Err(ToRustAGaugeError::UartTimeoutError(e));
+            None => {
+                break;
             }
         }
     }
-    Err(ToRustAGaugeError::UartBufferOverflowError())
+    supported_pids
 }
 
-async fn uart_write_read<'a>(uart: &mut uart::Uart<'a, UART0, uart::Async>,
-                             message: &[u8], 
-                             rx_buffer: &mut SizedUartBuffer<CharByte>
-) -> Result<(), ToRustAGaugeError>{
-    uart.blocking_write(message)?;
-    uart.blocking_flush()?;
-    // defmt::info!("`uart_write_read` wrote and flushed: {:?}", message);
-    embassy_time::block_for(Duration::from_millis(20));
-    uart_read_until_char(uart, DELIMITER_U8, rx_buffer).await?;
-    // defmt::info!("`uart_write_read` read: {:?}", rx_buffer);
-    Ok(())
-}
 
-async fn get_pid<'a>(pid: elm_commands::PidCommand, 
-                     uart: &mut uart::Uart<'a, UART0, uart::Async>, 
-                     rx_buffer: &mut SizedUartBuffer<CharByte>,
-                     intermediate_buffer: &mut SizedUartBuffer<HexDigit>,
-                     byte_buffer: &mut SizedUartBuffer<FullyAssembledByte>,
-) -> Result<f64, ToRustAGaugeError> {
-    uart_write_read(uart, &pid.ascii_command, rx_buffer).await?;
-    if rx_buffer.is_no_data(){
-        return Err(ToRustAGaugeError::UartResponseNoData())
-    }
-    rx_buffer.parse_bytes(intermediate_buffer);
-    let res = byte_buffer.populate_from_hex_digit_buffer(intermediate_buffer);
-    if let Err(err) = res{
-        defmt::warn!("Failed to get PID: {:?}\nSent: {:?}\nraw result was {:?}", &err, &pid.ascii_command, core::str::from_utf8(&rx_buffer.buffer[0..rx_buffer.end]).unwrap());
-        return Err(err)
-    }
-    let result = pid.extract_val_from_parsed_resp(byte_buffer.get_slice());
-    match result{
-        Ok(v) => Ok(v),
-        Err(er) => {
-            defmt::warn!("Failed to get PID: {:?}\nSent: {:?}\nraw result was {:?}", &er, &pid.ascii_command, core::str::from_utf8(&rx_buffer.buffer[0..rx_buffer.end]).unwrap());
-            Err(er)
+/// Reads the stored (Mode 03) and pending (Mode 07) trouble codes, and the freeze frame if there is a stored code, 
+/// and sends them to main.
+/// Nothing is sent if either read fails, so a flaky read doesn't make active codes disappear. 
+/// Returns whether both lists were read
+async fn read_dtcs<T: Read + Write>(elm: &mut ElmDriver<T>,
+                                    frame_format: FrameFormat,
+                                    ticker: &mut Ticker,
+                                    sender: Sender<'_, CriticalSectionRawMutex, ToMainEvents, 10>,
+) -> bool {
+    ticker.next().await;
+    let stored = result_unpacker(
+        elm.get_dtcs(&elm_commands::REQUEST_STORED_DTCS, STORED_DTC_RESPONSE_SERVICE, frame_format).await,
+        sender,
+        ToRustAGaugeErrorSeverity::BadIfReoccurring
+    ).await;
+    ticker.next().await;
+    let pending = result_unpacker(
+        elm.get_dtcs(&elm_commands::REQUEST_PENDING_DTCS, PENDING_DTC_RESPONSE_SERVICE, frame_format).await,
+        sender,
+        ToRustAGaugeErrorSeverity::BadIfReoccurring
+    ).await;
+    if let (Some(stored), Some(pending)) = (stored, pending) {
+        let mut freeze_frame = None;
+        if !stored.is_empty() {
+            ticker.next().await;
+            freeze_frame = result_unpacker(
+                get_freeze_frame(elm, frame_format, ticker).await,
+                sender,
+                ToRustAGaugeErrorSeverity::EntirelyRecoverable
+            ).await.flatten().filter(|frame| stored.iter().any(|code| code.dtc == frame.dtc));
         }
+        sender.send(ToMainEvents::ElmDiagnosticCodes(DtcReport{ stored, pending, freeze_frame })).await;
+        return true
+    }
+    false
+}
+
+/// Reads freeze frame 0. Returns `None` if no freeze frame is stored (the DTC in it is `P0000`).
+/// Values that fail to read are left as `None` instead of failing the whole frame
+async fn get_freeze_frame<T: Read + Write>(elm: &mut ElmDriver<T>,
+                                           frame_format: FrameFormat,
+                                           ticker: &mut Ticker,
+) -> Result<Option<FreezeFrame>, ToRustAGaugeError> {
+    let dtc_bytes = elm.get_freeze_frame_data(&mode_01_pids::FREEZE_DTC_PID, 0, frame_format).await?;
+    let dtc = Dtc::from_bytes(dtc_bytes[0], dtc_bytes[1]);
+    if dtc.0 == 0 {
+        return Ok(None)
+    }
+    let mut values: [Option<f64>; FREEZE_FRAME_PIDS.len()] = [None; FREEZE_FRAME_PIDS.len()];
+    for (pid, value) in FREEZE_FRAME_PIDS.iter().zip(values.iter_mut()) {
+        ticker.next().await;
+        *value = elm.get_freeze_frame_data(pid, 0, frame_format).await
+            .map(|data| pid.get_value(data))
+            .ok();
     }
+    Ok(Some(FreezeFrame{
+        dtc,
+        rpm: values[0],
+        coolant_temp_c: values[1],
+        engine_load_percent: values[2],
+    }))
 }
 
+/// Reads the VIN and calibration IDs (Mode 09) and sends them to main. Older vehicles don't support Mode 09, 
+/// so "NO DATA" is only logged. Nothing is sent if neither could be read
+async fn read_vehicle_info<T: Read + Write>(elm: &mut ElmDriver<T>,
+                                            frame_format: FrameFormat,
+                                            ticker: &mut Ticker,
+                                            sender: Sender<'_, CriticalSectionRawMutex, ToMainEvents, 10>,
+) {
+    let mut vehicle_info = VehicleInfo::new();
 
-async fn get_voltage<'a>(uart: &mut uart::Uart<'a, UART0, uart::Async>,
-                         rx_buffer: &mut SizedUartBuffer<CharByte>
-) -> Result<f64, ToRustAGaugeError>{
-    uart_write_read(uart, elm_commands::ELM_REQUEST_VBAT.as_bytes(), rx_buffer).await?;
-    parse_voltage(rx_buffer)
+    ticker.next().await;
+    match elm.request_frames(elm_commands::REQUEST_VIN.as_bytes(), frame_format).await
+        .and_then(|_| decode_vin(elm.frames(), frame_format)) {
+        Ok(vin) => vehicle_info.vin = Some(vin),
+        Err(ToRustAGaugeError::UartResponseNoData()) => defmt::info!("ECU did not report a VIN"),
+        Err(e) => {
+            result_unpacker::<()>(Err(e), sender, ToRustAGaugeErrorSeverity::EntirelyRecoverable).await;
+        }
+    }
+
+    ticker.next().await;
+    match elm.request_frames(elm_commands::REQUEST_CALIBRATION_IDS.as_bytes(), frame_format).await
+        .and_then(|_| decode_calibration_ids(elm.frames(), frame_format)) {
+        Ok(calibration_ids) => vehicle_info.calibration_ids = calibration_ids,
+        Err(ToRustAGaugeError::UartResponseNoData()) => defmt::info!("ECU did not report any calibration IDs"),
+        Err(e) => {
+            result_unpacker::<()>(Err(e), sender, ToRustAGaugeErrorSeverity::EntirelyRecoverable).await;
+        }
+    }
+
+    if vehicle_info.vin.is_some() || !vehicle_info.calibration_ids.is_empty() {
+        sender.send(ToMainEvents::ElmVehicleInfo(vehicle_info)).await;
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+    use embassy_futures::block_on;
+    use embassy_futures::select::{select, Either};
+    use embassy_sync::channel::Channel;
+    use crate::data_point::{DataPoint, Datum};
+    use crate::elm_emulator::{ElmEmulator, Fault, TEST_UART_TIMEOUT};
+    use super::*;
 
+    /// Runs `run_elm` against `emulator` until `until` returns true for an event, and returns every event
+    /// up to that one. The tickers tick every millisecond so this doesn't take as long as on the car
+    fn run_until(emulator: ElmEmulator, until: impl Fn(&ToMainEvents) -> bool) -> ArrayVec<ToMainEvents, 64> {
+        run_monitoring_until(emulator, &[], until)
+    }
 
+    /// Whether the clocks are gated, and how many times they were
+    #[derive(Default)]
+    struct TestClocks {
+        gated: bool,
+        times_gated: usize,
+    }
 
+    impl ClockGate for TestClocks {
+        fn gate(&mut self) {
+            assert!(!self.gated, "clocks gated twice");
+            self.gated = true;
+            self.times_gated += 1;
+        }
 
+        fn restore(&mut self) {
+            assert!(self.gated, "clocks restored without being gated");
+            self.gated = false;
+        }
+    }
 
+    /// Same as `run_until`, listening for `signals` between requests
+    fn run_monitoring_until(emulator: ElmEmulator,
+                            signals: &[BroadcastSignal],
+                            until: impl Fn(&ToMainEvents) -> bool,
+    ) -> ArrayVec<ToMainEvents, 64> {
+        let main_channel: Channel<CriticalSectionRawMutex, ToMainEvents, 10> = Channel::new();
+        let elm_channel: Channel<CriticalSectionRawMutex, ToElmEvents, 4> = Channel::new();
+        let mut elm = ElmDriver::new(emulator);
+        elm.set_uart_timeout(TEST_UART_TIMEOUT);
+        let mut short_ticker = Ticker::every(Duration::from_millis(1));
+        let mut long_ticker = Ticker::every(Duration::from_millis(1));
 
+        let collect = async {
+            let mut events = ArrayVec::new();
+            loop {
+                let event = main_channel.receive().await;
+                let done = until(&event);
+                if events.try_push(event).is_err() || done {
+                    return events
+                }
+            }
+        };
+        let mut clocks = TestClocks::default();
+        let run = run_elm(&mut elm, &mut clocks, signals, &mut short_ticker, &mut long_ticker, main_channel.sender(), elm_channel.receiver());
+        let events = match block_on(select(run, collect)) {
+            Either::First(never) => never,
+            Either::Second(events) => events,
+        };
+        // gated for every key-off, and back on with the ignition
+        let key_offs = events.iter().filter(|event| matches!(event, ToMainEvents::ElmIgnition(IgnitionState::Off))).count();
+        assert_eq!(clocks.times_gated, key_offs);
+        assert_eq!(clocks.gated, events.iter().rev().find_map(|event| match event {
+            ToMainEvents::ElmIgnition(state) => Some(*state == IgnitionState::Off),
+            _ => None,
+        }).unwrap_or(false));
+        events
+    }
+
+    fn errors(events: &[ToMainEvents]) -> impl Iterator<Item = &ToRustAGaugeError> {
+        events.iter().filter_map(|event| match event {
+            ToMainEvents::ElmError(error) => Some(&error.error),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn test_init_and_poll() {
+        for protocol in [ObdProtocol::Iso14230FastInit, ObdProtocol::Can11Bit500k, ObdProtocol::Can29Bit500k] {
+            let emulator = ElmEmulator::new(protocol)
+                .with_pid(0x0c, &[0x1a, 0xf8])
+                .with_pid(0x05, &[0x5a])
+                .rejecting_at_command("AT1");
+            // trouble codes have the lowest priority, so they are read last in the first round
+            let events = run_until(emulator, |event| matches!(event, ToMainEvents::ElmDiagnosticCodes(_)));
 
+            assert_eq!(errors(&events).count(), 0, "{:?}", protocol);
+            let states: ArrayVec<LinkState, 4> = events.iter().filter_map(|event| match event {
+                ToMainEvents::ElmLinkState(state) => Some(*state),
+                _ => None,
+            }).collect();
+            assert_eq!(states.as_slice(), &[LinkState::Initialising, LinkState::Connected]);
+            assert!(events.iter().any(|event| matches!(event, ToMainEvents::ElmInitComplete)));
+            assert!(events.iter().any(|event| matches!(
+                event,
+                ToMainEvents::ElmDataPoint(point) if matches!(point.data, Datum::RPM(rpm) if rpm == 1726.0)
+            )));
+            assert!(events.iter().any(|event| matches!(
+                event,
+                ToMainEvents::ElmDataPoint(point) if matches!(point.data, Datum::CoolantTempC(temp) if temp == 50.0)
+            )));
+            assert!(events.iter().any(|event| matches!(event, ToMainEvents::ElmDataPoint(point) if matches!(point.data, Datum::VBat(_)))));
+            // no codes stored
+            assert!(matches!(
+                events.last(),
+                Some(ToMainEvents::ElmDiagnosticCodes(report)) if report.stored.is_empty() && report.pending.is_empty()
+            ));
+        }
+    }
 
+    #[test]
+    fn test_values_from_chosen_ecu() {
+        // the transmission answers RPM requests first, the schedule takes RPM from the engine
+        let emulator = ElmEmulator::new(ObdProtocol::Can11Bit500k)
+            .with_pid(0x0c, &[0x1a, 0xf8])
+            .with_pid(0x05, &[0x5a])
+            .with_transmission_pid(0x0c, &[0x1a, 0xf0]);
+        let events = run_until(emulator, |event| matches!(event, ToMainEvents::ElmDiagnosticCodes(_)));
+
+        assert_eq!(errors(&events).count(), 0);
+        let rpm_points = || events.iter().filter_map(|event| match event {
+            ToMainEvents::ElmDataPoint(DataPoint { data: Datum::RPM(rpm), ecu, .. }) => Some((*rpm, *ecu)),
+            _ => None,
+        });
+        assert!(rpm_points().count() > 0);
+        for point in rpm_points() {
+            assert_eq!(point, (1726.0, Some(Ecu::Engine)));
+        }
+    }
+
+    #[test]
+    fn test_values_from_answering_ecu() {
+        // the schedule takes RPM from the engine, but only the transmission knows it
+        let emulator = ElmEmulator::new(ObdProtocol::Can11Bit500k)
+            .with_pid(0x05, &[0x5a])
+            .with_transmission_pid(0x0c, &[0x1a, 0xf0]);
+        let events = run_until(emulator, |event| matches!(
+            event,
+            ToMainEvents::ElmDataPoint(DataPoint { data: Datum::RPM(_), .. })
+        ));
+
+        assert!(matches!(
+            events.last(),
+            Some(ToMainEvents::ElmDataPoint(DataPoint { data: Datum::RPM(rpm), ecu: Some(Ecu::Transmission), .. })) if *rpm == 1724.0
+        ));
+    }
+
+    #[test]
+    fn test_broadcast_rpm() {
+        let emulator = ElmEmulator::new(ObdProtocol::Can11Bit500k)
+            .with_pid(0x0c, &[0x1a, 0xf8])
+            .with_pid(0x05, &[0x5a])
+            .with_broadcast(0x2c4, &[0x07, 0xd0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00])
+            .with_broadcast(0x3b7, &[0x00, 0x00, 0x00, 0x5a]);
+        // the first round of polling is done before anything is listened for
+        let events = run_monitoring_until(emulator, &BROADCAST_SIGNALS, |event| matches!(
+            event,
+            ToMainEvents::ElmDataPoint(DataPoint { data: Datum::RPM(_), .. })
+        ));
+
+        assert_eq!(errors(&events).count(), 0);
+        assert!(events.iter().any(|event| matches!(event, ToMainEvents::ElmDiagnosticCodes(_))));
+        // RPM isn't polled, so the only value is the broadcast one
+        assert!(matches!(
+            events.last(),
+            Some(ToMainEvents::ElmDataPoint(DataPoint { data: Datum::RPM(rpm), ecu: None, .. })) if *rpm == 2000.0
+        ));
+    }
 
+    #[test]
+    fn test_broadcast_buffer_full() {
+        let emulator = ElmEmulator::new(ObdProtocol::Can11Bit500k)
+            .with_pid(0x0c, &[0x1a, 0xf8])
+            .with_pid(0x05, &[0x5a])
+            .with_broadcast(0x2c4, &[0x07, 0xd0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00])
+            .with_buffer_full(0);
+        let events = run_monitoring_until(emulator, &BROADCAST_SIGNALS, |event| matches!(
+            event,
+            ToMainEvents::ElmDataPoint(DataPoint { data: Datum::RPM(_), .. })
+        ));
+
+        // reported, and the bus is listened to again afterwards
+        assert!(errors(&events).eq([&ToRustAGaugeError::ElmBufferFull()]));
+        assert!(matches!(
+            events.last(),
+            Some(ToMainEvents::ElmDataPoint(DataPoint { data: Datum::RPM(rpm), .. })) if *rpm == 2000.0
+        ));
+    }
+
+    #[test]
+    fn test_adapter_without_voltage() {
+        let emulator = ElmEmulator::new(ObdProtocol::Can11Bit500k)
+            .with_pid(0x0c, &[0x1a, 0xf8])
+            .with_pid(0x05, &[0x5a])
+            .rejecting_at_command("RV");
+        let events = run_until(emulator, |event| matches!(event, ToMainEvents::ElmDiagnosticCodes(_)));
+
+        // a missing optional command isn't an error, it's just left out
+        assert_eq!(errors(&events).count(), 0);
+        assert!(!events.iter().any(|event| matches!(event, ToMainEvents::ElmDataPoint(point) if matches!(point.data, Datum::VBat(_)))));
+        let adapter = events.iter().find_map(|event| match event {
+            ToMainEvents::ElmAdapter(profile) => Some(profile),
+            _ => None,
+        }).expect("no adapter profile");
+        assert_eq!(adapter.identity.as_slice(), crate::elm_emulator::BANNER.as_bytes());
+        assert!(!adapter.supports(Capability::ReadVoltage));
+        assert!(adapter.supports(Capability::SetTimeout));
+        assert!(adapter.supports(Capability::ReceiveFilter));
+        assert!(adapter.is_likely_clone());
+    }
+
+    #[test]
+    fn test_stn_adapter() {
+        let emulator = ElmEmulator::new(ObdProtocol::Can11Bit500k)
+            .as_stn()
+            .with_pid(0x0c, &[0x1a, 0xf8])
+            .with_pid(0x05, &[0x5a]);
+        let events = run_until(emulator, |event| matches!(event, ToMainEvents::ElmDiagnosticCodes(_)));
+
+        assert_eq!(errors(&events).count(), 0);
+        assert!(events.iter().any(|event| matches!(
+            event,
+            ToMainEvents::ElmAdapter(profile) if profile.backend() == Backend::Stn && !profile.is_likely_clone()
+        )));
+        // polled with STPX at the faster rate
+        assert!(events.iter().any(|event| matches!(
+            event,
+            ToMainEvents::ElmDataPoint(point) if matches!(point.data, Datum::RPM(rpm) if rpm == 1726.0)
+        )));
+    }
+
+    #[test]
+    fn test_baud_rate_fallback() {
+        // 500k doesn't get through, 250k does. A failed switch isn't an error, only slower
+        let emulator = ElmEmulator::new(ObdProtocol::Iso14230FastInit)
+            .with_pid(0x0c, &[0x1a, 0xf8])
+            .with_pid(0x05, &[0x5a])
+            .with_max_baud_rate(250_000);
+        let events = run_until(emulator, |event| matches!(event, ToMainEvents::ElmDiagnosticCodes(_)));
+
+        assert_eq!(errors(&events).count(), 0);
+        assert!(events.iter().any(|event| matches!(event, ToMainEvents::ElmInitComplete)));
+        assert!(events.iter().any(|event| matches!(
+            event,
+            ToMainEvents::ElmDataPoint(point) if matches!(point.data, Datum::RPM(rpm) if rpm == 1726.0)
+        )));
+    }
+
+    #[test]
+    fn test_unsupported_pid_reported() {
+        let emulator = ElmEmulator::new(ObdProtocol::Iso14230FastInit)
+            .with_pid(0x0c, &[0x1a, 0xf8]);
+        let events = run_until(emulator, |event| matches!(event, ToMainEvents::ElmInitComplete));
+        let errors: ArrayVec<&ToRustAGaugeError, 4> = errors(&events).collect();
+        assert_eq!(errors.as_slice(), &[&ToRustAGaugeError::UnsupportedPid(0x05)]);
+    }
+
+    #[test]
+    fn test_silent_ecu_reinitializes() {
+        let mut emulator = ElmEmulator::new(ObdProtocol::Iso14230FastInit)
+            .with_pid(0x0c, &[0x1a, 0xf8]);
+        // the protocol search finds nothing (`UNABLE TO CONNECT`), and every OBD request after it goes unanswered
+        emulator.inject(Fault::NoData);
+        emulator.set_persistent_fault(Some(Fault::Silent));
+        let events = run_until(emulator, |event| matches!(event, ToMainEvents::ElmLinkState(LinkState::Disconnected)));
+
+        assert!(errors(&events).any(|error| *error == ToRustAGaugeError::ProtocolDetectionFailed()));
+        let states: ArrayVec<LinkState, 4> = events.iter().filter_map(|event| match event {
+            ToMainEvents::ElmLinkState(state) => Some(*state),
+            _ => None,
+        }).collect();
+        // init itself works (the ELM answers), the ECU is what's missing
+        assert_eq!(
+            states.as_slice(),
+            &[LinkState::Initialising, LinkState::Connected, LinkState::Degraded, LinkState::Disconnected]
+        );
+    }
+
+    #[test]
+    fn test_init_fails_without_elm() {
+        let emulator = ElmEmulator::new(ObdProtocol::Iso14230FastInit)
+            .rejecting_at_command("Z");
+        let events = run_until(emulator, |event| matches!(event, ToMainEvents::ElmLinkState(LinkState::Disconnected)));
+        let errors: ArrayVec<&ToRustAGaugeError, 4> = errors(&events).collect();
+        assert_eq!(errors.as_slice(), &[&ToRustAGaugeError::AtCommandRejected("ATZ")]);
+        assert!(!events.iter().any(|event| matches!(event, ToMainEvents::ElmInitComplete)));
+    }
+
+    #[test]
+    fn test_dtc_clear_check() {
+        assert_eq!(dtc_clear_check(Some(0.0), None, false), Ok(()));
+        assert_eq!(dtc_clear_check(Some(850.0), Some(0.0), true), Err(ToRustAGaugeError::DtcClearRefused()));
+        // no RPM, the speed decides
+        assert_eq!(dtc_clear_check(None, Some(0.0), false), Ok(()));
+        assert_eq!(dtc_clear_check(None, Some(40.0), true), Err(ToRustAGaugeError::DtcClearRefusedMoving()));
+        // neither, it has to be confirmed
+        assert_eq!(dtc_clear_check(None, None, false), Err(ToRustAGaugeError::DtcClearUnconfirmed()));
+        assert_eq!(dtc_clear_check(None, None, true), Ok(()));
+    }
+
+    #[test]
+    fn test_stale_rpm_for_dtc_clear() {
+        let now = Instant::from_millis(60_000);
+        let engine_off = Some((0.0, now - Duration::from_millis(150)));
+        assert_eq!(dtc_clear_check(recent_rpm(engine_off, now), None, false), Ok(()));
+        // the engine may have been started since, so it falls through to the speed, or to confirming
+        let engine_off = Some((0.0, now - Duration::from_millis(5_000)));
+        assert_eq!(recent_rpm(engine_off, now), None);
+        assert_eq!(dtc_clear_check(recent_rpm(engine_off, now), Some(40.0), false), Err(ToRustAGaugeError::DtcClearRefusedMoving()));
+        assert_eq!(dtc_clear_check(recent_rpm(engine_off, now), None, false), Err(ToRustAGaugeError::DtcClearUnconfirmed()));
+    }
+
+    #[test]
+    fn test_batch_fallback() {
+        // an answer in the wrong shape stops batching straight away
+        let mut batch = BatchMode::new(true);
+        batch.record(Some(&ToRustAGaugeError::UartPidMismatchError()));
+        assert!(!batch.enabled());
+        // anything else has to happen MAX_BATCH_FAILURES times in a row
+        let mut batch = BatchMode::new(true);
+        for _ in 0..10 {
+            batch.record(Some(&ToRustAGaugeError::UartResponseNoData()));
+            batch.record(None);
+        }
+        assert!(batch.enabled());
+        for _ in 0..MAX_BATCH_FAILURES {
+            assert!(batch.enabled());
+            batch.record(Some(&ToRustAGaugeError::ElmCanError()));
+        }
+        assert!(!batch.enabled());
+        assert!(!BatchMode::new(false).enabled());
+    }
+
+    #[test]
+    fn test_key_off_sleeps() {
+        let emulator = ElmEmulator::new(ObdProtocol::Iso14230FastInit)
+            .with_pid(0x0c, &[0x1a, 0xf8])
+            .with_pid(0x05, &[0x5a])
+            .with_key_off(8, Duration::from_millis(2000));
+        let back_on = Cell::new(false);
+        let events = run_until(emulator, |event| {
+            back_on.set(back_on.get() || matches!(event, ToMainEvents::ElmIgnition(IgnitionState::On)));
+            back_on.get() && matches!(event, ToMainEvents::ElmDataPoint(DataPoint { data: Datum::RPM(_), .. }))
+        });
+
+        let adapter = events.iter().find_map(|event| match event {
+            ToMainEvents::ElmAdapter(profile) => Some(profile),
+            _ => None,
+        }).expect("no adapter profile");
+        assert!(adapter.supports(Capability::IgnitionMonitor));
+        let ignition: ArrayVec<IgnitionState, 4> = events.iter().filter_map(|event| match event {
+            ToMainEvents::ElmIgnition(state) => Some(*state),
+            _ => None,
+        }).collect();
+        // slept until it came back (the adapter woke up by itself), long after the requests started failing
+        assert_eq!(ignition.as_slice(), &[IgnitionState::Off, IgnitionState::On]);
+        assert!(matches!(
+            events.last(),
+            Some(ToMainEvents::ElmDataPoint(DataPoint { data: Datum::RPM(rpm), .. })) if *rpm == 1726.0
+        ));
+    }
+}
@@ -0,0 +1,124 @@
+use embassy_rp::{pac, peripherals::UART0, uart, uart::BufferedUart};
+use embassy_rp::pac::clocks::regs::{SleepEn0, SleepEn1};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Sender;
+use embassy_time::{Duration, Ticker};
+use embedded_io_async::{ErrorKind, ErrorType, Read, Write};
+use static_cell::StaticCell;
+use to_rust_a_gauge::elm_driver::{ElmDriver, SetBaudRate, DEFAULT_BAUD_RATE};
+use to_rust_a_gauge::elm_uart::{run_elm, BROADCAST_SIGNALS};
+use to_rust_a_gauge::ignition::ClockGate;
+use to_rust_a_gauge::ToMainEvents;
+use crate::{ElmUart, Irqs, INCOMING_EVENT_CHANNEL, ELM_EVENT_CHANNEL};
+
+/// Big enough for the longest response (a VIN over KWP), so nothing is lost while the poll loop is busy elsewhere
+const UART_RX_BUFFER_LEN: usize = 256;
+/// Commands are short (an `STPX` request is the longest), this only has to hold a few of them
+const UART_TX_BUFFER_LEN: usize = 64;
+
+#[embassy_executor::task]
+pub async fn elm_uart_task(r: ElmUart){
+    let sender: Sender<CriticalSectionRawMutex, ToMainEvents, 10> = INCOMING_EVENT_CHANNEL.sender();
+
+    let mut uart_config = uart::Config::default();
+    uart_config.baudrate = DEFAULT_BAUD_RATE;
+
+    static TX_BUFFER: StaticCell<[u8; UART_TX_BUFFER_LEN]> = StaticCell::new();
+    static RX_BUFFER: StaticCell<[u8; UART_RX_BUFFER_LEN]> = StaticCell::new();
+    let uart = BufferedUart::new(
+        r.uart0,
+        Irqs,
+        r.tx_pin,
+        r.rx_pin,
+        TX_BUFFER.init([0u8; UART_TX_BUFFER_LEN]),
+        RX_BUFFER.init([0u8; UART_RX_BUFFER_LEN]),
+        uart_config
+    );
+    let mut elm = ElmDriver::new(ElmTransport(uart));
+    let mut clocks = KeyOffClocks { saved: None };
+
+    let mut short_ticker = Ticker::every(Duration::from_millis(160));
+    let mut long_ticker = Ticker::every(Duration::from_millis(500));
+
+    run_elm(&mut elm, &mut clocks, &BROADCAST_SIGNALS, &mut short_ticker, &mut long_ticker, sender, ELM_EVENT_CHANNEL.receiver()).await
+}
+
+/// Stops the PIO (LEDs, servo), SPI (display), PWM (backlight, RPM counter), ADC and USB clocks whenever the cores
+/// wait for an interrupt. `clk_ref`, the timer and the UART to the adapter keep running
+struct KeyOffClocks {
+    /// `SLEEP_EN0` and `SLEEP_EN1` from before `gate`
+    saved: Option<(SleepEn0, SleepEn1)>,
+}
+
+impl ClockGate for KeyOffClocks {
+    fn gate(&mut self) {
+        self.saved.get_or_insert((pac::CLOCKS.sleep_en0().read(), pac::CLOCKS.sleep_en1().read()));
+        pac::CLOCKS.sleep_en0().modify(|w| {
+            w.set_clk_sys_pio0(false);
+            w.set_clk_sys_pio1(false);
+            w.set_clk_peri_spi0(false);
+            w.set_clk_sys_spi0(false);
+            w.set_clk_peri_spi1(false);
+            w.set_clk_sys_spi1(false);
+            w.set_clk_sys_pwm(false);
+            w.set_clk_adc_adc(false);
+            w.set_clk_sys_adc(false);
+        });
+        pac::CLOCKS.sleep_en1().modify(|w| {
+            w.set_clk_sys_usbctrl(false);
+            w.set_clk_usb_usbctrl(false);
+        });
+    }
+
+    fn restore(&mut self) {
+        if let Some((sleep_en0, sleep_en1)) = self.saved.take() {
+            pac::CLOCKS.sleep_en0().write_value(sleep_en0);
+            pac::CLOCKS.sleep_en1().write_value(sleep_en1);
+        }
+    }
+}
+
+/// The UART is interrupt driven and buffered both ways, so writes, flushes and reads are all awaited and
+/// a response that arrives while the poll loop is busy (after `send_next`) waits in the buffer.
+/// This only turns its errors into `ErrorKind`s that say more than `Other`, and lets `switch_baud_rate` change its speed
+struct ElmTransport(BufferedUart<'static, UART0>);
+
+/// An overrun (received bytes lost before they were read) has no `ErrorKind` of its own, it's `Other` like a break
+fn uart_error_kind(error: uart::Error) -> ErrorKind {
+    match error {
+        uart::Error::Parity | uart::Error::Framing => ErrorKind::InvalidData,
+        _ => ErrorKind::Other,
+    }
+}
+
+impl ErrorType for ElmTransport {
+    type Error = ErrorKind;
+}
+
+impl Read for ElmTransport {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.0.read(buf).await
+            .inspect_err(|e| defmt::warn!("UART read error: {:?}", e))
+            .map_err(uart_error_kind)
+    }
+}
+
+impl Write for ElmTransport {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.0.write(buf).await
+            .inspect_err(|e| defmt::warn!("UART write error: {:?}", e))
+            .map_err(uart_error_kind)
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.0.flush().await
+            .inspect_err(|e| defmt::warn!("UART flush error: {:?}", e))
+            .map_err(uart_error_kind)
+    }
+}
+
+impl SetBaudRate for ElmTransport {
+    fn set_baud_rate(&mut self, baud_rate: u32) {
+        self.0.set_baudrate(baud_rate);
+    }
+}
@@ -96,7 +96,9 @@ impl ErrorFifo{
             }
         });
         if !exists_already {
-            self.0.push(ErrorWithLifetime::new(new_error));
+            if let Err(e) = self.0.try_push(ErrorWithLifetime::new(new_error)) {
+                defmt::warn!("Error FIFO is full, dropping {:?}", e.element().error_with_severity);
+            }
         }
     }
 }
\ No newline at end of file
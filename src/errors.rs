@@ -1,6 +1,8 @@
 use core::cmp::Ordering;
 use core::fmt::{Debug, Formatter};
 use thiserror_no_std::Error;
+use crate::dtc::Dtc;
+use crate::obd_protocol::Ecu;
 
 
 // TODO: WTF is this file. Valve pls fix
@@ -10,12 +12,12 @@ use thiserror_no_std::Error;
 pub enum ToRustAGaugeError {
     #[error("Nondescript error")]
     NondescriptError(),
-    #[error("Embassy uart error")]
-    UartError(#[from] embassy_rp::uart::Error),
+    #[error("Error from the transport the ELM is connected over")]
+    UartError(embedded_io_async::ErrorKind),
     #[error("Embassy uart timeout error")]
     UartTimeoutError(#[from] embassy_time::TimeoutError),
-    #[error("Embassy buffer overflow error. Attempted to read until a delimiter, \
-    but read LOCAL_RX_BUFFER_LEN (currently 256, but subject to change) bytes with no delimiter.")]
+    #[error("Buffer overflow error. The response from the ELM didn't fit in the parser's buffers \
+    (see `ResponseParser`).")]
     UartBufferOverflowError(),
     #[error("Failed to parse bytes from UART.")]
     UartByteParseError(),
@@ -27,6 +29,8 @@ pub enum ToRustAGaugeError {
     UartPidMismatchError(),
     #[error("Failed to parse voltage from ELM")]
     UartVoltageParseError(),
+    #[error("Failed to parse the ignition input state from ELM")]
+    UartIgnitionParseError(),
     #[error("Error communicating with LCD")]
     MipiDsiError(),
     #[error("RPM data does NOT pass the vibe check. This data is guaranteed to be impossible.")]
@@ -45,6 +49,50 @@ pub enum ToRustAGaugeError {
     UartResponseNoData(),
     #[error("ELM returned an RPM value that differs from the measured value by a significant amount")]
     RpmSourceDiscrepancy(),
+    #[error("A PID that is configured to be polled is not in the ECU's supported PID bitmap")]
+    UnsupportedPid(u8),
+    #[error("Response from ELM did not match the requested service (mode)")]
+    UartServiceMismatchError(),
+    #[error("ECU has a stored diagnostic trouble code")]
+    StoredDtc(Ecu, Dtc),
+    #[error("ECU has a pending diagnostic trouble code")]
+    PendingDtc(Ecu, Dtc),
+    #[error("Refused to clear diagnostic trouble codes, the engine has to be off (ECU RPM of 0)")]
+    DtcClearRefused(),
+    #[error("Refused to clear diagnostic trouble codes, the ECU reports no RPM and the vehicle is moving")]
+    DtcClearRefusedMoving(),
+    #[error("Not clearing diagnostic trouble codes yet, the ECU reports neither RPM nor speed so it has to be confirmed")]
+    DtcClearUnconfirmed(),
+    #[error("Multi-frame response from ELM was missing a frame")]
+    UartMissingFrameError(),
+    #[error("ELM could not detect the OBD protocol, falling back to KWP2000 fast init")]
+    ProtocolDetectionFailed(),
+    #[error("ELM did not understand the command ('?')")]
+    ElmUnknownCommand(),
+    #[error("Response from ELM ended while it was still searching for a protocol ('SEARCHING...')")]
+    ElmStillSearching(),
+    #[error("ELM failed to initialize the bus ('BUS INIT: ...ERROR')")]
+    ElmBusInitError(),
+    #[error("ELM could not connect to the ECU with any protocol ('UNABLE TO CONNECT')")]
+    ElmUnableToConnect(),
+    #[error("ELM had trouble with the CAN bus ('CAN ERROR')")]
+    ElmCanError(),
+    #[error("ELM could not send, the bus was busy ('BUS BUSY')")]
+    ElmBusBusy(),
+    #[error("ELM's internal buffer filled up before the response was sent ('BUFFER FULL')")]
+    ElmBufferFull(),
+    #[error("ELM stopped waiting for the response because it received a character ('STOPPED')")]
+    ElmStopped(),
+    #[error("ELM received a frame with a bad checksum or format from the vehicle ('<DATA ERROR')")]
+    ElmDataError(),
+    #[error("ELM rejected or didn't answer an AT command during init")]
+    AtCommandRejected(&'static str),
+    #[error("Lost the connection to the ELM or ECU, initializing again")]
+    EcuLinkLost(),
+    #[error("Several requests to the ECU in a row failed")]
+    EcuLinkDegraded(),
+    #[error("A polled value has not been requested for several of its intervals, higher priority values are using up the bus")]
+    PollItemStarved(&'static str),
 }
 
 const NONDESCRIPT_ERROR_STR: &'static str =           "non-descr- \nipt error! \n   :(      \n   :(      ";
@@ -56,6 +104,7 @@ const UART_BAD_CHECKSUM_ERROR_STR: &'static str =     "UART soft- \nware failed\
 const UART_INCORRECT_LENGTH_ERROR_STR: &'static str = "UART resp. \nincluded   \nwrong num  \nof bytes   ";
 const UART_PID_MISMATCH_ERROR_STR: &'static str =     "UART resp. \nincluded   \nwrong PID  \n           ";
 const UART_VOLTAGE_PARSE_ERROR_STR: &'static str =    "UART soft- \nware failed\nto parse   \nvoltage!   ";
+const UART_IGNITION_PARSE_ERROR_STR: &'static str =   "UART soft- \nware failed\nto parse   \nignition!  ";
 const MIPI_DSI_ERROR_STR: &'static str =              "LCD Error! \nSPI commun-\nication    \nfailure!   ";
 const UNRELIABLE_RPM: &'static str =                  "Unreliable \nRPM data!  \nIgnoring!  \n           ";
 const UNRELIABLE_VBAT: &'static str =                 "Unreliable \nVBAT data! \nIgnoring!  \n           ";
@@ -65,6 +114,37 @@ const STRANGE_VBAT: &'static str =                    "Weird VBAT \ndata! Maybe\
 const STRANGE_COOLANT: &'static str =                 "Weird Temp \ndata! Maybe\nreal but   \nProblematic";
 const UART_RESPONSE_NO_DATA: &'static str =           "UART NoData\nECU 2 slow!\nExpected on\nstart up.  ";
 const RPM_SOURCE_DISCREPANCY: &'static str =          "Measured   \nRPM differs\nfrom ECU   \nval by alot";
+const UNSUPPORTED_PID: &'static str =                 "ECU does   \nnot support\na requested\nPID        ";
+const UART_SERVICE_MISMATCH_ERROR_STR: &'static str = "UART resp. \nincluded   \nwrong mode \n           ";
+const STORED_DTC: &'static str =                      "Check eng! \nStored DTC:\n????? #### \n           ";
+const PENDING_DTC: &'static str =                     "Pending DTC\n????? #### \nnot yet    \nconfirmed  ";
+const DTC_CLEAR_REFUSED: &'static str =               "Won't clear\nDTCs while \nengine is  \nrunning!   ";
+const DTC_CLEAR_REFUSED_MOVING: &'static str =        "Won't clear\nDTCs while \nmoving!    \n           ";
+const DTC_CLEAR_UNCONFIRMED: &'static str =           "No RPM, is \nengine off?\nHold again \nto clear   ";
+const UART_MISSING_FRAME_ERROR_STR: &'static str =    "UART multi-\nframe resp.\nwas missing\na frame    ";
+const PROTOCOL_DETECTION_FAILED: &'static str =       "Couldn't   \ndetect OBD \nprotocol,  \nusing KWP  ";
+const ELM_UNKNOWN_COMMAND: &'static str =             "ELM did not\nunderstand \ncommand (?)\n           ";
+const ELM_STILL_SEARCHING: &'static str =             "ELM still  \nsearching  \nfor OBD    \nprotocol   ";
+const ELM_BUS_INIT_ERROR: &'static str =              "ELM bus    \ninit error,\nis ignition\non?        ";
+const ELM_UNABLE_TO_CONNECT: &'static str =           "ELM unable \nto connect \nto ECU!    \n           ";
+const ELM_CAN_ERROR: &'static str =                   "ELM CAN    \nerror! Chk \nOBD wiring \n           ";
+const ELM_BUS_BUSY: &'static str =                    "ELM says   \nbus busy,  \ntoo much   \ntraffic    ";
+const ELM_BUFFER_FULL: &'static str =                 "ELM buffer \nfull! UART \ntoo slow?  \n           ";
+const ELM_STOPPED: &'static str =                     "ELM stopped\nby incoming\nchar before\nresponse   ";
+const ELM_DATA_ERROR: &'static str =                  "ELM got bad\ndata from  \nvehicle bus\n           ";
+const AT_COMMAND_REJECTED: &'static str =             "ELM init   \nfailed at: \n???????????\n           ";
+const ECU_LINK_LOST: &'static str =                   "ECU link   \nlost!      \nReconnect- \ning...     ";
+const ECU_LINK_DEGRADED: &'static str =               "ECU link   \nunstable,  \nrequests   \nfailing    ";
+const POLL_ITEM_STARVED: &'static str =               "Polling too\nslowly:    \n???????????\n(bus full) ";
+
+/// Every display string is 4 lines of 11 characters
+pub const DISPLAY_TEXT_LEN: usize = 47;
+/// Replaced with the code in DTC display strings
+const DTC_PLACEHOLDER: &'static [u8] = b"?????";
+/// Replaced with the ECU that reported the code in DTC display strings
+const ECU_PLACEHOLDER: &'static [u8] = b"####";
+/// Replaced with the command in `AtCommandRejected`'s (or the label in `PollItemStarved`'s) display string, padded with spaces
+const COMMAND_PLACEHOLDER: &'static [u8] = b"???????????";
 
 
 impl ToRustAGaugeError{
@@ -80,6 +160,7 @@ impl ToRustAGaugeError{
             ToRustAGaugeError::UartIncorrectLengthError() => { UART_INCORRECT_LENGTH_ERROR_STR }
             ToRustAGaugeError::UartPidMismatchError() => { UART_PID_MISMATCH_ERROR_STR }
             ToRustAGaugeError::UartVoltageParseError() => { UART_VOLTAGE_PARSE_ERROR_STR }
+            ToRustAGaugeError::UartIgnitionParseError() => { UART_IGNITION_PARSE_ERROR_STR }
             ToRustAGaugeError::MipiDsiError() => { MIPI_DSI_ERROR_STR }
             ToRustAGaugeError::UnreliableRPM() => { UNRELIABLE_RPM }
             ToRustAGaugeError::UnreliableVBAT() => { UNRELIABLE_VBAT }
@@ -89,7 +170,75 @@ impl ToRustAGaugeError{
             ToRustAGaugeError::StrangeCoolant() => { STRANGE_COOLANT }
             ToRustAGaugeError::UartResponseNoData() => { UART_RESPONSE_NO_DATA }
             ToRustAGaugeError::RpmSourceDiscrepancy() => { RPM_SOURCE_DISCREPANCY }
+            ToRustAGaugeError::UnsupportedPid(_) => { UNSUPPORTED_PID }
+            ToRustAGaugeError::UartServiceMismatchError() => { UART_SERVICE_MISMATCH_ERROR_STR }
+            ToRustAGaugeError::StoredDtc(..) => { STORED_DTC }
+            ToRustAGaugeError::PendingDtc(..) => { PENDING_DTC }
+            ToRustAGaugeError::DtcClearRefused() => { DTC_CLEAR_REFUSED }
+            ToRustAGaugeError::DtcClearRefusedMoving() => { DTC_CLEAR_REFUSED_MOVING }
+            ToRustAGaugeError::DtcClearUnconfirmed() => { DTC_CLEAR_UNCONFIRMED }
+            ToRustAGaugeError::UartMissingFrameError() => { UART_MISSING_FRAME_ERROR_STR }
+            ToRustAGaugeError::ProtocolDetectionFailed() => { PROTOCOL_DETECTION_FAILED }
+            ToRustAGaugeError::ElmUnknownCommand() => { ELM_UNKNOWN_COMMAND }
+            ToRustAGaugeError::ElmStillSearching() => { ELM_STILL_SEARCHING }
+            ToRustAGaugeError::ElmBusInitError() => { ELM_BUS_INIT_ERROR }
+            ToRustAGaugeError::ElmUnableToConnect() => { ELM_UNABLE_TO_CONNECT }
+            ToRustAGaugeError::ElmCanError() => { ELM_CAN_ERROR }
+            ToRustAGaugeError::ElmBusBusy() => { ELM_BUS_BUSY }
+            ToRustAGaugeError::ElmBufferFull() => { ELM_BUFFER_FULL }
+            ToRustAGaugeError::ElmStopped() => { ELM_STOPPED }
+            ToRustAGaugeError::ElmDataError() => { ELM_DATA_ERROR }
+            ToRustAGaugeError::AtCommandRejected(_) => { AT_COMMAND_REJECTED }
+            ToRustAGaugeError::EcuLinkLost() => { ECU_LINK_LOST }
+            ToRustAGaugeError::EcuLinkDegraded() => { ECU_LINK_DEGRADED }
+            ToRustAGaugeError::PollItemStarved(_) => { POLL_ITEM_STARVED }
+        }
+    }
+
+    /// Errors the ELM reports about itself or the bus are as bad no matter which request caused them,
+    /// so they override the severity the caller picked
+    pub const fn elm_response_severity(&self) -> Option<ToRustAGaugeErrorSeverity> {
+        match self {
+            ToRustAGaugeError::UartResponseNoData() => Some(ToRustAGaugeErrorSeverity::EntirelyRecoverable),
+            ToRustAGaugeError::ElmUnknownCommand() => Some(ToRustAGaugeErrorSeverity::MaybeRecoverable),
+            ToRustAGaugeError::ElmStillSearching() => Some(ToRustAGaugeErrorSeverity::EntirelyRecoverable),
+            ToRustAGaugeError::ElmBusInitError() => Some(ToRustAGaugeErrorSeverity::MaybeRecoverable),
+            ToRustAGaugeError::ElmUnableToConnect() => Some(ToRustAGaugeErrorSeverity::LossOfSomeFunctionality),
+            ToRustAGaugeError::ElmCanError() => Some(ToRustAGaugeErrorSeverity::LossOfSomeFunctionality),
+            ToRustAGaugeError::ElmBusBusy() => Some(ToRustAGaugeErrorSeverity::BadIfReoccurring),
+            ToRustAGaugeError::ElmBufferFull() => Some(ToRustAGaugeErrorSeverity::BadIfReoccurring),
+            ToRustAGaugeError::ElmStopped() => Some(ToRustAGaugeErrorSeverity::EntirelyRecoverable),
+            ToRustAGaugeError::ElmDataError() => Some(ToRustAGaugeErrorSeverity::BadIfReoccurring),
+            _ => None,
+        }
+    }
+
+    /// Same as `to_str`, but with values that are only known at runtime (DTCs) filled in.
+    /// The result only lives as long as `buffer`
+    pub fn to_display_str<'b>(&self, buffer: &'b mut [u8; DISPLAY_TEXT_LEN]) -> &'b str {
+        let template = self.to_str().as_bytes();
+        let len = template.len().min(DISPLAY_TEXT_LEN);
+        buffer[..len].copy_from_slice(&template[..len]);
+        match self {
+            ToRustAGaugeError::StoredDtc(ecu, dtc) | ToRustAGaugeError::PendingDtc(ecu, dtc) => {
+                if let Some(start) = buffer[..len].windows(DTC_PLACEHOLDER.len()).position(|w| w == DTC_PLACEHOLDER) {
+                    buffer[start..start + DTC_PLACEHOLDER.len()].copy_from_slice(&dtc.as_ascii());
+                }
+                if let Some(start) = buffer[..len].windows(ECU_PLACEHOLDER.len()).position(|w| w == ECU_PLACEHOLDER) {
+                    buffer[start..start + ECU_PLACEHOLDER.len()].copy_from_slice(&ecu.label());
+                }
+            }
+            ToRustAGaugeError::AtCommandRejected(command) | ToRustAGaugeError::PollItemStarved(command) => {
+                if let Some(start) = buffer[..len].windows(COMMAND_PLACEHOLDER.len()).position(|w| w == COMMAND_PLACEHOLDER) {
+                    let placeholder = &mut buffer[start..start + COMMAND_PLACEHOLDER.len()];
+                    placeholder.fill(b' ');
+                    let command_len = command.len().min(COMMAND_PLACEHOLDER.len());
+                    placeholder[..command_len].copy_from_slice(&command.as_bytes()[..command_len]);
+                }
+            }
+            _ => {}
         }
+        core::str::from_utf8(&buffer[..len]).unwrap_or(NONDESCRIPT_ERROR_STR)
     }
 }
 
@@ -1,7 +1,8 @@
 // when you read the name of the file in your head, it is imperative that you think of it as 'freak' counter
 
 use embassy_rp::gpio::Pull;
-use crate::{FreakyResources, ToMainEvents, INCOMING_EVENT_CHANNEL};
+use crate::{FreakyResources, ToFreqCounterEvents, FREQ_COUNTER_EVENT_CHANNEL, INCOMING_EVENT_CHANNEL};
+use to_rust_a_gauge::ToMainEvents;
 use embassy_rp::pwm;
 use embassy_rp::pwm::InputMode;
 
@@ -23,7 +24,15 @@ pub async fn freq_counter_task(r: FreakyResources) {
     let mut update_ticker = embassy_time::Ticker::every(MIN_DELAY_BETWEEN_UPDATES);
     let mut rpm_history: circular_buffer::CircularBuffer<RPM_HISTORY_LEN, f64> = circular_buffer::CircularBuffer::<RPM_HISTORY_LEN, f64>::new();
     let mut pulses: u16;
+    let receiver = FREQ_COUNTER_EVENT_CHANNEL.receiver();
     loop {
+        if let Ok(ToFreqCounterEvents::IsIgnitionOn(false)) = receiver.try_receive() {
+            defmt::info!("Ignition off, not measuring RPM until it's back");
+            // nothing wakes the executor up every window while the key is off
+            while let ToFreqCounterEvents::IsIgnitionOn(false) = receiver.receive().await {}
+            rpm_history.clear();
+            update_ticker.reset();
+        }
         start_time = embassy_time::Instant::now();
         pwm.set_counter(0);
         
@@ -3,9 +3,10 @@ use embassy_futures::join::join;
 use embassy_rp::peripherals::PIO0;
 use embassy_rp::pio::Pio;
 use smart_leds::RGB8;
-use crate::{GaugePins, Irqs, ToGaugeEvents, ToLcdEvents, ToMainEvents, GAUGE_EVENT_CHANNEL, INCOMING_EVENT_CHANNEL};
-use crate::data_point::{DataPoint, Datum};
-use crate::errors::{ToRustAGaugeErrorSeverity, ToRustAGaugeErrorWithSeverity};
+use crate::{GaugePins, Irqs, ToGaugeEvents, ToLcdEvents, GAUGE_EVENT_CHANNEL, INCOMING_EVENT_CHANNEL};
+use to_rust_a_gauge::ToMainEvents;
+use to_rust_a_gauge::data_point::{DataPoint, Datum};
+use to_rust_a_gauge::errors::{ToRustAGaugeErrorSeverity, ToRustAGaugeErrorWithSeverity};
 use crate::pio_servo::{PwmPio, ServoBuilder, ServoDegrees};
 use crate::ws2812::Ws2812;
 
@@ -23,6 +24,9 @@ const BACKLIGHT_DIM_BRIGHTNESS_MULTIPLIER: f32 = 0.5;
 /// has a 20ms period, and only one 'command' can be sent during that time
 const MIN_UPDATE_DELAY: embassy_time::Duration = embassy_time::Duration::from_millis(50);
 
+/// How long the needle takes to get back to 0 from anywhere, the servo signal is only stopped after that
+const NEEDLE_PARK_TIME: embassy_time::Duration = embassy_time::Duration::from_millis(1000);
+
 /// the maximum RPM value that can be displayed. Higher values will be checked for and handled,
 /// but this value is used for scaling.
 const GAUGE_MAX_RPM: f64 = 9000.0;
@@ -51,10 +55,13 @@ pub async fn gauge_task(r: GaugePins) {
     sender.send(ToMainEvents::GaugeInitComplete).await;
     
     let mut is_backlight_on = false;
+    let mut is_ignition_on = true;
     let mut ticker = embassy_time::Ticker::every(MIN_UPDATE_DELAY);
     loop {
         ticker.next().await;
         match receiver.receive().await {
+            // the measured RPM that was already on its way when the key was turned off
+            ToGaugeEvents::NewData(_) if !is_ignition_on => {}
             ToGaugeEvents::NewData(data) => {
                 match data.data {
                     Datum::RPM(rpm) => {
@@ -68,6 +75,19 @@ pub async fn gauge_task(r: GaugePins) {
             ToGaugeEvents::IsBackLightOn(new_bl_state) => {
                 is_backlight_on = new_bl_state;
             }
+            ToGaugeEvents::IsIgnitionOn(new_ignition_state) => {
+                match (is_ignition_on, new_ignition_state) {
+                    (true, false) => {
+                        servo.rotate(rpm_to_servo_degrees(0.0));
+                        ws2812.write(&[BLACK; NUM_LEDS]).await;
+                        embassy_time::Timer::after(NEEDLE_PARK_TIME).await;
+                        servo.stop();
+                    }
+                    (false, true) => servo.start(),
+                    _ => {}
+                }
+                is_ignition_on = new_ignition_state;
+            }
         }
     }
 }
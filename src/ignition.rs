@@ -0,0 +1,170 @@
+use embassy_time::{Duration, Instant};
+
+/// Only reached with the engine running, the alternator charging
+pub const MIN_CHARGING_VBAT: f64 = 13.2;
+/// How long the battery has to stay below `MIN_CHARGING_VBAT` with the ECU silent before it counts as key-off,
+/// when the adapter's ignition input can't say
+const KEY_OFF_DELAY: Duration = Duration::from_millis(60000);
+
+#[derive(defmt::Format, Debug, Copy, Clone, PartialEq)]
+pub enum IgnitionState {
+    On,
+    /// Key-off: nothing is polled, the gauge and display are off and the adapter sleeps
+    Off,
+}
+
+/// Turns off the clocks of the RP2040's peripherals that aren't used while the key is off, see `sleep_until_ignition`
+pub trait ClockGate {
+    fn gate(&mut self);
+    /// Back to how they were before `gate`
+    fn restore(&mut self);
+}
+
+/// Decides whether the key is off from the adapter's ignition input (`ATIGN`), the battery voltage and whether
+/// the ECU answers. Whichever of the first two says so, it's only off while the ECU is silent too, so an ignition
+/// input that isn't wired can't put a running car's gauge to sleep.
+/// Like `LinkSupervisor`, every method that can change the state returns the new state if it did change
+#[derive(defmt::Format, Debug, Clone, PartialEq)]
+pub struct IgnitionSense {
+    state: IgnitionState,
+    /// The ignition input has read `OFF`, so it's wired to the ignition and `ON` means something.
+    /// Most adapters tie it high and always say `ON`
+    pin_wired: bool,
+    pin_on: bool,
+    /// The key was found off by the battery voltage while the input still read `ON`, so it's tied high
+    /// and reading it is a waste of time
+    pin_unwired: bool,
+    ecu_answering: bool,
+    /// The last voltage reading was at least `MIN_CHARGING_VBAT`
+    charging: bool,
+    /// Since when the battery has been below `MIN_CHARGING_VBAT` with the ECU silent
+    low_voltage_since: Option<Instant>,
+}
+
+impl IgnitionSense {
+    pub const fn new() -> Self {
+        Self {
+            state: IgnitionState::On,
+            pin_wired: false,
+            pin_on: true,
+            pin_unwired: false,
+            // nothing has answered yet, so a gauge powered up with the key off goes to sleep too
+            ecu_answering: false,
+            charging: false,
+            low_voltage_since: None,
+        }
+    }
+
+    pub fn state(&self) -> IgnitionState {
+        self.state
+    }
+
+    /// Whether `ATIGN` is only ever going to say `ON`, see `pin_unwired`
+    pub fn pin_unwired(&self) -> bool {
+        self.pin_unwired
+    }
+
+    /// While the key is off, whether only the ECU can say it's back: the ignition input has never read `OFF`
+    /// (or can't be read) and the battery isn't charging
+    pub fn needs_ecu_check(&self) -> bool {
+        self.state == IgnitionState::Off && !self.pin_wired && !self.charging
+    }
+
+    /// `ATIGN`'s answer, `true` for `ON`
+    pub fn record_pin(&mut self, on: bool, now: Instant) -> Option<IgnitionState> {
+        self.pin_wired |= !on;
+        self.pin_on = on;
+        self.update(now)
+    }
+
+    /// Called with the outcome of every request to the ECU
+    pub fn record_ecu(&mut self, answered: bool, now: Instant) -> Option<IgnitionState> {
+        self.ecu_answering = answered;
+        if answered {
+            self.low_voltage_since = None;
+        }
+        self.update(now)
+    }
+
+    /// `ATRV`'s answer
+    pub fn record_voltage(&mut self, vbat: f64, now: Instant) -> Option<IgnitionState> {
+        self.charging = vbat >= MIN_CHARGING_VBAT;
+        if self.charging || self.ecu_answering {
+            self.low_voltage_since = None;
+        } else {
+            self.low_voltage_since.get_or_insert(now);
+        }
+        self.update(now)
+    }
+
+    fn update(&mut self, now: Instant) -> Option<IgnitionState> {
+        let pin_on = self.pin_wired && self.pin_on;
+        let pin_off = self.pin_wired && !self.pin_on;
+        let voltage_off = self.low_voltage_since.is_some_and(|since| now - since >= KEY_OFF_DELAY);
+        let state = match self.state {
+            IgnitionState::On if !self.ecu_answering && (pin_off || voltage_off) => IgnitionState::Off,
+            IgnitionState::Off if self.ecu_answering || pin_on || self.charging => IgnitionState::On,
+            state => state,
+        };
+        if state == self.state {
+            return None
+        }
+        self.pin_unwired |= state == IgnitionState::Off && !self.pin_wired && self.pin_on;
+        // the battery has to be low for the whole delay again before the next key-off
+        self.low_voltage_since = None;
+        self.state = state;
+        Some(state)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    #[test]
+    fn test_ignition_input() {
+        let mut ignition = IgnitionSense::new();
+        assert_eq!(ignition.record_ecu(true, at(0)), None);
+        // the ECU is still answering, whatever the input says
+        assert_eq!(ignition.record_pin(false, at(100)), None);
+        assert_eq!(ignition.record_ecu(false, at(200)), Some(IgnitionState::Off));
+        assert_eq!(ignition.record_voltage(12.2, at(300)), None);
+        // the input wakes it up, no need to ask the ECU
+        assert!(!ignition.needs_ecu_check());
+        assert_eq!(ignition.record_pin(true, at(400)), Some(IgnitionState::On));
+        // not answering yet after the key is turned, and it isn't off for that
+        assert_eq!(ignition.record_ecu(false, at(500)), None);
+        assert_eq!(ignition.record_ecu(true, at(600)), None);
+        assert!(!ignition.pin_unwired());
+    }
+
+    #[test]
+    fn test_battery_voltage() {
+        // an input tied high says nothing
+        let mut ignition = IgnitionSense::new();
+        assert_eq!(ignition.record_pin(true, at(0)), None);
+        ignition.record_ecu(true, at(0));
+        // key on, engine off: low, but the ECU answers
+        assert_eq!(ignition.record_voltage(12.4, at(0)), None);
+        assert_eq!(ignition.record_voltage(12.4, at(120_000)), None);
+
+        ignition.record_ecu(false, at(120_000));
+        assert_eq!(ignition.record_voltage(12.4, at(121_000)), None);
+        assert_eq!(ignition.record_voltage(12.3, at(121_000) + KEY_OFF_DELAY), Some(IgnitionState::Off));
+        assert!(ignition.pin_unwired());
+        // `ON` doesn't count from an input that never read `OFF`
+        assert_eq!(ignition.record_pin(true, at(200_000)), None);
+        assert_eq!(ignition.record_voltage(12.3, at(300_000)), None);
+        assert!(ignition.needs_ecu_check());
+        // started
+        assert_eq!(ignition.record_voltage(14.1, at(330_000)), Some(IgnitionState::On));
+        assert!(!ignition.needs_ecu_check());
+        assert_eq!(ignition.record_voltage(12.3, at(331_000)), None);
+        assert_eq!(ignition.record_voltage(12.3, at(332_000)), None);
+    }
+}
@@ -0,0 +1,92 @@
+//! Everything that doesn't touch the RP2040's peripherals: the ELM327 and OBD-II side, decoding and error handling.
+//! The firmware (`main.rs`) runs it on the hardware, the tests run it on the host (see the README)
+#![cfg_attr(not(test), no_std)]
+pub mod data_point;
+pub mod elm_commands;
+pub mod elm_uart;
+pub mod errors;
+pub mod byte_parsing;
+pub mod error_lifetime;
+pub mod mode_01_pids;
+pub mod supported_pids;
+pub mod dtc;
+pub mod vehicle_info;
+pub mod obd_protocol;
+pub mod elm_link;
+pub mod elm_driver;
+pub mod response_parser;
+pub mod elm_timing;
+pub mod elm_trace;
+pub mod bus_monitor;
+pub mod elm_adapter;
+pub mod poll_scheduler;
+pub mod ignition;
+pub mod button;
+#[cfg(test)]
+mod elm_emulator;
+
+use crate::button::ButtonPress;
+use crate::dtc::DtcReport;
+use crate::elm_adapter::AdapterProfile;
+use crate::elm_link::LinkState;
+use crate::elm_timing::TimingReport;
+use crate::elm_trace::TraceSummary;
+use crate::ignition::IgnitionState;
+use crate::vehicle_info::VehicleInfo;
+
+pub enum ToMainEvents {
+    GaugeInitComplete,
+    GaugeError(errors::ToRustAGaugeErrorWithSeverity),
+    LcdInitComplete,
+    LcdError(errors::ToRustAGaugeErrorWithSeverity),
+    ElmInitComplete,
+    /// What the adapter is and what it supports, sent once per init
+    ElmAdapter(AdapterProfile),
+    ElmError(errors::ToRustAGaugeErrorWithSeverity),
+    ElmDataPoint(data_point::DataPoint),
+    /// Sent every time the ELM task reads the trouble codes, an empty report means there are none
+    ElmDiagnosticCodes(DtcReport),
+    /// VIN and calibration IDs, read once after the ELM is initialized
+    ElmVehicleInfo(VehicleInfo),
+    /// Sent whenever the state of the connection to the ELM/ECU changes
+    ElmLinkState(LinkState),
+    /// ELM timeout and request latency statistics, sent every few seconds while polling
+    ElmTiming(TimingReport),
+    /// One exchange from the ELM trace, in answer to `ToElmEvents::DumpTrace`, `None` if there's none that far back
+    ElmTraceEntry(Option<TraceSummary>),
+    /// Sent whenever the ELM task decides the key was turned off or back on, see `IgnitionSense`
+    ElmIgnition(IgnitionState),
+    ButtonPressed(ButtonPress),
+    FreqCountedRpm(f64)
+}
+
+pub enum ToElmEvents {
+    /// Clears stored trouble codes (Mode 04), on a long press of the button. Only done if the ECU reports the engine
+    /// isn't running, or if it doesn't report RPM, that the vehicle isn't moving. If it reports neither, the ELM task
+    /// answers with `ToRustAGaugeError::DtcClearUnconfirmed` and it's only done when sent again with `confirmed`.
+    /// Every refusal is sent to main as an error saying why
+    ClearDiagnosticCodes { confirmed: bool },
+    /// Sends the exchange with the ELM `index` back from the newest as `ToMainEvents::ElmTraceEntry`.
+    /// Each short press of the button asks for the next older one, to step through them on the display
+    DumpTrace { index: usize },
+}
+
+/// Nothing to log to on the host, but defmt still needs a logger to link
+#[cfg(test)]
+#[defmt::global_logger]
+struct TestLogger;
+
+#[cfg(test)]
+unsafe impl defmt::Logger for TestLogger {
+    fn acquire() {}
+    unsafe fn flush() {}
+    unsafe fn release() {}
+    unsafe fn write(_bytes: &[u8]) {}
+}
+
+/// `defmt::panic!` and friends, on the host they're ordinary panics so the test fails
+#[cfg(test)]
+#[defmt::panic_handler]
+fn defmt_panic() -> ! {
+    panic!("defmt panic")
+}
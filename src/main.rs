@@ -1,18 +1,12 @@
-
-
 #![no_std]
 #![no_main]
-mod data_point;
-mod elm_commands;
-mod elm_uart;
-mod errors;
 mod display;
-mod byte_parsing;
 mod gauge;
 mod ws2812;
-mod error_lifetime;
 mod freq_counter;
 mod pio_servo;
+mod button_task;
+mod elm_uart_task;
 
 
 use embassy_rp::{bind_interrupts};
@@ -21,14 +15,24 @@ use embassy_rp::peripherals;
 use {defmt_rtt as _, panic_probe as _};
 use defmt;
 use embassy_rp::gpio::Level;
-use embassy_sync::channel::{Channel, TryReceiveError};
+use embassy_sync::channel::Channel;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
-use crate::data_point::{DataPoint, Datum};
+use to_rust_a_gauge::{data_point, mode_01_pids, ToElmEvents, ToMainEvents};
+use to_rust_a_gauge::data_point::{DataPoint, Datum};
+use to_rust_a_gauge::dtc::{DtcReport, FreezeFrame};
+use to_rust_a_gauge::elm_adapter::AdapterProfile;
+use to_rust_a_gauge::elm_link::LinkState;
+use to_rust_a_gauge::elm_timing::{TimingReport, TimingSummary};
+use to_rust_a_gauge::elm_trace::TraceSummary;
+use to_rust_a_gauge::ignition::IgnitionState;
+use to_rust_a_gauge::button::ButtonPress;
+use crate::button_task::button_task;
+use to_rust_a_gauge::vehicle_info::VehicleInfo;
 use crate::display::display_task;
-use crate::elm_uart::elm_uart_task;
+use crate::elm_uart_task::elm_uart_task;
 use crate::gauge::gauge_task;
-use crate::error_lifetime::ErrorFifo;
-use crate::errors::{ToRustAGaugeError, ToRustAGaugeErrorSeverity, ToRustAGaugeErrorWithSeverity};
+use to_rust_a_gauge::error_lifetime::ErrorFifo;
+use to_rust_a_gauge::errors::{ToRustAGaugeError, ToRustAGaugeErrorSeverity, ToRustAGaugeErrorWithSeverity};
 use crate::freq_counter::freq_counter_task;
 
 /// error checking will wait at least this long, maybe more
@@ -36,18 +40,12 @@ const ERROR_CHECKING_INTERVAL: embassy_time::Duration = embassy_time::Duration::
 
 const CONSECUTIVE_RPM_DISCREPANCIES_COUNT_THRESHOLD: u8 = 4;
 
+/// After the ELM task asks for a trouble code clear to be confirmed, a long press within this long confirms it
+const DTC_CLEAR_CONFIRM_TIME: embassy_time::Duration = embassy_time::Duration::from_millis(10000);
+
 pub static INCOMING_EVENT_CHANNEL: Channel<CriticalSectionRawMutex, ToMainEvents, 10> = Channel::new();
 
-pub enum ToMainEvents {
-    GaugeInitComplete,
-    GaugeError(errors::ToRustAGaugeErrorWithSeverity),
-    LcdInitComplete,
-    LcdError(errors::ToRustAGaugeErrorWithSeverity),
-    ElmInitComplete,
-    ElmError(errors::ToRustAGaugeErrorWithSeverity),
-    ElmDataPoint(data_point::DataPoint),
-    FreqCountedRpm(f64)
-}
+pub static ELM_EVENT_CHANNEL: Channel<CriticalSectionRawMutex, ToElmEvents, 4> = Channel::new();
 
 pub static LCD_EVENT_CHANNEL: Channel<CriticalSectionRawMutex, ToLcdEvents, 10> = Channel::new();
 
@@ -55,12 +53,32 @@ pub enum ToLcdEvents {
     NewData(data_point::DataPoint),
     Error(Option<ToRustAGaugeErrorWithSeverity>),
     IsBackLightOn(bool),
+    /// Shown in the error quadrant until the vehicle info or the first error
+    AdapterInfo(AdapterProfile),
+    /// Shown in the error quadrant until the first error
+    VehicleInfo(VehicleInfo),
+    /// From the last trouble code read, shown instead of the stored code it's for
+    FreezeFrame(Option<FreezeFrame>),
+    /// Shown in the error quadrant instead of the errors, until `None`
+    Trace(Option<TraceSummary>),
+    /// Shown like a trace entry, after the oldest one
+    Timing(TimingSummary),
+    /// Off puts the display to sleep and turns the backlight off
+    IsIgnitionOn(bool),
 }
 
 pub static GAUGE_EVENT_CHANNEL: Channel<CriticalSectionRawMutex, ToGaugeEvents, 10> = Channel::new();
 pub enum ToGaugeEvents {
     NewData(data_point::DataPoint),
     IsBackLightOn(bool),
+    /// Off parks the needle, blanks the LEDs and stops the servo signal
+    IsIgnitionOn(bool),
+}
+
+pub static FREQ_COUNTER_EVENT_CHANNEL: Channel<CriticalSectionRawMutex, ToFreqCounterEvents, 2> = Channel::new();
+pub enum ToFreqCounterEvents {
+    /// Nothing is measured (or sent to main) while it's off
+    IsIgnitionOn(bool),
 }
 
 const RPM_SOURCE_DISCREPANCY_THRESHOLD: f64 = 1000.0f64;
@@ -70,12 +88,13 @@ assign_resources! { // I hate this macro shit
         tx_pin: PIN_0,
         rx_pin: PIN_1,
         uart0: UART0,
-        dma0: DMA_CH0,
-        dma1: DMA_CH1,
     },
     backlight_sensor: BacklightSensor{
         bl_pin: PIN_14,
     },
+    button: ButtonPins{
+        button_pin: PIN_16,
+    },
     gauge: GaugePins{
         servo_pin: PIN_2,
         neo_pixel: PIN_3,
@@ -101,7 +120,7 @@ assign_resources! { // I hate this macro shit
 }
 
 bind_interrupts!(struct Irqs {
-    UART0_IRQ => embassy_rp::uart::InterruptHandler<peripherals::UART0>;
+    UART0_IRQ => embassy_rp::uart::BufferedInterruptHandler<peripherals::UART0>;
     PIO0_IRQ_0 => embassy_rp::pio::InterruptHandler<peripherals::PIO0>; // servo
     PIO1_IRQ_0 => embassy_rp::pio::InterruptHandler<peripherals::PIO1>; // ws2812
 });
@@ -126,6 +145,7 @@ async fn main(spawner: embassy_executor::Spawner) {
     spawner.spawn(elm_uart_task(r.elm_uart)).expect("failed to spawn elm uart task");
     spawner.spawn(display_task(r.display)).expect("failed to spawn display task");
     spawner.spawn(freq_counter_task(r.freak_counter)).expect("failed to spawn freaky task");
+    spawner.spawn(button_task(r.button)).expect("failed to spawn button task");
 
     let lcd_sender = LCD_EVENT_CHANNEL.sender();
     let gauge_sender = GAUGE_EVENT_CHANNEL.sender();
@@ -139,10 +159,42 @@ async fn main(spawner: embassy_executor::Spawner) {
     
     let mut freq_counted_rpm: f64 = 0.0;
     
+    let mut active_dtcs = DtcReport::new();
+    
+    let mut adapter: Option<AdapterProfile> = None;
+    let mut vehicle_info: Option<VehicleInfo> = None;
+    
+    let mut is_ignition_on: bool = true;
+    
+    // set when the ELM task can't tell whether the engine is running, see `ToElmEvents::ClearDiagnosticCodes`
+    let mut dtc_clear_confirmable_until: Option<embassy_time::Instant> = None;
+    // the trace entry on the display, see `ToElmEvents::DumpTrace`
+    let mut trace_index: Option<usize> = None;
+    // the latest from the ELM task, shown after the trace entries
+    let mut last_timing: Option<TimingReport> = None;
+    let mut is_timing_shown: bool = false;
+    
+    let mut elm_link_state = LinkState::Disconnected;
+    // "link lost" only makes sense once there was a link
+    let mut was_elm_connected: bool = false;
+    
     loop {
         if last_error_check.elapsed() > ERROR_CHECKING_INTERVAL {
             last_error_check = embassy_time::Instant::now();
             error_fifo.clear_inactive();
+            // trouble codes stay until the next read says they're gone, so keep refreshing them
+            active_dtcs.to_errors().for_each(|e| error_fifo.add(e));
+            match elm_link_state {
+                LinkState::Degraded => error_fifo.add(ToRustAGaugeErrorWithSeverity{
+                    error: ToRustAGaugeError::EcuLinkDegraded(),
+                    severity: ToRustAGaugeErrorSeverity::BadIfReoccurring,
+                }),
+                LinkState::Disconnected | LinkState::Initialising if was_elm_connected => error_fifo.add(ToRustAGaugeErrorWithSeverity{
+                    error: ToRustAGaugeError::EcuLinkLost(),
+                    severity: ToRustAGaugeErrorSeverity::LossOfSomeFunctionality,
+                }),
+                _ => {}
+            }
             lcd_sender.send(ToLcdEvents::Error(error_fifo.get_most_relevant_error())).await;
 
             is_backlight_on = match backlight_input.get_level(){
@@ -159,9 +211,13 @@ async fn main(spawner: embassy_executor::Spawner) {
                 defmt::info!("Gauge initialized");
                 is_gauge_init = true;
                 gauge_sender.send(ToGaugeEvents::IsBackLightOn(is_backlight_on)).await;
+                if !is_ignition_on {
+                    gauge_sender.send(ToGaugeEvents::IsIgnitionOn(false)).await;
+                }
                 gauge_sender.send(ToGaugeEvents::NewData(DataPoint{
                     data: Datum::RPM(0.0),
                     time: embassy_time::Instant::now(),
+                    ecu: None,
                 })).await;
             }
             ToMainEvents::GaugeError(e) => {
@@ -173,6 +229,18 @@ async fn main(spawner: embassy_executor::Spawner) {
                 is_lcd_init = true;
                 lcd_sender.send(ToLcdEvents::IsBackLightOn(is_backlight_on)).await;
                 lcd_sender.send(ToLcdEvents::Error(None)).await;
+                if let Some(profile) = &adapter {
+                    lcd_sender.send(ToLcdEvents::AdapterInfo(profile.clone())).await;
+                }
+                if let Some(info) = &vehicle_info {
+                    lcd_sender.send(ToLcdEvents::VehicleInfo(info.clone())).await;
+                }
+                if active_dtcs.freeze_frame.is_some() {
+                    lcd_sender.send(ToLcdEvents::FreezeFrame(active_dtcs.freeze_frame.clone())).await;
+                }
+                if !is_ignition_on {
+                    lcd_sender.send(ToLcdEvents::IsIgnitionOn(false)).await;
+                }
             }
             ToMainEvents::LcdError(e) => {
                 defmt::warn!("LCD error: {:?}", e);
@@ -183,6 +251,9 @@ async fn main(spawner: embassy_executor::Spawner) {
             }
             ToMainEvents::ElmError(e) => {
                 defmt::warn!("Elm error: {:?}", e);
+                if e.error == ToRustAGaugeError::DtcClearUnconfirmed() {
+                    dtc_clear_confirmable_until = Some(embassy_time::Instant::now() + DTC_CLEAR_CONFIRM_TIME);
+                }
                 error_fifo.add(e);
             }
             ToMainEvents::ElmDataPoint(d) => {
@@ -244,6 +315,98 @@ async fn main(spawner: embassy_executor::Spawner) {
                             }
                         }
                     }
+                    Datum::ObdPid(pid, value) => {
+                        match mode_01_pids::find_mode_01_pid(pid) {
+                            Some(command) => defmt::debug!("{}: {} {}", command.name, value, command.units.symbol()),
+                            None => defmt::debug!("PID {:x}: {}", pid, value),
+                        }
+                    }
+                    Datum::ExtendedPid(service, identifier, value) => {
+                        defmt::debug!("Service {:x} identifier {:x}: {}", service, identifier, value);
+                    }
+                }
+            }
+            ToMainEvents::ElmDiagnosticCodes(report) => {
+                defmt::info!("ECU trouble codes: {:?}", report);
+                report.to_errors().for_each(|e| error_fifo.add(e));
+                if is_lcd_init && report.freeze_frame != active_dtcs.freeze_frame {
+                    lcd_sender.send(ToLcdEvents::FreezeFrame(report.freeze_frame.clone())).await;
+                }
+                active_dtcs = report;
+            }
+            ToMainEvents::ElmAdapter(profile) => {
+                defmt::info!("ELM adapter: {:?}", profile);
+                if is_lcd_init {
+                    lcd_sender.send(ToLcdEvents::AdapterInfo(profile.clone())).await;
+                }
+                adapter = Some(profile);
+            }
+            ToMainEvents::ElmVehicleInfo(info) => {
+                defmt::info!("Vehicle: {:?}", info);
+                if is_lcd_init {
+                    lcd_sender.send(ToLcdEvents::VehicleInfo(info.clone())).await;
+                }
+                vehicle_info = Some(info);
+            }
+            ToMainEvents::ElmLinkState(state) => {
+                defmt::info!("ELM link state: {:?}", state);
+                was_elm_connected |= state == LinkState::Connected;
+                elm_link_state = state;
+            }
+            ToMainEvents::ElmTiming(report) => {
+                defmt::debug!("ELM timing: {:?}", report);
+                if is_timing_shown && is_lcd_init {
+                    lcd_sender.send(ToLcdEvents::Timing(report.summary())).await;
+                }
+                last_timing = Some(report);
+            }
+            ToMainEvents::ElmTraceEntry(summary) => {
+                defmt::info!("ELM trace: {:?}", summary);
+                if summary.is_none() {
+                    // stepped past the oldest, on to the timing, or back to the errors if there's none yet
+                    trace_index = None;
+                    is_timing_shown = last_timing.is_some();
+                }
+                if is_lcd_init {
+                    match &last_timing {
+                        Some(report) if is_timing_shown => lcd_sender.send(ToLcdEvents::Timing(report.summary())).await,
+                        _ => lcd_sender.send(ToLcdEvents::Trace(summary)).await,
+                    }
+                }
+            }
+            ToMainEvents::ElmIgnition(state) => {
+                defmt::info!("Ignition: {:?}", state);
+                is_ignition_on = state == IgnitionState::On;
+                FREQ_COUNTER_EVENT_CHANNEL.send(ToFreqCounterEvents::IsIgnitionOn(is_ignition_on)).await;
+                if is_gauge_init {
+                    gauge_sender.send(ToGaugeEvents::IsIgnitionOn(is_ignition_on)).await;
+                }
+                if is_lcd_init {
+                    lcd_sender.send(ToLcdEvents::IsIgnitionOn(is_ignition_on)).await;
+                }
+            }
+            ToMainEvents::ButtonPressed(ButtonPress::Long) => {
+                let confirmed = dtc_clear_confirmable_until.take()
+                    .is_some_and(|until| embassy_time::Instant::now() < until);
+                // the ELM task only looks for events while polling, this mustn't hold main up until then
+                if ELM_EVENT_CHANNEL.try_send(ToElmEvents::ClearDiagnosticCodes { confirmed }).is_err() {
+                    defmt::warn!("ELM event channel full, not clearing trouble codes");
+                }
+            }
+            ToMainEvents::ButtonPressed(ButtonPress::Short) if is_timing_shown => {
+                // the last page, back to the errors
+                is_timing_shown = false;
+                if is_lcd_init {
+                    lcd_sender.send(ToLcdEvents::Trace(None)).await;
+                }
+            }
+            ToMainEvents::ButtonPressed(ButtonPress::Short) => {
+                let index = trace_index.map_or(0, |index| index + 1);
+                // same as clearing the trouble codes, the ELM task answers once it's polling
+                if ELM_EVENT_CHANNEL.try_send(ToElmEvents::DumpTrace { index }).is_ok() {
+                    trace_index = Some(index);
+                } else {
+                    defmt::warn!("ELM event channel full, not showing the trace");
                 }
             }
             ToMainEvents::FreqCountedRpm(rpm) => {
@@ -270,6 +433,8 @@ async fn main(spawner: embassy_executor::Spawner) {
                             gauge_sender.send(ToGaugeEvents::NewData(DataPoint{
                                 data: Datum::RPM(rpm),
                                 time: embassy_time::Instant::now(),
+                                // measured from the ignition signal
+                                ecu: None,
                             })).await;
                         }
                     }
@@ -285,4 +450,4 @@ fn abs(value: f64) -> f64 {
     } else {
         value
     }
-}
\ No newline at end of file
+}
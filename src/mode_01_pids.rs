@@ -0,0 +1,319 @@
+//! SAE J1979 Mode 01 (show current data) PIDs.
+//!
+//! Every entry is a `PidCommand` with its response length, units and formula. To read a new value,
+//! pick the matching const (or look it up with `find_mode_01_pid`) and hand it to `get_pid`.
+//! Formulas take the data bytes only (`A`, `B`, `C`, `D` in the J1979 tables), header, PID and
+//! checksum have already been stripped by `PidCommand::extract_val_from_parsed_resp`.
+//!
+//! PIDs that report more than one value (O2 sensors, max values, etc.) only decode the first one.
+
+use crate::elm_commands::{PidCommand, PidUnits, ENGINE_COOLANT_TEMP_PID, ENGINE_RPM_PID, HEARTBEAT_PID};
+
+
+/// `A`
+fn byte_a(slice: &[u8]) -> f64 {
+    slice[0] as f64
+}
+
+/// `256A + B`
+fn word_ab(slice: &[u8]) -> f64 {
+    slice[0] as f64 * 256.0 + slice[1] as f64
+}
+
+/// `A` and `B` as a two's complement value
+fn signed_word_ab(slice: &[u8]) -> f64 {
+    i16::from_be_bytes([slice[0], slice[1]]) as f64
+}
+
+/// `2^24 A + 2^16 B + 2^8 C + D`
+fn dword_abcd(slice: &[u8]) -> f64 {
+    u32::from_be_bytes([slice[0], slice[1], slice[2], slice[3]]) as f64
+}
+
+/// `100 A / 255`
+fn percent_a(slice: &[u8]) -> f64 {
+    byte_a(slice) * 100.0 / 255.0
+}
+
+/// `A - 40`
+fn temperature_a(slice: &[u8]) -> f64 {
+    byte_a(slice) - 40.0
+}
+
+/// `100 A / 128 - 100`
+fn fuel_trim_a(slice: &[u8]) -> f64 {
+    byte_a(slice) * 100.0 / 128.0 - 100.0
+}
+
+/// `A / 200`, narrow band O2 sensor voltage. `B` (short term fuel trim) is ignored
+fn o2_voltage_a(slice: &[u8]) -> f64 {
+    byte_a(slice) / 200.0
+}
+
+/// `2 (256A + B) / 65536`, wide band O2 sensor equivalence ratio (lambda). `C` and `D`, the sensor voltage
+/// (PIDs `24` - `2B`) or current (`34` - `3B`), are ignored
+fn equivalence_ratio_ab(slice: &[u8]) -> f64 {
+    word_ab(slice) * 2.0 / 65536.0
+}
+
+/// `(256A + B) / 10 - 40`
+fn catalyst_temperature_ab(slice: &[u8]) -> f64 {
+    word_ab(slice) / 10.0 - 40.0
+}
+
+/// `A - 125`
+fn torque_percent_a(slice: &[u8]) -> f64 {
+    byte_a(slice) - 125.0
+}
+
+
+pub const MONITOR_STATUS_SINCE_DTCS_CLEARED_PID: PidCommand = PidCommand::new(0x01, 4, "Monitor status since DTCs cleared", PidUnits::Bitfield, dword_abcd);
+pub const FREEZE_DTC_PID: PidCommand = PidCommand::new(0x02, 2, "DTC that caused freeze frame", PidUnits::Bitfield, word_ab);
+pub const FUEL_SYSTEM_STATUS_PID: PidCommand = PidCommand::new(0x03, 2, "Fuel system status", PidUnits::Bitfield, word_ab);
+pub const CALCULATED_ENGINE_LOAD_PID: PidCommand = PidCommand::new(0x04, 1, "Calculated engine load", PidUnits::Percent, percent_a);
+pub const SHORT_TERM_FUEL_TRIM_BANK_1_PID: PidCommand = PidCommand::new(0x06, 1, "Short term fuel trim, bank 1", PidUnits::Percent, fuel_trim_a);
+pub const LONG_TERM_FUEL_TRIM_BANK_1_PID: PidCommand = PidCommand::new(0x07, 1, "Long term fuel trim, bank 1", PidUnits::Percent, fuel_trim_a);
+pub const SHORT_TERM_FUEL_TRIM_BANK_2_PID: PidCommand = PidCommand::new(0x08, 1, "Short term fuel trim, bank 2", PidUnits::Percent, fuel_trim_a);
+pub const LONG_TERM_FUEL_TRIM_BANK_2_PID: PidCommand = PidCommand::new(0x09, 1, "Long term fuel trim, bank 2", PidUnits::Percent, fuel_trim_a);
+pub const FUEL_PRESSURE_PID: PidCommand = PidCommand::new(0x0a, 1, "Fuel pressure (gauge)", PidUnits::Kpa, |slice| byte_a(slice) * 3.0);
+pub const INTAKE_MANIFOLD_PRESSURE_PID: PidCommand = PidCommand::new(0x0b, 1, "Intake manifold absolute pressure", PidUnits::Kpa, byte_a);
+pub const VEHICLE_SPEED_PID: PidCommand = PidCommand::new(0x0d, 1, "Vehicle speed", PidUnits::KmH, byte_a);
+pub const TIMING_ADVANCE_PID: PidCommand = PidCommand::new(0x0e, 1, "Timing advance", PidUnits::DegreesBeforeTdc, |slice| byte_a(slice) / 2.0 - 64.0);
+pub const INTAKE_AIR_TEMP_PID: PidCommand = PidCommand::new(0x0f, 1, "Intake air temperature", PidUnits::DegreesC, temperature_a);
+pub const MAF_AIR_FLOW_RATE_PID: PidCommand = PidCommand::new(0x10, 2, "MAF air flow rate", PidUnits::GramsPerSecond, |slice| word_ab(slice) / 100.0);
+pub const THROTTLE_POSITION_PID: PidCommand = PidCommand::new(0x11, 1, "Throttle position", PidUnits::Percent, percent_a);
+pub const COMMANDED_SECONDARY_AIR_STATUS_PID: PidCommand = PidCommand::new(0x12, 1, "Commanded secondary air status", PidUnits::Bitfield, byte_a);
+pub const O2_SENSORS_PRESENT_2_BANKS_PID: PidCommand = PidCommand::new(0x13, 1, "O2 sensors present (2 banks)", PidUnits::Bitfield, byte_a);
+pub const O2_SENSOR_1_VOLTAGE_PID: PidCommand = PidCommand::new(0x14, 2, "O2 sensor 1 voltage", PidUnits::Volts, o2_voltage_a);
+pub const O2_SENSOR_2_VOLTAGE_PID: PidCommand = PidCommand::new(0x15, 2, "O2 sensor 2 voltage", PidUnits::Volts, o2_voltage_a);
+pub const O2_SENSOR_3_VOLTAGE_PID: PidCommand = PidCommand::new(0x16, 2, "O2 sensor 3 voltage", PidUnits::Volts, o2_voltage_a);
+pub const O2_SENSOR_4_VOLTAGE_PID: PidCommand = PidCommand::new(0x17, 2, "O2 sensor 4 voltage", PidUnits::Volts, o2_voltage_a);
+pub const O2_SENSOR_5_VOLTAGE_PID: PidCommand = PidCommand::new(0x18, 2, "O2 sensor 5 voltage", PidUnits::Volts, o2_voltage_a);
+pub const O2_SENSOR_6_VOLTAGE_PID: PidCommand = PidCommand::new(0x19, 2, "O2 sensor 6 voltage", PidUnits::Volts, o2_voltage_a);
+pub const O2_SENSOR_7_VOLTAGE_PID: PidCommand = PidCommand::new(0x1a, 2, "O2 sensor 7 voltage", PidUnits::Volts, o2_voltage_a);
+pub const O2_SENSOR_8_VOLTAGE_PID: PidCommand = PidCommand::new(0x1b, 2, "O2 sensor 8 voltage", PidUnits::Volts, o2_voltage_a);
+pub const OBD_STANDARDS_PID: PidCommand = PidCommand::new(0x1c, 1, "OBD standards this vehicle conforms to", PidUnits::Enumerated, byte_a);
+pub const O2_SENSORS_PRESENT_4_BANKS_PID: PidCommand = PidCommand::new(0x1d, 1, "O2 sensors present (4 banks)", PidUnits::Bitfield, byte_a);
+pub const AUXILIARY_INPUT_STATUS_PID: PidCommand = PidCommand::new(0x1e, 1, "Auxiliary input status", PidUnits::Bitfield, byte_a);
+pub const RUN_TIME_SINCE_ENGINE_START_PID: PidCommand = PidCommand::new(0x1f, 2, "Run time since engine start", PidUnits::Seconds, word_ab);
+pub const SUPPORTED_PIDS_21_40_PID: PidCommand = PidCommand::new(0x20, 4, "PIDs supported [21 - 40]", PidUnits::Bitfield, dword_abcd);
+pub const DISTANCE_WITH_MIL_ON_PID: PidCommand = PidCommand::new(0x21, 2, "Distance traveled with MIL on", PidUnits::Km, word_ab);
+pub const FUEL_RAIL_PRESSURE_PID: PidCommand = PidCommand::new(0x22, 2, "Fuel rail pressure (relative to manifold)", PidUnits::Kpa, |slice| word_ab(slice) * 0.079);
+pub const FUEL_RAIL_GAUGE_PRESSURE_PID: PidCommand = PidCommand::new(0x23, 2, "Fuel rail gauge pressure", PidUnits::Kpa, |slice| word_ab(slice) * 10.0);
+pub const O2_SENSOR_1_RATIO_VOLTAGE_PID: PidCommand = PidCommand::new(0x24, 4, "O2 sensor 1 lambda (V)", PidUnits::Ratio, equivalence_ratio_ab);
+pub const O2_SENSOR_2_RATIO_VOLTAGE_PID: PidCommand = PidCommand::new(0x25, 4, "O2 sensor 2 lambda (V)", PidUnits::Ratio, equivalence_ratio_ab);
+pub const O2_SENSOR_3_RATIO_VOLTAGE_PID: PidCommand = PidCommand::new(0x26, 4, "O2 sensor 3 lambda (V)", PidUnits::Ratio, equivalence_ratio_ab);
+pub const O2_SENSOR_4_RATIO_VOLTAGE_PID: PidCommand = PidCommand::new(0x27, 4, "O2 sensor 4 lambda (V)", PidUnits::Ratio, equivalence_ratio_ab);
+pub const O2_SENSOR_5_RATIO_VOLTAGE_PID: PidCommand = PidCommand::new(0x28, 4, "O2 sensor 5 lambda (V)", PidUnits::Ratio, equivalence_ratio_ab);
+pub const O2_SENSOR_6_RATIO_VOLTAGE_PID: PidCommand = PidCommand::new(0x29, 4, "O2 sensor 6 lambda (V)", PidUnits::Ratio, equivalence_ratio_ab);
+pub const O2_SENSOR_7_RATIO_VOLTAGE_PID: PidCommand = PidCommand::new(0x2a, 4, "O2 sensor 7 lambda (V)", PidUnits::Ratio, equivalence_ratio_ab);
+pub const O2_SENSOR_8_RATIO_VOLTAGE_PID: PidCommand = PidCommand::new(0x2b, 4, "O2 sensor 8 lambda (V)", PidUnits::Ratio, equivalence_ratio_ab);
+pub const COMMANDED_EGR_PID: PidCommand = PidCommand::new(0x2c, 1, "Commanded EGR", PidUnits::Percent, percent_a);
+pub const EGR_ERROR_PID: PidCommand = PidCommand::new(0x2d, 1, "EGR error", PidUnits::Percent, fuel_trim_a);
+pub const COMMANDED_EVAPORATIVE_PURGE_PID: PidCommand = PidCommand::new(0x2e, 1, "Commanded evaporative purge", PidUnits::Percent, percent_a);
+pub const FUEL_TANK_LEVEL_PID: PidCommand = PidCommand::new(0x2f, 1, "Fuel tank level input", PidUnits::Percent, percent_a);
+pub const WARM_UPS_SINCE_CODES_CLEARED_PID: PidCommand = PidCommand::new(0x30, 1, "Warm-ups since codes cleared", PidUnits::Count, byte_a);
+pub const DISTANCE_SINCE_CODES_CLEARED_PID: PidCommand = PidCommand::new(0x31, 2, "Distance traveled since codes cleared", PidUnits::Km, word_ab);
+pub const EVAP_SYSTEM_VAPOR_PRESSURE_PID: PidCommand = PidCommand::new(0x32, 2, "Evap. system vapor pressure", PidUnits::Pa, |slice| signed_word_ab(slice) / 4.0);
+pub const BAROMETRIC_PRESSURE_PID: PidCommand = PidCommand::new(0x33, 1, "Absolute barometric pressure", PidUnits::Kpa, byte_a);
+pub const O2_SENSOR_1_RATIO_CURRENT_PID: PidCommand = PidCommand::new(0x34, 4, "O2 sensor 1 lambda (mA)", PidUnits::Ratio, equivalence_ratio_ab);
+pub const O2_SENSOR_2_RATIO_CURRENT_PID: PidCommand = PidCommand::new(0x35, 4, "O2 sensor 2 lambda (mA)", PidUnits::Ratio, equivalence_ratio_ab);
+pub const O2_SENSOR_3_RATIO_CURRENT_PID: PidCommand = PidCommand::new(0x36, 4, "O2 sensor 3 lambda (mA)", PidUnits::Ratio, equivalence_ratio_ab);
+pub const O2_SENSOR_4_RATIO_CURRENT_PID: PidCommand = PidCommand::new(0x37, 4, "O2 sensor 4 lambda (mA)", PidUnits::Ratio, equivalence_ratio_ab);
+pub const O2_SENSOR_5_RATIO_CURRENT_PID: PidCommand = PidCommand::new(0x38, 4, "O2 sensor 5 lambda (mA)", PidUnits::Ratio, equivalence_ratio_ab);
+pub const O2_SENSOR_6_RATIO_CURRENT_PID: PidCommand = PidCommand::new(0x39, 4, "O2 sensor 6 lambda (mA)", PidUnits::Ratio, equivalence_ratio_ab);
+pub const O2_SENSOR_7_RATIO_CURRENT_PID: PidCommand = PidCommand::new(0x3a, 4, "O2 sensor 7 lambda (mA)", PidUnits::Ratio, equivalence_ratio_ab);
+pub const O2_SENSOR_8_RATIO_CURRENT_PID: PidCommand = PidCommand::new(0x3b, 4, "O2 sensor 8 lambda (mA)", PidUnits::Ratio, equivalence_ratio_ab);
+pub const CATALYST_TEMP_BANK_1_SENSOR_1_PID: PidCommand = PidCommand::new(0x3c, 2, "Catalyst temperature, bank 1 sensor 1", PidUnits::DegreesC, catalyst_temperature_ab);
+pub const CATALYST_TEMP_BANK_2_SENSOR_1_PID: PidCommand = PidCommand::new(0x3d, 2, "Catalyst temperature, bank 2 sensor 1", PidUnits::DegreesC, catalyst_temperature_ab);
+pub const CATALYST_TEMP_BANK_1_SENSOR_2_PID: PidCommand = PidCommand::new(0x3e, 2, "Catalyst temperature, bank 1 sensor 2", PidUnits::DegreesC, catalyst_temperature_ab);
+pub const CATALYST_TEMP_BANK_2_SENSOR_2_PID: PidCommand = PidCommand::new(0x3f, 2, "Catalyst temperature, bank 2 sensor 2", PidUnits::DegreesC, catalyst_temperature_ab);
+pub const SUPPORTED_PIDS_41_60_PID: PidCommand = PidCommand::new(0x40, 4, "PIDs supported [41 - 60]", PidUnits::Bitfield, dword_abcd);
+pub const MONITOR_STATUS_THIS_DRIVE_CYCLE_PID: PidCommand = PidCommand::new(0x41, 4, "Monitor status this drive cycle", PidUnits::Bitfield, dword_abcd);
+pub const CONTROL_MODULE_VOLTAGE_PID: PidCommand = PidCommand::new(0x42, 2, "Control module voltage", PidUnits::Volts, |slice| word_ab(slice) / 1000.0);
+pub const ABSOLUTE_LOAD_PID: PidCommand = PidCommand::new(0x43, 2, "Absolute load value", PidUnits::Percent, |slice| word_ab(slice) * 100.0 / 255.0);
+pub const COMMANDED_EQUIVALENCE_RATIO_PID: PidCommand = PidCommand::new(0x44, 2, "Commanded air-fuel equivalence ratio", PidUnits::Ratio, equivalence_ratio_ab);
+pub const RELATIVE_THROTTLE_POSITION_PID: PidCommand = PidCommand::new(0x45, 1, "Relative throttle position", PidUnits::Percent, percent_a);
+pub const AMBIENT_AIR_TEMP_PID: PidCommand = PidCommand::new(0x46, 1, "Ambient air temperature", PidUnits::DegreesC, temperature_a);
+pub const ABSOLUTE_THROTTLE_POSITION_B_PID: PidCommand = PidCommand::new(0x47, 1, "Absolute throttle position B", PidUnits::Percent, percent_a);
+pub const ABSOLUTE_THROTTLE_POSITION_C_PID: PidCommand = PidCommand::new(0x48, 1, "Absolute throttle position C", PidUnits::Percent, percent_a);
+pub const ACCELERATOR_PEDAL_POSITION_D_PID: PidCommand = PidCommand::new(0x49, 1, "Accelerator pedal position D", PidUnits::Percent, percent_a);
+pub const ACCELERATOR_PEDAL_POSITION_E_PID: PidCommand = PidCommand::new(0x4a, 1, "Accelerator pedal position E", PidUnits::Percent, percent_a);
+pub const ACCELERATOR_PEDAL_POSITION_F_PID: PidCommand = PidCommand::new(0x4b, 1, "Accelerator pedal position F", PidUnits::Percent, percent_a);
+pub const COMMANDED_THROTTLE_ACTUATOR_PID: PidCommand = PidCommand::new(0x4c, 1, "Commanded throttle actuator", PidUnits::Percent, percent_a);
+pub const TIME_RUN_WITH_MIL_ON_PID: PidCommand = PidCommand::new(0x4d, 2, "Time run with MIL on", PidUnits::Minutes, word_ab);
+pub const TIME_SINCE_CODES_CLEARED_PID: PidCommand = PidCommand::new(0x4e, 2, "Time since trouble codes cleared", PidUnits::Minutes, word_ab);
+pub const MAX_EQUIVALENCE_RATIO_PID: PidCommand = PidCommand::new(0x4f, 4, "Maximum equivalence ratio", PidUnits::Ratio, byte_a);
+pub const MAX_MAF_AIR_FLOW_RATE_PID: PidCommand = PidCommand::new(0x50, 4, "Maximum MAF air flow rate", PidUnits::GramsPerSecond, |slice| byte_a(slice) * 10.0);
+pub const FUEL_TYPE_PID: PidCommand = PidCommand::new(0x51, 1, "Fuel type", PidUnits::Enumerated, byte_a);
+pub const ETHANOL_FUEL_PERCENT_PID: PidCommand = PidCommand::new(0x52, 1, "Ethanol fuel percentage", PidUnits::Percent, percent_a);
+pub const ABSOLUTE_EVAP_SYSTEM_VAPOR_PRESSURE_PID: PidCommand = PidCommand::new(0x53, 2, "Absolute evap. system vapor pressure", PidUnits::Kpa, |slice| word_ab(slice) / 200.0);
+pub const EVAP_SYSTEM_VAPOR_PRESSURE_WIDE_PID: PidCommand = PidCommand::new(0x54, 2, "Evap. system vapor pressure (wide range)", PidUnits::Pa, signed_word_ab);
+pub const SHORT_TERM_SECONDARY_O2_TRIM_BANK_1_PID: PidCommand = PidCommand::new(0x55, 2, "Short term secondary O2 trim, bank 1", PidUnits::Percent, fuel_trim_a);
+pub const LONG_TERM_SECONDARY_O2_TRIM_BANK_1_PID: PidCommand = PidCommand::new(0x56, 2, "Long term secondary O2 trim, bank 1", PidUnits::Percent, fuel_trim_a);
+pub const SHORT_TERM_SECONDARY_O2_TRIM_BANK_2_PID: PidCommand = PidCommand::new(0x57, 2, "Short term secondary O2 trim, bank 2", PidUnits::Percent, fuel_trim_a);
+pub const LONG_TERM_SECONDARY_O2_TRIM_BANK_2_PID: PidCommand = PidCommand::new(0x58, 2, "Long term secondary O2 trim, bank 2", PidUnits::Percent, fuel_trim_a);
+pub const FUEL_RAIL_ABSOLUTE_PRESSURE_PID: PidCommand = PidCommand::new(0x59, 2, "Fuel rail absolute pressure", PidUnits::Kpa, |slice| word_ab(slice) * 10.0);
+pub const RELATIVE_ACCELERATOR_PEDAL_POSITION_PID: PidCommand = PidCommand::new(0x5a, 1, "Relative accelerator pedal position", PidUnits::Percent, percent_a);
+pub const HYBRID_BATTERY_REMAINING_LIFE_PID: PidCommand = PidCommand::new(0x5b, 1, "Hybrid battery pack remaining life", PidUnits::Percent, percent_a);
+pub const ENGINE_OIL_TEMP_PID: PidCommand = PidCommand::new(0x5c, 1, "Engine oil temperature", PidUnits::DegreesC, temperature_a);
+pub const FUEL_INJECTION_TIMING_PID: PidCommand = PidCommand::new(0x5d, 2, "Fuel injection timing", PidUnits::DegreesBeforeTdc, |slice| word_ab(slice) / 128.0 - 210.0);
+pub const ENGINE_FUEL_RATE_PID: PidCommand = PidCommand::new(0x5e, 2, "Engine fuel rate", PidUnits::LitersPerHour, |slice| word_ab(slice) / 20.0);
+pub const EMISSION_REQUIREMENTS_PID: PidCommand = PidCommand::new(0x5f, 1, "Emission requirements", PidUnits::Enumerated, byte_a);
+pub const SUPPORTED_PIDS_61_80_PID: PidCommand = PidCommand::new(0x60, 4, "PIDs supported [61 - 80]", PidUnits::Bitfield, dword_abcd);
+pub const DRIVER_DEMAND_TORQUE_PID: PidCommand = PidCommand::new(0x61, 1, "Driver's demand engine percent torque", PidUnits::Percent, torque_percent_a);
+pub const ACTUAL_ENGINE_TORQUE_PID: PidCommand = PidCommand::new(0x62, 1, "Actual engine percent torque", PidUnits::Percent, torque_percent_a);
+pub const ENGINE_REFERENCE_TORQUE_PID: PidCommand = PidCommand::new(0x63, 2, "Engine reference torque", PidUnits::NewtonMeters, word_ab);
+pub const ENGINE_PERCENT_TORQUE_DATA_PID: PidCommand = PidCommand::new(0x64, 5, "Engine percent torque at idle", PidUnits::Percent, torque_percent_a);
+pub const SUPPORTED_PIDS_81_A0_PID: PidCommand = PidCommand::new(0x80, 4, "PIDs supported [81 - A0]", PidUnits::Bitfield, dword_abcd);
+pub const SUPPORTED_PIDS_A1_C0_PID: PidCommand = PidCommand::new(0xa0, 4, "PIDs supported [A1 - C0]", PidUnits::Bitfield, dword_abcd);
+pub const ODOMETER_PID: PidCommand = PidCommand::new(0xa6, 4, "Odometer", PidUnits::Km, |slice| dword_abcd(slice) / 10.0);
+pub const SUPPORTED_PIDS_C1_E0_PID: PidCommand = PidCommand::new(0xc0, 4, "PIDs supported [C1 - E0]", PidUnits::Bitfield, dword_abcd);
+pub const SUPPORTED_PIDS_E1_FF_PID: PidCommand = PidCommand::new(0xe0, 4, "PIDs supported [E1 - FF]", PidUnits::Bitfield, dword_abcd);
+
+
+/// Every Mode 01 PID this firmware knows how to decode, sorted by PID
+pub const MODE_01_PID_TABLE: &[PidCommand] = &[
+    HEARTBEAT_PID,
+    MONITOR_STATUS_SINCE_DTCS_CLEARED_PID,
+    FREEZE_DTC_PID,
+    FUEL_SYSTEM_STATUS_PID,
+    CALCULATED_ENGINE_LOAD_PID,
+    ENGINE_COOLANT_TEMP_PID,
+    SHORT_TERM_FUEL_TRIM_BANK_1_PID,
+    LONG_TERM_FUEL_TRIM_BANK_1_PID,
+    SHORT_TERM_FUEL_TRIM_BANK_2_PID,
+    LONG_TERM_FUEL_TRIM_BANK_2_PID,
+    FUEL_PRESSURE_PID,
+    INTAKE_MANIFOLD_PRESSURE_PID,
+    ENGINE_RPM_PID,
+    VEHICLE_SPEED_PID,
+    TIMING_ADVANCE_PID,
+    INTAKE_AIR_TEMP_PID,
+    MAF_AIR_FLOW_RATE_PID,
+    THROTTLE_POSITION_PID,
+    COMMANDED_SECONDARY_AIR_STATUS_PID,
+    O2_SENSORS_PRESENT_2_BANKS_PID,
+    O2_SENSOR_1_VOLTAGE_PID,
+    O2_SENSOR_2_VOLTAGE_PID,
+    O2_SENSOR_3_VOLTAGE_PID,
+    O2_SENSOR_4_VOLTAGE_PID,
+    O2_SENSOR_5_VOLTAGE_PID,
+    O2_SENSOR_6_VOLTAGE_PID,
+    O2_SENSOR_7_VOLTAGE_PID,
+    O2_SENSOR_8_VOLTAGE_PID,
+    OBD_STANDARDS_PID,
+    O2_SENSORS_PRESENT_4_BANKS_PID,
+    AUXILIARY_INPUT_STATUS_PID,
+    RUN_TIME_SINCE_ENGINE_START_PID,
+    SUPPORTED_PIDS_21_40_PID,
+    DISTANCE_WITH_MIL_ON_PID,
+    FUEL_RAIL_PRESSURE_PID,
+    FUEL_RAIL_GAUGE_PRESSURE_PID,
+    O2_SENSOR_1_RATIO_VOLTAGE_PID,
+    O2_SENSOR_2_RATIO_VOLTAGE_PID,
+    O2_SENSOR_3_RATIO_VOLTAGE_PID,
+    O2_SENSOR_4_RATIO_VOLTAGE_PID,
+    O2_SENSOR_5_RATIO_VOLTAGE_PID,
+    O2_SENSOR_6_RATIO_VOLTAGE_PID,
+    O2_SENSOR_7_RATIO_VOLTAGE_PID,
+    O2_SENSOR_8_RATIO_VOLTAGE_PID,
+    COMMANDED_EGR_PID,
+    EGR_ERROR_PID,
+    COMMANDED_EVAPORATIVE_PURGE_PID,
+    FUEL_TANK_LEVEL_PID,
+    WARM_UPS_SINCE_CODES_CLEARED_PID,
+    DISTANCE_SINCE_CODES_CLEARED_PID,
+    EVAP_SYSTEM_VAPOR_PRESSURE_PID,
+    BAROMETRIC_PRESSURE_PID,
+    O2_SENSOR_1_RATIO_CURRENT_PID,
+    O2_SENSOR_2_RATIO_CURRENT_PID,
+    O2_SENSOR_3_RATIO_CURRENT_PID,
+    O2_SENSOR_4_RATIO_CURRENT_PID,
+    O2_SENSOR_5_RATIO_CURRENT_PID,
+    O2_SENSOR_6_RATIO_CURRENT_PID,
+    O2_SENSOR_7_RATIO_CURRENT_PID,
+    O2_SENSOR_8_RATIO_CURRENT_PID,
+    CATALYST_TEMP_BANK_1_SENSOR_1_PID,
+    CATALYST_TEMP_BANK_2_SENSOR_1_PID,
+    CATALYST_TEMP_BANK_1_SENSOR_2_PID,
+    CATALYST_TEMP_BANK_2_SENSOR_2_PID,
+    SUPPORTED_PIDS_41_60_PID,
+    MONITOR_STATUS_THIS_DRIVE_CYCLE_PID,
+    CONTROL_MODULE_VOLTAGE_PID,
+    ABSOLUTE_LOAD_PID,
+    COMMANDED_EQUIVALENCE_RATIO_PID,
+    RELATIVE_THROTTLE_POSITION_PID,
+    AMBIENT_AIR_TEMP_PID,
+    ABSOLUTE_THROTTLE_POSITION_B_PID,
+    ABSOLUTE_THROTTLE_POSITION_C_PID,
+    ACCELERATOR_PEDAL_POSITION_D_PID,
+    ACCELERATOR_PEDAL_POSITION_E_PID,
+    ACCELERATOR_PEDAL_POSITION_F_PID,
+    COMMANDED_THROTTLE_ACTUATOR_PID,
+    TIME_RUN_WITH_MIL_ON_PID,
+    TIME_SINCE_CODES_CLEARED_PID,
+    MAX_EQUIVALENCE_RATIO_PID,
+    MAX_MAF_AIR_FLOW_RATE_PID,
+    FUEL_TYPE_PID,
+    ETHANOL_FUEL_PERCENT_PID,
+    ABSOLUTE_EVAP_SYSTEM_VAPOR_PRESSURE_PID,
+    EVAP_SYSTEM_VAPOR_PRESSURE_WIDE_PID,
+    SHORT_TERM_SECONDARY_O2_TRIM_BANK_1_PID,
+    LONG_TERM_SECONDARY_O2_TRIM_BANK_1_PID,
+    SHORT_TERM_SECONDARY_O2_TRIM_BANK_2_PID,
+    LONG_TERM_SECONDARY_O2_TRIM_BANK_2_PID,
+    FUEL_RAIL_ABSOLUTE_PRESSURE_PID,
+    RELATIVE_ACCELERATOR_PEDAL_POSITION_PID,
+    HYBRID_BATTERY_REMAINING_LIFE_PID,
+    ENGINE_OIL_TEMP_PID,
+    FUEL_INJECTION_TIMING_PID,
+    ENGINE_FUEL_RATE_PID,
+    EMISSION_REQUIREMENTS_PID,
+    SUPPORTED_PIDS_61_80_PID,
+    DRIVER_DEMAND_TORQUE_PID,
+    ACTUAL_ENGINE_TORQUE_PID,
+    ENGINE_REFERENCE_TORQUE_PID,
+    ENGINE_PERCENT_TORQUE_DATA_PID,
+    SUPPORTED_PIDS_81_A0_PID,
+    SUPPORTED_PIDS_A1_C0_PID,
+    ODOMETER_PID,
+    SUPPORTED_PIDS_C1_E0_PID,
+    SUPPORTED_PIDS_E1_FF_PID,
+];
+
+/// Looks up a Mode 01 PID in `MODE_01_PID_TABLE`
+pub fn find_mode_01_pid(pid: u8) -> Option<&'static PidCommand> {
+    MODE_01_PID_TABLE.iter().find(|command| command.standard_pid() == Some(pid))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table_sorted_and_unique() {
+        for pair in MODE_01_PID_TABLE.windows(2) {
+            assert!(pair[0].pid < pair[1].pid, "PID table out of order at {:x}", pair[1].pid);
+        }
+        // names are what's shown on screen, two PIDs with the same one can't be told apart
+        for (index, command) in MODE_01_PID_TABLE.iter().enumerate() {
+            assert!(
+                MODE_01_PID_TABLE[..index].iter().all(|earlier| earlier.name != command.name),
+                "PID {:x} has the same name as an earlier one", command.pid
+            );
+        }
+    }
+
+    #[test]
+    fn test_formulas() {
+        assert_eq!(find_mode_01_pid(0x0c).unwrap().get_value(&[0x1a, 0xf8]), 1726.0);
+        assert_eq!(find_mode_01_pid(0x05).unwrap().get_value(&[0x7b]), 83.0);
+        assert_eq!(find_mode_01_pid(0x06).unwrap().get_value(&[0x80]), 0.0);
+        assert_eq!(find_mode_01_pid(0x0e).unwrap().get_value(&[0x90]), 8.0);
+        assert_eq!(find_mode_01_pid(0x32).unwrap().get_value(&[0xff, 0xfc]), -1.0);
+        assert_eq!(find_mode_01_pid(0x42).unwrap().get_value(&[0x36, 0xb0]), 14.0);
+        assert!(find_mode_01_pid(0x65).is_none());
+    }
+}
@@ -0,0 +1,382 @@
+use arrayvec::ArrayVec;
+use crate::elm_commands::{additive_checksum, HexDigits, StaticCommand, SET_CUSTOM_HEADERS};
+use crate::errors::ToRustAGaugeError;
+
+/// Longest ISO-TP message kept, enough for a VIN (20 bytes) or a few calibration IDs
+pub const MAX_ISO_TP_MESSAGE_LEN: usize = 64;
+
+pub type IsoTpMessage = ArrayVec<u8, MAX_ISO_TP_MESSAGE_LEN>;
+
+/// SAE J1979 physical address of the engine ECU, the source address in the header of its responses
+pub const ENGINE_ECU_ADDRESS: u8 = 0x10;
+/// SAE J1979 physical address of the transmission ECU
+pub const TRANSMISSION_ECU_ADDRESS: u8 = 0x18;
+
+/// Which ECU a response came from, worked out from the header of its frames (`ATH1`)
+#[derive(defmt::Format, Debug, Copy, Clone, PartialEq)]
+pub enum Ecu {
+    /// Source address 0x10, CAN ID 7E8 or 18DAF110
+    Engine,
+    /// Source address 0x18, CAN ID 7E9 or 18DAF118
+    Transmission,
+    /// Any other ECU, by the last byte of its header (ex: `EA` for CAN ID 7EA)
+    Other(u8),
+}
+
+impl Ecu {
+    /// 4 characters for the display, ex: `eng `, or `#EA ` for `Other(0xea)`
+    pub const fn label(&self) -> [u8; 4] {
+        match self {
+            Ecu::Engine => *b"eng ",
+            Ecu::Transmission => *b"trns",
+            Ecu::Other(address) => [b'#', HexDigits::from_val(*address >> 4) as u8, HexDigits::from_val(*address) as u8, b' '],
+        }
+    }
+}
+
+/// OBD protocols as numbered by the ELM327 (`ATSPn` / `ATDPN`)
+#[derive(defmt::Format, Debug, Copy, Clone, PartialEq)]
+pub enum ObdProtocol {
+    /// SAE J1850 PWM (41.6 kbaud)
+    J1850Pwm,
+    /// SAE J1850 VPW (10.4 kbaud)
+    J1850Vpw,
+    Iso9141,
+    /// ISO 14230-4 KWP (5 baud init)
+    Iso14230SlowInit,
+    /// ISO 14230-4 KWP (fast init), what the Hijet uses
+    Iso14230FastInit,
+    /// ISO 15765-4 CAN (11 bit ID, 500 kbaud)
+    Can11Bit500k,
+    /// ISO 15765-4 CAN (29 bit ID, 500 kbaud)
+    Can29Bit500k,
+    /// ISO 15765-4 CAN (11 bit ID, 250 kbaud)
+    Can11Bit250k,
+    /// ISO 15765-4 CAN (29 bit ID, 250 kbaud)
+    Can29Bit250k,
+    /// SAE J1939 CAN (29 bit ID, 250 kbaud)
+    J1939,
+    /// User1 CAN (11 bit ID, 125 kbaud by default)
+    UserCan1,
+    /// User2 CAN (11 bit ID, 50 kbaud by default)
+    UserCan2,
+}
+
+/// How the bytes of one frame are laid out when headers are on (`ATH1`)
+#[derive(defmt::Format, Debug, Copy, Clone, PartialEq)]
+pub enum FrameFormat {
+    /// 3 byte header, data, additive checksum
+    Iso9141,
+    /// Same as `Iso9141`, but the format byte also holds the data length
+    Kwp,
+    /// 3 byte header, data, CRC
+    J1850,
+    /// 11 bit ID (3 hex digits, parsed as 2 bytes), ISO-TP PCI byte(s), then the data. No checksum
+    Can11Bit,
+    /// 29 bit ID (4 bytes), ISO-TP PCI byte(s), then the data. No checksum
+    Can29Bit,
+}
+
+impl ObdProtocol {
+    /// Parses the response to `ATDPN`, ex: `A5` (automatically selected protocol 5) or `6`
+    pub fn from_protocol_number_response(response: &[u8]) -> Option<Self> {
+        let mut digits = response.iter().filter(|c| !c.is_ascii_whitespace());
+        let mut number = digits.next()?;
+        if *number == b'A' {
+            number = digits.next()?;
+        }
+        match number {
+            b'1' => Some(ObdProtocol::J1850Pwm),
+            b'2' => Some(ObdProtocol::J1850Vpw),
+            b'3' => Some(ObdProtocol::Iso9141),
+            b'4' => Some(ObdProtocol::Iso14230SlowInit),
+            b'5' => Some(ObdProtocol::Iso14230FastInit),
+            b'6' => Some(ObdProtocol::Can11Bit500k),
+            b'7' => Some(ObdProtocol::Can29Bit500k),
+            b'8' => Some(ObdProtocol::Can11Bit250k),
+            b'9' => Some(ObdProtocol::Can29Bit250k),
+            b'A' => Some(ObdProtocol::J1939),
+            b'B' => Some(ObdProtocol::UserCan1),
+            b'C' => Some(ObdProtocol::UserCan2),
+            _ => None,
+        }
+    }
+
+    /// The digit `ATSPn` / `ATDPN` use
+    pub const fn number(&self) -> u8 {
+        match self {
+            ObdProtocol::J1850Pwm => b'1',
+            ObdProtocol::J1850Vpw => b'2',
+            ObdProtocol::Iso9141 => b'3',
+            ObdProtocol::Iso14230SlowInit => b'4',
+            ObdProtocol::Iso14230FastInit => b'5',
+            ObdProtocol::Can11Bit500k => b'6',
+            ObdProtocol::Can29Bit500k => b'7',
+            ObdProtocol::Can11Bit250k => b'8',
+            ObdProtocol::Can29Bit250k => b'9',
+            ObdProtocol::J1939 => b'A',
+            ObdProtocol::UserCan1 => b'B',
+            ObdProtocol::UserCan2 => b'C',
+        }
+    }
+
+    pub const fn frame_format(&self) -> FrameFormat {
+        match self {
+            ObdProtocol::J1850Pwm | ObdProtocol::J1850Vpw => FrameFormat::J1850,
+            ObdProtocol::Iso9141 => FrameFormat::Iso9141,
+            ObdProtocol::Iso14230SlowInit | ObdProtocol::Iso14230FastInit => FrameFormat::Kwp,
+            ObdProtocol::Can11Bit500k | ObdProtocol::Can11Bit250k |
+            ObdProtocol::UserCan1 | ObdProtocol::UserCan2 => FrameFormat::Can11Bit,
+            ObdProtocol::Can29Bit500k | ObdProtocol::Can29Bit250k | ObdProtocol::J1939 => FrameFormat::Can29Bit,
+        }
+    }
+
+    /// Header to set after the protocol is known. `None` means the ELM's default header for the protocol is fine.
+    /// KWP uses physical addressing to the engine ECU, which is what the Hijet needs
+    pub const fn header_command(&self) -> Option<StaticCommand> {
+        match self.frame_format() {
+            FrameFormat::Kwp => Some(SET_CUSTOM_HEADERS),
+            _ => None,
+        }
+    }
+}
+
+impl FrameFormat {
+    /// Bytes before the data (KWP/ISO 9141/J1850) or before the PCI byte (CAN)
+    pub const fn header_len(&self) -> usize {
+        match self {
+            FrameFormat::Iso9141 | FrameFormat::Kwp | FrameFormat::J1850 => 3,
+            FrameFormat::Can11Bit => 2,
+            FrameFormat::Can29Bit => 4,
+        }
+    }
+
+    pub const fn is_can(&self) -> bool {
+        matches!(self, FrameFormat::Can11Bit | FrameFormat::Can29Bit)
+    }
+
+    /// The ECU that sent `frame`. The source address is the last byte of the header in every format:
+    /// the third header byte, or the last byte of the CAN ID. 11 bit CAN IDs number the ECUs from 7E8 up instead
+    pub fn source_ecu(&self, frame: &[u8]) -> Option<Ecu> {
+        let address = *frame.get(self.header_len() - 1)?;
+        let ecu = match (self, address) {
+            (FrameFormat::Can11Bit, 0xe8) => Ecu::Engine,
+            (FrameFormat::Can11Bit, 0xe9) => Ecu::Transmission,
+            (FrameFormat::Can11Bit, _) => Ecu::Other(address),
+            (_, ENGINE_ECU_ADDRESS) => Ecu::Engine,
+            (_, TRANSMISSION_ECU_ADDRESS) => Ecu::Transmission,
+            _ => Ecu::Other(address),
+        };
+        Some(ecu)
+    }
+
+    /// Checks one frame (one line of a response, see `ResponseParser`) and returns its data:
+    /// everything between the header and the checksum, or for CAN everything after the PCI byte 
+    /// up to the length in it (the ELM can include padding bytes). 
+    /// Only CAN single frames can be handled here, multi-frame CAN messages go through `iso_tp_message`
+    pub fn frame_data<'b>(&self, frame: &'b [u8]) -> Result<&'b [u8], ToRustAGaugeError> {
+        const LENGTH_MASK: u8 = 0b_0011_1111;
+
+        let header_len = self.header_len();
+        let frame_len = frame.len();
+        match self {
+            FrameFormat::Iso9141 | FrameFormat::Kwp | FrameFormat::J1850 => {
+                if frame_len < header_len + 2 {
+                    defmt::warn!("UartIncorrectLengthError: {:?}", frame);
+                    return Err(ToRustAGaugeError::UartIncorrectLengthError())
+                }
+                let data_len = (frame[0] & LENGTH_MASK) as usize;
+                if *self == FrameFormat::Kwp && data_len != 0 && frame_len != header_len + data_len + 1 {
+                    defmt::warn!("UartIncorrectLengthError: {:?}", frame);
+                    return Err(ToRustAGaugeError::UartIncorrectLengthError())
+                }
+                let expected_checksum = match self {
+                    FrameFormat::J1850 => j1850_crc(&frame[0..frame_len-1]),
+                    _ => additive_checksum(&frame[0..frame_len-1]),
+                };
+                if frame[frame_len-1] != expected_checksum {
+                    return Err(ToRustAGaugeError::UartBadChecksumError())
+                }
+                Ok(&frame[header_len..frame_len-1])
+            }
+            FrameFormat::Can11Bit | FrameFormat::Can29Bit => {
+                if frame_len < header_len + 2 {
+                    defmt::warn!("UartIncorrectLengthError: {:?}", frame);
+                    return Err(ToRustAGaugeError::UartIncorrectLengthError())
+                }
+                let pci = frame[header_len];
+                if pci >> 4 != IsoTpFrameType::Single as u8 {
+                    defmt::warn!("Expected a single CAN frame, got PCI {:x}: {:?}", pci, frame);
+                    return Err(ToRustAGaugeError::UartMissingFrameError())
+                }
+                let data_len = (pci & 0x0f) as usize;
+                let data_start = header_len + 1;
+                if data_len == 0 || data_start + data_len > frame_len {
+                    defmt::warn!("UartIncorrectLengthError: {:?}", frame);
+                    return Err(ToRustAGaugeError::UartIncorrectLengthError())
+                }
+                Ok(&frame[data_start..data_start + data_len])
+            }
+        }
+    }
+
+    /// Puts a CAN (ISO 15765-2) message back together from a single frame, or a first frame followed by 
+    /// consecutive frames. Only frames from the same ID as the first one are used, 
+    /// so another ECU answering at the same time doesn't get mixed in
+    pub fn iso_tp_message<'a>(&self, frames: impl Iterator<Item = &'a [u8]>) -> Result<IsoTpMessage, ToRustAGaugeError> {
+        let header_len = self.header_len();
+        let mut message = IsoTpMessage::new();
+        let mut source: Option<&[u8]> = None;
+        let mut expected_len: usize = 0;
+        let mut next_sequence: u8 = 1;
+
+        for frame in frames {
+            if frame.len() < header_len + 2 {
+                defmt::warn!("UartIncorrectLengthError: {:?}", frame);
+                return Err(ToRustAGaugeError::UartIncorrectLengthError())
+            }
+            let (id, rest) = frame.split_at(header_len);
+            match source {
+                None => source = Some(id),
+                Some(first_id) if first_id != id => {
+                    defmt::debug!("Ignoring CAN frame from {:?} while reassembling a message from {:?}", id, first_id);
+                    continue;
+                }
+                Some(_) => {}
+            }
+            let pci = rest[0];
+            let payload = match IsoTpFrameType::from_pci(pci) {
+                Some(IsoTpFrameType::Single) if expected_len == 0 => {
+                    // the length of a single frame is one nibble, so this always fits
+                    return self.frame_data(frame).map(|data| data.iter().copied().collect())
+                }
+                Some(IsoTpFrameType::First) if expected_len == 0 && rest.len() > 2 => {
+                    expected_len = ((pci & 0x0f) as usize) << 8 | rest[1] as usize;
+                    if expected_len > MAX_ISO_TP_MESSAGE_LEN {
+                        defmt::warn!("CAN message is {} bytes, more than the {} that fit", expected_len, MAX_ISO_TP_MESSAGE_LEN);
+                        return Err(ToRustAGaugeError::UartBufferOverflowError())
+                    }
+                    &rest[2..]
+                }
+                Some(IsoTpFrameType::Consecutive) if expected_len != 0 => {
+                    if pci & 0x0f != next_sequence {
+                        defmt::warn!("CAN consecutive frame {} arrived, expected {}", pci & 0x0f, next_sequence);
+                        return Err(ToRustAGaugeError::UartMissingFrameError())
+                    }
+                    next_sequence = (next_sequence + 1) & 0x0f;
+                    &rest[1..]
+                }
+                _ => {
+                    defmt::warn!("Unexpected CAN frame while reassembling a message: {:?}", frame);
+                    return Err(ToRustAGaugeError::UartMissingFrameError())
+                }
+            };
+            // the last frame is padded, only take what's left of the message
+            let take = payload.len().min(expected_len - message.len());
+            message.extend(payload[..take].iter().copied());
+            if message.len() == expected_len {
+                return Ok(message)
+            }
+        }
+        defmt::warn!("CAN message ended after {} of {} bytes", message.len(), expected_len);
+        Err(ToRustAGaugeError::UartMissingFrameError())
+    }
+}
+
+/// Top nibble of the first byte after the CAN ID (the PCI)
+#[repr(u8)]
+#[derive(defmt::Format, Debug, Copy, Clone, PartialEq)]
+enum IsoTpFrameType {
+    Single = 0,
+    First = 1,
+    Consecutive = 2,
+    FlowControl = 3,
+}
+
+impl IsoTpFrameType {
+    const fn from_pci(pci: u8) -> Option<Self> {
+        match pci >> 4 {
+            0 => Some(IsoTpFrameType::Single),
+            1 => Some(IsoTpFrameType::First),
+            2 => Some(IsoTpFrameType::Consecutive),
+            3 => Some(IsoTpFrameType::FlowControl),
+            _ => None,
+        }
+    }
+}
+
+/// SAE J1850 CRC-8: polynomial 0x1D, initial value 0xFF, inverted at the end
+pub fn j1850_crc(bytes: &[u8]) -> u8 {
+    let mut crc: u8 = 0xff;
+    for byte in bytes {
+        crc ^= *byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x1d } else { crc << 1 };
+        }
+    }
+    !crc
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_protocol_number_parsing() {
+        assert_eq!(ObdProtocol::from_protocol_number_response(b"A5\r\r"), Some(ObdProtocol::Iso14230FastInit));
+        assert_eq!(ObdProtocol::from_protocol_number_response(b"6\r\r"), Some(ObdProtocol::Can11Bit500k));
+        assert_eq!(ObdProtocol::from_protocol_number_response(b"AA\r\r"), Some(ObdProtocol::J1939));
+        assert_eq!(ObdProtocol::from_protocol_number_response(b"A0\r\r"), None);
+        assert_eq!(ObdProtocol::from_protocol_number_response(b"?\r\r"), None);
+    }
+
+    #[test]
+    fn test_can_single_frame() {
+        // 7E8 06 41 00 BE 3F A8 13 00, padded to 8 data bytes
+        let frame = [0x07, 0xe8, 0x06, 0x41, 0x00, 0xbe, 0x3f, 0xa8, 0x13, 0x00];
+        assert_eq!(FrameFormat::Can11Bit.frame_data(&frame), Ok(&[0x41, 0x00, 0xbe, 0x3f, 0xa8, 0x13][..]));
+        // 18DAF110 04 41 0C 1A F8
+        let frame = [0x18, 0xda, 0xf1, 0x10, 0x04, 0x41, 0x0c, 0x1a, 0xf8];
+        assert_eq!(FrameFormat::Can29Bit.frame_data(&frame), Ok(&[0x41, 0x0c, 0x1a, 0xf8][..]));
+        // PCI says 7 bytes, only 6 are there
+        let frame = [0x07, 0xe8, 0x07, 0x41, 0x00, 0xbe, 0x3f, 0xa8, 0x13];
+        assert_eq!(FrameFormat::Can11Bit.frame_data(&frame), Err(ToRustAGaugeError::UartIncorrectLengthError()));
+    }
+
+    #[test]
+    fn test_iso_tp_reassembly() {
+        // VIN response with a second ECU (7E9) answering in between
+        let frames: [&[u8]; 4] = [
+            &[0x07, 0xe8, 0x10, 0x14, 0x49, 0x02, 0x01, 0x31, 0x47, 0x31],
+            &[0x07, 0xe9, 0x03, 0x7f, 0x09, 0x11],
+            &[0x07, 0xe8, 0x21, 0x4a, 0x43, 0x35, 0x34, 0x34, 0x34, 0x52],
+            &[0x07, 0xe8, 0x22, 0x37, 0x32, 0x35, 0x32, 0x33, 0x36, 0x37],
+        ];
+        let message = FrameFormat::Can11Bit.iso_tp_message(frames.iter().copied()).unwrap();
+        assert_eq!(message.as_slice(), b"\x49\x02\x011G1JC5444R7252367");
+
+        let missing_frame: [&[u8]; 2] = [frames[0], frames[3]];
+        assert_eq!(
+            FrameFormat::Can11Bit.iso_tp_message(missing_frame.iter().copied()),
+            Err(ToRustAGaugeError::UartMissingFrameError())
+        );
+    }
+
+    #[test]
+    fn test_source_ecu() {
+        assert_eq!(FrameFormat::Kwp.source_ecu(&[0x83, 0xf1, 0x10, 0x61, 0x0c, 0x1a]), Some(Ecu::Engine));
+        assert_eq!(FrameFormat::J1850.source_ecu(&[0x48, 0x6b, 0x18, 0x41]), Some(Ecu::Transmission));
+        assert_eq!(FrameFormat::Can11Bit.source_ecu(&[0x07, 0xe8, 0x04, 0x41]), Some(Ecu::Engine));
+        assert_eq!(FrameFormat::Can11Bit.source_ecu(&[0x07, 0xe9, 0x04, 0x41]), Some(Ecu::Transmission));
+        assert_eq!(FrameFormat::Can11Bit.source_ecu(&[0x07, 0xea, 0x04, 0x41]), Some(Ecu::Other(0xea)));
+        assert_eq!(FrameFormat::Can29Bit.source_ecu(&[0x18, 0xda, 0xf1, 0x18, 0x04, 0x41]), Some(Ecu::Transmission));
+        assert_eq!(FrameFormat::Can29Bit.source_ecu(&[0x18, 0xda]), None);
+    }
+
+    #[test]
+    fn test_j1850_crc() {
+        // J1850 request for PID 0x00: 68 6A F1 01 00, CRC 17
+        assert_eq!(j1850_crc(&[0x68, 0x6a, 0xf1, 0x01, 0x00]), 0x17);
+    }
+}
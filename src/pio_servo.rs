@@ -1,5 +1,5 @@
 use core::time::Duration;
-use embassy_rp::pio::{Common, Config, Direction, Instance, InterruptHandler, Pio, PioPin, StateMachine};
+use embassy_rp::pio::{Common, Config, Direction, Instance, InterruptHandler, Pin, Pio, PioPin, StateMachine};
 use embassy_rp::{bind_interrupts, clocks};
 use embassy_rp::gpio::Level;
 
@@ -19,6 +19,7 @@ pub fn to_pio_cycles(duration: Duration) -> u32 {
 
 pub struct PwmPio<'d, T: Instance, const SM: usize> {
     sm: StateMachine<'d, T, SM>,
+    pin: Pin<'d, T>,
 }
 
 impl<'d, T: Instance, const SM: usize> PwmPio<'d, T, SM> {
@@ -49,15 +50,17 @@ impl<'d, T: Instance, const SM: usize> PwmPio<'d, T, SM> {
 
         sm.set_config(&cfg);
 
-        Self { sm }
+        Self { sm, pin }
     }
 
     pub fn start(&mut self) {
         self.sm.set_enable(true);
     }
 
+    /// The pin is left low, stopping in the middle of a pulse would otherwise leave it high
     pub fn stop(&mut self) {
         self.sm.set_enable(false);
+        self.sm.set_pins(Level::Low, &[&self.pin]);
     }
 
     pub fn set_period(&mut self, duration: Duration) {
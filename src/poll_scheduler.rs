@@ -0,0 +1,284 @@
+use core::cmp::Reverse;
+use arrayvec::ArrayVec;
+use embassy_time::{Duration, Instant};
+use crate::elm_commands::{PidCommand, MAX_PIDS_PER_REQUEST};
+use crate::elm_timing::{ExchangeTiming, LatencyStats};
+use crate::obd_protocol::Ecu;
+
+/// Most items one schedule can hold
+pub const MAX_POLL_ITEMS: usize = 12;
+
+/// An item that hasn't run for this many of its intervals is reported as starved
+const STARVED_AFTER_INTERVALS: u32 = 4;
+
+/// Something the poll loop asks the ELM for on a schedule
+#[derive(defmt::Format, Copy, Clone)]
+pub enum PollItem {
+    Pid(&'static PidCommand),
+    /// `ATRV`, answered by the ELM itself
+    Voltage,
+    /// `ATIGN`, also answered by the ELM, see `IgnitionSense`
+    Ignition,
+    /// Mode 03 and 07, plus the freeze frame if there is a stored code
+    TroubleCodes,
+}
+
+#[derive(defmt::Format, Debug, Copy, Clone, PartialEq, PartialOrd, Eq, Ord)]
+pub enum PollPriority {
+    Low,
+    Normal,
+    High,
+}
+
+/// One line of a poll schedule: what to poll, how often, and what wins when several items are due at once
+#[derive(defmt::Format, Copy, Clone)]
+pub struct PollRegistration {
+    /// Short enough for the error display (11 characters)
+    pub label: &'static str,
+    pub item: PollItem,
+    pub interval: Duration,
+    pub priority: PollPriority,
+    /// The ECU whose answer is used when several answer the same request, `None` for whichever answers first
+    pub ecu: Option<Ecu>,
+}
+
+impl PollRegistration {
+    pub const fn new(label: &'static str, item: PollItem, interval_ms: u64, priority: PollPriority) -> Self {
+        Self {
+            label,
+            item,
+            interval: Duration::from_millis(interval_ms),
+            priority,
+            ecu: None,
+        }
+    }
+
+    /// Use the answer from `ecu`, ex: vehicle speed from the engine rather than the transmission.
+    /// If it stops answering while another ECU does, the poll loop takes the value from that one instead
+    pub const fn from_ecu(mut self, ecu: Ecu) -> Self {
+        self.ecu = Some(ecu);
+        self
+    }
+}
+
+#[derive(defmt::Format, Debug, Copy, Clone, PartialEq, Default)]
+pub struct PollStats {
+    pub runs: u32,
+    pub failures: u32,
+    /// Runs that started more than a whole interval after they were due
+    pub late_runs: u32,
+    /// Round trip of the requests, see `record_latency`
+    pub latency: LatencyStats,
+}
+
+#[derive(defmt::Format)]
+struct ScheduledItem {
+    registration: PollRegistration,
+    next_due: Instant,
+    stats: PollStats,
+    /// Set once the item is reported as starved, so it's only reported once until it runs again
+    starved: bool,
+}
+
+/// Decides what the poll loop asks for next. Whatever is due with the highest priority goes first
+/// (the one that has waited longest if the priorities are the same), so the loop sends requests back to back
+/// while anything is due and low priority items get whatever bandwidth is left
+pub struct PollScheduler {
+    items: ArrayVec<ScheduledItem, MAX_POLL_ITEMS>,
+}
+
+impl PollScheduler {
+    pub fn new() -> Self {
+        Self {
+            items: ArrayVec::new(),
+        }
+    }
+
+    /// Everything registered is due right away. Returns `false` if the schedule is full
+    pub fn register(&mut self, registration: PollRegistration, now: Instant) -> bool {
+        self.items.try_push(ScheduledItem {
+            registration,
+            next_due: now,
+            stats: PollStats::default(),
+            starved: false,
+        }).is_ok()
+    }
+
+    /// The index of the item to poll now, `None` if nothing is due
+    pub fn next_due(&self, now: Instant) -> Option<usize> {
+        self.items.iter()
+            .enumerate()
+            .filter(|(_, item)| item.next_due <= now)
+            // the first registered wins a tie
+            .max_by_key(|(index, item)| (item.registration.priority, now - item.next_due, Reverse(*index)))
+            .map(|(index, _)| index)
+    }
+
+    /// PIDs to send in one request together with the PID at `index` (CAN only, see `ElmDriver::get_pid_batch`):
+    /// any other SAE J1979 PID from the same ECU that is due, or will be within half its interval, highest priority first.
+    /// `index` is always the first one
+    pub fn pid_batch(&self, index: usize, now: Instant) -> ArrayVec<usize, MAX_PIDS_PER_REQUEST> {
+        let ecu = self.items[index].registration.ecu;
+        let mut others: ArrayVec<usize, MAX_POLL_ITEMS> = self.items.iter()
+            .enumerate()
+            .filter(|(other, item)| {
+                *other != index &&
+                    matches!(item.registration.item, PollItem::Pid(PidCommand { standard: true, .. })) &&
+                    item.registration.ecu == ecu &&
+                    item.next_due <= now + item.registration.interval / 2
+            })
+            .map(|(other, _)| other)
+            .collect();
+        others.sort_unstable_by_key(|other| (Reverse(self.items[*other].registration.priority), self.items[*other].next_due));
+        core::iter::once(index)
+            .chain(others)
+            .take(MAX_PIDS_PER_REQUEST)
+            .collect()
+    }
+
+    /// When the next item is due, `None` if nothing is registered
+    pub fn next_wakeup(&self) -> Option<Instant> {
+        self.items.iter().map(|item| item.next_due).min()
+    }
+
+    pub fn registration(&self, index: usize) -> &PollRegistration {
+        &self.items[index].registration
+    }
+
+    /// Takes the item's value from `ecu` from now on, see `PollRegistration::ecu`
+    pub fn set_ecu(&mut self, index: usize, ecu: Option<Ecu>) {
+        self.items[index].registration.ecu = ecu;
+    }
+
+    /// Call after polling the item from `next_due`, `started` is when the request went out.
+    /// The next run is due one interval after this one was due. If that's already in the past,
+    /// it's one interval from now instead, so missed runs are skipped rather than sent back to back to catch up
+    pub fn completed(&mut self, index: usize, started: Instant, success: bool) {
+        let item = &mut self.items[index];
+        let interval = item.registration.interval;
+        item.stats.runs = item.stats.runs.saturating_add(1);
+        if !success {
+            item.stats.failures = item.stats.failures.saturating_add(1);
+        }
+        // batched PIDs can run a little before they are due
+        if let Some(lateness) = started.checked_duration_since(item.next_due).filter(|lateness| *lateness > interval) {
+            item.stats.late_runs = item.stats.late_runs.saturating_add(1);
+            defmt::warn!("{} was polled {} ms late", item.registration.label, lateness.as_millis());
+        }
+        let next_due = item.next_due + interval;
+        item.next_due = if next_due < started { started + interval } else { next_due };
+        item.starved = false;
+    }
+
+    /// How long the request for an item took, for items answered by a single request.
+    /// Pipelined requests are left out, their round trip includes the wait before their response was read
+    pub fn record_latency(&mut self, index: usize, exchange: &ExchangeTiming) {
+        if !exchange.pipelined {
+            self.items[index].stats.latency.record(exchange.round_trip);
+        }
+    }
+
+    /// Items that have been due for several of their intervals without running.
+    /// Each one is only returned once until it runs again
+    pub fn newly_starved(&mut self, now: Instant) -> impl Iterator<Item = &PollRegistration> + '_ {
+        self.items.iter_mut()
+            .filter_map(move |item| {
+                let starved = !item.starved &&
+                    item.next_due < now &&
+                    now - item.next_due > item.registration.interval * STARVED_AFTER_INTERVALS;
+                item.starved |= starved;
+                starved.then_some(&item.registration)
+            })
+    }
+
+    pub fn stats(&self) -> impl Iterator<Item = (&PollRegistration, &PollStats)> + '_ {
+        self.items.iter().map(|item| (&item.registration, &item.stats))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elm_commands::{ENGINE_COOLANT_TEMP_PID, ENGINE_RPM_PID};
+    use crate::mode_01_pids::VEHICLE_SPEED_PID;
+
+    fn at(ms: u64) -> Instant {
+        Instant::from_millis(ms)
+    }
+
+    #[test]
+    fn test_priority_and_rates() {
+        let mut scheduler = PollScheduler::new();
+        scheduler.register(PollRegistration::new("VBat", PollItem::Voltage, 1000, PollPriority::Low), at(0));
+        scheduler.register(PollRegistration::new("RPM", PollItem::Pid(&ENGINE_RPM_PID), 100, PollPriority::High), at(0));
+        scheduler.register(PollRegistration::new("Coolant", PollItem::Pid(&ENGINE_COOLANT_TEMP_PID), 1000, PollPriority::Normal), at(0));
+
+        // everything is due at the start, highest priority first
+        let order: ArrayVec<&str, 3> = (0..3).map(|i| {
+            let index = scheduler.next_due(at(i * 30)).unwrap();
+            scheduler.completed(index, at(i * 30), true);
+            scheduler.registration(index).label
+        }).collect();
+        assert_eq!(order.as_slice(), &["RPM", "Coolant", "VBat"]);
+
+        // only RPM is due again before a second has passed
+        assert_eq!(scheduler.next_due(at(90)), None);
+        assert_eq!(scheduler.next_wakeup(), Some(at(100)));
+        let index = scheduler.next_due(at(160)).unwrap();
+        assert_eq!(scheduler.registration(index).label, "RPM");
+        scheduler.completed(index, at(160), true);
+        assert_eq!(scheduler.next_wakeup(), Some(at(200)));
+    }
+
+    #[test]
+    fn test_pid_batch() {
+        let mut scheduler = PollScheduler::new();
+        scheduler.register(PollRegistration::new("Coolant", PollItem::Pid(&ENGINE_COOLANT_TEMP_PID), 1000, PollPriority::Normal), at(0));
+        scheduler.register(PollRegistration::new("VBat", PollItem::Voltage, 1000, PollPriority::Normal), at(0));
+        scheduler.register(PollRegistration::new("RPM", PollItem::Pid(&ENGINE_RPM_PID), 100, PollPriority::High), at(0));
+        scheduler.register(PollRegistration::new("Speed", PollItem::Pid(&VEHICLE_SPEED_PID), 1000, PollPriority::Low), at(600));
+        scheduler.register(PollRegistration::new("Gearbox", PollItem::Pid(&VEHICLE_SPEED_PID), 1000, PollPriority::Low)
+            .from_ecu(Ecu::Transmission), at(0));
+
+        // voltage can't go in a PID request, speed isn't due soon enough and the gearbox is asked separately
+        let index = scheduler.next_due(at(0)).unwrap();
+        assert_eq!(scheduler.pid_batch(index, at(0)).as_slice(), &[2, 0]);
+        for index in [2, 0] {
+            scheduler.completed(index, at(0), true);
+        }
+        // speed is due within half its interval, coolant isn't
+        let index = scheduler.next_due(at(100)).unwrap();
+        assert_eq!(scheduler.pid_batch(index, at(100)).as_slice(), &[2, 3]);
+        scheduler.completed(3, at(100), true);
+        assert_eq!(scheduler.stats().nth(3).unwrap().1.late_runs, 0);
+        assert_eq!(scheduler.next_wakeup(), Some(at(0)));
+    }
+
+    #[test]
+    fn test_starvation() {
+        let mut scheduler = PollScheduler::new();
+        scheduler.register(PollRegistration::new("RPM", PollItem::Pid(&ENGINE_RPM_PID), 100, PollPriority::High), at(0));
+        scheduler.register(PollRegistration::new("VBat", PollItem::Voltage, 100, PollPriority::Low), at(0));
+
+        // RPM takes up all the time
+        for now in (0..=1000).step_by(100) {
+            let index = scheduler.next_due(at(now)).unwrap();
+            assert_eq!(scheduler.registration(index).label, "RPM");
+            scheduler.completed(index, at(now), true);
+        }
+        let starved: ArrayVec<&str, 2> = scheduler.newly_starved(at(1000)).map(|registration| registration.label).collect();
+        assert_eq!(starved.as_slice(), &["VBat"]);
+        // only reported once
+        assert_eq!(scheduler.newly_starved(at(1000)).count(), 0);
+
+        let index = scheduler.next_due(at(1050)).unwrap();
+        assert_eq!(scheduler.registration(index).label, "VBat");
+        scheduler.completed(index, at(1050), true);
+        let (_, stats) = scheduler.stats().nth(1).unwrap();
+        assert_eq!(stats, &PollStats { runs: 1, failures: 0, late_runs: 1, ..PollStats::default() });
+        // missed runs are skipped, not sent back to back
+        assert_eq!(scheduler.next_due(at(1060)), None);
+        assert_eq!(scheduler.next_wakeup(), Some(at(1100)));
+    }
+}
@@ -0,0 +1,388 @@
+use arrayvec::ArrayVec;
+use crate::byte_parsing::parse_byte;
+use crate::elm_commands::MAX_STPX_COMMAND_LEN;
+use crate::errors::ToRustAGaugeError;
+use crate::obd_protocol::FrameFormat;
+
+/// Decoded bytes kept from one response, every frame together. Two hex digits make a byte,
+/// so this is enough for a response of over 256 digits
+pub const MAX_RESPONSE_BYTES: usize = 128;
+/// Most lines kept from a single response
+pub const MAX_FRAMES_PER_RESPONSE: usize = 16;
+/// Text lines (AT replies, the ELM's own messages) kept from one response, separated by `\r`
+const MAX_TEXT_LEN: usize = 64;
+/// Characters of the current line kept for when it turns out to be text.
+/// Enough for a KWP frame followed by `<DATA ERROR`, anything longer is cut
+const MAX_LINE_LEN: usize = 48;
+/// Longest command whose echo is recognized, an `STPX` request
+const MAX_ECHO_LEN: usize = MAX_STPX_COMMAND_LEN;
+/// The ELM never sends this many characters in answer to one request
+const MAX_RESPONSE_LEN: usize = 1024;
+
+#[derive(defmt::Format, Debug, Copy, Clone, PartialEq)]
+pub enum ParseErrorKind {
+    /// A frame line ended with a digit that doesn't make up a byte
+    OddDigitCount,
+    /// The frames don't fit in `MAX_RESPONSE_BYTES`
+    TooManyBytes,
+    /// More than `MAX_FRAMES_PER_RESPONSE` lines of frames
+    TooManyFrames,
+    /// More than `MAX_RESPONSE_LEN` characters
+    TooLong,
+}
+
+/// What went wrong and where: `position` counts the characters of the response (echo included) up to the one
+/// that caused it, from 0
+#[derive(defmt::Format, Debug, Copy, Clone, PartialEq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub position: usize,
+}
+
+impl From<ParseError> for ToRustAGaugeError {
+    fn from(error: ParseError) -> Self {
+        match error.kind {
+            ParseErrorKind::OddDigitCount => ToRustAGaugeError::UartByteParseError(),
+            _ => ToRustAGaugeError::UartBufferOverflowError(),
+        }
+    }
+}
+
+/// Parses a response from the ELM as it arrives, one character at a time (see `ElmDriver::read_until_char`).
+/// Lines of hex digits are decoded into frames straight away, any other line is text: an AT reply, or one of
+/// the ELM's own messages (`NO DATA`, `SEARCHING...`), which are recognized at the end of their line.
+/// The echo of the command (clones that ignore `ATE0`) is dropped.
+/// Only the decoded bytes and a little text are kept, never the whole response
+pub struct ResponseParser {
+    /// `None` when the whole response is text (AT commands)
+    frame_format: Option<FrameFormat>,
+    echo: ArrayVec<u8, MAX_ECHO_LEN>,
+    /// Characters fed since `start`
+    position: usize,
+    /// Raw characters of the current line, cut at `MAX_LINE_LEN`
+    line: ArrayVec<u8, MAX_LINE_LEN>,
+    /// Cleared once a line that isn't empty has ended
+    first_line: bool,
+    /// Set while the current line is nothing but hex digits and spaces
+    hex_line: bool,
+    digits_in_line: usize,
+    high_digit: Option<u8>,
+    /// Where the last digit was, for `OddDigitCount`
+    last_digit_at: usize,
+    bytes: ArrayVec<u8, MAX_RESPONSE_BYTES>,
+    frame_ends: ArrayVec<u8, MAX_FRAMES_PER_RESPONSE>,
+    text: ArrayVec<u8, MAX_TEXT_LEN>,
+    searching: bool,
+    has_data: bool,
+    elm_error: Option<ToRustAGaugeError>,
+    parse_error: Option<ParseError>,
+}
+
+impl ResponseParser {
+    pub const fn new() -> Self {
+        Self {
+            frame_format: None,
+            echo: ArrayVec::new_const(),
+            position: 0,
+            line: ArrayVec::new_const(),
+            first_line: true,
+            hex_line: true,
+            digits_in_line: 0,
+            high_digit: None,
+            last_digit_at: 0,
+            bytes: ArrayVec::new_const(),
+            frame_ends: ArrayVec::new_const(),
+            text: ArrayVec::new_const(),
+            searching: false,
+            has_data: false,
+            elm_error: None,
+            parse_error: None,
+        }
+    }
+
+    /// Forgets the last response. `command` is what was sent (its echo is dropped), `frame_format` is `None`
+    /// if the response is only text
+    pub fn start(&mut self, command: &[u8], frame_format: Option<FrameFormat>) {
+        let command = command.strip_suffix(b"\r").unwrap_or(command);
+        *self = Self::new();
+        self.frame_format = frame_format;
+        // a command too long to keep can't be recognized, its echo is parsed like any other line
+        if let Ok(echo) = ArrayVec::try_from(command) {
+            self.echo = echo;
+        }
+    }
+
+    pub fn push(&mut self, c: u8) {
+        let position = self.position;
+        self.position += 1;
+        if position == MAX_RESPONSE_LEN {
+            self.fail(ParseErrorKind::TooLong, position);
+        }
+        if position >= MAX_RESPONSE_LEN {
+            return
+        }
+        match c {
+            b'\r' | b'\n' => self.end_line(position),
+            _ => {
+                let _ = self.line.try_push(c);
+                if self.hex_line && self.frame_format.is_some() {
+                    self.push_digit(c, position);
+                }
+            }
+        }
+    }
+
+    /// Call at the prompt, for the last line if it didn't end with `\r`
+    pub fn finish(&mut self) {
+        self.end_line(self.position);
+    }
+
+    /// The frames of the response, one per line, as bytes
+    pub fn frames(&self) -> impl Iterator<Item = &[u8]> + Clone + '_ {
+        let mut start: usize = 0;
+        self.frame_ends.iter().map(move |end| {
+            let frame = &self.bytes[start..*end as usize];
+            start = *end as usize;
+            frame
+        })
+    }
+
+    /// Every line that isn't a frame, separated by `\r`
+    pub fn text(&self) -> &[u8] {
+        &self.text
+    }
+
+    /// The first of the ELM's own messages in the response, as an error.
+    /// `SEARCHING...` is only an error if nothing came after it
+    pub fn elm_error(&self) -> Option<ToRustAGaugeError> {
+        match &self.elm_error {
+            Some(error) => Some(error.clone()),
+            None if self.searching && !self.has_data => Some(ToRustAGaugeError::ElmStillSearching()),
+            None => None,
+        }
+    }
+
+    /// The first thing that couldn't be parsed
+    pub fn parse_error(&self) -> Option<ParseError> {
+        self.parse_error
+    }
+
+    fn push_digit(&mut self, c: u8, position: usize) {
+        if c == b' ' {
+            return
+        }
+        let Ok(digit) = parse_byte(&c) else {
+            // not a frame after all (ex: `BUS INIT: ...OK`), the whole line is text
+            self.hex_line = false;
+            self.high_digit = None;
+            self.bytes.truncate(self.frame_start());
+            return
+        };
+        // 11 bit CAN IDs are 3 digits, `7E8` is parsed as `07 E8`
+        if self.digits_in_line == 0 && self.frame_format == Some(FrameFormat::Can11Bit) {
+            self.high_digit = Some(0);
+        }
+        self.digits_in_line += 1;
+        self.last_digit_at = position;
+        match self.high_digit.take() {
+            None => self.high_digit = Some(digit),
+            Some(high) => {
+                if self.bytes.try_push(high << 4 | digit).is_err() {
+                    self.fail(ParseErrorKind::TooManyBytes, position);
+                }
+            }
+        }
+    }
+
+    fn end_line(&mut self, position: usize) {
+        let (start, end) = trimmed(&self.line);
+        if start < end {
+            let is_echo = self.first_line && !self.echo.is_empty() && self.line.as_slice() == self.echo.as_slice();
+            self.first_line = false;
+            if is_echo {
+                self.bytes.truncate(self.frame_start());
+            } else if self.frame_format.is_some() && self.hex_line {
+                self.end_frame(position);
+            } else {
+                self.end_text_line(start, end);
+            }
+        }
+        self.line.clear();
+        self.hex_line = true;
+        self.digits_in_line = 0;
+        self.high_digit = None;
+    }
+
+    fn end_frame(&mut self, position: usize) {
+        self.has_data = true;
+        if self.high_digit.is_some() {
+            self.fail(ParseErrorKind::OddDigitCount, self.last_digit_at);
+            self.bytes.truncate(self.frame_start());
+            return
+        }
+        if self.bytes.len() > self.frame_start() && self.frame_ends.try_push(self.bytes.len() as u8).is_err() {
+            self.fail(ParseErrorKind::TooManyFrames, position);
+            self.bytes.truncate(self.frame_start());
+        }
+    }
+
+    /// Looks for the ELM's own messages and keeps the line in `text`
+    fn end_text_line(&mut self, start: usize, end: usize) {
+        let line = &self.line[start..end];
+        if line.starts_with(b"SEARCHING") {
+            self.searching = true;
+        } else if line.starts_with(b"BUS INIT") {
+            if line.ends_with(b"ERROR") {
+                self.elm_error.get_or_insert(ToRustAGaugeError::ElmBusInitError());
+            }
+        } else {
+            let error = match line {
+                b"NO DATA" => Some(ToRustAGaugeError::UartResponseNoData()),
+                b"?" => Some(ToRustAGaugeError::ElmUnknownCommand()),
+                b"UNABLE TO CONNECT" => Some(ToRustAGaugeError::ElmUnableToConnect()),
+                b"BUS BUSY" => Some(ToRustAGaugeError::ElmBusBusy()),
+                b"BUFFER FULL" => Some(ToRustAGaugeError::ElmBufferFull()),
+                b"STOPPED" => Some(ToRustAGaugeError::ElmStopped()),
+                _ if line.starts_with(b"CAN ERROR") => Some(ToRustAGaugeError::ElmCanError()),
+                // `<DATA ERROR` comes after the bad frame on the same line
+                _ if line.ends_with(b"DATA ERROR") => Some(ToRustAGaugeError::ElmDataError()),
+                _ => None,
+            };
+            match error {
+                Some(error) => { self.elm_error.get_or_insert(error); }
+                None => self.has_data = true,
+            }
+        }
+
+        if !self.text.is_empty() {
+            let _ = self.text.try_push(b'\r');
+        }
+        for c in self.line[start..end].iter() {
+            if self.text.try_push(*c).is_err() {
+                break;
+            }
+        }
+    }
+
+    fn frame_start(&self) -> usize {
+        self.frame_ends.last().map_or(0, |end| *end as usize)
+    }
+
+    fn fail(&mut self, kind: ParseErrorKind, position: usize) {
+        self.parse_error.get_or_insert(ParseError { kind, position });
+    }
+}
+
+impl defmt::Format for ResponseParser {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(f, "Response(text = {:?}, frames = [", core::str::from_utf8(&self.text).unwrap_or("<not ascii>"));
+        for frame in self.frames() {
+            defmt::write!(f, "{:?} ", frame);
+        }
+        defmt::write!(f, "], parse error = {:?})", self.parse_error)
+    }
+}
+
+/// Start and end of `line` without the spaces around it
+fn trimmed(line: &[u8]) -> (usize, usize) {
+    let start = line.iter().position(|c| *c != b' ').unwrap_or(line.len());
+    let end = line.iter().rposition(|c| *c != b' ').map_or(start, |i| i + 1);
+    (start, end)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(response: &[u8], command: &[u8], frame_format: Option<FrameFormat>) -> ResponseParser {
+        let mut parser = ResponseParser::new();
+        parser.start(command, frame_format);
+        for c in response.iter() {
+            parser.push(*c);
+        }
+        parser.finish();
+        parser
+    }
+
+    #[test]
+    fn test_frame_splitting() {
+        let parser = parse(
+            b"SEARCHING...\r87F1104902010000003199\r87 F1 10 49 02 02 47 31 4A 43 12\r\r",
+            b"0902\r",
+            Some(FrameFormat::Kwp),
+        );
+        let mut frames = parser.frames();
+        assert_eq!(frames.next().unwrap(), &[0x87, 0xf1, 0x10, 0x49, 0x02, 0x01, 0x00, 0x00, 0x00, 0x31, 0x99]);
+        assert_eq!(frames.next().unwrap(), &[0x87, 0xf1, 0x10, 0x49, 0x02, 0x02, 0x47, 0x31, 0x4a, 0x43, 0x12]);
+        assert!(frames.next().is_none());
+        assert_eq!(parser.text(), b"SEARCHING...");
+        assert_eq!(parser.elm_error(), None);
+
+        let parser = parse(b"7E8064100BE3FA813\r7E9 03 41 00 80\r\r", b"0100\r", Some(FrameFormat::Can11Bit));
+        let mut frames = parser.frames();
+        assert_eq!(frames.next().unwrap(), &[0x07, 0xe8, 0x06, 0x41, 0x00, 0xbe, 0x3f, 0xa8, 0x13]);
+        assert_eq!(frames.next().unwrap(), &[0x07, 0xe9, 0x03, 0x41, 0x00, 0x80]);
+        assert!(frames.next().is_none());
+    }
+
+    #[test]
+    fn test_parsing() {
+        // upper and lower case digits, with spaces anywhere, even inside a byte
+        let parser = parse(b"00 0102030405060708090A0b0C0 d0E0fff10 1112 131415\n", b"0100\r", Some(FrameFormat::Kwp));
+        let mut expected: ArrayVec<u8, 23> = (0x00..=0x0f).collect();
+        expected.push(0xff);
+        expected.extend(0x10..=0x15);
+        let mut frames = parser.frames();
+        assert_eq!(frames.next(), Some(expected.as_slice()));
+        assert_eq!(frames.next(), None);
+        assert_eq!(parser.parse_error(), None);
+    }
+
+    #[test]
+    fn test_echo_and_text() {
+        // echo on (before ATE0), so the command comes back first
+        let parser = parse(b"010C\r83F110410C1AF8F3\r\r", b"010C\r", Some(FrameFormat::Kwp));
+        assert_eq!(parser.frames().count(), 1);
+        assert_eq!(parser.text(), b"");
+
+        let parser = parse(b"ATZ\r\r\rELM327 v1.5\r\r", b"ATZ\r", None);
+        assert_eq!(parser.text(), b"ELM327 v1.5");
+        assert_eq!(parser.frames().count(), 0);
+        // in text responses hex digits are text too
+        assert_eq!(parse(b"A6\r\r", b"ATDPN\r", None).text(), b"A6");
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let parser = parse(b"83F110410C1AF8F\r\r", b"010C\r", Some(FrameFormat::Kwp));
+        assert_eq!(parser.parse_error(), Some(ParseError { kind: ParseErrorKind::OddDigitCount, position: 14 }));
+        assert_eq!(parser.frames().count(), 0);
+
+        let mut long_response: ArrayVec<u8, 300> = ArrayVec::new();
+        for _ in 0..MAX_RESPONSE_BYTES + 1 {
+            long_response.try_extend_from_slice(b"00").unwrap();
+        }
+        let parser = parse(&long_response, b"0100\r", Some(FrameFormat::Kwp));
+        assert_eq!(parser.parse_error(), Some(ParseError { kind: ParseErrorKind::TooManyBytes, position: MAX_RESPONSE_BYTES * 2 + 1 }));
+    }
+
+    #[test]
+    fn test_elm_error_detection() {
+        let cases: [(&[u8], Option<ToRustAGaugeError>); 9] = [
+            (b"NO DATA\r\r", Some(ToRustAGaugeError::UartResponseNoData())),
+            (b"SEARCHING...\rNO DATA\r\r", Some(ToRustAGaugeError::UartResponseNoData())),
+            (b"?\r\r", Some(ToRustAGaugeError::ElmUnknownCommand())),
+            (b"BUS INIT: ...ERROR\r\r", Some(ToRustAGaugeError::ElmBusInitError())),
+            (b"BUS INIT: ...OK\r83F11061", None),
+            (b"SEARCHING...\r\r", Some(ToRustAGaugeError::ElmStillSearching())),
+            (b"SEARCHING...\r7E8064100BE3FA813\r\r", None),
+            (b"CAN ERROR\r\r", Some(ToRustAGaugeError::ElmCanError())),
+            (b"83F110610C1A <DATA ERROR\r\r", Some(ToRustAGaugeError::ElmDataError())),
+        ];
+        for (response, expected) in cases {
+            let parser = parse(response, b"0100\r", Some(FrameFormat::Kwp));
+            assert_eq!(parser.elm_error(), expected, "{:?}", core::str::from_utf8(response));
+        }
+    }
+}
@@ -0,0 +1,104 @@
+use crate::elm_commands::{PidCommand, HEARTBEAT_PID};
+use crate::mode_01_pids::{SUPPORTED_PIDS_21_40_PID, SUPPORTED_PIDS_41_60_PID, SUPPORTED_PIDS_61_80_PID,
+                          SUPPORTED_PIDS_81_A0_PID, SUPPORTED_PIDS_A1_C0_PID, SUPPORTED_PIDS_C1_E0_PID,
+                          SUPPORTED_PIDS_E1_FF_PID};
+
+/// Number of PIDs described by the bitmap returned from one of the "PIDs supported" PIDs
+const PIDS_PER_RANGE: usize = 32;
+const NUM_RANGES: usize = 8;
+
+/// The PIDs that report which of the next 32 PIDs are supported, in the order they should be asked.
+/// The last bit of each bitmap says whether the next range PID is supported, so discovery stops at the
+/// first range that isn't.
+pub const SUPPORTED_PID_RANGE_COMMANDS: [PidCommand; NUM_RANGES] = [
+    HEARTBEAT_PID,
+    SUPPORTED_PIDS_21_40_PID,
+    SUPPORTED_PIDS_41_60_PID,
+    SUPPORTED_PIDS_61_80_PID,
+    SUPPORTED_PIDS_81_A0_PID,
+    SUPPORTED_PIDS_A1_C0_PID,
+    SUPPORTED_PIDS_C1_E0_PID,
+    SUPPORTED_PIDS_E1_FF_PID,
+];
+
+/// Which Mode 01 PIDs the ECU claims to support, decoded from the PID 0x00, 0x20, 0x40 ... bitmaps.
+/// `ranges[n]` holds the bitmap returned by PID `n * 0x20`, describing PIDs `n * 0x20 + 1` to `n * 0x20 + 0x20`.
+/// The most significant bit is the lowest PID.
+#[derive(defmt::Format, Debug, Clone, PartialEq)]
+pub struct SupportedPids {
+    ranges: [u32; NUM_RANGES],
+}
+
+impl SupportedPids {
+    /// Nothing known yet, only PID 0x00 is assumed to be supported
+    pub const fn new() -> Self {
+        Self {
+            ranges: [0u32; NUM_RANGES],
+        }
+    }
+
+    /// Used when the ECU won't answer PID 0x00. Assumes everything is supported, so polling
+    /// behaves the same as it would without discovery.
+    pub const fn all() -> Self {
+        Self {
+            ranges: [u32::MAX; NUM_RANGES],
+        }
+    }
+
+    /// Adds the bitmap returned by `range_pid` (0x00, 0x20, 0x40 ...).
+    /// `bitmap` is the 4 data bytes of the response, big endian.
+    /// If several ECUs answer, a PID any of them supports counts as supported
+    pub fn add_range(&mut self, range_pid: u8, bitmap: &[u8]) {
+        if bitmap.len() != 4 || range_pid as usize % PIDS_PER_RANGE != 0 {
+            defmt::warn!("Ignoring malformed supported PID bitmap for range {:x}: {:?}", range_pid, bitmap);
+            return;
+        }
+        self.ranges[range_pid as usize / PIDS_PER_RANGE] |= u32::from_be_bytes([bitmap[0], bitmap[1], bitmap[2], bitmap[3]]);
+    }
+
+    /// `is_supported` for a command. Manufacturer specific identifiers aren't in the bitmaps, 
+    /// so they are assumed to be supported
+    pub fn supports(&self, command: &PidCommand) -> bool {
+        command.standard_pid().map_or(true, |pid| self.is_supported(pid))
+    }
+
+    pub fn is_supported(&self, pid: u8) -> bool {
+        if pid == 0x00 {
+            return true;
+        }
+        let index = (pid - 1) as usize;
+        let bit = (PIDS_PER_RANGE - 1) - (index % PIDS_PER_RANGE);
+        self.ranges[index / PIDS_PER_RANGE] & (1 << bit) != 0
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bitmap_decoding() {
+        let mut supported = SupportedPids::new();
+        // PIDs 01, 03-07, 0C-11, 13, 15, 1C, 1F and 20
+        supported.add_range(0x00, &[0xbe, 0x1f, 0xa8, 0x13]);
+        supported.add_range(0x20, &[0x80, 0x00, 0x00, 0x00]);
+        assert!(supported.is_supported(0x00));
+        assert!(supported.is_supported(0x01));
+        assert!(!supported.is_supported(0x02));
+        assert!(supported.is_supported(0x05));
+        assert!(supported.is_supported(0x0c));
+        assert!(!supported.is_supported(0x0b));
+        assert!(supported.is_supported(0x0e));
+        assert!(supported.is_supported(0x20));
+        assert!(supported.is_supported(0x21));
+        assert!(!supported.is_supported(0x22));
+        assert!(!supported.is_supported(0x41));
+        assert!(SupportedPids::all().is_supported(0xff));
+
+        // a second ECU adds to the first one's PIDs
+        supported.add_range(0x00, &[0x40, 0x00, 0x00, 0x00]);
+        assert!(supported.is_supported(0x02));
+        assert!(supported.is_supported(0x01));
+    }
+}
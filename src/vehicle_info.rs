@@ -0,0 +1,205 @@
+use arrayvec::ArrayVec;
+use defmt::Formatter;
+use crate::errors::{ToRustAGaugeError, DISPLAY_TEXT_LEN};
+use crate::obd_protocol::FrameFormat;
+
+pub const VIN_LEN: usize = 17;
+pub const CALIBRATION_ID_LEN: usize = 16;
+/// Most calibration IDs kept, most vehicles only have one or two
+pub const MAX_CALIBRATION_IDS: usize = 2;
+
+/// Mode 09 info types and response service byte
+pub const VIN_INFO_TYPE: u8 = 0x02;
+pub const CALIBRATION_ID_INFO_TYPE: u8 = 0x04;
+pub const VEHICLE_INFO_RESPONSE_SERVICE: u8 = 0x49;
+
+/// Most payload bytes reassembled from one Mode 09 response
+const MAX_REASSEMBLED_LEN: usize = 64;
+
+pub type CalibrationId = ArrayVec<u8, CALIBRATION_ID_LEN>;
+
+/// Identifies the vehicle, read once (Mode 09) after the ELM is initialized
+#[derive(Debug, Clone, PartialEq)]
+pub struct VehicleInfo {
+    pub vin: Option<[u8; VIN_LEN]>,
+    pub calibration_ids: ArrayVec<CalibrationId, MAX_CALIBRATION_IDS>,
+}
+
+impl VehicleInfo {
+    pub fn new() -> Self {
+        Self {
+            vin: None,
+            calibration_ids: ArrayVec::new(),
+        }
+    }
+
+    /// Boot screen text, same 4 lines of 11 characters as the error strings:
+    /// `VIN:`, the VIN split over 2 lines, then the start of the first calibration ID
+    pub fn to_display_str<'b>(&self, buffer: &'b mut [u8; DISPLAY_TEXT_LEN]) -> &'b str {
+        const TEMPLATE: &'static [u8] = b"VIN:       \n???????????\n??????     \nCAL:???????";
+        const VIN_LINE_1_START: usize = 12;
+        const VIN_LINE_2_START: usize = 24;
+        const CALIBRATION_ID_START: usize = 40;
+        buffer.copy_from_slice(TEMPLATE);
+        if let Some(vin) = &self.vin {
+            buffer[VIN_LINE_1_START..VIN_LINE_1_START + 11].copy_from_slice(&vin[..11]);
+            buffer[VIN_LINE_2_START..VIN_LINE_2_START + 6].copy_from_slice(&vin[11..]);
+        }
+        if let Some(calibration_id) = self.calibration_ids.first() {
+            let len = calibration_id.len().min(DISPLAY_TEXT_LEN - CALIBRATION_ID_START);
+            buffer[CALIBRATION_ID_START..CALIBRATION_ID_START + len].copy_from_slice(&calibration_id[..len]);
+        }
+        // anything that isn't printable would break the layout
+        buffer.iter_mut()
+            .filter(|c| **c != b'\n' && !c.is_ascii_graphic() && **c != b' ')
+            .for_each(|c| *c = b'?');
+        core::str::from_utf8(buffer).unwrap_or("")
+    }
+}
+
+impl defmt::Format for VehicleInfo {
+    fn format(&self, fmt: Formatter) {
+        defmt::write!(fmt, "VehicleInfo(vin = {:?}, calibration_ids = [", self.vin.as_ref().map(|vin| core::str::from_utf8(vin).unwrap_or("<not ascii>")));
+        for calibration_id in self.calibration_ids.iter() {
+            defmt::write!(fmt, "{:?} ", core::str::from_utf8(calibration_id).unwrap_or("<not ascii>"));
+        }
+        defmt::write!(fmt, "])")
+    }
+}
+
+/// Puts the frames of a multi-frame Mode 09 response back together. On KWP/ISO 9141/J1850 every frame is
+/// `0x49`, info type, sequence number (starting at 1), then 4 payload bytes. Frames can arrive in any order,
+/// but none may be missing. On CAN it's one ISO-TP message: `0x49`, info type, number of data items, then the payload
+fn reassemble_frames<'a>(frames: impl Iterator<Item = &'a [u8]>, frame_format: FrameFormat, info_type: u8) -> Result<ArrayVec<u8, MAX_REASSEMBLED_LEN>, ToRustAGaugeError> {
+    const PAYLOAD_LEN: usize = 4;
+    const MAX_SEQUENCE: usize = MAX_REASSEMBLED_LEN / PAYLOAD_LEN;
+
+    if frame_format.is_can() {
+        let message = frame_format.iso_tp_message(frames)?;
+        if message.len() < 3 {
+            defmt::warn!("UartIncorrectLengthError: {:?}", message.as_slice());
+            return Err(ToRustAGaugeError::UartIncorrectLengthError())
+        }
+        if message[0] != VEHICLE_INFO_RESPONSE_SERVICE {
+            defmt::warn!("UartServiceMismatchError: {:?}", message.as_slice());
+            return Err(ToRustAGaugeError::UartServiceMismatchError())
+        }
+        if message[1] != info_type {
+            defmt::warn!("UartPidMismatchError: {:?}", message.as_slice());
+            return Err(ToRustAGaugeError::UartPidMismatchError())
+        }
+        return Ok(message[3..].iter().copied().collect())
+    }
+
+    let mut payloads: [Option<[u8; PAYLOAD_LEN]>; MAX_SEQUENCE] = [None; MAX_SEQUENCE];
+    let mut highest_sequence: usize = 0;
+    for frame in frames {
+        let frame_data = frame_format.frame_data(frame)?;
+        if frame_data.len() != 3 + PAYLOAD_LEN {
+            defmt::warn!("UartIncorrectLengthError: {:?}", frame_data);
+            return Err(ToRustAGaugeError::UartIncorrectLengthError())
+        }
+        if frame_data[0] != VEHICLE_INFO_RESPONSE_SERVICE {
+            defmt::warn!("UartServiceMismatchError: {:?}", frame_data);
+            return Err(ToRustAGaugeError::UartServiceMismatchError())
+        }
+        if frame_data[1] != info_type {
+            defmt::warn!("UartPidMismatchError: {:?}", frame_data);
+            return Err(ToRustAGaugeError::UartPidMismatchError())
+        }
+        let sequence = frame_data[2] as usize;
+        if sequence == 0 || sequence > MAX_SEQUENCE {
+            defmt::warn!("Mode 09 frame has an invalid sequence number: {:?}", frame_data);
+            return Err(ToRustAGaugeError::UartMissingFrameError())
+        }
+        payloads[sequence - 1] = Some([frame_data[3], frame_data[4], frame_data[5], frame_data[6]]);
+        highest_sequence = highest_sequence.max(sequence);
+    }
+
+    let mut reassembled: ArrayVec<u8, MAX_REASSEMBLED_LEN> = ArrayVec::new();
+    for payload in payloads[..highest_sequence].iter() {
+        match payload {
+            Some(bytes) => reassembled.extend(bytes.iter().copied()),
+            None => return Err(ToRustAGaugeError::UartMissingFrameError()),
+        }
+    }
+    Ok(reassembled)
+}
+
+/// Decodes a Mode 09 info type 02 response. The VIN is padded at the front with zeros to fill the frames
+pub fn decode_vin<'a>(frames: impl Iterator<Item = &'a [u8]>, frame_format: FrameFormat) -> Result<[u8; VIN_LEN], ToRustAGaugeError> {
+    let reassembled = reassemble_frames(frames, frame_format, VIN_INFO_TYPE)?;
+    let first_char = reassembled.iter().position(|c| *c != 0).unwrap_or(reassembled.len());
+    let vin_bytes = &reassembled[first_char..];
+    if vin_bytes.len() != VIN_LEN {
+        defmt::warn!("UartIncorrectLengthError, VIN was: {:?}", vin_bytes);
+        return Err(ToRustAGaugeError::UartIncorrectLengthError())
+    }
+    let mut vin = [0u8; VIN_LEN];
+    vin.copy_from_slice(vin_bytes);
+    Ok(vin)
+}
+
+/// Decodes a Mode 09 info type 04 response. Each calibration ID is 16 bytes, padded at the end with zeros
+pub fn decode_calibration_ids<'a>(frames: impl Iterator<Item = &'a [u8]>, frame_format: FrameFormat) -> Result<ArrayVec<CalibrationId, MAX_CALIBRATION_IDS>, ToRustAGaugeError> {
+    let reassembled = reassemble_frames(frames, frame_format, CALIBRATION_ID_INFO_TYPE)?;
+    let mut calibration_ids = ArrayVec::new();
+    for chunk in reassembled.chunks(CALIBRATION_ID_LEN) {
+        let calibration_id: CalibrationId = chunk.iter().copied().take_while(|c| *c != 0).collect();
+        if calibration_ids.try_push(calibration_id).is_err() {
+            defmt::warn!("More than {} calibration IDs, ignoring the rest", MAX_CALIBRATION_IDS);
+            break;
+        }
+    }
+    Ok(calibration_ids)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elm_commands::additive_checksum;
+
+    fn kwp_frame(sequence: u8, payload: [u8; 4]) -> [u8; 11] {
+        let mut frame = [0x87, 0xf1, 0x10, 0x49, VIN_INFO_TYPE, sequence, payload[0], payload[1], payload[2], payload[3], 0];
+        frame[10] = additive_checksum(&frame[..10]);
+        frame
+    }
+
+    #[test]
+    fn test_vin_reassembly() {
+        // out of order on purpose
+        let frames = [
+            kwp_frame(2, *b"1GTH"),
+            kwp_frame(1, [0, 0, 0, b'1']),
+            kwp_frame(3, *b"K23D"),
+            kwp_frame(4, *b"76F1"),
+            kwp_frame(5, *b"2345"),
+        ];
+        let vin = decode_vin(frames.iter().map(|frame| frame.as_slice()), FrameFormat::Kwp).unwrap();
+        assert_eq!(&vin, b"11GTHK23D76F12345");
+    }
+
+    #[test]
+    fn test_can_vin() {
+        let frames: [&[u8]; 3] = [
+            &[0x07, 0xe8, 0x10, 0x14, 0x49, 0x02, 0x01, 0x31, 0x47, 0x31],
+            &[0x07, 0xe8, 0x21, 0x4a, 0x43, 0x35, 0x34, 0x34, 0x34, 0x52],
+            &[0x07, 0xe8, 0x22, 0x37, 0x32, 0x35, 0x32, 0x33, 0x36, 0x37],
+        ];
+        let vin = decode_vin(frames.iter().copied(), FrameFormat::Can11Bit).unwrap();
+        assert_eq!(&vin, b"1G1JC5444R7252367");
+    }
+
+    #[test]
+    fn test_vin_missing_frame() {
+        let frames = [
+            kwp_frame(1, [0, 0, 0, b'1']),
+            kwp_frame(3, *b"K23D"),
+        ];
+        assert_eq!(
+            decode_vin(frames.iter().map(|frame| frame.as_slice()), FrameFormat::Kwp),
+            Err(ToRustAGaugeError::UartMissingFrameError())
+        );
+    }
+}